@@ -0,0 +1,248 @@
+use crate::{
+    ControlMessage, DecoderInputItem, DecoderInputQueue, DecoderInputQueueProducer, PopError,
+    PushError,
+};
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::error::Error;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+/// The error returned by [`DecoderInputSink`] when a frame can't be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The consumer side of the queue has been dropped.
+    Closed,
+    /// The queue has been cancelled. See [`CancellationToken`](crate::CancellationToken).
+    Cancelled,
+    /// An item was sent while an earlier one was still pending, without a successful
+    /// `poll_ready` in between. The new item is not sent.
+    NotReady,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => f.write_str("the decoder input queue has been closed"),
+            Self::Cancelled => f.write_str("the decoder input queue has been cancelled"),
+            Self::NotReady => f.write_str("an item was sent before the sink was ready"),
+        }
+    }
+}
+
+impl Error for SendError {}
+
+/// An async producer for a [`DecoderInputQueue`]. Tasks waiting for space are woken by the
+/// consumer instead of spinning.
+pub struct DecoderInputSink<E> {
+    producer: DecoderInputQueueProducer<E>,
+    pending: Option<DecoderInputItem<E>>,
+}
+
+// The pending item is never pinned.
+impl<E> Unpin for DecoderInputSink<E> {}
+
+impl<E> DecoderInputQueueProducer<E> {
+    pub fn into_sink(self) -> DecoderInputSink<E> {
+        DecoderInputSink {
+            producer: self,
+            pending: None,
+        }
+    }
+}
+
+impl<E> DecoderInputSink<E> {
+    /// Returns true once the consumer side of the queue has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.producer.is_closed()
+    }
+
+    fn poll_push_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        let mut registered = false;
+        while let Some(item) = self.pending.take() {
+            match self.producer.push_or_overflow(item) {
+                // Frames dropped by the overflow policy are only counted, as a sink error would
+                // end the stream feeding it.
+                Ok(()) | Err(PushError::Dropped(_)) => {}
+                Err(PushError::Full(item)) => {
+                    self.pending = Some(item);
+                    if registered {
+                        return Poll::Pending;
+                    }
+                    // Try once more after registering in case the consumer made space in between.
+                    self.producer.shared.space_available.register(cx.waker());
+                    registered = true;
+                }
+                Err(PushError::Cancelled(_)) => return Poll::Ready(Err(SendError::Cancelled)),
+                Err(PushError::Closed(_) | PushError::TimedOut(_)) => {
+                    return Poll::Ready(Err(SendError::Closed))
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queues a control message to be sent, like [`Sink::start_send`] does for frames. It must
+    /// be preceded by a successful `poll_ready`, and is pushed by the next `poll_flush`. Control
+    /// messages are never dropped by the overflow policy.
+    pub fn start_send_control(&mut self, message: ControlMessage) -> Result<(), SendError> {
+        self.start_send_item(message.into())
+    }
+
+    fn start_send_item(&mut self, item: DecoderInputItem<E>) -> Result<(), SendError> {
+        if self.producer.shared.is_cancelled() {
+            return Err(SendError::Cancelled);
+        }
+        if self.producer.is_closed() {
+            return Err(SendError::Closed);
+        }
+        if self.pending.is_some() {
+            return Err(SendError::NotReady);
+        }
+        self.pending = Some(item);
+        Ok(())
+    }
+
+    fn poll_close_inner(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        let result = futures_core::ready!(self.poll_push_pending(cx));
+        self.producer.close();
+        Poll::Ready(result)
+    }
+}
+
+impl<E> Sink<Result<XcoderDecoderInputFrame, E>> for DecoderInputSink<E> {
+    type Error = SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.get_mut().poll_push_pending(cx)
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        frame: Result<XcoderDecoderInputFrame, E>,
+    ) -> Result<(), SendError> {
+        self.get_mut().start_send_item(frame.into())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.get_mut().poll_push_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.get_mut().poll_close_inner(cx)
+    }
+}
+
+/// An async consumer for a [`DecoderInputQueue`]. Tasks waiting for frames are woken by the
+/// producer instead of spinning.
+pub struct DecoderInputStream<E> {
+    queue: DecoderInputQueue<E>,
+}
+
+impl<E> DecoderInputQueue<E> {
+    pub fn into_stream(self) -> DecoderInputStream<E> {
+        DecoderInputStream { queue: self }
+    }
+}
+
+impl<E> DecoderInputStream<E> {
+    /// Returns true once the producer has been dropped. Frames it pushed beforehand may still be
+    /// waiting in the queue.
+    pub fn is_closed(&self) -> bool {
+        self.queue.is_closed()
+    }
+
+    /// Takes the control message that ended the stream, if any. The stream can be polled for
+    /// frames again afterwards.
+    pub fn take_control(&mut self) -> Option<ControlMessage> {
+        self.queue.take_control()
+    }
+}
+
+impl<E> Stream for DecoderInputStream<E> {
+    type Item = Result<XcoderDecoderInputFrame, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = &mut self.get_mut().queue;
+        match queue.try_pop() {
+            Ok(frame) => return Poll::Ready(Some(frame)),
+            Err(PopError::Empty) => {}
+            Err(_) => return Poll::Ready(None),
+        }
+        // Try once more after registering in case the producer pushed a frame in between.
+        queue.reader.shared.items_available.register(cx.waker());
+        match queue.try_pop() {
+            Ok(frame) => Poll::Ready(Some(frame)),
+            Err(PopError::Empty) => Poll::Pending,
+            Err(_) => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_frame;
+    use std::task::Waker;
+
+    #[test]
+    fn test_sink_and_stream() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let (queue, producer) = DecoderInputQueue::<()>::new(1);
+        let mut sink = producer.into_sink();
+        let mut stream = queue.into_stream();
+
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+
+        assert!(matches!(
+            Pin::new(&mut sink).poll_ready(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        Pin::new(&mut sink).start_send(Ok(test_frame(0))).unwrap();
+        assert!(matches!(
+            Pin::new(&mut sink).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+
+        // The queue is full, so the next frame stays pending until the stream makes space.
+        Pin::new(&mut sink).start_send(Ok(test_frame(1))).unwrap();
+        assert!(Pin::new(&mut sink).poll_flush(&mut cx).is_pending());
+
+        match Pin::new(&mut stream).poll_next(&mut cx) {
+            Poll::Ready(Some(frame)) => assert_eq!(frame.unwrap().pts, 0),
+            _ => panic!("expected a frame"),
+        }
+        assert!(matches!(
+            Pin::new(&mut sink).poll_close(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+
+        match Pin::new(&mut stream).poll_next(&mut cx) {
+            Poll::Ready(Some(frame)) => assert_eq!(frame.unwrap().pts, 1),
+            _ => panic!("expected a frame"),
+        }
+        assert!(matches!(
+            Pin::new(&mut stream).poll_next(&mut cx),
+            Poll::Ready(None)
+        ));
+    }
+
+    #[test]
+    fn test_send_without_ready() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let (mut queue, producer) = DecoderInputQueue::<()>::new(1);
+        let mut sink = producer.into_sink();
+        Pin::new(&mut sink).start_send(Ok(test_frame(0))).unwrap();
+        assert_eq!(
+            Pin::new(&mut sink).start_send(Ok(test_frame(1))),
+            Err(SendError::NotReady)
+        );
+        assert!(matches!(
+            Pin::new(&mut sink).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(queue.try_pop().unwrap().unwrap().pts, 0);
+    }
+}
@@ -0,0 +1,788 @@
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+use crate::bits::BitReader;
+use crate::framer::{ErrorPolicy, FramerConfig, FramingError};
+use crate::params::Rational;
+use crate::timestamps::{PictureTiming, Timestamper};
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) const OBU_SEQUENCE_HEADER: u8 = 1;
+pub(crate) const OBU_TEMPORAL_DELIMITER: u8 = 2;
+const OBU_FRAME_HEADER: u8 = 3;
+pub(crate) const OBU_FRAME: u8 = 6;
+
+/// The header and size of an OBU, as found at the start of a buffer.
+pub(crate) struct ObuHeader {
+    pub header: u8,
+    /// The length of the header, its extension and its size field.
+    pub header_len: usize,
+    pub size: usize,
+}
+
+impl ObuHeader {
+    pub fn obu_type(&self) -> u8 {
+        self.header >> 3 & 0xf
+    }
+
+    /// Whether the forbidden bit or the reserved bit is set.
+    pub fn is_malformed(&self) -> bool {
+        self.header & 0x81 != 0
+    }
+
+    /// The length of the whole OBU.
+    pub fn len(&self) -> usize {
+        self.header_len + self.size
+    }
+
+    /// Reads the header of the OBU at the start of `buf`, or returns `Ok(None)` if `buf` ends
+    /// before its size field does. Fails if the OBU has no size field, or an invalid one, as the
+    /// low overhead bitstream format requires one.
+    pub fn read(buf: &[u8]) -> Result<Option<Self>, ()> {
+        let Some(&header) = buf.first() else {
+            return Ok(None);
+        };
+        if header & 0x2 == 0 {
+            return Err(());
+        }
+        let size_start = if header & 0x4 != 0 { 2 } else { 1 };
+        let mut size = 0u64;
+        // The size is leb128 coded, in at most eight bytes.
+        for (i, &byte) in buf.iter().skip(size_start).take(8).enumerate() {
+            size |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return match usize::try_from(size) {
+                    Ok(size) if size <= u32::MAX as usize => Ok(Some(Self {
+                        header,
+                        header_len: size_start + i + 1,
+                        size,
+                    })),
+                    _ => Err(()),
+                };
+            }
+        }
+        if buf.len() < size_start + 8 {
+            Ok(None)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// An AV1 sequence header. Only the fields needed to time the stream, and to configure a decoder
+/// for it, are kept. The level and tier are those of the first operating point.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Av1SequenceHeader {
+    pub seq_profile: u8,
+    pub still_picture: bool,
+    pub reduced_still_picture_header: bool,
+    /// `num_units_in_display_tick` and `time_scale`, if the sequence header has timing info.
+    pub timing_info: Option<(u32, u32)>,
+    /// `num_ticks_per_picture_minus_1 + 1`, if pictures are evenly spaced.
+    pub num_ticks_per_picture: Option<u32>,
+    pub seq_level_idx: u8,
+    pub seq_tier: bool,
+    pub max_frame_width: u32,
+    pub max_frame_height: u32,
+    pub bit_depth: u8,
+    pub mono_chrome: bool,
+    pub color_primaries: Option<u8>,
+    pub transfer_characteristics: Option<u8>,
+    pub matrix_coefficients: Option<u8>,
+    pub color_range: bool,
+}
+
+impl Av1SequenceHeader {
+    /// Parses the payload of a sequence header OBU, up to and including the colour configuration.
+    pub fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut reader = BitReader::new(payload);
+        let seq_profile = reader.read_bits(3)? as u8;
+        if seq_profile > 2 {
+            return Err(invalid("seq_profile out of range"));
+        }
+        let still_picture = reader.read_flag()?;
+        let reduced_still_picture_header = reader.read_flag()?;
+        let mut timing_info = None;
+        let mut num_ticks_per_picture = None;
+        let (seq_level_idx, seq_tier) = if reduced_still_picture_header {
+            (reader.read_bits(5)? as u8, false)
+        } else {
+            let mut buffer_delay_length = 0;
+            let mut decoder_model_info_present = false;
+            if reader.read_flag()? {
+                timing_info = Some((reader.read_bits(32)?, reader.read_bits(32)?));
+                if reader.read_flag()? {
+                    // uvlc() codes num_ticks_per_picture_minus_1 like an Exp-Golomb code.
+                    num_ticks_per_picture = Some(reader.read_ue()?.saturating_add(1));
+                }
+                decoder_model_info_present = reader.read_flag()?;
+                if decoder_model_info_present {
+                    buffer_delay_length = reader.read_bits(5)? as usize + 1;
+                    // num_units_in_decoding_tick, buffer_removal_time_length_minus_1 and
+                    // frame_presentation_time_length_minus_1
+                    reader.skip_bits(32 + 5 + 5)?;
+                }
+            }
+            let initial_display_delay_present = reader.read_flag()?;
+            let operating_points = reader.read_bits(5)? + 1;
+            let mut first = None;
+            for _ in 0..operating_points {
+                // operating_point_idc
+                reader.skip_bits(12)?;
+                let level = reader.read_bits(5)? as u8;
+                let tier = level > 7 && reader.read_flag()?;
+                first.get_or_insert((level, tier));
+                if decoder_model_info_present && reader.read_flag()? {
+                    // decoder_buffer_delay, encoder_buffer_delay and low_delay_mode_flag
+                    reader.skip_bits(2 * buffer_delay_length + 1)?;
+                }
+                if initial_display_delay_present && reader.read_flag()? {
+                    // initial_display_delay_minus_1
+                    reader.skip_bits(4)?;
+                }
+            }
+            first.unwrap_or_default()
+        };
+
+        let frame_width_bits = reader.read_bits(4)? + 1;
+        let frame_height_bits = reader.read_bits(4)? + 1;
+        let max_frame_width = reader.read_bits(frame_width_bits)? + 1;
+        let max_frame_height = reader.read_bits(frame_height_bits)? + 1;
+        if !reduced_still_picture_header && reader.read_flag()? {
+            // delta_frame_id_length_minus_2 and additional_frame_id_length_minus_1
+            reader.skip_bits(7)?;
+        }
+        // use_128x128_superblock, enable_filter_intra and enable_intra_edge_filter
+        reader.skip_bits(3)?;
+        if !reduced_still_picture_header {
+            // enable_interintra_compound, enable_masked_compound, enable_warped_motion and
+            // enable_dual_filter
+            reader.skip_bits(4)?;
+            let enable_order_hint = reader.read_flag()?;
+            if enable_order_hint {
+                // enable_jnt_comp and enable_ref_frame_mvs
+                reader.skip_bits(2)?;
+            }
+            // seq_choose_screen_content_tools, or else seq_force_screen_content_tools
+            let force_screen_content_tools = reader.read_flag()? || reader.read_flag()?;
+            if force_screen_content_tools {
+                // seq_choose_integer_mv, or else seq_force_integer_mv
+                if !reader.read_flag()? {
+                    reader.skip_bits(1)?;
+                }
+            }
+            if enable_order_hint {
+                // order_hint_bits_minus_1
+                reader.skip_bits(3)?;
+            }
+        }
+        // enable_superres, enable_cdef and enable_restoration
+        reader.skip_bits(3)?;
+
+        // color_config
+        let high_bitdepth = reader.read_flag()?;
+        let bit_depth = if !high_bitdepth {
+            8
+        } else if seq_profile == 2 && reader.read_flag()? {
+            12
+        } else {
+            10
+        };
+        let mono_chrome = seq_profile != 1 && reader.read_flag()?;
+        let mut color_primaries = None;
+        let mut transfer_characteristics = None;
+        let mut matrix_coefficients = None;
+        if reader.read_flag()? {
+            color_primaries = Some(reader.read_bits(8)? as u8);
+            transfer_characteristics = Some(reader.read_bits(8)? as u8);
+            matrix_coefficients = Some(reader.read_bits(8)? as u8);
+        }
+        // sRGB is always full range and signals no range.
+        let srgb = (
+            color_primaries,
+            transfer_characteristics,
+            matrix_coefficients,
+        ) == (Some(1), Some(13), Some(0));
+        let color_range = if srgb && !mono_chrome {
+            true
+        } else {
+            reader.read_flag()?
+        };
+
+        Ok(Self {
+            seq_profile,
+            still_picture,
+            reduced_still_picture_header,
+            timing_info,
+            num_ticks_per_picture,
+            seq_level_idx,
+            seq_tier,
+            max_frame_width,
+            max_frame_height,
+            bit_depth,
+            mono_chrome,
+            color_primaries,
+            transfer_characteristics,
+            matrix_coefficients,
+            color_range,
+        })
+    }
+
+    /// Returns the frame rate signalled by the timing info, if pictures are evenly spaced.
+    pub fn frame_rate(&self) -> Option<Rational> {
+        let (num_units_in_display_tick, time_scale) = self.timing_info?;
+        let den = num_units_in_display_tick.checked_mul(self.num_ticks_per_picture?)?;
+        if den == 0 || time_scale == 0 {
+            return None;
+        }
+        Some(Rational::new(time_scale, den))
+    }
+}
+
+/// The type of an AV1 frame, from its frame header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Av1FrameType {
+    Key,
+    Inter,
+    IntraOnly,
+    Switch,
+}
+
+/// An OBU of a temporal unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ObuMetadata {
+    pub obu_type: u8,
+    /// The offset of the OBU header in the temporal unit data.
+    pub offset: usize,
+    /// The length of the whole OBU, including its header and size field.
+    pub len: usize,
+}
+
+/// What an AV1 temporal unit holds, like [`FrameMetadata`](crate::FrameMetadata) does for H.264
+/// and H.265 access units.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TemporalUnitMetadata {
+    /// The size of the temporal unit data in bytes.
+    pub size: usize,
+    /// Whether decoding can start at this temporal unit: its first frame is a shown key frame.
+    pub keyframe: bool,
+    /// Whether the temporal unit carries a sequence header.
+    pub sequence_header: bool,
+    /// The types of the frames coded in the temporal unit, in order. Frame headers that show an
+    /// existing frame, and those that fail to parse, are left out.
+    pub frame_types: Vec<Av1FrameType>,
+    pub obus: Vec<ObuMetadata>,
+}
+
+impl TemporalUnitMetadata {
+    /// Describes a temporal unit of OBUs with valid headers, given the latest sequence header.
+    pub(crate) fn new(data: &[u8], sequence_header: Option<&Av1SequenceHeader>) -> Self {
+        let reduced_still_picture_header =
+            sequence_header.is_some_and(|header| header.reduced_still_picture_header);
+        let mut metadata = Self {
+            size: data.len(),
+            ..Self::default()
+        };
+        let mut start = 0;
+        while let Ok(Some(header)) = ObuHeader::read(&data[start..]) {
+            let Some(obu) = data.get(start..start + header.len()) else {
+                break;
+            };
+            metadata.obus.push(ObuMetadata {
+                obu_type: header.obu_type(),
+                offset: start,
+                len: header.len(),
+            });
+            match header.obu_type() {
+                OBU_SEQUENCE_HEADER => metadata.sequence_header = true,
+                OBU_FRAME_HEADER | OBU_FRAME => {
+                    let payload = &obu[header.header_len..];
+                    if let Ok(Some((frame_type, show_frame))) =
+                        read_frame_type(payload, reduced_still_picture_header)
+                    {
+                        if metadata.frame_types.is_empty() {
+                            metadata.keyframe = frame_type == Av1FrameType::Key && show_frame;
+                        }
+                        metadata.frame_types.push(frame_type);
+                    }
+                }
+                _ => {}
+            }
+            start += header.len();
+        }
+        metadata
+    }
+}
+
+/// Reads the type of a frame and whether it's shown from the start of its frame header, or
+/// returns `Ok(None)` if the header shows an existing frame instead.
+fn read_frame_type(
+    payload: &[u8],
+    reduced_still_picture_header: bool,
+) -> io::Result<Option<(Av1FrameType, bool)>> {
+    if reduced_still_picture_header {
+        return Ok(Some((Av1FrameType::Key, true)));
+    }
+    let mut reader = BitReader::new(payload);
+    // show_existing_frame
+    if reader.read_flag()? {
+        return Ok(None);
+    }
+    let frame_type = match reader.read_bits(2)? {
+        0 => Av1FrameType::Key,
+        1 => Av1FrameType::Inter,
+        2 => Av1FrameType::IntraOnly,
+        _ => Av1FrameType::Switch,
+    };
+    Ok(Some((frame_type, reader.read_flag()?)))
+}
+
+/// The state kept across the OBUs of a stream.
+#[derive(Default)]
+pub(crate) struct ObuState {
+    pub obu_index: u64,
+    pub sequence_header: Option<Av1SequenceHeader>,
+}
+
+impl ObuState {
+    /// Indexes a complete OBU and keeps its payload if it's a sequence header. A sequence header
+    /// that fails to parse only costs the stream its timing info, so it's not treated as an
+    /// error.
+    pub fn add_obu(
+        &mut self,
+        obu: &[u8],
+        header: &ObuHeader,
+        offset: u64,
+    ) -> Result<(), FramingError> {
+        let obu_index = self.obu_index;
+        self.obu_index += 1;
+        if header.is_malformed() {
+            return Err(FramingError::MalformedObuHeader {
+                offset,
+                obu_index,
+                header: header.header,
+            });
+        }
+        if header.obu_type() == OBU_SEQUENCE_HEADER {
+            if let Ok(sequence_header) = Av1SequenceHeader::parse(&obu[header.header_len..]) {
+                self.sequence_header = Some(sequence_header);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits an AV1 stream in the low overhead bitstream format, a sequence of OBUs that all have
+/// size fields, into temporal units as it arrives.
+///
+/// A temporal unit starts at each temporal delimiter OBU and is emitted once the next one
+/// arrives, or by [`finish`](Self::finish). Each temporal unit holds exactly one shown frame, so
+/// presentation and decoding timestamps are equal. They're derived from the timing info in the
+/// sequence header, or the configured frame rate.
+///
+/// A malformed OBU header is handled according to the [`ErrorPolicy`], but an OBU without a valid
+/// size field leaves no way to find the next one, so it always stops the framer.
+pub struct ObuFramer {
+    config: FramerConfig,
+    /// Input that hasn't been split into OBUs yet.
+    pending: Vec<u8>,
+    /// The stream offset of the start of `pending`.
+    offset: u64,
+    stopped: bool,
+    obus: ObuState,
+    temporal_unit: Vec<u8>,
+    timestamper: Timestamper,
+    ready: VecDeque<Result<(XcoderDecoderInputFrame, TemporalUnitMetadata), FramingError>>,
+}
+
+impl Default for ObuFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ObuFramer {
+    pub fn new() -> Self {
+        Self::with_config(FramerConfig::default())
+    }
+
+    pub fn with_error_policy(error_policy: ErrorPolicy) -> Self {
+        Self::with_config(FramerConfig {
+            error_policy,
+            ..FramerConfig::default()
+        })
+    }
+
+    /// Creates a framer with the given configuration. [`FramerConfig::codec`],
+    /// [`FramerConfig::join_mid_stream`] and [`FramerConfig::filter`] are ignored.
+    pub fn with_config(config: FramerConfig) -> Self {
+        Self {
+            pending: Vec::new(),
+            offset: 0,
+            stopped: false,
+            obus: ObuState::default(),
+            temporal_unit: Vec::new(),
+            timestamper: Timestamper::new(config.timebase, config.frame_rate, 1),
+            ready: VecDeque::new(),
+            config,
+        }
+    }
+
+    /// Returns the latest sequence header seen.
+    pub fn sequence_header(&self) -> Option<&Av1SequenceHeader> {
+        self.obus.sequence_header.as_ref()
+    }
+
+    /// Adds a chunk of input and returns the temporal units it completed.
+    pub fn push(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = Result<XcoderDecoderInputFrame, FramingError>> + '_ {
+        self.push_with_metadata(chunk)
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+
+    /// Ends the input, returning the remaining temporal unit. The framer can be reused for
+    /// another stream afterwards.
+    pub fn finish(
+        &mut self,
+    ) -> impl Iterator<Item = Result<XcoderDecoderInputFrame, FramingError>> + '_ {
+        self.finish_with_metadata()
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+
+    /// Like [`push`](Self::push), but also describes each temporal unit.
+    pub fn push_with_metadata(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = Result<(XcoderDecoderInputFrame, TemporalUnitMetadata), FramingError>> + '_
+    {
+        if !self.stopped {
+            self.pending.extend_from_slice(chunk);
+            self.split_pending();
+        }
+        self.ready.drain(..)
+    }
+
+    /// Like [`finish`](Self::finish), but also describes each temporal unit.
+    pub fn finish_with_metadata(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(XcoderDecoderInputFrame, TemporalUnitMetadata), FramingError>> + '_
+    {
+        if !self.stopped {
+            self.emit_temporal_unit();
+            if !self.pending.is_empty() {
+                self.fail(FramingError::TruncatedObu {
+                    offset: self.offset,
+                });
+            }
+        }
+        let ready = mem::take(&mut self.ready);
+        *self = Self {
+            ready,
+            ..Self::with_config(mem::take(&mut self.config))
+        };
+        self.ready.drain(..)
+    }
+
+    fn split_pending(&mut self) {
+        let pending = mem::take(&mut self.pending);
+        let mut start = 0;
+        loop {
+            match ObuHeader::read(&pending[start..]) {
+                Ok(Some(header)) if header.len() <= pending.len() - start => {
+                    let obu = &pending[start..start + header.len()];
+                    self.add_obu(obu, &header, self.offset + start as u64);
+                    start += header.len();
+                    if self.stopped {
+                        return;
+                    }
+                }
+                Ok(_) => break,
+                Err(()) => {
+                    let obu_index = self.obus.obu_index;
+                    self.temporal_unit.clear();
+                    self.stopped = true;
+                    self.ready.push_back(Err(FramingError::UnsizedObu {
+                        offset: self.offset + start as u64,
+                        obu_index,
+                    }));
+                    return;
+                }
+            }
+        }
+        self.pending = pending;
+        self.pending.drain(..start);
+        self.offset += start as u64;
+    }
+
+    fn add_obu(&mut self, obu: &[u8], header: &ObuHeader, offset: u64) {
+        if let Err(err) = self.obus.add_obu(obu, header, offset) {
+            return self.fail(err);
+        }
+        if header.obu_type() == OBU_TEMPORAL_DELIMITER {
+            self.emit_temporal_unit();
+        }
+        self.temporal_unit.extend_from_slice(obu);
+    }
+
+    fn fail(&mut self, err: FramingError) {
+        match self.config.error_policy {
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Stop => {
+                self.temporal_unit.clear();
+                self.pending.clear();
+                self.stopped = true;
+                self.ready.push_back(Err(err));
+            }
+            ErrorPolicy::Propagate => self.ready.push_back(Err(err)),
+        }
+    }
+
+    fn emit_temporal_unit(&mut self) {
+        if self.temporal_unit.is_empty() {
+            return;
+        }
+        let timing = self
+            .obus
+            .sequence_header
+            .as_ref()
+            .map(|sequence_header| PictureTiming {
+                poc: 0,
+                duration: 1,
+                starts_period: false,
+                decoding_order: true,
+                max_num_reorder_frames: 0,
+                frame_rate: sequence_header.frame_rate(),
+            });
+        let (pts, dts) = self.timestamper.next(timing.as_ref());
+        let data = mem::take(&mut self.temporal_unit);
+        let metadata = TemporalUnitMetadata::new(&data, self.obus.sequence_header.as_ref());
+        self.ready
+            .push_back(Ok((XcoderDecoderInputFrame { data, pts, dts }, metadata)));
+    }
+}
+
+/// Builds an OBU with a size field and no extension.
+#[cfg(test)]
+pub(crate) fn obu(obu_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut obu = vec![obu_type << 3 | 0x2];
+    let mut size = payload.len();
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        if size == 0 {
+            obu.push(byte);
+            break;
+        }
+        obu.push(byte | 0x80);
+    }
+    obu.extend_from_slice(payload);
+    obu
+}
+
+/// Builds the payload of a sequence header for 1080p at 29.97 fps, 10 bits per sample.
+#[cfg(test)]
+pub(crate) fn sequence_header() -> Vec<u8> {
+    crate::bits::BitWriter::default()
+        .bits(3, 0)
+        .bits(2, 0)
+        // timing_info with one picture every two ticks
+        .flag(true)
+        .bits(32, 1001)
+        .bits(32, 60000)
+        .flag(true)
+        .ue(1)
+        // decoder_model_info_present_flag and initial_display_delay_present_flag
+        .bits(2, 0)
+        // A single operating point at level 4.0, high tier
+        .bits(5, 0)
+        .bits(12, 0)
+        .bits(5, 8)
+        .flag(true)
+        .bits(4, 10)
+        .bits(4, 10)
+        .bits(11, 1919)
+        .bits(11, 1079)
+        .flag(false)
+        .bits(3, 0b111)
+        .bits(4, 0)
+        // enable_order_hint, enable_jnt_comp and enable_ref_frame_mvs
+        .bits(3, 0b111)
+        // seq_choose_screen_content_tools and seq_choose_integer_mv
+        .bits(2, 0b11)
+        .bits(3, 6)
+        .bits(3, 0b011)
+        // high_bitdepth, mono_chrome and color_description_present_flag
+        .bits(3, 0b101)
+        .bits(24, 0x091009)
+        .flag(false)
+        .trailing_bits()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sequence_header() {
+        let sequence_header = Av1SequenceHeader::parse(&sequence_header()).unwrap();
+        assert_eq!(sequence_header.seq_profile, 0);
+        assert_eq!(
+            (sequence_header.seq_level_idx, sequence_header.seq_tier),
+            (8, true)
+        );
+        assert_eq!(
+            (
+                sequence_header.max_frame_width,
+                sequence_header.max_frame_height
+            ),
+            (1920, 1080)
+        );
+        assert_eq!(sequence_header.bit_depth, 10);
+        assert!(!sequence_header.mono_chrome);
+        assert_eq!(sequence_header.color_primaries, Some(9));
+        assert_eq!(sequence_header.transfer_characteristics, Some(16));
+        assert!(!sequence_header.color_range);
+        assert_eq!(
+            sequence_header.frame_rate(),
+            Some(Rational::new(60000, 2002))
+        );
+    }
+
+    #[test]
+    fn test_temporal_units() {
+        let units = [
+            [
+                obu(OBU_TEMPORAL_DELIMITER, &[]),
+                obu(OBU_SEQUENCE_HEADER, &sequence_header()),
+                obu(OBU_FRAME, &[0x10, 0x20, 0x30]),
+            ]
+            .concat(),
+            [
+                obu(OBU_TEMPORAL_DELIMITER, &[]),
+                obu(OBU_FRAME, &[0x40; 200]),
+            ]
+            .concat(),
+            [
+                obu(OBU_TEMPORAL_DELIMITER, &[]),
+                obu(3, &[0x50]),
+                obu(4, &[0x60, 0x70]),
+            ]
+            .concat(),
+        ];
+        let mut framer = ObuFramer::new();
+        let mut frames = vec![];
+        for &b in units.concat().iter() {
+            frames.extend(framer.push(&[b]).map(Result::unwrap));
+        }
+        assert_eq!(frames.len(), 2);
+        assert!(framer.sequence_header().is_some());
+        frames.extend(framer.finish().map(Result::unwrap));
+        assert!(framer.sequence_header().is_none());
+
+        for (frame, unit) in frames.iter().zip(&units) {
+            assert_eq!(&frame.data, unit);
+            assert_eq!(frame.pts, frame.dts);
+        }
+        let pts: Vec<_> = frames.iter().map(|frame| frame.pts).collect();
+        assert_eq!(pts, vec![0, 3003, 6006]);
+
+        let mut framer = ObuFramer::new();
+        let mut metadata: Vec<_> = framer
+            .push_with_metadata(&units.concat())
+            .map(|result| result.unwrap().1)
+            .collect();
+        metadata.extend(
+            framer
+                .finish_with_metadata()
+                .map(|result| result.unwrap().1),
+        );
+        let keyframes: Vec<_> = metadata.iter().map(|metadata| metadata.keyframe).collect();
+        assert_eq!(keyframes, [true, false, false]);
+        assert!(metadata[0].sequence_header && !metadata[1].sequence_header);
+        let frame_types: Vec<_> = metadata
+            .iter()
+            .map(|metadata| metadata.frame_types.clone())
+            .collect();
+        assert_eq!(
+            frame_types,
+            [
+                [Av1FrameType::Key],
+                [Av1FrameType::IntraOnly],
+                [Av1FrameType::IntraOnly]
+            ]
+        );
+        let sizes: Vec<_> = metadata.iter().map(|metadata| metadata.size).collect();
+        assert_eq!(sizes, units.iter().map(Vec::len).collect::<Vec<_>>());
+        assert_eq!(
+            metadata[2].obus,
+            [
+                ObuMetadata {
+                    obu_type: OBU_TEMPORAL_DELIMITER,
+                    offset: 0,
+                    len: 2
+                },
+                ObuMetadata {
+                    obu_type: 3,
+                    offset: 2,
+                    len: 3
+                },
+                ObuMetadata {
+                    obu_type: 4,
+                    offset: 5,
+                    len: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut malformed = obu(OBU_FRAME, &[0x10]);
+        malformed[0] |= 0x80;
+        let stream = [
+            obu(OBU_TEMPORAL_DELIMITER, &[]),
+            malformed,
+            obu(OBU_FRAME, &[0x20]),
+            obu(OBU_TEMPORAL_DELIMITER, &[]),
+            obu(OBU_FRAME, &[0x30, 0x40])[..3].to_vec(),
+        ]
+        .concat();
+        let mut framer = ObuFramer::new();
+        let mut results: Vec<_> = framer.push(&stream).collect();
+        results.extend(framer.finish());
+        assert!(matches!(
+            results[..],
+            [
+                Err(FramingError::MalformedObuHeader {
+                    offset: 2,
+                    obu_index: 1,
+                    header: 0xb2,
+                }),
+                Ok(_),
+                Ok(_),
+                Err(FramingError::TruncatedObu { offset: 10 }),
+            ]
+        ));
+        assert_eq!(results[1].as_ref().unwrap().data, [0x12, 0, 0x32, 1, 0x20]);
+
+        // Without a size field, the OBUs that follow can't be found whatever the policy.
+        let mut framer = ObuFramer::with_error_policy(ErrorPolicy::Skip);
+        let stream = [obu(OBU_TEMPORAL_DELIMITER, &[]), vec![OBU_FRAME << 3, 0x10]].concat();
+        let results: Vec<_> = framer.push(&stream).collect();
+        assert!(matches!(
+            results[..],
+            [Err(FramingError::UnsizedObu {
+                offset: 2,
+                obu_index: 1
+            })]
+        ));
+        assert_eq!(framer.push(&obu(OBU_TEMPORAL_DELIMITER, &[])).count(), 0);
+    }
+}
@@ -0,0 +1,370 @@
+use std::error::Error;
+use std::fmt;
+use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+use crate::framer::Codec;
+use crate::hevc;
+use crate::metadata::{FrameInspector, FrameMetadata};
+use crate::probe::StreamInfo;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// An error converting length-prefixed input to Annex B.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConversionError {
+    /// The configuration record is truncated or has an unknown version.
+    InvalidConfigurationRecord,
+    /// The configuration record gives a NAL unit length size other than 1, 2 or 4 bytes.
+    UnsupportedNalLengthSize { size: u8 },
+    /// The length of a NAL unit, or its length field, runs past the end of the sample. The
+    /// offset is in bytes from the start of the sample.
+    TruncatedNalUnit { offset: usize },
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidConfigurationRecord => write!(f, "invalid decoder configuration record"),
+            Self::UnsupportedNalLengthSize { size } => {
+                write!(f, "unsupported NAL unit length size {size}")
+            }
+            Self::TruncatedNalUnit { offset } => {
+                write!(f, "truncated NAL unit at byte {offset} of the sample")
+            }
+        }
+    }
+}
+
+impl Error for ConversionError {}
+
+/// Reads big-endian fields from a configuration record.
+struct RecordReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> RecordReader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], ConversionError> {
+        if self.data.len() < n {
+            return Err(ConversionError::InvalidConfigurationRecord);
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ConversionError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ConversionError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Reads a NAL unit preceded by its 16-bit length.
+    fn nalu(&mut self) -> Result<Vec<u8>, ConversionError> {
+        let len = self.u16()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+}
+
+/// A decoder configuration record, from the `avcC` box of H.264 or the `hvcC` box of H.265.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecoderConfigurationRecord {
+    pub codec: Codec,
+    /// The size of the length field in front of each NAL unit of the samples.
+    pub nal_length_size: u8,
+    /// The parameter set NAL units, in decoding order: the VPSs for H.265, then the SPSs and
+    /// the PPSs. Other NAL units in the record are left out.
+    pub parameter_sets: Vec<Vec<u8>>,
+}
+
+impl DecoderConfigurationRecord {
+    /// Parses the payload of an `avcC` box.
+    pub fn parse_avcc(data: &[u8]) -> Result<Self, ConversionError> {
+        let mut reader = RecordReader { data };
+        if reader.u8()? != 1 {
+            return Err(ConversionError::InvalidConfigurationRecord);
+        }
+        // AVCProfileIndication, profile_compatibility and AVCLevelIndication
+        reader.bytes(3)?;
+        let nal_length_size = Self::nal_length_size(reader.u8()?)?;
+        let mut parameter_sets = Vec::new();
+        for _ in 0..reader.u8()? & 0x1f {
+            parameter_sets.push(reader.nalu()?);
+        }
+        for _ in 0..reader.u8()? {
+            parameter_sets.push(reader.nalu()?);
+        }
+        // Any extension for the high profiles only repeats what's in the SPS.
+        Ok(Self {
+            codec: Codec::H264,
+            nal_length_size,
+            parameter_sets,
+        })
+    }
+
+    /// Parses the payload of an `hvcC` box.
+    pub fn parse_hvcc(data: &[u8]) -> Result<Self, ConversionError> {
+        let mut reader = RecordReader { data };
+        if reader.u8()? != 1 {
+            return Err(ConversionError::InvalidConfigurationRecord);
+        }
+        // The profile, tier and level, the chroma format, bit depths and frame rate
+        reader.bytes(20)?;
+        let nal_length_size = Self::nal_length_size(reader.u8()?)?;
+        let mut arrays = Vec::new();
+        for _ in 0..reader.u8()? {
+            let nal_unit_type = reader.u8()? & 0x3f;
+            let mut nal_units = Vec::new();
+            for _ in 0..reader.u16()? {
+                nal_units.push(reader.nalu()?);
+            }
+            arrays.push((nal_unit_type, nal_units));
+        }
+        let mut parameter_sets = Vec::new();
+        for nal_unit_type in [hevc::VPS, hevc::SPS, hevc::PPS] {
+            for (_, nal_units) in arrays.iter().filter(|(t, _)| *t == nal_unit_type) {
+                parameter_sets.extend(nal_units.iter().cloned());
+            }
+        }
+        Ok(Self {
+            codec: Codec::H265,
+            nal_length_size,
+            parameter_sets,
+        })
+    }
+
+    /// Returns the stream info from the first SPS of the record.
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.parameter_sets
+            .iter()
+            .find_map(|nalu| StreamInfo::from_nalu(self.codec, nalu))
+    }
+
+    fn nal_length_size(byte: u8) -> Result<u8, ConversionError> {
+        match (byte & 0x3) + 1 {
+            3 => Err(ConversionError::UnsupportedNalLengthSize { size: 3 }),
+            size => Ok(size),
+        }
+    }
+}
+
+/// Rewrites samples of length-prefixed NAL units, as stored in MP4 and Matroska, to Annex B
+/// access units ready for the decoder.
+///
+/// Out-of-band streams only carry their parameter sets in the configuration record, so they're
+/// inserted in front of each random access point: IDR pictures for H.264, and IRAP pictures for
+/// H.265. Samples that carry their own SPS already are left as they are. Access unit delimiters
+/// stay first.
+#[derive(Clone, Debug)]
+pub struct LengthPrefixedConverter {
+    record: DecoderConfigurationRecord,
+    inspector: FrameInspector,
+}
+
+impl LengthPrefixedConverter {
+    pub fn new(record: DecoderConfigurationRecord) -> Self {
+        Self {
+            inspector: FrameInspector::new(record.codec),
+            record,
+        }
+    }
+
+    pub fn record(&self) -> &DecoderConfigurationRecord {
+        &self.record
+    }
+
+    /// Converts a sample to an access unit with the given timestamps.
+    pub fn convert(
+        &self,
+        sample: &[u8],
+        pts: i64,
+        dts: i64,
+    ) -> Result<XcoderDecoderInputFrame, ConversionError> {
+        let nal_units = self.split(sample)?;
+        let nal_unit_type = |nalu: &[u8]| match self.record.codec {
+            Codec::H264 => nalu[0] & 0x1f,
+            Codec::H265 => hevc::nal_unit_type(nalu[0]),
+        };
+        let (aud, sps, is_random_access_point): (u8, u8, fn(u8) -> bool) = match self.record.codec {
+            Codec::H264 => (9, 7, |t| t == 5),
+            Codec::H265 => (hevc::AUD, hevc::SPS, hevc::is_irap),
+        };
+        let types: Vec<_> = nal_units.iter().map(|nalu| nal_unit_type(nalu)).collect();
+        let inject = types.iter().any(|&t| is_random_access_point(t)) && !types.contains(&sps);
+
+        let parameter_sets_len: usize = self
+            .record
+            .parameter_sets
+            .iter()
+            .map(|nalu| START_CODE.len() + nalu.len())
+            .sum();
+        let mut data = Vec::with_capacity(sample.len() + parameter_sets_len + 4 * nal_units.len());
+        let mut injected = !inject;
+        for (nalu, nal_unit_type) in nal_units.iter().zip(types) {
+            if !injected && nal_unit_type != aud {
+                for parameter_set in &self.record.parameter_sets {
+                    data.extend_from_slice(&START_CODE);
+                    data.extend_from_slice(parameter_set);
+                }
+                injected = true;
+            }
+            data.extend_from_slice(&START_CODE);
+            data.extend_from_slice(nalu);
+        }
+        Ok(XcoderDecoderInputFrame { data, pts, dts })
+    }
+
+    /// Like [`convert`](Self::convert), but also describes the access unit. Samples should be
+    /// converted in decoding order.
+    pub fn convert_with_metadata(
+        &mut self,
+        sample: &[u8],
+        pts: i64,
+        dts: i64,
+    ) -> Result<(XcoderDecoderInputFrame, FrameMetadata), ConversionError> {
+        let frame = self.convert(sample, pts, dts)?;
+        let metadata = self.inspector.inspect(&frame.data);
+        Ok((frame, metadata))
+    }
+
+    /// Splits a sample into its NAL units, skipping empty ones.
+    fn split<'a>(&self, sample: &'a [u8]) -> Result<Vec<&'a [u8]>, ConversionError> {
+        let length_size = self.record.nal_length_size as usize;
+        let mut nal_units = Vec::new();
+        let mut offset = 0;
+        while offset < sample.len() {
+            let truncated = || ConversionError::TruncatedNalUnit { offset };
+            let length = sample
+                .get(offset..offset + length_size)
+                .ok_or_else(truncated)?
+                .iter()
+                .fold(0usize, |length, &b| length << 8 | b as usize);
+            let start = offset + length_size;
+            let nalu = sample.get(start..start + length).ok_or_else(truncated)?;
+            if !nalu.is_empty() {
+                nal_units.push(nalu);
+            }
+            offset = start + length;
+        }
+        Ok(nal_units)
+    }
+}
+
+/// An H.264 SPS for the constrained baseline profile at level 3.0.
+#[cfg(test)]
+pub(crate) const SPS: &[u8] = &[0x67, 0x42, 0xc0, 0x1e, 0xd9];
+
+/// An H.264 PPS to go with [`SPS`].
+#[cfg(test)]
+pub(crate) const PPS: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+
+/// Writes NAL units with a big-endian length of `length_size` bytes in front of each.
+#[cfg(test)]
+pub(crate) fn length_prefixed(nal_units: &[&[u8]], length_size: usize) -> Vec<u8> {
+    let mut sample = vec![];
+    for nalu in nal_units {
+        sample.extend_from_slice(&nalu.len().to_be_bytes()[8 - length_size..]);
+        sample.extend_from_slice(nalu);
+    }
+    sample
+}
+
+/// Writes NAL units with a four byte start code in front of each.
+#[cfg(test)]
+pub(crate) fn annex_b(nal_units: &[&[u8]]) -> Vec<u8> {
+    let mut data = vec![];
+    for nalu in nal_units {
+        data.extend_from_slice(&START_CODE);
+        data.extend_from_slice(nalu);
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn avcc(length_size: u8) -> Vec<u8> {
+        let mut record = vec![1, 0x42, 0xc0, 0x1e, 0xfc | (length_size - 1), 0xe1];
+        record.extend_from_slice(&length_prefixed(&[SPS], 2));
+        record.push(1);
+        record.extend_from_slice(&length_prefixed(&[PPS], 2));
+        record
+    }
+
+    #[test]
+    fn test_avcc() {
+        for length_size in [1, 2, 4] {
+            let record = DecoderConfigurationRecord::parse_avcc(&avcc(length_size)).unwrap();
+            assert_eq!(record.nal_length_size, length_size);
+            assert_eq!(record.parameter_sets, vec![SPS.to_vec(), PPS.to_vec()]);
+            let converter = LengthPrefixedConverter::new(record);
+
+            let aud: &[u8] = &[0x09, 0xf0];
+            let idr: &[u8] = &[0x65, 0x88, 0x84];
+            let sample = length_prefixed(&[aud, idr], length_size as usize);
+            let frame = converter.convert(&sample, 3000, 0).unwrap();
+            assert_eq!(frame.data, annex_b(&[aud, SPS, PPS, idr]));
+            assert_eq!((frame.pts, frame.dts), (3000, 0));
+
+            let non_idr: &[u8] = &[0x41, 0x9a, 0x02];
+            let sample = length_prefixed(&[non_idr, &[]], length_size as usize);
+            let frame = converter.convert(&sample, 6000, 3000).unwrap();
+            assert_eq!(frame.data, annex_b(&[non_idr]));
+
+            // In-band parameter sets aren't repeated.
+            let sample = length_prefixed(&[SPS, PPS, idr], length_size as usize);
+            let frame = converter.convert(&sample, 0, 0).unwrap();
+            assert_eq!(frame.data, annex_b(&[SPS, PPS, idr]));
+        }
+
+        assert_eq!(
+            DecoderConfigurationRecord::parse_avcc(&avcc(3)),
+            Err(ConversionError::UnsupportedNalLengthSize { size: 3 })
+        );
+        assert_eq!(
+            DecoderConfigurationRecord::parse_avcc(&avcc(4)[..10]),
+            Err(ConversionError::InvalidConfigurationRecord)
+        );
+    }
+
+    #[test]
+    fn test_hvcc() {
+        let vps: &[u8] = &[0x40, 0x01, 0x0c];
+        let sps: &[u8] = &[0x42, 0x01, 0x01];
+        let pps: &[u8] = &[0x44, 0x01, 0xc1];
+        let sei: &[u8] = &[0x4e, 0x01, 0x05];
+        let mut record = vec![1];
+        record.extend_from_slice(&[0; 20]);
+        record.extend_from_slice(&[0x0f, 4]);
+        // The arrays come in a different order than the parameter sets are decoded in.
+        for (nal_unit_type, nalu) in [(34, pps), (39, sei), (32, vps), (33, sps)] {
+            record.extend_from_slice(&[0x80 | nal_unit_type, 0, 1]);
+            record.extend_from_slice(&length_prefixed(&[nalu], 2));
+        }
+        let record = DecoderConfigurationRecord::parse_hvcc(&record).unwrap();
+        assert_eq!(record.codec, Codec::H265);
+        assert_eq!(record.nal_length_size, 4);
+        let converter = LengthPrefixedConverter::new(record);
+
+        let cra: &[u8] = &[0x2a, 0x01, 0xaf];
+        let trail: &[u8] = &[0x02, 0x01, 0xd0];
+        let frame = converter
+            .convert(&length_prefixed(&[cra], 4), 0, 0)
+            .unwrap();
+        assert_eq!(frame.data, annex_b(&[vps, sps, pps, cra]));
+        let frame = converter
+            .convert(&length_prefixed(&[trail], 4), 1, 1)
+            .unwrap();
+        assert_eq!(frame.data, annex_b(&[trail]));
+
+        let mut sample = length_prefixed(&[trail, trail], 4);
+        sample.truncate(sample.len() - 1);
+        assert!(matches!(
+            converter.convert(&sample, 0, 0),
+            Err(ConversionError::TruncatedNalUnit { offset: 7 })
+        ));
+    }
+}
@@ -0,0 +1,209 @@
+use std::io;
+
+/// Strips the emulation prevention bytes from a NAL unit, leaving its raw byte sequence payload.
+pub(crate) fn to_rbsp(nalu: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nalu.len());
+    let mut zeros = 0;
+    for &b in nalu {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+/// Strips the emulation prevention bytes from the first `len` bytes of a NAL unit, for fields that
+/// are always within them, such as the start of a slice header. The rest isn't worth unescaping.
+pub(crate) fn header_rbsp(nalu: &[u8], len: usize) -> Vec<u8> {
+    to_rbsp(&nalu[..nalu.len().min(len)])
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated RBSP")
+}
+
+/// Reads the fixed and Exp-Golomb coded fields of an RBSP, most significant bit first.
+pub(crate) struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// The number of bits read so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn bits_left(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+
+    pub fn read_flag(&mut self) -> io::Result<bool> {
+        let byte = self.data.get(self.position / 8).ok_or_else(truncated)?;
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    /// Reads an unsigned integer of up to 32 bits.
+    pub fn read_bits(&mut self, n: u32) -> io::Result<u32> {
+        debug_assert!(n <= 32);
+        Ok(self.read_bits_u64(n)? as u32)
+    }
+
+    /// Reads an unsigned integer of up to 64 bits.
+    pub fn read_bits_u64(&mut self, n: u32) -> io::Result<u64> {
+        if self.bits_left() < n as usize {
+            return Err(truncated());
+        }
+        let mut value = 0;
+        for _ in 0..n {
+            value = value << 1 | self.read_flag()? as u64;
+        }
+        Ok(value)
+    }
+
+    pub fn skip_bits(&mut self, n: usize) -> io::Result<()> {
+        if self.bits_left() < n {
+            return Err(truncated());
+        }
+        self.position += n;
+        Ok(())
+    }
+
+    /// Reads an unsigned Exp-Golomb coded integer, `ue(v)`.
+    pub fn read_ue(&mut self) -> io::Result<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_flag()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Exp-Golomb code out of range",
+                ));
+            }
+        }
+        let value = (1u64 << leading_zeros) - 1 + self.read_bits_u64(leading_zeros)?;
+        u32::try_from(value)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Exp-Golomb code out of range"))
+    }
+
+    /// Reads a signed Exp-Golomb coded integer, `se(v)`.
+    pub fn read_se(&mut self) -> io::Result<i32> {
+        let value = self.read_ue()? as i64;
+        Ok(if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -value / 2
+        } as i32)
+    }
+}
+
+/// Writes RBSP fields, for rewriting NAL units and building them in tests.
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    data: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    pub fn flag(mut self, value: bool) -> Self {
+        if self.bits.is_multiple_of(8) {
+            self.data.push(0);
+        }
+        if value {
+            *self.data.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+        }
+        self.bits += 1;
+        self
+    }
+
+    pub fn bits(mut self, n: u32, value: u64) -> Self {
+        for i in (0..n).rev() {
+            self = self.flag(value >> i & 1 == 1);
+        }
+        self
+    }
+
+    #[cfg(test)]
+    pub fn ue(self, value: u32) -> Self {
+        let value = value as u64 + 1;
+        let len = 64 - value.leading_zeros();
+        self.bits(len - 1, 0).bits(len, value)
+    }
+
+    #[cfg(test)]
+    pub fn se(self, value: i32) -> Self {
+        let value = if value > 0 {
+            2 * value as u32 - 1
+        } else {
+            2 * value.unsigned_abs()
+        };
+        self.ue(value)
+    }
+
+    /// Adds the trailing bits, a one followed by zeros up to the next byte, returning the data.
+    pub fn trailing_bits(self) -> Vec<u8> {
+        let mut writer = self.flag(true);
+        while !writer.bits.is_multiple_of(8) {
+            writer = writer.flag(false);
+        }
+        writer.data
+    }
+
+    /// Adds the RBSP trailing bits and emulation prevention bytes, returning the NAL unit.
+    pub fn finish(self) -> Vec<u8> {
+        let data = self.trailing_bits();
+        let mut nalu = Vec::with_capacity(data.len());
+        let mut zeros = 0;
+        for b in data {
+            if zeros >= 2 && b <= 3 {
+                nalu.push(3);
+                zeros = 0;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            nalu.push(b);
+        }
+        nalu
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let nalu = BitWriter::default()
+            .bits(8, 0x67)
+            .ue(0)
+            .ue(1)
+            .ue(254)
+            .se(-3)
+            .se(4)
+            .bits(24, 0)
+            .flag(true)
+            .finish();
+        assert!(nalu.windows(3).any(|window| window == [0, 0, 3]));
+
+        let rbsp = to_rbsp(&nalu);
+        let mut reader = BitReader::new(&rbsp);
+        assert_eq!(reader.read_bits(8).unwrap(), 0x67);
+        assert_eq!(reader.read_ue().unwrap(), 0);
+        assert_eq!(reader.read_ue().unwrap(), 1);
+        assert_eq!(reader.read_ue().unwrap(), 254);
+        assert_eq!(reader.read_se().unwrap(), -3);
+        assert_eq!(reader.read_se().unwrap(), 4);
+        assert_eq!(reader.read_bits(24).unwrap(), 0);
+        assert!(reader.read_flag().unwrap());
+        assert!(reader.read_flag().unwrap());
+        assert!(reader.bits_left() < 8);
+    }
+}
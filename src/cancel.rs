@@ -0,0 +1,137 @@
+use crate::{DecoderInputQueue, DecoderInputQueueProducer, Shared};
+use std::fmt;
+use std::sync::Arc;
+
+impl Shared {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancellation.get().is_some()
+    }
+
+    /// Cancels the queue unless it already has been, and wakes up both halves so that they notice.
+    fn cancel(&self, reason: String) -> bool {
+        let cancelled = self.cancellation.set(reason).is_ok();
+        if cancelled {
+            self.items_available.notify();
+            self.space_available.notify();
+        }
+        cancelled
+    }
+
+    pub(crate) fn cancellation_reason(&self) -> Option<&str> {
+        self.cancellation.get().map(String::as_str)
+    }
+}
+
+/// A handle for cancelling a queue from anywhere, e.g. a controller that aborts a decode job.
+///
+/// Once cancelled, pushes fail with [`PushError::Cancelled`](crate::PushError::Cancelled), even
+/// ones already waiting for space, and the consumer stops yielding frames. Any frames still in
+/// the queue are abandoned. A queue can only be cancelled once; the first reason given is kept.
+#[derive(Clone)]
+pub struct CancellationToken {
+    shared: Arc<Shared>,
+}
+
+impl CancellationToken {
+    pub(crate) fn new(shared: Arc<Shared>) -> Self {
+        Self { shared }
+    }
+
+    /// Cancels the queue. Returns false if it had already been cancelled.
+    pub fn cancel(&self, reason: impl Into<String>) -> bool {
+        self.shared.cancel(reason.into())
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.is_cancelled()
+    }
+
+    /// Returns the reason the queue was cancelled with, if it has been.
+    pub fn reason(&self) -> Option<&str> {
+        self.shared.cancellation_reason()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("reason", &self.reason())
+            .finish()
+    }
+}
+
+impl<E> DecoderInputQueue<E> {
+    pub fn cancellation_token(&self) -> CancellationToken {
+        CancellationToken::new(self.reader.shared.clone())
+    }
+
+    /// Cancels the queue, telling the producer to stop. See [`CancellationToken`].
+    pub fn cancel(&self, reason: impl Into<String>) -> bool {
+        self.reader.shared.cancel(reason.into())
+    }
+
+    /// Returns the reason the queue was cancelled with, if it has been.
+    pub fn cancellation_reason(&self) -> Option<&str> {
+        self.reader.shared.cancellation_reason()
+    }
+}
+
+impl<E> DecoderInputQueueProducer<E> {
+    pub fn cancellation_token(&self) -> CancellationToken {
+        CancellationToken::new(self.shared.clone())
+    }
+
+    /// Returns the reason the queue was cancelled with, if it has been.
+    pub fn cancellation_reason(&self) -> Option<&str> {
+        self.shared.cancellation_reason()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        test_frame, DecoderInputQueue, DecoderInputQueueConfig, PopError, PushError, WaitStrategy,
+    };
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_cancel_wakes_blocked_producer() {
+        let (queue, mut producer) = DecoderInputQueue::<()>::new(DecoderInputQueueConfig {
+            producer_wait: WaitStrategy::Block,
+            ..1.into()
+        });
+        let producer = thread::spawn(move || {
+            producer.push(Ok(test_frame(0))).unwrap();
+            let result = producer.push(Ok(test_frame(1)));
+            (result, producer.cancellation_reason().map(str::to_owned))
+        });
+        thread::sleep(Duration::from_millis(10));
+        assert!(queue.cancel("job aborted"));
+        assert!(!queue.cancel("again"));
+
+        let (result, reason) = producer.join().unwrap();
+        match result {
+            Err(PushError::Cancelled(frame)) => assert_eq!(frame.unwrap().pts, 1),
+            _ => panic!("expected the push to be cancelled"),
+        }
+        assert_eq!(reason.as_deref(), Some("job aborted"));
+        assert_eq!(queue.cancellation_reason(), Some("job aborted"));
+    }
+
+    #[test]
+    fn test_token_ends_iteration() {
+        let (mut queue, mut producer) = DecoderInputQueue::<()>::new(4);
+        let token = producer.cancellation_token();
+        producer.push(Ok(test_frame(0))).unwrap();
+        token.cancel("shutting down");
+
+        assert!(queue.next().is_none());
+        assert_eq!(queue.try_pop().err(), Some(PopError::Cancelled));
+        assert!(matches!(
+            producer.try_push(Ok(test_frame(1))),
+            Err(PushError::Cancelled(_))
+        ));
+        assert_eq!(token.reason(), Some("shutting down"));
+    }
+}
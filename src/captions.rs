@@ -0,0 +1,469 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::cea608::Cea608Decoder;
+use crate::cea708::Cea708Decoder;
+use crate::framer::{ErrorPolicy, FramerConfig, FramingError};
+use crate::metadata::FrameMetadata;
+use crate::params::Rational;
+use crate::read_frames_with_metadata;
+use crate::sei::{CcData, SeiMessage};
+
+/// How many frames caption data is held back to be put in presentation order. No H.264 or H.265
+/// stream reorders more frames than that.
+const REORDER_DEPTH: usize = 16;
+
+/// A caption, shown from `start` until `end`, in the timebase of the stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Caption {
+    pub start: i64,
+    pub end: i64,
+    /// The caption text, with rows separated by newlines.
+    pub text: String,
+}
+
+/// The captions extracted from a stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captions {
+    pub timebase: Rational,
+    /// The captions of CEA-608 channel CC1.
+    pub cea608: Vec<Caption>,
+    /// The captions of the primary CEA-708 service.
+    pub cea708: Vec<Caption>,
+    /// The CEA-608 byte pairs of the first field, with their parity bits, by presentation time.
+    /// This is what [`write_scc`] takes.
+    pub cea608_data: Vec<(i64, [u8; 2])>,
+}
+
+/// Follows what a caption decoder shows on screen, and cuts it into captions.
+#[derive(Default)]
+struct Track {
+    /// The caption on screen, and when it was shown.
+    current: Option<(i64, String)>,
+    captions: Vec<Caption>,
+}
+
+impl Track {
+    fn update(&mut self, pts: i64, screen: &str) {
+        if self.current.as_ref().map_or("", |(_, text)| text) == screen {
+            return;
+        }
+        self.close(pts);
+        if !screen.is_empty() {
+            self.current = Some((pts, screen.to_owned()));
+        }
+    }
+
+    fn close(&mut self, end: i64) {
+        if let Some((start, text)) = self.current.take() {
+            if end > start {
+                self.captions.push(Caption { start, end, text });
+            }
+        }
+    }
+}
+
+/// Decodes the CEA-608 and CEA-708 captions carried in A/53 user data SEI messages.
+///
+/// Frames are pushed in decoding order with their presentation timestamps, as
+/// [`AnnexBFramer::push_with_metadata`](crate::AnnexBFramer::push_with_metadata) emits them. The
+/// caption data is put back in presentation order before it's decoded. No hardware is needed.
+pub struct CaptionExtractor {
+    timebase: Rational,
+    /// The caption data that isn't known to be in presentation order yet, by timestamp.
+    pending: BTreeMap<i64, Vec<CcData>>,
+    cea608: Cea608Decoder,
+    cea708: Cea708Decoder,
+    cea608_track: Track,
+    cea708_track: Track,
+    cea608_data: Vec<(i64, [u8; 2])>,
+    /// The last two timestamps decoded, to tell how long the last frame lasts.
+    last_pts: [Option<i64>; 2],
+}
+
+impl CaptionExtractor {
+    /// Creates an extractor for timestamps in the given timebase, in seconds per tick.
+    pub fn new(timebase: Rational) -> Self {
+        Self {
+            timebase,
+            pending: BTreeMap::new(),
+            cea608: Cea608Decoder::new(),
+            cea708: Cea708Decoder::new(),
+            cea608_track: Track::default(),
+            cea708_track: Track::default(),
+            cea608_data: Vec::new(),
+            last_pts: [None; 2],
+        }
+    }
+
+    /// Adds the caption data of a frame, from its SEI messages.
+    pub fn push(&mut self, pts: i64, metadata: &FrameMetadata) {
+        let cc_data = metadata.sei.iter().flat_map(|message| match message {
+            SeiMessage::UserDataRegistered(user_data) => user_data.cc_data().unwrap_or_default(),
+            _ => Vec::new(),
+        });
+        self.pending.entry(pts).or_default().extend(cc_data);
+        while self.pending.len() > REORDER_DEPTH {
+            let (pts, cc_data) = self.pending.pop_first().unwrap();
+            self.decode(pts, &cc_data);
+        }
+    }
+
+    /// Decodes the caption data left, and returns all the captions. A caption still on screen
+    /// lasts until the end of the last frame, taken to be as long as the one before it.
+    pub fn finish(mut self) -> Captions {
+        while let Some((pts, cc_data)) = self.pending.pop_first() {
+            self.decode(pts, &cc_data);
+        }
+        let end = match self.last_pts {
+            [Some(previous), Some(last)] => 2 * last - previous,
+            [_, last] => last.unwrap_or_default(),
+        };
+        self.cea608_track.close(end);
+        self.cea708_track.close(end);
+        Captions {
+            timebase: self.timebase,
+            cea608: self.cea608_track.captions,
+            cea708: self.cea708_track.captions,
+            cea608_data: self.cea608_data,
+        }
+    }
+
+    fn decode(&mut self, pts: i64, cc_data: &[CcData]) {
+        for cc in cc_data.iter().filter(|cc| cc.valid) {
+            match cc.cc_type {
+                0 => {
+                    self.cea608_data.push((pts, cc.data));
+                    self.cea608.decode(cc.data);
+                }
+                // CC3 and CC4, in the second field, aren't decoded.
+                1 => {}
+                _ => self.cea708.push(cc.cc_type, cc.data),
+            }
+        }
+        self.cea608_track.update(pts, self.cea608.screen());
+        self.cea708_track.update(pts, &self.cea708.screen());
+        self.last_pts = [self.last_pts[1], Some(pts)];
+    }
+}
+
+/// Extracts the captions of a whole H.264 or H.265 Annex B stream. See [`CaptionExtractor`].
+///
+/// Framing errors are handled according to the [`ErrorPolicy`]: with [`ErrorPolicy::Stop`] the
+/// first one is returned, otherwise the NAL units in error are dropped and the captions of the
+/// rest of the stream are still extracted.
+pub fn extract_captions(buf: &[u8], config: FramerConfig) -> Result<Captions, FramingError> {
+    let mut extractor = CaptionExtractor::new(config.timebase);
+    let error_policy = config.error_policy;
+    for result in read_frames_with_metadata(buf, config) {
+        match result {
+            Ok((frame, metadata)) => extractor.push(frame.pts, &metadata),
+            Err(err) if error_policy == ErrorPolicy::Stop => return Err(err),
+            Err(_) => {}
+        }
+    }
+    Ok(extractor.finish())
+}
+
+/// Converts a timestamp to milliseconds.
+fn millis(pts: i64, timebase: Rational) -> i64 {
+    (i128::from(pts) * i128::from(timebase.num) * 1000 / i128::from(timebase.den)) as i64
+}
+
+/// Writes captions as a WebVTT file.
+pub fn write_webvtt(
+    captions: &[Caption],
+    timebase: Rational,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    let timestamp = |pts| {
+        let ms = millis(pts, timebase).max(0);
+        format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            ms / 3_600_000,
+            ms / 60_000 % 60,
+            ms / 1000 % 60,
+            ms % 1000
+        )
+    };
+    writeln!(writer, "WEBVTT")?;
+    for caption in captions {
+        let text = caption
+            .text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "{} --> {}",
+            timestamp(caption.start),
+            timestamp(caption.end)
+        )?;
+        writeln!(writer, "{text}")?;
+    }
+    Ok(())
+}
+
+/// Writes CEA-608 byte pairs as a Scenarist SCC file. Each run of pairs up to padding goes on a
+/// line, timed by the first of them with a 29.97 fps drop-frame time code, as SCC files are.
+pub fn write_scc(
+    data: &[(i64, [u8; 2])],
+    timebase: Rational,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    write!(writer, "Scenarist_SCC V1.0")?;
+    let mut in_run = false;
+    for &(pts, pair) in data {
+        if pair == [0x80, 0x80] {
+            in_run = false;
+            continue;
+        }
+        if in_run {
+            write!(writer, " ")?;
+        } else {
+            write!(writer, "\n\n{}\t", drop_frame_time_code(pts, timebase))?;
+            in_run = true;
+        }
+        write!(writer, "{:02x}{:02x}", pair[0], pair[1])?;
+    }
+    writeln!(writer)
+}
+
+/// Formats a timestamp as a 29.97 fps drop-frame time code, which skips the first two frame
+/// numbers of each minute but every tenth.
+fn drop_frame_time_code(pts: i64, timebase: Rational) -> String {
+    let frames = i128::from(pts.max(0)) * i128::from(timebase.num) * 30000;
+    let den = i128::from(timebase.den) * 1001;
+    let mut n = ((frames + den / 2) / den) as i64;
+    let (tens, rest) = (n / 17982, n % 17982);
+    n += 18 * tens + if rest < 2 { 0 } else { 2 * ((rest - 2) / 1798) };
+    format!(
+        "{:02}:{:02}:{:02};{:02}",
+        n / 108_000,
+        n / 1800 % 60,
+        n / 30 % 60,
+        n % 30
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::avcc::annex_b;
+    use crate::cea608::parity;
+    use crate::sei::UserDataRegistered;
+
+    const TIMEBASE: Rational = Rational::new(1, 90000);
+
+    fn cea608(text: &[u8]) -> Vec<[u8; 2]> {
+        text.chunks(2)
+            .map(|pair| [parity(pair[0]), parity(pair.get(1).copied().unwrap_or(0))])
+            .collect()
+    }
+
+    fn user_data(cc_data: &[(u8, [u8; 2])]) -> Vec<u8> {
+        let mut payload = vec![0x00, 0x31, b'G', b'A', b'9', b'4', 0x03];
+        payload.push(0x40 | cc_data.len() as u8);
+        payload.push(0xff);
+        for &(cc_type, data) in cc_data {
+            payload.extend([0xfc | cc_type, data[0], data[1]]);
+        }
+        payload.push(0xff);
+        payload
+    }
+
+    fn frame(cc_data: &[(u8, [u8; 2])]) -> FrameMetadata {
+        FrameMetadata {
+            sei: vec![SeiMessage::UserDataRegistered(UserDataRegistered {
+                country_code: 0xb5,
+                country_code_extension: None,
+                payload: user_data(cc_data),
+            })],
+            ..FrameMetadata::default()
+        }
+    }
+
+    /// Extracts the captions of frames with one CEA-608 pair each, pushed with the first two
+    /// frames of every three swapped, as B-frames are.
+    fn extract_cea608(pairs: &[[u8; 2]]) -> Captions {
+        let mut extractor = CaptionExtractor::new(TIMEBASE);
+        let mut frames: Vec<_> = pairs.iter().enumerate().collect();
+        for chunk in frames.chunks_mut(3) {
+            if chunk.len() == 3 {
+                chunk.swap(0, 1);
+            }
+        }
+        for (i, &pair) in frames {
+            extractor.push(i as i64 * 3003, &frame(&[(0, pair)]));
+        }
+        extractor.finish()
+    }
+
+    #[test]
+    fn test_cea608() {
+        let control = |b1, b2| [[parity(b1), parity(b2)]; 2];
+        let padding = [[0x80, 0x80]; 2];
+        // Pop-on: resume caption loading, a preamble for row 15, the text, then end of caption.
+        let pairs = [
+            &control(0x14, 0x20)[..],
+            &control(0x14, 0x70),
+            &cea608(b"Fish & chips"),
+            // CC2 data is ignored.
+            &control(0x1c, 0x20),
+            &cea608(b"xx"),
+            &control(0x14, 0x2f),
+            &padding,
+            // Erase displayed memory.
+            &control(0x14, 0x2c),
+        ]
+        .concat();
+        let captions = extract_cea608(&pairs);
+        assert_eq!(
+            captions.cea608,
+            [Caption {
+                start: 13 * 3003,
+                end: 17 * 3003,
+                text: "Fish & chips".to_owned(),
+            }]
+        );
+        assert!(captions.cea708.is_empty());
+        assert_eq!(captions.cea608_data.len(), pairs.len());
+
+        let mut vtt = Vec::new();
+        write_webvtt(&captions.cea608, captions.timebase, &mut vtt).unwrap();
+        assert_eq!(
+            String::from_utf8(vtt).unwrap(),
+            "WEBVTT\n\n00:00:00.433 --> 00:00:00.567\nFish &amp; chips\n"
+        );
+
+        // Roll-up: rows show up once they're complete, and scroll off past three, the last of
+        // which is being written.
+        let carriage_return = control(0x14, 0x2d);
+        let pairs = [
+            &control(0x14, 0x26)[..],
+            &cea608(b"One"),
+            &carriage_return,
+            &cea608(b"Two"),
+            &carriage_return,
+            // An extended character replaces the standard one before it.
+            &cea608(b"TrE"),
+            &[[parity(0x12), parity(0x21)]],
+            &carriage_return,
+        ]
+        .concat();
+        let text: Vec<_> = extract_cea608(&pairs)
+            .cea608
+            .into_iter()
+            .map(|caption| caption.text)
+            .collect();
+        assert_eq!(text, ["One", "One\nTwo", "Two\nTrÉ"]);
+    }
+
+    #[test]
+    fn test_cea708() {
+        // A hidden window gets text, then is shown; the next packet clears it.
+        let block = [
+            &[0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..],
+            b"Hey",
+            &[0x0d],
+            b"there",
+            &[0x89, 0x01],
+        ]
+        .concat();
+        let packet = [&[0x0a, 0x20 | block.len() as u8][..], &block].concat();
+        assert_eq!(packet.len(), 20);
+        let mut cc_data: Vec<_> = packet
+            .chunks(2)
+            .map(|pair| (2, [pair[0], pair[1]]))
+            .collect();
+        cc_data[0].0 = 3;
+
+        let mut extractor = CaptionExtractor::new(TIMEBASE);
+        extractor.push(0, &frame(&cc_data));
+        extractor.push(3600, &frame(&[]));
+        // ClearWindows, with another service's block that's skipped.
+        extractor.push(
+            7200,
+            &frame(&[(3, [0x43, 0x22]), (2, [0x88, 0x01]), (2, [0x41, 0x41])]),
+        );
+        let captions = extractor.finish();
+        assert_eq!(
+            captions.cea708,
+            [Caption {
+                start: 0,
+                end: 7200,
+                text: "Hey\nthere".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_extract_captions() {
+        let pairs = [[0x14, 0x20], [b'H', b'i'], [0x14, 0x2f], [0x14, 0x2c]];
+        let seis: Vec<_> = pairs
+            .iter()
+            .map(|pair| {
+                let payload = [&[0xb5][..], &user_data(&[(0, pair.map(parity))])].concat();
+                [&[0x06, 0x04, payload.len() as u8][..], &payload, &[0x80]].concat()
+            })
+            .collect();
+        let p: &[u8] = &[0x41, 0x9a, 0x02];
+        // A NAL unit with the forbidden bit set follows the first frame.
+        let stream = annex_b(&[
+            &seis[0],
+            &[0x65, 0x88, 0x84],
+            &[0xc1, 0x9a],
+            &seis[1],
+            p,
+            &seis[2],
+            p,
+            &seis[3],
+            p,
+        ]);
+        let pts: Vec<_> = crate::read_frames_with_metadata(&stream, FramerConfig::default())
+            .into_iter()
+            .filter_map(|result| result.ok())
+            .map(|(frame, _)| frame.pts)
+            .collect();
+
+        let captions = extract_captions(&stream, FramerConfig::default()).unwrap();
+        assert_eq!(
+            captions.cea608,
+            [Caption {
+                start: pts[2],
+                end: pts[3],
+                text: "Hi".to_owned(),
+            }]
+        );
+        assert_eq!(captions.cea608_data.len(), 4);
+
+        let config = FramerConfig {
+            error_policy: ErrorPolicy::Stop,
+            ..FramerConfig::default()
+        };
+        assert!(matches!(
+            extract_captions(&stream, config),
+            Err(FramingError::MalformedNalHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn test_scc() {
+        let data = [
+            (0, [0x94, 0x20]),
+            (3003, [0x94, 0x20]),
+            (6006, [0x80, 0x80]),
+            (1800 * 3003, [0xc8, 0xe9]),
+            (1801 * 3003, [0x94, 0x2f]),
+        ];
+        let mut scc = Vec::new();
+        write_scc(&data, TIMEBASE, &mut scc).unwrap();
+        // A minute in, the time code skips the first two frame numbers.
+        assert_eq!(
+            String::from_utf8(scc).unwrap(),
+            "Scenarist_SCC V1.0\n\n00:00:00;00\t9420 9420\n\n00:01:00;02\tc8e9 942f\n"
+        );
+        assert_eq!(drop_frame_time_code(17982 * 3003, TIMEBASE), "00:10:00;00");
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+/// The bottom row of the screen, where roll-up captions are placed by default.
+const BOTTOM_ROW: u8 = 15;
+
+/// The characters of the special character set, `0x11 0x30` to `0x11 0x3f`. The transparent space
+/// is taken as a space.
+const SPECIAL: [char; 16] = [
+    '®', '°', '½', '¿', '™', '¢', '£', '♪', 'à', ' ', 'è', 'â', 'ê', 'î', 'ô', 'û',
+];
+
+/// The extended characters, `0x12 0x20` to `0x12 0x3f` then `0x13 0x20` to `0x13 0x3f`. Each one
+/// replaces the standard character sent before it, as a fallback for older decoders.
+const EXTENDED: [char; 64] = [
+    'Á', 'É', 'Ó', 'Ú', 'Ü', 'ü', '‘', '¡', '*', '\'', '—', '©', '℠', '•', '“', '”', 'À', 'Â', 'Ç',
+    'È', 'Ê', 'Ë', 'ë', 'Î', 'Ï', 'ï', 'Ô', 'Ù', 'ù', 'Û', '«', '»', 'Ã', 'ã', 'Í', 'Ì', 'ì', 'Ò',
+    'ò', 'Õ', 'õ', '{', '}', '\\', '^', '_', '|', '~', 'Ä', 'ä', 'Ö', 'ö', 'ß', '¥', '¤', '│', 'Å',
+    'å', 'Ø', 'ø', '┌', '┐', '└', '┘',
+];
+
+/// The rows set by preamble address codes, by the first byte's low three bits and then by bit 5 of
+/// the second byte.
+const PAC_ROWS: [[u8; 2]; 8] = [
+    [11, 11],
+    [1, 2],
+    [3, 4],
+    [12, 13],
+    [14, 15],
+    [5, 6],
+    [7, 8],
+    [9, 10],
+];
+
+/// Maps a character of the standard set, which is ASCII save for a few code points.
+fn standard_char(byte: u8) -> char {
+    match byte {
+        0x2a => 'á',
+        0x5c => 'é',
+        0x5e => 'í',
+        0x5f => 'ó',
+        0x60 => 'ú',
+        0x7b => 'ç',
+        0x7c => '÷',
+        0x7d => 'Ñ',
+        0x7e => 'ñ',
+        0x7f => '█',
+        _ => byte as char,
+    }
+}
+
+/// Sets the odd parity bit of a byte.
+#[cfg(test)]
+pub(crate) fn parity(byte: u8) -> u8 {
+    if byte.count_ones().is_multiple_of(2) {
+        byte | 0x80
+    } else {
+        byte
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    PopOn,
+    /// Roll-up with the given number of rows.
+    RollUp(u8),
+    PaintOn,
+}
+
+/// Caption memory, as rows of text. Column positions aren't kept.
+#[derive(Default)]
+struct Memory {
+    rows: BTreeMap<u8, String>,
+}
+
+impl Memory {
+    fn row(&mut self, row: u8) -> &mut String {
+        self.rows.entry(row).or_default()
+    }
+
+    fn render(&self) -> String {
+        let rows: Vec<_> = self
+            .rows
+            .values()
+            .map(|row| row.trim())
+            .filter(|row| !row.is_empty())
+            .collect();
+        rows.join("\n")
+    }
+}
+
+/// Decodes the CEA-608 byte pairs of the first field into the text of channel CC1.
+pub(crate) struct Cea608Decoder {
+    mode: Mode,
+    displayed: Memory,
+    non_displayed: Memory,
+    row: u8,
+    /// Whether the data that follows is for CC1, rather than CC2.
+    cc1: bool,
+    /// Whether a text mode command directed the data that follows to a text service.
+    text_mode: bool,
+    /// The last control code, as they're sent twice and the repeat is to be ignored.
+    last_control: Option<[u8; 2]>,
+    /// The text on screen. Roll-up captions only update it when a row is complete.
+    screen: String,
+}
+
+impl Cea608Decoder {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::PopOn,
+            displayed: Memory::default(),
+            non_displayed: Memory::default(),
+            row: BOTTOM_ROW,
+            cc1: true,
+            text_mode: false,
+            last_control: None,
+            screen: String::new(),
+        }
+    }
+
+    /// The text currently on screen, with rows separated by newlines.
+    pub fn screen(&self) -> &str {
+        &self.screen
+    }
+
+    /// Decodes a byte pair, with its parity bits. Pairs that fail the parity check are dropped.
+    pub fn decode(&mut self, pair: [u8; 2]) {
+        if pair.iter().any(|byte| byte.count_ones().is_multiple_of(2)) {
+            self.last_control = None;
+            return;
+        }
+        let [b1, b2] = pair.map(|byte| byte & 0x7f);
+        if b1 == 0 && b2 == 0 {
+            return;
+        }
+        if (0x10..=0x1f).contains(&b1) {
+            if self.last_control.replace([b1, b2]) == Some([b1, b2]) {
+                self.last_control = None;
+                return;
+            }
+            self.cc1 = b1 & 0x08 == 0;
+            if self.cc1 {
+                self.control(b1, b2);
+            }
+        } else {
+            self.last_control = None;
+            if !self.cc1 || self.text_mode {
+                return;
+            }
+            self.write(standard_char(b1));
+            if b2 >= 0x20 {
+                self.write(standard_char(b2));
+            }
+        }
+        if !matches!(self.mode, Mode::RollUp(_)) {
+            self.screen = self.displayed.render();
+        }
+    }
+
+    fn control(&mut self, b1: u8, b2: u8) {
+        match (b1, b2) {
+            (0x14, 0x20..=0x2f) => self.misc_control(b2),
+            (0x11, 0x20..=0x2f) => self.write(' '),
+            (0x11, 0x30..=0x3f) => self.write(SPECIAL[usize::from(b2 - 0x30)]),
+            (0x12 | 0x13, 0x20..=0x3f) => {
+                let row = self.row;
+                self.memory().row(row).pop();
+                let index = usize::from(b1 - 0x12) * 32 + usize::from(b2 - 0x20);
+                self.write(EXTENDED[index]);
+            }
+            // Roll-up captions stay on their base row.
+            (_, 0x40..=0x7f) if !matches!(self.mode, Mode::RollUp(_)) => {
+                self.row = PAC_ROWS[usize::from(b1 & 0x07)][usize::from(b2 & 0x20 != 0)];
+            }
+            _ => {}
+        }
+    }
+
+    fn misc_control(&mut self, command: u8) {
+        match command {
+            // Resume caption loading
+            0x20 => self.set_mode(Mode::PopOn),
+            // Backspace
+            0x21 => {
+                let row = self.row;
+                self.memory().row(row).pop();
+            }
+            // Roll-up captions, with two to four rows
+            0x25..=0x27 => {
+                if !matches!(self.mode, Mode::RollUp(_)) {
+                    self.displayed = Memory::default();
+                    self.non_displayed = Memory::default();
+                    self.row = BOTTOM_ROW;
+                    self.screen.clear();
+                }
+                self.set_mode(Mode::RollUp(command - 0x23));
+            }
+            // Resume direct captioning
+            0x29 => self.set_mode(Mode::PaintOn),
+            // Text restart and resume text display
+            0x2a | 0x2b => self.text_mode = true,
+            // Erase displayed memory
+            0x2c => {
+                self.displayed = Memory::default();
+                self.screen.clear();
+            }
+            // Carriage return
+            0x2d => match self.mode {
+                Mode::RollUp(rows) => {
+                    let top = self.row.saturating_sub(rows - 1);
+                    let rows = mem::take(&mut self.displayed.rows);
+                    self.displayed.rows = rows
+                        .into_iter()
+                        .filter(|&(row, _)| row > top && row <= self.row)
+                        .map(|(row, text)| (row - 1, text))
+                        .collect();
+                    self.screen = self.displayed.render();
+                }
+                _ => self.row = (self.row + 1).min(BOTTOM_ROW),
+            },
+            // Erase non-displayed memory
+            0x2e => self.non_displayed = Memory::default(),
+            // End of caption
+            0x2f => {
+                mem::swap(&mut self.displayed, &mut self.non_displayed);
+                self.set_mode(Mode::PopOn);
+            }
+            _ => {}
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.text_mode = false;
+    }
+
+    /// The memory that characters go to: the non-displayed one for pop-on captions.
+    fn memory(&mut self) -> &mut Memory {
+        match self.mode {
+            Mode::PopOn => &mut self.non_displayed,
+            Mode::RollUp(_) | Mode::PaintOn => &mut self.displayed,
+        }
+    }
+
+    fn write(&mut self, c: char) {
+        let row = self.row;
+        self.memory().row(row).push(c);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Decodes bytes without their parity bits, two at a time.
+    fn decode(decoder: &mut Cea608Decoder, bytes: &[u8]) {
+        for pair in bytes.chunks(2) {
+            decoder.decode([parity(pair[0]), parity(pair.get(1).copied().unwrap_or(0))]);
+        }
+    }
+
+    #[test]
+    fn test_misc_control() {
+        let mut decoder = Cea608Decoder::new();
+        // Pop-on captions are loaded off screen, with a backspace, until the end of caption.
+        decode(&mut decoder, &[0x14, 0x20, 0x14, 0x70]);
+        decode(&mut decoder, b"Hellp");
+        decode(&mut decoder, &[0x14, 0x21]);
+        decode(&mut decoder, b"o");
+        assert_eq!(decoder.screen(), "");
+        decode(&mut decoder, &[0x14, 0x2f]);
+        assert_eq!(decoder.screen(), "Hello");
+
+        // Erasing non-displayed memory leaves nothing to swap in.
+        decode(&mut decoder, &[0x14, 0x20]);
+        decode(&mut decoder, b"Bye");
+        decode(&mut decoder, &[0x14, 0x2e, 0x14, 0x2f]);
+        assert_eq!(decoder.screen(), "");
+
+        // Paint-on captions show up as they're written, and text mode data isn't captions.
+        decode(&mut decoder, &[0x14, 0x29]);
+        decode(&mut decoder, b"Now");
+        assert_eq!(decoder.screen(), "Now");
+        decode(&mut decoder, &[0x14, 0x2a]);
+        decode(&mut decoder, b"text");
+        assert_eq!(decoder.screen(), "Now");
+        decode(&mut decoder, &[0x14, 0x2c]);
+        assert_eq!(decoder.screen(), "");
+
+        // Roll-up captions with two rows, one of which is the row being written.
+        decode(&mut decoder, &[0x14, 0x25]);
+        for row in [b"A", b"B", b"C"] {
+            decode(&mut decoder, row);
+            decode(&mut decoder, &[0x14, 0x2d]);
+        }
+        assert_eq!(decoder.screen(), "C");
+    }
+
+    #[test]
+    fn test_duplicate_control() {
+        let mut decoder = Cea608Decoder::new();
+        decode(
+            &mut decoder,
+            &[0x14, 0x20, 0x14, 0x20, 0x14, 0x70, 0x14, 0x70],
+        );
+        // Of three backspaces in a row, the second is a repeat but the third isn't.
+        decode(&mut decoder, b"abc");
+        decode(&mut decoder, &[0x14, 0x21, 0x14, 0x21, 0x14, 0x21]);
+        // A pair that fails the parity check doesn't count as the first of the two.
+        decode(&mut decoder, b"bc");
+        decode(&mut decoder, &[0x14, 0x21]);
+        decoder.decode([0x14, 0x21]);
+        decode(&mut decoder, &[0x14, 0x21]);
+        decode(&mut decoder, &[0x14, 0x2f, 0x14, 0x2f]);
+        assert_eq!(decoder.screen(), "a");
+        // Text in between makes the same code count again.
+        decode(&mut decoder, b"xy");
+        decode(&mut decoder, &[0x14, 0x2f]);
+        assert_eq!(decoder.screen(), "xy");
+    }
+}
@@ -0,0 +1,350 @@
+/// The caption service that's decoded, the primary one.
+const PRIMARY_SERVICE: u8 = 1;
+
+/// A caption window. Only its text and visibility are kept, not its position or style.
+#[derive(Default)]
+struct Window {
+    visible: bool,
+    text: String,
+}
+
+/// Reassembles the DTVCC packets of CEA-708 caption data and decodes the text of the primary
+/// caption service.
+pub(crate) struct Cea708Decoder {
+    /// The packet being assembled, from its header on.
+    packet: Vec<u8>,
+    windows: [Option<Window>; 8],
+    current: usize,
+}
+
+impl Cea708Decoder {
+    pub fn new() -> Self {
+        Self {
+            packet: Vec::new(),
+            windows: Default::default(),
+            current: 0,
+        }
+    }
+
+    /// The text of the visible windows, each on its own rows.
+    pub fn screen(&self) -> String {
+        let windows: Vec<_> = self
+            .windows
+            .iter()
+            .flatten()
+            .filter(|window| window.visible)
+            .map(|window| window.text.trim())
+            .filter(|text| !text.is_empty())
+            .collect();
+        windows.join("\n")
+    }
+
+    /// Adds a byte pair of type 3, which starts a packet, or 2, which continues one.
+    pub fn push(&mut self, cc_type: u8, data: [u8; 2]) {
+        if cc_type == 3 {
+            // A packet that was cut short is dropped.
+            self.packet.clear();
+        } else if self.packet.is_empty() {
+            return;
+        }
+        self.packet.extend_from_slice(&data);
+        let size = match self.packet[0] & 0x3f {
+            0 => 128,
+            code => usize::from(code) * 2,
+        };
+        if self.packet.len() >= size {
+            let mut packet = std::mem::take(&mut self.packet);
+            packet.truncate(size);
+            self.packet_complete(&packet[1..]);
+        }
+    }
+
+    /// Splits a packet, after its header, into service blocks.
+    fn packet_complete(&mut self, mut data: &[u8]) {
+        while let Some((&header, rest)) = data.split_first() {
+            let mut service = header >> 5;
+            let size = usize::from(header & 0x1f);
+            let mut rest = rest;
+            if service == 0 || size == 0 {
+                return;
+            }
+            if service == 7 {
+                let Some((&extended, tail)) = rest.split_first() else {
+                    return;
+                };
+                service = extended & 0x3f;
+                rest = tail;
+            }
+            let Some(block) = rest.get(..size) else {
+                return;
+            };
+            if service == PRIMARY_SERVICE {
+                self.service_block(block);
+            }
+            data = &rest[size..];
+        }
+    }
+
+    fn service_block(&mut self, mut block: &[u8]) {
+        while let Some((&code, rest)) = block.split_first() {
+            let params = match code {
+                0x10 => self.extended(rest),
+                0x00..=0x0f => {
+                    self.c0(code);
+                    0
+                }
+                0x11..=0x17 => 1,
+                0x18..=0x1f => 2,
+                0x20..=0x7e => {
+                    self.write(code as char);
+                    0
+                }
+                0x7f => {
+                    self.write('♪');
+                    0
+                }
+                0x80..=0x9f => match self.c1(code, rest) {
+                    Some(params) => params,
+                    None => return,
+                },
+                // G1 is Latin-1.
+                0xa0..=0xff => {
+                    self.write(code as char);
+                    0
+                }
+            };
+            block = rest.get(params..).unwrap_or_default();
+        }
+    }
+
+    fn c0(&mut self, code: u8) {
+        let Some(window) = self.window() else {
+            return;
+        };
+        match code {
+            // Backspace
+            0x08 => {
+                window.text.pop();
+            }
+            // Form feed
+            0x0c => window.text.clear(),
+            // Carriage return
+            0x0d => window.text.push('\n'),
+            // Horizontal carriage return, which erases the current row
+            0x0e => {
+                let start = window.text.rfind('\n').map_or(0, |i| i + 1);
+                window.text.truncate(start);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles a C1 command, returning the number of parameter bytes it takes, or `None` if they
+    /// run past the block.
+    fn c1(&mut self, code: u8, params: &[u8]) -> Option<usize> {
+        let len = match code {
+            0x80..=0x87 | 0x8e | 0x8f | 0x93..=0x96 => 0,
+            0x88..=0x8d => 1,
+            0x90 | 0x92 => 2,
+            0x91 => 3,
+            0x97 => 4,
+            0x98..=0x9f => 6,
+            _ => unreachable!(),
+        };
+        let params = params.get(..len)?;
+        let windows = params.first().copied().unwrap_or_default();
+        let selected = (0..8).filter(move |i| windows & 1 << i != 0);
+        match code {
+            // SetCurrentWindow
+            0x80..=0x87 => self.current = usize::from(code - 0x80),
+            // ClearWindows
+            0x88 => {
+                for i in selected {
+                    if let Some(window) = &mut self.windows[i] {
+                        window.text.clear();
+                    }
+                }
+            }
+            // DisplayWindows, HideWindows and ToggleWindows
+            0x89..=0x8b => {
+                for i in selected {
+                    if let Some(window) = &mut self.windows[i] {
+                        window.visible = match code {
+                            0x89 => true,
+                            0x8a => false,
+                            _ => !window.visible,
+                        };
+                    }
+                }
+            }
+            // DeleteWindows
+            0x8c => {
+                for i in selected {
+                    self.windows[i] = None;
+                }
+            }
+            // Reset
+            0x8f => self.windows = Default::default(),
+            // DefineWindow
+            0x98..=0x9f => {
+                self.current = usize::from(code - 0x98);
+                let window = self.windows[self.current].get_or_insert_with(Window::default);
+                window.visible = params[0] & 0x20 != 0;
+            }
+            _ => {}
+        }
+        Some(len)
+    }
+
+    /// Handles a code of the extended sets, after `EXT1`, returning the number of bytes it and its
+    /// parameters take.
+    fn extended(&mut self, rest: &[u8]) -> usize {
+        let Some(&code) = rest.first() else {
+            return 0;
+        };
+        match code {
+            0x00..=0x07 => 1,
+            0x08..=0x0f => 2,
+            0x10..=0x17 => 3,
+            0x18..=0x1f => 4,
+            0x80..=0x87 => 5,
+            0x88..=0x8f => 6,
+            0x90..=0x9f => 2 + rest.get(1).map_or(0, |&len| usize::from(len & 0x3f)),
+            // G3, of which only the caption icon is defined.
+            0xa0..=0xff => 1,
+            _ => {
+                let c = match code {
+                    0x20 => ' ',
+                    0x21 => '\u{a0}',
+                    0x25 => '…',
+                    0x2a => 'Š',
+                    0x2c => 'Œ',
+                    0x30 => '█',
+                    0x31 => '‘',
+                    0x32 => '’',
+                    0x33 => '“',
+                    0x34 => '”',
+                    0x35 => '•',
+                    0x39 => '™',
+                    0x3a => 'š',
+                    0x3c => 'œ',
+                    0x3d => '℠',
+                    0x3f => 'Ÿ',
+                    _ => '_',
+                };
+                self.write(c);
+                1
+            }
+        }
+    }
+
+    fn window(&mut self) -> Option<&mut Window> {
+        self.windows[self.current].as_mut()
+    }
+
+    fn write(&mut self, c: char) {
+        if let Some(window) = self.window() {
+            window.text.push(c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// DefineWindow for window 0, visible.
+    const DEFINE_WINDOW: [u8; 7] = [0x98, 0x20, 0, 0, 0, 0, 0];
+
+    /// Builds a packet with a block of the primary service, padded to a whole number of pairs.
+    fn packet(block: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 0x20 | block.len() as u8];
+        packet.extend_from_slice(block);
+        if packet.len() % 2 == 1 {
+            packet.push(0);
+        }
+        packet[0] = (packet.len() / 2) as u8;
+        packet
+    }
+
+    fn push(decoder: &mut Cea708Decoder, packet: &[u8]) {
+        for (i, pair) in packet.chunks(2).enumerate() {
+            let cc_type = if i == 0 { 3 } else { 2 };
+            decoder.push(cc_type, [pair[0], pair[1]]);
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut decoder = Cea708Decoder::new();
+        // Backspace, carriage return and horizontal carriage return.
+        let block = [&DEFINE_WINDOW[..], b"ab\x08\rcd\x0eef"].concat();
+        push(&mut decoder, &packet(&block));
+        assert_eq!(decoder.screen(), "a\nef");
+
+        // A hidden window, with characters of the extended and G0 sets, then shown.
+        let block = [
+            &[0x99, 0x00, 0, 0, 0, 0, 0][..],
+            &[0x10, 0x25, 0x7f],
+            &[0x89, 0x02],
+        ]
+        .concat();
+        push(&mut decoder, &packet(&block));
+        assert_eq!(decoder.screen(), "a\nef\n…♪");
+
+        // HideWindows, ToggleWindows and ClearWindows, then DeleteWindows, after which the text
+        // for the deleted window goes nowhere.
+        push(&mut decoder, &packet(&[0x8a, 0x01]));
+        assert_eq!(decoder.screen(), "…♪");
+        push(&mut decoder, &packet(&[0x8b, 0x03]));
+        assert_eq!(decoder.screen(), "a\nef");
+        push(&mut decoder, &packet(&[0x88, 0x01]));
+        assert_eq!(decoder.screen(), "");
+        push(&mut decoder, &packet(&[0x8c, 0x01, 0x80, b'x']));
+        assert_eq!(decoder.screen(), "");
+    }
+
+    #[test]
+    fn test_truncated_packet() {
+        let mut decoder = Cea708Decoder::new();
+        // A packet cut short by the start of the next one is dropped, as are pairs that continue
+        // no packet.
+        let block = [&DEFINE_WINDOW[..], b"Hi"].concat();
+        push(&mut decoder, &packet(&block)[..4]);
+        assert_eq!(decoder.screen(), "");
+        let block = [&DEFINE_WINDOW[..], b"Yo"].concat();
+        push(&mut decoder, &packet(&block));
+        assert_eq!(decoder.screen(), "Yo");
+        decoder.push(2, *b"ab");
+        assert_eq!(decoder.screen(), "Yo");
+
+        // A command whose parameters run past the block ends it.
+        push(&mut decoder, &packet(&[b'!', 0x88]));
+        assert_eq!(decoder.screen(), "Yo!");
+    }
+
+    #[test]
+    fn test_oversized_service_block() {
+        let mut decoder = Cea708Decoder::new();
+        push(&mut decoder, &packet(&[&DEFINE_WINDOW[..], b"Yo"].concat()));
+
+        // A block that claims more bytes than the packet holds is dropped, with the rest of the
+        // packet.
+        push(&mut decoder, &[0x03, 0x20 | 31, b'A', b'B', 0x21, b'C']);
+        assert_eq!(decoder.screen(), "Yo");
+
+        // So is an extended service number past the end of the packet.
+        push(&mut decoder, &[0x01, 0xe1]);
+        assert_eq!(decoder.screen(), "Yo");
+
+        // Bytes past the packet size are ignored, and a size code of zero is 128 bytes.
+        push(&mut decoder, &[0x02, 0x21, b'!', 0x21, b'?', b'?']);
+        assert_eq!(decoder.screen(), "Yo!");
+        let mut packet = vec![0x00, 0x20 | 3, b'a', b'b', b'c'];
+        packet.resize(128, 0);
+        push(&mut decoder, &packet[..126]);
+        assert_eq!(decoder.screen(), "Yo!");
+        decoder.push(2, [0, 0]);
+        assert_eq!(decoder.screen(), "Yo!abc");
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{DecoderInputQueue, PopError, Reader};
+use std::fmt;
+use std::time::Duration;
+use xcoder_quadra::decoder::{XcoderDecoderConfig, XcoderDecoderInputFrame};
+
+/// An in-band message for the pipeline consuming the queue. Each one ends the current run of
+/// frames, so that the decoder fed by that run can be drained before the message is acted upon.
+pub enum ControlMessage {
+    /// Drain the decoder, then continue with the same configuration.
+    Flush,
+    /// The stream ended cleanly. No more frames will follow.
+    EndOfStream,
+    /// The frames that follow belong to a new stream with the same parameters, e.g. after a
+    /// timestamp jump or a splice.
+    Discontinuity,
+    /// The frames that follow need a decoder with a different configuration.
+    Reconfigure(XcoderDecoderConfig),
+}
+
+impl fmt::Debug for ControlMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flush => f.write_str("Flush"),
+            Self::EndOfStream => f.write_str("EndOfStream"),
+            Self::Discontinuity => f.write_str("Discontinuity"),
+            Self::Reconfigure(_) => f.write_str("Reconfigure(..)"),
+        }
+    }
+}
+
+/// An item carried by the queue.
+pub enum DecoderInputItem<E> {
+    Frame(Result<XcoderDecoderInputFrame, E>),
+    Control(ControlMessage),
+}
+
+impl<E> From<Result<XcoderDecoderInputFrame, E>> for DecoderInputItem<E> {
+    fn from(frame: Result<XcoderDecoderInputFrame, E>) -> Self {
+        Self::Frame(frame)
+    }
+}
+
+impl<E> From<ControlMessage> for DecoderInputItem<E> {
+    fn from(message: ControlMessage) -> Self {
+        Self::Control(message)
+    }
+}
+
+impl<E> DecoderInputItem<E> {
+    pub fn is_frame(&self) -> bool {
+        matches!(self, Self::Frame(_))
+    }
+
+    /// Unwraps an item that's known to be a frame, e.g. one handed back by a frame push.
+    pub(crate) fn expect_frame(self) -> Result<XcoderDecoderInputFrame, E> {
+        match self {
+            Self::Frame(frame) => frame,
+            Self::Control(_) => unreachable!("expected a frame"),
+        }
+    }
+
+    /// Unwraps an item that's known to be a control message.
+    pub(crate) fn expect_control(self) -> ControlMessage {
+        match self {
+            Self::Control(message) => message,
+            Self::Frame(_) => unreachable!("expected a control message"),
+        }
+    }
+}
+
+/// A plain-frame view of a [`DecoderInputQueue`], suitable as the input of an `XcoderDecoder`.
+/// Iteration ends when the producer is dropped or a control message is reached. The pipeline
+/// can then drain the decoder, handle the message returned by
+/// [`DecoderInputQueue::take_control`], and continue with a fresh view.
+pub struct DecoderInputFrames<'a, E> {
+    reader: &'a mut Reader<E>,
+}
+
+impl<E> Iterator for DecoderInputFrames<'_, E> {
+    type Item = Result<XcoderDecoderInputFrame, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.pop_within(None, Reader::try_pop_frame).ok()
+    }
+}
+
+impl<E> DecoderInputQueue<E> {
+    /// Returns a plain-frame view of the queue, which borrows it until the view is dropped.
+    pub fn frames(&mut self) -> DecoderInputFrames<'_, E> {
+        DecoderInputFrames {
+            reader: &mut self.reader,
+        }
+    }
+
+    /// Takes the control message that ended the last run of frames, if any. Frames can be popped
+    /// again afterwards.
+    pub fn take_control(&mut self) -> Option<ControlMessage> {
+        self.reader.control.take()
+    }
+
+    /// Pops the next frame or control message without waiting.
+    pub fn try_pop_item(&mut self) -> Result<DecoderInputItem<E>, PopError> {
+        self.reader.try_pop_item()
+    }
+
+    /// Pops the next frame or control message, waiting up to `timeout` for one if the queue is
+    /// empty.
+    pub fn pop_item_timeout(&mut self, timeout: Duration) -> Result<DecoderInputItem<E>, PopError> {
+        self.reader.pop_within(Some(timeout), Reader::try_pop_item)
+    }
+
+    /// Pops the next frame or control message, waiting for one if necessary. Returns `None` once
+    /// the producer has been dropped and the queue is empty.
+    pub fn pop_item(&mut self) -> Option<DecoderInputItem<E>> {
+        self.reader.pop_within(None, Reader::try_pop_item).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_frame;
+
+    #[test]
+    fn test_control_messages_end_runs_of_frames() {
+        let (mut queue, mut producer) = DecoderInputQueue::<()>::new(8);
+        producer.push(Ok(test_frame(0))).unwrap();
+        producer.push(Ok(test_frame(1))).unwrap();
+        producer.push_control(ControlMessage::Flush).unwrap();
+        producer.push(Ok(test_frame(2))).unwrap();
+        producer.push_control(ControlMessage::EndOfStream).unwrap();
+        drop(producer);
+
+        let pts: Vec<_> = queue.frames().map(|frame| frame.unwrap().pts).collect();
+        assert_eq!(pts, vec![0, 1]);
+        // The run stays ended until the message is taken.
+        assert!(queue.frames().next().is_none());
+        assert!(matches!(queue.take_control(), Some(ControlMessage::Flush)));
+
+        let pts: Vec<_> = queue.frames().map(|frame| frame.unwrap().pts).collect();
+        assert_eq!(pts, vec![2]);
+        assert!(matches!(
+            queue.try_pop_item(),
+            Ok(DecoderInputItem::Control(ControlMessage::EndOfStream))
+        ));
+        assert!(queue.take_control().is_none());
+        assert!(queue.pop_item().is_none());
+    }
+}
@@ -0,0 +1,280 @@
+use std::borrow::Cow;
+
+use crate::bits::{to_rbsp, BitWriter};
+use crate::framer::Codec;
+use crate::hevc;
+use crate::params::{Rational, Sps};
+use crate::sei;
+
+/// An H.264 access unit delimiter that allows any slice type.
+const H264_AUD: &[u8] = &[0x09, 0xf0];
+/// An H.265 access unit delimiter that allows any slice type.
+const HEVC_AUD: &[u8] = &[0x46, 0x01, 0x50];
+
+/// A rule of a [`NalFilter`]. NAL unit types are those of the codec being framed: for H.264, 9 for
+/// access unit delimiters, 12 for filler data, and 14, 15 and 20 for the SVC and MVC extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NalRule {
+    /// Drops the NAL units of a type.
+    DropNalType(u8),
+    /// Removes the SEI messages of a payload type. SEI NAL units left without messages are
+    /// dropped.
+    DropSeiPayload(u32),
+    /// Starts each access unit with an access unit delimiter, unless it has one.
+    InsertAud,
+    /// Rewrites the VUI timing info of each H.264 SPS to signal a fixed frame rate. H.265 SPSs are
+    /// left as they are.
+    SetFrameRate(Rational),
+}
+
+/// Drops and rewrites NAL units before they're assembled into access units, see
+/// [`FramerConfig::filter`](crate::FramerConfig::filter). The rules apply to each NAL
+/// unit in order, and NAL units that fail to parse for a rewrite are passed on as they are.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NalFilter {
+    rules: Vec<NalRule>,
+}
+
+impl NalFilter {
+    pub fn new(rules: impl IntoIterator<Item = NalRule>) -> Self {
+        Self {
+            rules: rules.into_iter().collect(),
+        }
+    }
+
+    /// Applies the rules to a NAL unit, returning `None` if it's dropped.
+    pub(crate) fn apply<'a>(&self, codec: Codec, nalu: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        let (nal_unit_type, header_len, sei) = match codec {
+            Codec::H264 => (nalu[0] & 0x1f, 1, nalu[0] & 0x1f == 6),
+            Codec::H265 => {
+                let nal_unit_type = hevc::nal_unit_type(nalu[0]);
+                let sei = matches!(nal_unit_type, hevc::PREFIX_SEI | hevc::SUFFIX_SEI);
+                (nal_unit_type, 2, sei)
+            }
+        };
+        let mut nalu = Cow::Borrowed(nalu);
+        for rule in &self.rules {
+            match *rule {
+                NalRule::DropNalType(dropped) if dropped == nal_unit_type => return None,
+                NalRule::DropSeiPayload(dropped) if sei && nalu.len() > header_len => {
+                    // Malformed SEI NAL units are passed on whole rather than guessed at.
+                    let rbsp = to_rbsp(&nalu[header_len..]);
+                    let Some(messages) = sei::all_messages(&rbsp) else {
+                        continue;
+                    };
+                    let kept: Vec<_> = messages
+                        .iter()
+                        .filter(|&&(payload_type, _)| payload_type != dropped)
+                        .collect();
+                    if kept.is_empty() {
+                        return None;
+                    }
+                    if kept.len() == messages.len() {
+                        continue;
+                    }
+                    let mut writer = BitWriter::default();
+                    for byte in &nalu[..header_len] {
+                        writer = writer.bits(8, (*byte).into());
+                    }
+                    for &&(payload_type, payload) in &kept {
+                        writer = write_value(writer, payload_type);
+                        writer = write_value(writer, payload.len() as u32);
+                        for &byte in payload {
+                            writer = writer.bits(8, byte.into());
+                        }
+                    }
+                    nalu = Cow::Owned(writer.finish());
+                }
+                NalRule::SetFrameRate(frame_rate) if codec == Codec::H264 && nal_unit_type == 7 => {
+                    if let Ok(sps) = Sps::rewrite_frame_rate(&nalu, frame_rate) {
+                        nalu = Cow::Owned(sps);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(nalu)
+    }
+
+    /// Returns the access unit delimiter to insert before a NAL unit that starts an access unit.
+    pub(crate) fn delimiter(&self, codec: Codec, nalu: &[u8]) -> Option<&'static [u8]> {
+        if !self.rules.contains(&NalRule::InsertAud) {
+            return None;
+        }
+        match codec {
+            Codec::H264 if nalu[0] & 0x1f != 9 => Some(H264_AUD),
+            Codec::H265 if hevc::nal_unit_type(nalu[0]) != hevc::AUD => Some(HEVC_AUD),
+            _ => None,
+        }
+    }
+}
+
+/// Writes an SEI `payloadType` or `payloadSize`, as a run of 0xff bytes and a last byte that sum
+/// up to it.
+fn write_value(mut writer: BitWriter, mut value: u32) -> BitWriter {
+    while value >= 0xff {
+        writer = writer.bits(8, 0xff);
+        value -= 0xff;
+    }
+    writer.bits(8, value.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::avcc::annex_b;
+    use crate::{read_frames_with_config, AnnexBFramer, FramerConfig};
+
+    /// Builds a 720p Baseline SPS, with a VUI that has 25 fps timing info and a bitstream
+    /// restriction after it, or without a VUI.
+    fn sps(vui: bool) -> Vec<u8> {
+        let writer = BitWriter::default()
+            .bits(8, 0x67)
+            .bits(8, 66)
+            .bits(8, 0)
+            .bits(8, 31)
+            .ue(0)
+            .ue(0)
+            // pic_order_cnt_type and max_num_ref_frames
+            .ue(2)
+            .ue(1)
+            .flag(false)
+            .ue(79)
+            .ue(44)
+            .flag(true)
+            .flag(true)
+            .flag(false)
+            .flag(vui);
+        if !vui {
+            return writer.finish();
+        }
+        writer
+            .bits(4, 0)
+            .flag(true)
+            .bits(32, 1)
+            .bits(32, 50)
+            .flag(true)
+            // No HRD parameters, then pic_struct_present_flag and the bitstream restriction
+            .bits(2, 0)
+            .flag(true)
+            .flag(true)
+            .flag(true)
+            .ue(2)
+            .ue(1)
+            .ue(16)
+            .ue(16)
+            .ue(0)
+            .ue(1)
+            .finish()
+    }
+
+    #[test]
+    fn test_set_frame_rate() {
+        let frame_rate = Rational::new(30000, 1001);
+        for vui in [true, false] {
+            let original = Sps::parse(&sps(vui)).unwrap();
+            let rewritten = Sps::rewrite_frame_rate(&sps(vui), frame_rate).unwrap();
+            let rewritten = Sps::parse(&rewritten).unwrap();
+
+            // The frame rate comes back as `time_scale` over twice `num_units_in_tick`.
+            assert_eq!(rewritten.frame_rate(), Some(Rational::new(60000, 2002)));
+            let vui = rewritten.vui.clone().unwrap();
+            assert!(vui.timing_info.unwrap().fixed_frame_rate);
+            assert_eq!(
+                vui.bitstream_restriction,
+                original
+                    .vui
+                    .as_ref()
+                    .and_then(|vui| vui.bitstream_restriction)
+            );
+            assert_eq!(
+                vui.pic_struct_present,
+                original.vui.is_some_and(|vui| vui.pic_struct_present)
+            );
+            assert_eq!((rewritten.width(), rewritten.height()), (1280, 720));
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let aud: &[u8] = &[0x09, 0x10];
+        // An unregistered user data message then a recovery point.
+        let sei = [
+            &[0x06, 0x05, 0x10][..],
+            &[0x42; 16],
+            &[0x06, 0x01, 0x84, 0x80],
+        ]
+        .concat();
+        let filler: &[u8] = &[0x0c, 0xff, 0xff, 0x80];
+        let prefix: &[u8] = &[0x0e, 0x80, 0x00];
+        let pps: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+        let idr: &[u8] = &[0x65, 0x88, 0x84];
+        let p: &[u8] = &[0x41, 0x9a, 0x02];
+        let sps = sps(false);
+        let stream = annex_b(&[aud, &sei, &sps, pps, prefix, idr, filler, prefix, p]);
+        let filter = NalFilter::new([
+            NalRule::DropNalType(9),
+            NalRule::DropNalType(12),
+            NalRule::DropNalType(14),
+            NalRule::DropNalType(20),
+            NalRule::DropSeiPayload(5),
+            NalRule::InsertAud,
+            NalRule::SetFrameRate(Rational::new(24, 1)),
+        ]);
+        let config = FramerConfig {
+            filter,
+            ..FramerConfig::default()
+        };
+        let frames: Vec<_> = read_frames_with_config(&stream, config)
+            .into_iter()
+            .map(|frame| frame.unwrap().data)
+            .collect();
+
+        let rewritten_sps = Sps::rewrite_frame_rate(&sps, Rational::new(24, 1)).unwrap();
+        assert_eq!(
+            frames,
+            [
+                annex_b(&[
+                    H264_AUD,
+                    &[0x06, 0x06, 0x01, 0x84, 0x80],
+                    &rewritten_sps,
+                    pps,
+                    idr
+                ]),
+                annex_b(&[H264_AUD, p]),
+            ]
+        );
+
+        // SEI NAL units left empty are dropped, and filters carry over a reset.
+        let filter = NalFilter::new([NalRule::DropSeiPayload(5), NalRule::DropSeiPayload(6)]);
+        let mut framer = AnnexBFramer::with_config(FramerConfig {
+            filter,
+            ..FramerConfig::default()
+        });
+        assert_eq!(framer.push(&stream).count(), 1);
+        assert_eq!(framer.finish().count(), 1);
+        let frame = framer.push(&stream).next().unwrap().unwrap();
+        assert_eq!(frame.data[..6], [0, 0, 0, 1, 0x09, 0x10]);
+        assert_eq!(frame.data[6..11], [0, 0, 0, 1, 0x67]);
+    }
+
+    #[test]
+    fn test_malformed_sei() {
+        let filter = NalFilter::new([NalRule::DropSeiPayload(5)]);
+        // The first message overruns the NAL unit, then a message after a droppable one does, then
+        // the trailing bits are missing.
+        let malformed: [&[u8]; 3] = [
+            &[0x06, 0x05, 0x20, 0x42, 0x42, 0x80],
+            &[0x06, 0x05, 0x01, 0x42, 0x06, 0x30, 0x84, 0x80],
+            &[0x06, 0x05, 0x01, 0x42, 0x06, 0x01, 0x84],
+        ];
+        for nalu in malformed {
+            assert_eq!(filter.apply(Codec::H264, nalu).as_deref(), Some(nalu));
+        }
+        let nalu = [0x06, 0x05, 0x01, 0x42, 0x06, 0x01, 0x84, 0x80];
+        assert_eq!(
+            filter.apply(Codec::H264, &nalu).as_deref(),
+            Some(&[0x06, 0x06, 0x01, 0x84, 0x80][..])
+        );
+    }
+}
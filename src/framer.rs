@@ -0,0 +1,868 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+use crate::filter::NalFilter;
+use crate::hevc::{self, HevcParameterSets, HevcPocState, HevcPps, HevcSliceHeader, HevcSps};
+use crate::join::Joiner;
+use crate::metadata::{FrameInspector, FrameMetadata};
+use crate::params::{Pps, Rational, Sps};
+use crate::poc::PocState;
+use crate::probe::{StreamChange, StreamInfo};
+use crate::slice::{ParameterSets, SliceHeader};
+use crate::timestamps::{PictureTiming, Timestamper};
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// An error found while splitting a stream into access units or temporal units. Offsets are in
+/// bytes from the start of the stream, and NAL units and OBUs are indexed in stream order from
+/// zero.
+#[derive(Debug)]
+pub enum FramingError {
+    /// The forbidden zero bit is set, or a field of the header is out of range for the NAL unit
+    /// type: a zero `nal_ref_idc` for an H.264 type that must be used as a reference, or a zero
+    /// `nuh_temporal_id_plus1` in H.265. `header` holds the header bytes, one for H.264 and two for
+    /// H.265.
+    MalformedNalHeader {
+        offset: u64,
+        nal_index: u64,
+        header: u16,
+    },
+    /// The input ended before the start code of a NAL unit was complete, or right after it.
+    TruncatedStartCode { offset: u64 },
+    /// The NAL unit type is reserved or unspecified by the codec.
+    UnsupportedNalType {
+        offset: u64,
+        nal_index: u64,
+        nal_unit_type: u8,
+    },
+    /// The NAL unit couldn't be assigned to an access unit.
+    AccessUnitCounting {
+        offset: u64,
+        nal_index: u64,
+        source: io::Error,
+    },
+    /// The forbidden bit or the reserved bit of an OBU header is set.
+    MalformedObuHeader {
+        offset: u64,
+        obu_index: u64,
+        header: u8,
+    },
+    /// The OBU has no size field, or an invalid one, so the OBUs that follow it can't be found.
+    UnsizedObu { offset: u64, obu_index: u64 },
+    /// The input ended in the middle of an OBU, or an OBU overruns the IVF frame holding it.
+    TruncatedObu { offset: u64 },
+    /// The input doesn't start with a valid IVF file header.
+    InvalidIvfHeader,
+    /// The IVF file holds a codec other than AV1.
+    UnsupportedIvfCodec { fourcc: [u8; 4] },
+    /// The input ended in the middle of an IVF frame.
+    TruncatedIvfFrame { offset: u64 },
+    /// The size in the header of an IVF frame is beyond any AV1 frame, so it's taken to be
+    /// corrupt. `offset` is that of the frame header.
+    OversizedIvfFrame { offset: u64, size: u32 },
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedNalHeader {
+                offset,
+                nal_index,
+                header,
+            } => write!(
+                f,
+                "malformed header {header:#x} in NAL unit {nal_index} at byte {offset}"
+            ),
+            Self::TruncatedStartCode { offset } => {
+                write!(f, "truncated start code at byte {offset}")
+            }
+            Self::UnsupportedNalType {
+                offset,
+                nal_index,
+                nal_unit_type,
+            } => write!(
+                f,
+                "unsupported type {nal_unit_type} of NAL unit {nal_index} at byte {offset}"
+            ),
+            Self::AccessUnitCounting {
+                offset,
+                nal_index,
+                source,
+            } => write!(
+                f,
+                "failed to assign NAL unit {nal_index} at byte {offset} to an access unit: {source}"
+            ),
+            Self::MalformedObuHeader {
+                offset,
+                obu_index,
+                header,
+            } => write!(
+                f,
+                "malformed header {header:#04x} in OBU {obu_index} at byte {offset}"
+            ),
+            Self::UnsizedObu { offset, obu_index } => {
+                write!(
+                    f,
+                    "missing or invalid size of OBU {obu_index} at byte {offset}"
+                )
+            }
+            Self::TruncatedObu { offset } => write!(f, "truncated OBU at byte {offset}"),
+            Self::InvalidIvfHeader => write!(f, "invalid IVF file header"),
+            Self::UnsupportedIvfCodec { fourcc } => write!(
+                f,
+                "unsupported IVF codec {:?}",
+                String::from_utf8_lossy(fourcc)
+            ),
+            Self::TruncatedIvfFrame { offset } => {
+                write!(f, "truncated IVF frame at byte {offset}")
+            }
+            Self::OversizedIvfFrame { offset, size } => {
+                write!(f, "oversized IVF frame of {size} bytes at byte {offset}")
+            }
+        }
+    }
+}
+
+impl Error for FramingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::AccessUnitCounting { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// What the framer does when it runs into a [`FramingError`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Drop the offending NAL unit and carry on.
+    Skip,
+    /// Emit the error, drop the access unit in progress and ignore the rest of the input.
+    Stop,
+    /// Emit the error in place and carry on. The offending NAL unit is dropped.
+    #[default]
+    Propagate,
+}
+
+/// The codec of an Annex B elementary stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Codec {
+    #[default]
+    H264,
+    H265,
+}
+
+/// Configuration for an [`AnnexBFramer`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FramerConfig {
+    pub codec: Codec,
+    pub error_policy: ErrorPolicy,
+    /// The timebase of the emitted timestamps, in seconds per tick.
+    pub timebase: Rational,
+    /// The frame rate to assume when the SPS has no timing info. Defaults to 25 fps if unset.
+    pub frame_rate: Option<Rational>,
+    /// Whether the stream may have been joined mid-way, as live streams are. Access units are
+    /// dropped until the first random access point: an IDR picture, a recovery point SEI message,
+    /// as intra-refresh streams use instead of IDRs, or another H.265 IRAP picture. Random access
+    /// points that don't carry an SPS get the latest parameter sets injected, so that decoding
+    /// can start at each one.
+    pub join_mid_stream: bool,
+    /// Drops and rewrites NAL units before they're assembled into access units. Empty by default.
+    pub filter: NalFilter,
+}
+
+impl Default for FramerConfig {
+    fn default() -> Self {
+        Self {
+            codec: Codec::default(),
+            error_policy: ErrorPolicy::default(),
+            timebase: Rational::new(1, 90000),
+            frame_rate: None,
+            join_mid_stream: false,
+            filter: NalFilter::default(),
+        }
+    }
+}
+
+/// Splits an H.264 or H.265 Annex B elementary stream into access units as it arrives.
+///
+/// Input can be pushed in chunks of any size, split anywhere, including in the middle of a start
+/// code. An access unit is emitted as soon as the first NAL unit of the next one is complete, i.e.
+/// once the start code following that NAL unit arrives. The last access unit is only emitted by
+/// [`finish`](Self::finish).
+///
+/// Timestamps are derived from the frame rate in the SPS, or the configured one, and from the
+/// picture order count of each access unit. Presentation timestamps are offset by the maximum
+/// reordering depth of the stream, so that they're never earlier than the decoding timestamps.
+pub struct AnnexBFramer {
+    config: FramerConfig,
+    /// Input that hasn't been split into NAL units yet. Once synced, it starts with the payload of
+    /// the current NAL unit, right after its start code.
+    pending: Vec<u8>,
+    /// The stream offset of the start of `pending`.
+    offset: u64,
+    /// How far `pending` has been searched for a start code.
+    scanned: usize,
+    /// Whether a start code has been seen. Anything before the first one is discarded.
+    synced: bool,
+    /// Set when an error stopped the framer, see [`ErrorPolicy::Stop`].
+    stopped: bool,
+    nal_index: u64,
+    syntax: Syntax,
+    access_unit: Vec<u8>,
+    inspector: FrameInspector,
+    /// Set when joining mid-stream.
+    joiner: Option<Joiner>,
+    /// A change of SPS in the access unit in progress.
+    stream_change: Option<StreamChange>,
+    timestamper: Timestamper,
+    ready: VecDeque<Result<(XcoderDecoderInputFrame, FrameMetadata), FramingError>>,
+}
+
+impl Default for AnnexBFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnnexBFramer {
+    pub fn new() -> Self {
+        Self::with_config(FramerConfig::default())
+    }
+
+    pub fn with_error_policy(error_policy: ErrorPolicy) -> Self {
+        Self::with_config(FramerConfig {
+            error_policy,
+            ..FramerConfig::default()
+        })
+    }
+
+    pub fn with_config(config: FramerConfig) -> Self {
+        Self {
+            pending: Vec::new(),
+            offset: 0,
+            scanned: 0,
+            synced: false,
+            stopped: false,
+            nal_index: 0,
+            syntax: Syntax::new(config.codec),
+            access_unit: Vec::new(),
+            inspector: FrameInspector::new(config.codec),
+            joiner: config.join_mid_stream.then(|| Joiner::new(config.codec)),
+            stream_change: None,
+            timestamper: Timestamper::new(
+                config.timebase,
+                config.frame_rate,
+                Syntax::units_per_frame(config.codec),
+            ),
+            ready: VecDeque::new(),
+            config,
+        }
+    }
+
+    /// Returns the stream info from the latest SPS seen, once one has been pushed. It can be used
+    /// to configure the decoder before the frames emitted so far are fed to it.
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.syntax.stream_info()
+    }
+
+    /// Adds a chunk of input and returns the access units it completed.
+    pub fn push(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = Result<XcoderDecoderInputFrame, FramingError>> + '_ {
+        self.push_with_metadata(chunk)
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+
+    /// Ends the input, returning the remaining access units. The framer can be reused for another
+    /// stream afterwards.
+    pub fn finish(
+        &mut self,
+    ) -> impl Iterator<Item = Result<XcoderDecoderInputFrame, FramingError>> + '_ {
+        self.finish_with_metadata()
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+
+    /// Like [`push`](Self::push), but also describes each access unit.
+    pub fn push_with_metadata(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = Result<(XcoderDecoderInputFrame, FrameMetadata), FramingError>> + '_
+    {
+        if !self.stopped {
+            self.pending.extend_from_slice(chunk);
+            self.split_pending();
+        }
+        self.ready.drain(..)
+    }
+
+    /// Like [`finish`](Self::finish), but also describes each access unit.
+    pub fn finish_with_metadata(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(XcoderDecoderInputFrame, FrameMetadata), FramingError>> + '_
+    {
+        if !self.stopped {
+            let nalu = mem::take(&mut self.pending);
+            let trimmed = trim_trailing_zeros(&nalu);
+            if self.synced && !trimmed.is_empty() {
+                self.add_nalu(trimmed, self.offset);
+            }
+            self.emit_access_unit();
+            if !self.synced && !nalu.is_empty() || self.synced && trimmed.is_empty() {
+                self.fail(FramingError::TruncatedStartCode {
+                    offset: self.offset,
+                });
+            }
+        }
+        let ready = mem::take(&mut self.ready);
+        *self = Self {
+            ready,
+            ..Self::with_config(mem::take(&mut self.config))
+        };
+        self.ready.drain(..)
+    }
+
+    fn split_pending(&mut self) {
+        let mut pending = mem::take(&mut self.pending);
+        let mut start = 0;
+        while let Some(offset) = find_start_code(&pending[self.scanned..]) {
+            let start_code = self.scanned + offset;
+            if self.synced {
+                let nalu = trim_trailing_zeros(&pending[start..start_code]);
+                self.add_nalu(nalu, self.offset + start as u64);
+                if self.stopped {
+                    return;
+                }
+            }
+            self.synced = true;
+            start = start_code + 3;
+            self.scanned = start;
+        }
+        if !self.synced {
+            start = pending.len().saturating_sub(2);
+        }
+        pending.drain(..start);
+        self.offset += start as u64;
+        // The last two bytes may be the beginning of a start code that continues in the next chunk.
+        self.scanned = pending.len().saturating_sub(2);
+        self.pending = pending;
+    }
+
+    fn add_nalu(&mut self, nalu: &[u8], offset: u64) {
+        if nalu.is_empty() {
+            return;
+        }
+        let nal_index = self.nal_index;
+        self.nal_index += 1;
+
+        match self.syntax.check_header(nalu) {
+            Ok(()) => {}
+            Err(InvalidHeader::Malformed(header)) => {
+                return self.fail(FramingError::MalformedNalHeader {
+                    offset,
+                    nal_index,
+                    header,
+                });
+            }
+            Err(InvalidHeader::Unsupported(nal_unit_type)) => {
+                return self.fail(FramingError::UnsupportedNalType {
+                    offset,
+                    nal_index,
+                    nal_unit_type,
+                });
+            }
+        }
+        let Some(nalu) = self.config.filter.apply(self.config.codec, nalu) else {
+            return;
+        };
+        match self.syntax.starts_access_unit(&nalu) {
+            Ok(true) => self.emit_access_unit(),
+            Ok(false) => {}
+            Err(source) => {
+                return self.fail(FramingError::AccessUnitCounting {
+                    offset,
+                    nal_index,
+                    source,
+                });
+            }
+        }
+        let previous = self.syntax.stream_info();
+        self.syntax.parse(&nalu);
+        if let (Some(previous), Some(current)) = (previous, self.syntax.stream_info()) {
+            if let Some(change) = StreamChange::between(previous, current) {
+                self.stream_change = Some(change);
+            }
+        }
+        if self.access_unit.is_empty() {
+            if let Some(delimiter) = self.config.filter.delimiter(self.config.codec, &nalu) {
+                self.append_nalu(delimiter);
+            }
+        }
+        self.append_nalu(&nalu);
+    }
+
+    fn append_nalu(&mut self, nalu: &[u8]) {
+        self.access_unit.extend_from_slice(&START_CODE);
+        self.inspector.add_nalu(nalu, self.access_unit.len());
+        self.access_unit.extend_from_slice(nalu);
+    }
+
+    fn fail(&mut self, err: FramingError) {
+        match self.config.error_policy {
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Stop => {
+                self.access_unit.clear();
+                self.pending.clear();
+                self.stopped = true;
+                self.ready.push_back(Err(err));
+            }
+            ErrorPolicy::Propagate => self.ready.push_back(Err(err)),
+        }
+    }
+
+    fn emit_access_unit(&mut self) {
+        if self.access_unit.is_empty() {
+            return;
+        }
+        let (pts, dts) = self.timestamper.next(self.syntax.take_timing().as_ref());
+        let mut metadata = self.inspector.finish_frame(self.access_unit.len());
+        let mut data = mem::take(&mut self.access_unit);
+        let stream_change = self.stream_change.take();
+        if let Some(joiner) = &mut self.joiner {
+            if !joiner.process(&mut data, &metadata) {
+                return;
+            }
+            if data.len() != metadata.size {
+                metadata = self.inspector.inspect(&data);
+            }
+        }
+        metadata.stream_change = stream_change;
+        let frame = XcoderDecoderInputFrame { data, pts, dts };
+        self.ready.push_back(Ok((frame, metadata)));
+    }
+}
+
+/// Why a NAL unit header was rejected.
+enum InvalidHeader {
+    Malformed(u16),
+    Unsupported(u8),
+}
+
+/// The codec-specific part of framing: checking NAL unit headers, finding where access units
+/// start, and timing their pictures.
+enum Syntax {
+    H264(H264Syntax),
+    H265(HevcSyntax),
+}
+
+impl Syntax {
+    fn new(codec: Codec) -> Self {
+        match codec {
+            Codec::H264 => Self::H264(H264Syntax::default()),
+            Codec::H265 => Self::H265(HevcSyntax::default()),
+        }
+    }
+
+    /// The units picture order counts advance by per frame.
+    fn units_per_frame(codec: Codec) -> i64 {
+        match codec {
+            Codec::H264 => 2,
+            Codec::H265 => 1,
+        }
+    }
+
+    fn check_header(&self, nalu: &[u8]) -> Result<(), InvalidHeader> {
+        match self {
+            Self::H264(_) => H264Syntax::check_header(nalu),
+            Self::H265(_) => HevcSyntax::check_header(nalu),
+        }
+    }
+
+    /// Returns whether the NAL unit is the first of a new access unit.
+    fn starts_access_unit(&mut self, nalu: &[u8]) -> io::Result<bool> {
+        match self {
+            Self::H264(syntax) => syntax.starts_access_unit(nalu),
+            Self::H265(syntax) => syntax.starts_access_unit(nalu),
+        }
+    }
+
+    /// Takes in the parameter sets and the first slice of each picture. Those that fail to parse
+    /// only cost the access unit its timestamps, so they're not treated as errors.
+    fn parse(&mut self, nalu: &[u8]) {
+        match self {
+            Self::H264(syntax) => syntax.parse(nalu),
+            Self::H265(syntax) => syntax.parse(nalu),
+        }
+    }
+
+    /// Returns the stream info from the latest SPS that could be parsed.
+    fn stream_info(&self) -> Option<StreamInfo> {
+        match self {
+            Self::H264(syntax) => syntax.stream_info,
+            Self::H265(syntax) => syntax.stream_info,
+        }
+    }
+
+    /// Returns the timing of the picture in the access unit in progress, if it could be parsed.
+    fn take_timing(&mut self) -> Option<PictureTiming> {
+        match self {
+            Self::H264(syntax) => syntax.timing.take(),
+            Self::H265(syntax) => syntax.timing.take(),
+        }
+    }
+}
+
+struct H264Syntax {
+    counter: h264::AccessUnitCounter,
+    parameter_sets: ParameterSets,
+    poc: PocState,
+    timing: Option<PictureTiming>,
+    stream_info: Option<StreamInfo>,
+}
+
+impl Default for H264Syntax {
+    fn default() -> Self {
+        Self {
+            counter: h264::AccessUnitCounter::new(),
+            parameter_sets: ParameterSets::default(),
+            poc: PocState::default(),
+            timing: None,
+            stream_info: None,
+        }
+    }
+}
+
+impl H264Syntax {
+    fn check_header(nalu: &[u8]) -> Result<(), InvalidHeader> {
+        let header = nalu[0];
+        let nal_unit_type = header & 0x1f;
+        let nal_ref_idc = (header >> 5) & 0x3;
+        if header & 0x80 != 0 || nal_ref_idc == 0 && matches!(nal_unit_type, 5 | 7 | 8) {
+            return Err(InvalidHeader::Malformed(header.into()));
+        }
+        // The unspecified types, 0 and 24 to 31, pass through.
+        if matches!(nal_unit_type, 17 | 18 | 22 | 23) {
+            return Err(InvalidHeader::Unsupported(nal_unit_type));
+        }
+        Ok(())
+    }
+
+    fn starts_access_unit(&mut self, nalu: &[u8]) -> io::Result<bool> {
+        let before = self.counter.count();
+        self.counter.count_nalu(nalu)?;
+        Ok(self.counter.count() != before)
+    }
+
+    fn parse(&mut self, nalu: &[u8]) {
+        match nalu[0] & 0x1f {
+            7 => {
+                if let Ok(sps) = Sps::parse(nalu) {
+                    self.stream_info = Some(StreamInfo::from(&sps));
+                    self.parameter_sets.insert_sps(sps);
+                }
+            }
+            8 => {
+                if let Ok(pps) = Pps::parse(nalu) {
+                    self.parameter_sets.insert_pps(pps);
+                }
+            }
+            1 | 5 if self.timing.is_none() => {
+                if let Ok(header) = SliceHeader::parse(nalu, &self.parameter_sets) {
+                    self.timing = Some(self.picture_timing(&header));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn picture_timing(&mut self, header: &SliceHeader) -> PictureTiming {
+        PictureTiming {
+            poc: self.poc.next(header),
+            duration: if header.field_pic { 1 } else { 2 },
+            starts_period: header.is_idr() || header.has_mmco5,
+            // Output order is always the decoding order with this type, and the picture order
+            // counts of non-reference pictures don't advance evenly.
+            decoding_order: header.sps.pic_order_cnt_type == 2,
+            max_num_reorder_frames: header.sps.max_num_reorder_frames(),
+            frame_rate: header.sps.frame_rate(),
+        }
+    }
+}
+
+struct HevcSyntax {
+    parameter_sets: HevcParameterSets,
+    poc: HevcPocState,
+    /// Whether the access unit in progress has a coded slice segment yet.
+    has_vcl: bool,
+    /// Whether the next random access point starts a coded video sequence, as it does at the
+    /// start of the stream and after an end of sequence NAL unit.
+    sequence_start: bool,
+    timing: Option<PictureTiming>,
+    stream_info: Option<StreamInfo>,
+}
+
+impl Default for HevcSyntax {
+    fn default() -> Self {
+        Self {
+            parameter_sets: HevcParameterSets::default(),
+            poc: HevcPocState::default(),
+            has_vcl: false,
+            sequence_start: true,
+            timing: None,
+            stream_info: None,
+        }
+    }
+}
+
+impl HevcSyntax {
+    fn check_header(nalu: &[u8]) -> Result<(), InvalidHeader> {
+        let &[first, second, ..] = nalu else {
+            return Err(InvalidHeader::Malformed(nalu[0].into()));
+        };
+        let nal_unit_type = hevc::nal_unit_type(first);
+        let temporal_id_plus1 = second & 0x7;
+        let must_be_tid0 = hevc::is_irap(nal_unit_type)
+            || matches!(nal_unit_type, hevc::VPS | hevc::SPS | hevc::EOS | 37);
+        if first & 0x80 != 0 || temporal_id_plus1 == 0 || must_be_tid0 && temporal_id_plus1 != 1 {
+            return Err(InvalidHeader::Malformed(u16::from_be_bytes([
+                first, second,
+            ])));
+        }
+        // The unspecified types from 48 up, such as Dolby Vision's, pass through.
+        if matches!(nal_unit_type, 10..=15 | 22..=31 | 41..=47) {
+            return Err(InvalidHeader::Unsupported(nal_unit_type));
+        }
+        Ok(())
+    }
+
+    /// Applies the access unit boundary rules of clause 7.4.2.4.4 of H.265 to the base layer.
+    /// Units of other layers belong to the access unit in progress.
+    fn starts_access_unit(&mut self, nalu: &[u8]) -> io::Result<bool> {
+        let layer_id = (nalu[0] & 0x1) << 5 | nalu[1] >> 3;
+        if layer_id != 0 {
+            return Ok(false);
+        }
+        let starts = match hevc::nal_unit_type(nalu[0]) {
+            0..=31 => {
+                let first_slice_segment_in_pic = nalu.get(2).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "empty slice segment")
+                })? & 0x80
+                    != 0;
+                let starts = first_slice_segment_in_pic && self.has_vcl;
+                self.has_vcl = true;
+                starts
+            }
+            hevc::VPS
+            | hevc::SPS
+            | hevc::PPS
+            | hevc::AUD
+            | hevc::PREFIX_SEI
+            | 41..=44
+            | 48..=55 => mem::take(&mut self.has_vcl),
+            _ => false,
+        };
+        Ok(starts)
+    }
+
+    fn parse(&mut self, nalu: &[u8]) {
+        match hevc::nal_unit_type(nalu[0]) {
+            hevc::SPS => {
+                if let Ok(sps) = HevcSps::parse(nalu) {
+                    self.stream_info = Some(StreamInfo::from(&sps));
+                    self.parameter_sets.insert_sps(sps);
+                }
+            }
+            hevc::PPS => {
+                if let Ok(pps) = HevcPps::parse(nalu) {
+                    self.parameter_sets.insert_pps(pps);
+                }
+            }
+            hevc::EOS => self.sequence_start = true,
+            0..=21 if self.timing.is_none() => {
+                if let Ok(header) = HevcSliceHeader::parse(nalu, &self.parameter_sets) {
+                    self.timing = Some(self.picture_timing(&header));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn picture_timing(&mut self, header: &HevcSliceHeader) -> PictureTiming {
+        let no_rasl_output = header.is_irap()
+            && (mem::take(&mut self.sequence_start) || !matches!(header.nal_unit_type, 21));
+        PictureTiming {
+            poc: self.poc.next(header, no_rasl_output),
+            duration: 1,
+            starts_period: no_rasl_output,
+            decoding_order: false,
+            max_num_reorder_frames: header.sps.max_num_reorder_pics,
+            frame_rate: header.sps.frame_rate(),
+        }
+    }
+}
+
+/// Returns the offset of the first three-byte start code in `buf`.
+fn find_start_code(buf: &[u8]) -> Option<usize> {
+    buf.windows(3).position(|window| window == [0, 0, 1])
+}
+
+/// Strips the zero bytes that belong to the next start code, or pad the stream between NAL units.
+fn trim_trailing_zeros(nalu: &[u8]) -> &[u8] {
+    let end = nalu.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &nalu[..end]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{read_frames, read_frames_with_config};
+
+    fn stream() -> (Vec<u8>, Vec<Vec<u8>>) {
+        let sps = [0x67, 0x42, 0xc0, 0x1e];
+        let pps = [0x68, 0xce, 0x3c, 0x80];
+        let idr = [0x65, 0x88, 0x84, 0x00, 0x33];
+        let p = [0x41, 0x9a, 0x02];
+        let mut frames =
+            vec![[&START_CODE[..], &sps, &START_CODE, &pps, &START_CODE, &idr].concat()];
+        for _ in 0..3 {
+            frames.push([&START_CODE[..], &p].concat());
+        }
+
+        // Mix in three-byte start codes and trailing zeros.
+        let mut stream = vec![0xff, 0x00];
+        stream.extend_from_slice(&[0, 0, 1]);
+        stream.extend_from_slice(&sps);
+        stream.extend_from_slice(&[0, 0, 0, 0, 1]);
+        stream.extend_from_slice(&pps);
+        for nalu in [&idr[..], &p, &p, &p] {
+            stream.extend_from_slice(&START_CODE);
+            stream.extend_from_slice(nalu);
+        }
+        (stream, frames)
+    }
+
+    #[test]
+    fn test_chunks_split_anywhere() {
+        let (stream, expected) = stream();
+        for chunk_size in 1..=stream.len() {
+            let mut framer = AnnexBFramer::new();
+            let mut frames = vec![];
+            for chunk in stream.chunks(chunk_size) {
+                frames.extend(framer.push(chunk).map(Result::unwrap));
+            }
+            // The last NAL unit can't be told apart from a partial one until the end of the input,
+            // so the last two access units are held back.
+            assert_eq!(frames.len(), expected.len() - 2);
+            frames.extend(framer.finish().map(Result::unwrap));
+
+            let data: Vec<_> = frames.iter().map(|frame| frame.data.clone()).collect();
+            assert_eq!(data, expected);
+            // The slices can't be parsed, so the frames get timestamps at the default 25 fps.
+            let pts: Vec<_> = frames.iter().map(|frame| frame.pts).collect();
+            assert_eq!(pts, vec![0, 3600, 7200, 10800]);
+        }
+    }
+
+    #[test]
+    fn test_finish_resets() {
+        let (stream, expected) = stream();
+        let mut framer = AnnexBFramer::new();
+        assert_eq!(framer.push(&stream).count(), expected.len() - 2);
+        assert_eq!(framer.finish().count(), 2);
+        assert_eq!(framer.finish().count(), 0);
+        assert_eq!(framer.push(&stream).next().unwrap().unwrap().pts, 0);
+    }
+
+    /// Two frames with a NAL unit with the forbidden bit set in the middle of the second one.
+    fn corrupt_stream() -> Vec<u8> {
+        [
+            &START_CODE[..],
+            &[0x65, 0x88],
+            &START_CODE,
+            &[0x41, 0x9a],
+            &START_CODE,
+            &[0xc1, 0x00, 0x01],
+            &START_CODE,
+            &[0x41, 0x9a],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_error_policies() {
+        let results = |policy| {
+            let config = FramerConfig {
+                error_policy: policy,
+                // One tick per frame at the default frame rate.
+                timebase: Rational::new(1, 25),
+                ..FramerConfig::default()
+            };
+            read_frames_with_config(&corrupt_stream(), config)
+                .into_iter()
+                .map(|result| result.map(|frame| frame.pts))
+                .collect::<Vec<_>>()
+        };
+
+        let skipped = results(ErrorPolicy::Skip);
+        assert_eq!(
+            skipped.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+
+        let propagated = results(ErrorPolicy::Propagate);
+        assert_eq!(propagated.len(), 4);
+        assert!(matches!(
+            propagated[1],
+            Err(FramingError::MalformedNalHeader {
+                offset: 16,
+                nal_index: 2,
+                header: 0xc1
+            })
+        ));
+        assert_eq!(*propagated[2].as_ref().unwrap(), 1);
+        assert_eq!(*propagated[3].as_ref().unwrap(), 2);
+
+        let stopped = results(ErrorPolicy::Stop);
+        assert_eq!(stopped.len(), 2);
+        assert_eq!(*stopped[0].as_ref().unwrap(), 0);
+        assert!(stopped[1].is_err());
+    }
+
+    #[test]
+    fn test_unsupported_and_truncated() {
+        let stream = [&START_CODE[..], &[0x65, 0x88], &START_CODE, &[0x17, 0x00]].concat();
+        let frames = read_frames(&stream);
+        assert!(matches!(
+            frames[0],
+            Err(FramingError::UnsupportedNalType {
+                offset: 10,
+                nal_index: 1,
+                nal_unit_type: 23
+            })
+        ));
+        assert!(frames[1].is_ok());
+
+        // Unspecified types are kept, as decoders ignore them.
+        let stream = [&START_CODE[..], &[0x65, 0x88], &START_CODE, &[0x18, 0x01]].concat();
+        let frames = read_frames(&stream);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].as_ref().unwrap().data, stream);
+
+        let frames = read_frames(&[&START_CODE[..], &[0x65, 0x88], &[0, 0, 1]].concat());
+        assert!(frames[0].is_ok());
+        assert!(matches!(
+            frames[1],
+            Err(FramingError::TruncatedStartCode { offset: 9 })
+        ));
+        let frames = read_frames(&[0, 0]);
+        assert!(matches!(
+            frames[..],
+            [Err(FramingError::TruncatedStartCode { offset: 0 })]
+        ));
+    }
+}
@@ -0,0 +1,1094 @@
+use crate::bits::{header_rbsp, to_rbsp, BitReader};
+use crate::params::{FrameCropping, Rational, SAMPLE_ASPECT_RATIOS};
+use crate::slice::SliceType;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The largest picture width or height in luma samples that any level allows,
+/// `Sqrt(MaxLumaPs * 8)` for the largest `MaxLumaPs` in table A.8 of H.265.
+const MAX_PIC_SIZE: u32 = 16888;
+
+pub(crate) const VPS: u8 = 32;
+pub(crate) const SPS: u8 = 33;
+pub(crate) const PPS: u8 = 34;
+pub(crate) const AUD: u8 = 35;
+pub(crate) const EOS: u8 = 36;
+pub(crate) const PREFIX_SEI: u8 = 39;
+pub(crate) const SUFFIX_SEI: u8 = 40;
+
+/// Returns the `nal_unit_type` of an H.265 NAL unit from the first byte of its header.
+pub(crate) fn nal_unit_type(header: u8) -> u8 {
+    header >> 1 & 0x3f
+}
+
+/// Whether a NAL unit type is an intra random access point: a BLA, IDR or CRA picture.
+pub(crate) fn is_irap(nal_unit_type: u8) -> bool {
+    (16..=23).contains(&nal_unit_type)
+}
+
+/// Reads the two-byte NAL unit header and checks the type.
+fn read_header(reader: &mut BitReader, expected: u8, message: &'static str) -> io::Result<()> {
+    let header = reader.read_bits(16)?;
+    if nal_unit_type((header >> 8) as u8) != expected {
+        return Err(invalid(message));
+    }
+    Ok(())
+}
+
+/// An H.265 sequence parameter set. Only the fields needed to frame and time the stream, and to
+/// configure a decoder for it, are kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HevcSps {
+    pub video_parameter_set_id: u8,
+    pub max_sub_layers: u8,
+    pub general_profile_space: u8,
+    pub general_tier: bool,
+    pub general_profile_idc: u8,
+    pub general_level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane: bool,
+    pub pic_width_in_luma_samples: u32,
+    pub pic_height_in_luma_samples: u32,
+    pub conformance_window: Option<FrameCropping>,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub log2_max_pic_order_cnt_lsb: u32,
+    /// `CtbLog2SizeY`, the log2 of the width and height of the coding tree blocks.
+    pub log2_ctb_size: u32,
+    /// `sps_max_dec_pic_buffering_minus1 + 1` for the highest sub-layer.
+    pub max_dec_pic_buffering: u32,
+    /// `sps_max_num_reorder_pics` for the highest sub-layer.
+    pub max_num_reorder_pics: u32,
+    pub sample_aspect_ratio: Option<Rational>,
+    pub video_full_range: bool,
+    pub colour_primaries: Option<u8>,
+    pub transfer_characteristics: Option<u8>,
+    pub matrix_coefficients: Option<u8>,
+    /// `vui_num_units_in_tick` and `vui_time_scale`, if the VUI has timing info.
+    pub timing_info: Option<(u32, u32)>,
+    /// `frame_field_info_present_flag`: whether picture timing SEI messages carry `pic_struct`.
+    pub frame_field_info_present: bool,
+}
+
+impl HevcSps {
+    /// Parses an SPS NAL unit, including its two header bytes.
+    pub fn parse(nalu: &[u8]) -> io::Result<Self> {
+        let rbsp = to_rbsp(nalu);
+        let mut reader = BitReader::new(&rbsp);
+        read_header(&mut reader, SPS, "not an SPS")?;
+        let video_parameter_set_id = reader.read_bits(4)? as u8;
+        let max_sub_layers = reader.read_bits(3)? as u8 + 1;
+        // sps_temporal_id_nesting_flag
+        reader.skip_bits(1)?;
+
+        // profile_tier_level
+        let general_profile_space = reader.read_bits(2)? as u8;
+        let general_tier = reader.read_flag()?;
+        let general_profile_idc = reader.read_bits(5)? as u8;
+        // The compatibility flags, the source and constraint flags, and general_inbld_flag
+        reader.skip_bits(32 + 4 + 43 + 1)?;
+        let general_level_idc = reader.read_bits(8)? as u8;
+        let mut sub_layers_present = Vec::new();
+        for _ in 1..max_sub_layers {
+            sub_layers_present.push((reader.read_flag()?, reader.read_flag()?));
+        }
+        if max_sub_layers > 1 {
+            // reserved_zero_2bits up to eight sub-layers
+            reader.skip_bits(2 * (9 - max_sub_layers as usize))?;
+        }
+        for (profile_present, level_present) in sub_layers_present {
+            if profile_present {
+                reader.skip_bits(88)?;
+            }
+            if level_present {
+                reader.skip_bits(8)?;
+            }
+        }
+
+        let seq_parameter_set_id = reader.read_ue()?;
+        if seq_parameter_set_id > 15 {
+            return Err(invalid("SPS id out of range"));
+        }
+        let chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc > 3 {
+            return Err(invalid("chroma_format_idc out of range"));
+        }
+        let separate_colour_plane = chroma_format_idc == 3 && reader.read_flag()?;
+        let pic_width_in_luma_samples = reader.read_ue()?;
+        let pic_height_in_luma_samples = reader.read_ue()?;
+        if !(1..=MAX_PIC_SIZE).contains(&pic_width_in_luma_samples)
+            || !(1..=MAX_PIC_SIZE).contains(&pic_height_in_luma_samples)
+        {
+            return Err(invalid("picture size out of range"));
+        }
+        let conformance_window = if reader.read_flag()? {
+            Some(FrameCropping {
+                left: reader.read_ue()?,
+                right: reader.read_ue()?,
+                top: reader.read_ue()?,
+                bottom: reader.read_ue()?,
+            })
+        } else {
+            None
+        };
+        let mut read_bit_depth = || {
+            reader
+                .read_ue()?
+                .checked_add(8)
+                .filter(|&n| n <= 16)
+                .ok_or_else(|| invalid("bit depth out of range"))
+        };
+        let bit_depth_luma = read_bit_depth()?;
+        let bit_depth_chroma = read_bit_depth()?;
+        let log2_max_pic_order_cnt_lsb = reader
+            .read_ue()?
+            .checked_add(4)
+            .filter(|&n| n <= 16)
+            .ok_or_else(|| invalid("log2_max_pic_order_cnt_lsb out of range"))?;
+        let ordering_info_present = reader.read_flag()?;
+        let mut max_dec_pic_buffering = 0;
+        let mut max_num_reorder_pics = 0;
+        let first = if ordering_info_present {
+            0
+        } else {
+            max_sub_layers - 1
+        };
+        for _ in first..max_sub_layers {
+            // The decoded picture buffer holds at most 16 pictures.
+            max_dec_pic_buffering = reader
+                .read_ue()?
+                .checked_add(1)
+                .filter(|&n| n <= 16)
+                .ok_or_else(|| invalid("sps_max_dec_pic_buffering out of range"))?;
+            max_num_reorder_pics = reader.read_ue()?;
+            if max_num_reorder_pics >= max_dec_pic_buffering {
+                return Err(invalid("sps_max_num_reorder_pics out of range"));
+            }
+            // sps_max_latency_increase_plus1
+            reader.read_ue()?;
+        }
+
+        let log2_min_luma_coding_block_size = reader.read_ue()?;
+        let log2_ctb_size = reader
+            .read_ue()?
+            .checked_add(log2_min_luma_coding_block_size)
+            .and_then(|n| n.checked_add(3))
+            .filter(|n| (4..=6).contains(n))
+            .ok_or_else(|| invalid("CtbLog2SizeY out of range"))?;
+        // The transform block sizes and depths
+        for _ in 0..4 {
+            reader.read_ue()?;
+        }
+        if reader.read_flag()? && reader.read_flag()? {
+            skip_scaling_list_data(&mut reader)?;
+        }
+        // amp_enabled_flag and sample_adaptive_offset_enabled_flag
+        reader.skip_bits(2)?;
+        if reader.read_flag()? {
+            // pcm_sample_bit_depth_luma_minus1 and pcm_sample_bit_depth_chroma_minus1
+            reader.skip_bits(8)?;
+            // The PCM coding block sizes
+            reader.read_ue()?;
+            reader.read_ue()?;
+            // pcm_loop_filter_disabled_flag
+            reader.skip_bits(1)?;
+        }
+        let num_short_term_ref_pic_sets = reader.read_ue()?;
+        if num_short_term_ref_pic_sets > 64 {
+            return Err(invalid("num_short_term_ref_pic_sets out of range"));
+        }
+        let mut num_delta_pocs = Vec::with_capacity(num_short_term_ref_pic_sets as usize);
+        for _ in 0..num_short_term_ref_pic_sets {
+            let count = skip_st_ref_pic_set(&mut reader, &num_delta_pocs)?;
+            num_delta_pocs.push(count);
+        }
+        if reader.read_flag()? {
+            let num_long_term_ref_pics = reader.read_ue()?;
+            if num_long_term_ref_pics > 32 {
+                return Err(invalid("num_long_term_ref_pics_sps out of range"));
+            }
+            for _ in 0..num_long_term_ref_pics {
+                // lt_ref_pic_poc_lsb_sps and used_by_curr_pic_lt_sps_flag
+                reader.skip_bits(log2_max_pic_order_cnt_lsb as usize + 1)?;
+            }
+        }
+        // sps_temporal_mvp_enabled_flag and strong_intra_smoothing_enabled_flag
+        reader.skip_bits(2)?;
+
+        let mut sps = Self {
+            video_parameter_set_id,
+            max_sub_layers,
+            general_profile_space,
+            general_tier,
+            general_profile_idc,
+            general_level_idc,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            separate_colour_plane,
+            pic_width_in_luma_samples,
+            pic_height_in_luma_samples,
+            conformance_window,
+            bit_depth_luma: bit_depth_luma as u8,
+            bit_depth_chroma: bit_depth_chroma as u8,
+            log2_max_pic_order_cnt_lsb,
+            log2_ctb_size,
+            max_dec_pic_buffering,
+            max_num_reorder_pics,
+            sample_aspect_ratio: None,
+            video_full_range: false,
+            colour_primaries: None,
+            transfer_characteristics: None,
+            matrix_coefficients: None,
+            timing_info: None,
+            frame_field_info_present: false,
+        };
+        if let Some(crop) = sps.conformance_window {
+            // The window must keep at least one sample in each direction.
+            let (unit_x, unit_y) = sps.crop_units();
+            if unit_x as u64 * (crop.left as u64 + crop.right as u64)
+                >= pic_width_in_luma_samples as u64
+                || unit_y as u64 * (crop.top as u64 + crop.bottom as u64)
+                    >= pic_height_in_luma_samples as u64
+            {
+                return Err(invalid("conformance window out of range"));
+            }
+        }
+        if reader.read_flag()? {
+            sps.parse_vui(&mut reader)?;
+        }
+        Ok(sps)
+    }
+
+    /// Parses the VUI up to and including the timing info. What follows isn't needed.
+    fn parse_vui(&mut self, reader: &mut BitReader) -> io::Result<()> {
+        if reader.read_flag()? {
+            let aspect_ratio_idc = reader.read_bits(8)?;
+            self.sample_aspect_ratio = match aspect_ratio_idc {
+                255 => Some(Rational::new(reader.read_bits(16)?, reader.read_bits(16)?)),
+                1..=16 => {
+                    let (num, den) = SAMPLE_ASPECT_RATIOS[aspect_ratio_idc as usize];
+                    Some(Rational::new(num, den))
+                }
+                _ => None,
+            };
+        }
+        if reader.read_flag()? {
+            // overscan_appropriate_flag
+            reader.skip_bits(1)?;
+        }
+        if reader.read_flag()? {
+            // video_format
+            reader.skip_bits(3)?;
+            self.video_full_range = reader.read_flag()?;
+            if reader.read_flag()? {
+                self.colour_primaries = Some(reader.read_bits(8)? as u8);
+                self.transfer_characteristics = Some(reader.read_bits(8)? as u8);
+                self.matrix_coefficients = Some(reader.read_bits(8)? as u8);
+            }
+        }
+        if reader.read_flag()? {
+            // chroma_sample_loc_type_top_field and chroma_sample_loc_type_bottom_field
+            reader.read_ue()?;
+            reader.read_ue()?;
+        }
+        // neutral_chroma_indication_flag and field_seq_flag
+        reader.skip_bits(2)?;
+        self.frame_field_info_present = reader.read_flag()?;
+        if reader.read_flag()? {
+            // The default display window offsets
+            for _ in 0..4 {
+                reader.read_ue()?;
+            }
+        }
+        if reader.read_flag()? {
+            self.timing_info = Some((reader.read_bits(32)?, reader.read_bits(32)?));
+        }
+        Ok(())
+    }
+
+    /// Returns the `ChromaArrayType` variable, which is zero when the colour planes are coded
+    /// separately.
+    pub fn chroma_array_type(&self) -> u32 {
+        if self.separate_colour_plane {
+            0
+        } else {
+            self.chroma_format_idc
+        }
+    }
+
+    fn crop_units(&self) -> (u32, u32) {
+        match self.chroma_array_type() {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        }
+    }
+
+    /// Returns the width of the decoded pictures after applying the conformance window.
+    pub fn width(&self) -> u32 {
+        let width = self.pic_width_in_luma_samples;
+        match self.conformance_window {
+            Some(crop) => width.saturating_sub(
+                self.crop_units()
+                    .0
+                    .saturating_mul(crop.left.saturating_add(crop.right)),
+            ),
+            None => width,
+        }
+    }
+
+    /// Returns the height of the decoded pictures after applying the conformance window.
+    pub fn height(&self) -> u32 {
+        let height = self.pic_height_in_luma_samples;
+        match self.conformance_window {
+            Some(crop) => height.saturating_sub(
+                self.crop_units()
+                    .1
+                    .saturating_mul(crop.top.saturating_add(crop.bottom)),
+            ),
+            None => height,
+        }
+    }
+
+    /// Returns the frame rate signalled by the VUI timing info, if any.
+    pub fn frame_rate(&self) -> Option<Rational> {
+        match self.timing_info? {
+            (0, _) | (_, 0) => None,
+            (num_units_in_tick, time_scale) => Some(Rational::new(time_scale, num_units_in_tick)),
+        }
+    }
+
+    /// `PicSizeInCtbsY`, the number of coding tree blocks in a picture.
+    fn pic_size_in_ctbs(&self) -> u32 {
+        let ctb_size = 1u32.checked_shl(self.log2_ctb_size).unwrap_or(u32::MAX);
+        self.pic_width_in_luma_samples
+            .div_ceil(ctb_size)
+            .saturating_mul(self.pic_height_in_luma_samples.div_ceil(ctb_size))
+    }
+}
+
+fn skip_scaling_list_data(reader: &mut BitReader) -> io::Result<()> {
+    for size_id in 0..4 {
+        let step = if size_id == 3 { 3 } else { 1 };
+        for _ in (0..6).step_by(step) {
+            if !reader.read_flag()? {
+                // scaling_list_pred_matrix_id_delta
+                reader.read_ue()?;
+                continue;
+            }
+            let coef_num = 64.min(1 << (4 + (size_id << 1)));
+            if size_id > 1 {
+                // scaling_list_dc_coef_minus8
+                reader.read_se()?;
+            }
+            for _ in 0..coef_num {
+                reader.read_se()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Skips the `st_ref_pic_set` at index `num_delta_pocs.len()` of the SPS, returning its number of
+/// pictures. `num_delta_pocs` holds the numbers of pictures in the preceding sets.
+fn skip_st_ref_pic_set(reader: &mut BitReader, num_delta_pocs: &[u32]) -> io::Result<u32> {
+    if let Some(&reference) = num_delta_pocs.last() {
+        if reader.read_flag()? {
+            // delta_rps_sign and abs_delta_rps_minus1
+            reader.skip_bits(1)?;
+            reader.read_ue()?;
+            let mut count = 0;
+            for _ in 0..=reference {
+                let used_by_curr_pic = reader.read_flag()?;
+                if used_by_curr_pic || reader.read_flag()? {
+                    count += 1;
+                }
+            }
+            return Ok(count);
+        }
+    }
+    let num_negative_pics = reader.read_ue()?;
+    let num_positive_pics = reader.read_ue()?;
+    if num_negative_pics > 16 || num_positive_pics > 16 {
+        return Err(invalid(
+            "too many pictures in a short-term reference picture set",
+        ));
+    }
+    for _ in 0..num_negative_pics + num_positive_pics {
+        // delta_poc_minus1 and used_by_curr_pic_flag
+        reader.read_ue()?;
+        reader.skip_bits(1)?;
+    }
+    Ok(num_negative_pics + num_positive_pics)
+}
+
+/// An H.265 picture parameter set, up to the fields needed to parse slice segment headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HevcPps {
+    pub pic_parameter_set_id: u32,
+    pub seq_parameter_set_id: u32,
+    pub dependent_slice_segments_enabled: bool,
+    pub output_flag_present: bool,
+    pub num_extra_slice_header_bits: u32,
+}
+
+impl HevcPps {
+    /// Parses a PPS NAL unit, including its two header bytes.
+    pub fn parse(nalu: &[u8]) -> io::Result<Self> {
+        let rbsp = to_rbsp(nalu);
+        let mut reader = BitReader::new(&rbsp);
+        read_header(&mut reader, PPS, "not a PPS")?;
+        let pic_parameter_set_id = reader.read_ue()?;
+        if pic_parameter_set_id > 63 {
+            return Err(invalid("PPS id out of range"));
+        }
+        let seq_parameter_set_id = reader.read_ue()?;
+        if seq_parameter_set_id > 15 {
+            return Err(invalid("SPS id out of range"));
+        }
+        Ok(Self {
+            pic_parameter_set_id,
+            seq_parameter_set_id,
+            dependent_slice_segments_enabled: reader.read_flag()?,
+            output_flag_present: reader.read_flag()?,
+            num_extra_slice_header_bits: reader.read_bits(3)?,
+        })
+    }
+}
+
+/// The H.265 parameter sets seen so far in a stream, by id.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HevcParameterSets {
+    sps: HashMap<u32, Arc<HevcSps>>,
+    pps: HashMap<u32, Arc<HevcPps>>,
+}
+
+impl HevcParameterSets {
+    pub fn insert_sps(&mut self, sps: HevcSps) {
+        self.sps.insert(sps.seq_parameter_set_id, Arc::new(sps));
+    }
+
+    pub fn insert_pps(&mut self, pps: HevcPps) {
+        self.pps.insert(pps.pic_parameter_set_id, Arc::new(pps));
+    }
+
+    /// Returns the PPS with the given id and the SPS it refers to.
+    pub fn get(&self, pps_id: u32) -> Option<(&Arc<HevcSps>, &Arc<HevcPps>)> {
+        let pps = self.pps.get(&pps_id)?;
+        Some((self.sps.get(&pps.seq_parameter_set_id)?, pps))
+    }
+}
+
+/// The start of the header of the first slice segment of an H.265 picture, up to and including
+/// the picture order count.
+#[derive(Clone, Debug)]
+pub(crate) struct HevcSliceHeader {
+    pub nal_unit_type: u8,
+    pub temporal_id: u8,
+    pub pic_order_cnt_lsb: u32,
+    pub sps: Arc<HevcSps>,
+}
+
+impl HevcSliceHeader {
+    pub fn is_irap(&self) -> bool {
+        is_irap(self.nal_unit_type)
+    }
+
+    /// Whether the picture can be used for reference by pictures of the same temporal sub-layer,
+    /// and isn't a leading picture. Only such pictures anchor the picture order count.
+    fn anchors_poc(&self) -> bool {
+        let sub_layer_non_reference =
+            self.nal_unit_type <= 14 && self.nal_unit_type.is_multiple_of(2);
+        let leading = (6..=9).contains(&self.nal_unit_type);
+        self.temporal_id == 0 && !sub_layer_non_reference && !leading
+    }
+
+    /// Parses the header of the first slice segment of a picture, including its two header bytes.
+    pub fn parse(nalu: &[u8], parameter_sets: &HevcParameterSets) -> io::Result<Self> {
+        let rbsp = to_rbsp(nalu);
+        let mut reader = BitReader::new(&rbsp);
+        let header = reader.read_bits(16)?;
+        let nal_unit_type = nal_unit_type((header >> 8) as u8);
+        let temporal_id = (header & 0x7).saturating_sub(1) as u8;
+        if nal_unit_type > 21 {
+            return Err(invalid("not a coded slice segment"));
+        }
+        if !reader.read_flag()? {
+            return Err(invalid("not the first slice segment of a picture"));
+        }
+        if is_irap(nal_unit_type) {
+            // no_output_of_prior_pics_flag
+            reader.skip_bits(1)?;
+        }
+        let pic_parameter_set_id = reader.read_ue()?;
+        let (sps, pps) = parameter_sets
+            .get(pic_parameter_set_id)
+            .ok_or_else(|| invalid("slice refers to an unknown parameter set"))?;
+        let (sps, pps) = (sps.clone(), pps.clone());
+
+        // slice_reserved_flag
+        reader.skip_bits(pps.num_extra_slice_header_bits as usize)?;
+        if reader.read_ue()? > 2 {
+            return Err(invalid("slice_type out of range"));
+        }
+        if pps.output_flag_present {
+            // pic_output_flag
+            reader.skip_bits(1)?;
+        }
+        if sps.separate_colour_plane {
+            // colour_plane_id
+            reader.skip_bits(2)?;
+        }
+        let pic_order_cnt_lsb = if matches!(nal_unit_type, 19 | 20) {
+            0
+        } else {
+            reader.read_bits(sps.log2_max_pic_order_cnt_lsb)?
+        };
+
+        Ok(Self {
+            nal_unit_type,
+            temporal_id,
+            pic_order_cnt_lsb,
+            sps,
+        })
+    }
+}
+
+/// Returns the slice type of a coded slice segment NAL unit, or `None` for a dependent slice
+/// segment, which takes the type of the segment it depends on.
+pub(crate) fn slice_segment_type(
+    nalu: &[u8],
+    parameter_sets: &HevcParameterSets,
+) -> io::Result<Option<SliceType>> {
+    let rbsp = header_rbsp(nalu, 32);
+    let mut reader = BitReader::new(&rbsp);
+    let nal_unit_type = nal_unit_type((reader.read_bits(16)? >> 8) as u8);
+    if nal_unit_type > 21 {
+        return Err(invalid("not a coded slice segment"));
+    }
+    let first_slice_segment_in_pic = reader.read_flag()?;
+    if is_irap(nal_unit_type) {
+        // no_output_of_prior_pics_flag
+        reader.skip_bits(1)?;
+    }
+    let (sps, pps) = parameter_sets
+        .get(reader.read_ue()?)
+        .ok_or_else(|| invalid("slice refers to an unknown parameter set"))?;
+    if !first_slice_segment_in_pic {
+        if pps.dependent_slice_segments_enabled && reader.read_flag()? {
+            return Ok(None);
+        }
+        // slice_segment_address
+        let address_bits = 32 - sps.pic_size_in_ctbs().saturating_sub(1).leading_zeros();
+        reader.skip_bits(address_bits as usize)?;
+    }
+    // slice_reserved_flag
+    reader.skip_bits(pps.num_extra_slice_header_bits as usize)?;
+    let slice_type = match reader.read_ue()? {
+        0 => SliceType::B,
+        1 => SliceType::P,
+        2 => SliceType::I,
+        _ => return Err(invalid("slice_type out of range")),
+    };
+    Ok(Some(slice_type))
+}
+
+/// The state carried between pictures to derive picture order counts, as specified in clause
+/// 8.3.1 of H.265.
+#[derive(Default)]
+pub(crate) struct HevcPocState {
+    /// The picture order count of the previous picture that anchors the count.
+    prev_tid0_poc: i64,
+}
+
+impl HevcPocState {
+    /// Returns the picture order count of a picture from its first slice segment header.
+    /// `no_rasl_output` is `NoRaslOutputFlag`, set for random access points that start a coded
+    /// video sequence.
+    pub fn next(&mut self, header: &HevcSliceHeader, no_rasl_output: bool) -> i64 {
+        let max_lsb = 1i64 << header.sps.log2_max_pic_order_cnt_lsb;
+        let lsb = header.pic_order_cnt_lsb as i64;
+        let msb = if header.is_irap() && no_rasl_output {
+            0
+        } else {
+            let prev_lsb = self.prev_tid0_poc.rem_euclid(max_lsb);
+            let prev_msb = self.prev_tid0_poc - prev_lsb;
+            if lsb < prev_lsb && prev_lsb - lsb >= max_lsb / 2 {
+                prev_msb + max_lsb
+            } else if lsb > prev_lsb && lsb - prev_lsb > max_lsb / 2 {
+                prev_msb - max_lsb
+            } else {
+                prev_msb
+            }
+        };
+        let poc = msb + lsb;
+        if header.anchors_poc() {
+            self.prev_tid0_poc = poc;
+        }
+        poc
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bits::BitWriter;
+    use crate::{
+        read_frames_with_config, read_frames_with_metadata, Codec, FramerConfig, FramingError,
+        StreamInfo,
+    };
+
+    fn header(writer: BitWriter, nal_unit_type: u8) -> BitWriter {
+        writer.bits(16, (nal_unit_type as u64) << 9 | 1)
+    }
+
+    /// Builds an SPS for 1080p at 29.97 fps, coded as 1088 lines cropped by the conformance window.
+    /// The fields of the SPS from [`sps_with`] that tests vary, as they're coded.
+    #[derive(Clone, Copy)]
+    struct SpsFields {
+        size: [u32; 2],
+        conformance_window: [u32; 4],
+        bit_depth_minus8: [u32; 2],
+        log2_max_pic_order_cnt_lsb_minus4: u32,
+        max_dec_pic_buffering_minus1: u32,
+        max_num_reorder_pics: u32,
+        /// `log2_min_luma_coding_block_size_minus3` and
+        /// `log2_diff_max_min_luma_coding_block_size`.
+        log2_ctb_size: [u32; 2],
+    }
+
+    impl Default for SpsFields {
+        fn default() -> Self {
+            Self {
+                size: [1920, 1088],
+                conformance_window: [0, 0, 0, 4],
+                bit_depth_minus8: [0, 0],
+                log2_max_pic_order_cnt_lsb_minus4: 4,
+                max_dec_pic_buffering_minus1: 4,
+                max_num_reorder_pics: 1,
+                log2_ctb_size: [0, 3],
+            }
+        }
+    }
+
+    fn sps() -> Vec<u8> {
+        sps_with(SpsFields::default())
+    }
+
+    fn sps_with(fields: SpsFields) -> Vec<u8> {
+        let [left, right, top, bottom] = fields.conformance_window;
+        header(BitWriter::default(), SPS)
+            .bits(4, 0)
+            .bits(3, 0)
+            .flag(true)
+            // profile_tier_level for Main at level 3.1
+            .bits(2, 0)
+            .flag(false)
+            .bits(5, 1)
+            .bits(32, 0x6000_0000)
+            .bits(4, 0b1011)
+            .bits(44, 0)
+            .bits(8, 93)
+            .ue(0)
+            .ue(1)
+            .ue(fields.size[0])
+            .ue(fields.size[1])
+            .flag(true)
+            .ue(left)
+            .ue(right)
+            .ue(top)
+            .ue(bottom)
+            .ue(fields.bit_depth_minus8[0])
+            .ue(fields.bit_depth_minus8[1])
+            .ue(fields.log2_max_pic_order_cnt_lsb_minus4)
+            .flag(true)
+            .ue(fields.max_dec_pic_buffering_minus1)
+            .ue(fields.max_num_reorder_pics)
+            .ue(0)
+            .ue(fields.log2_ctb_size[0])
+            .ue(fields.log2_ctb_size[1])
+            .ue(0)
+            .ue(3)
+            .ue(0)
+            .ue(0)
+            // scaling_list_enabled_flag, amp_enabled_flag, sample_adaptive_offset_enabled_flag and
+            // pcm_enabled_flag
+            .bits(4, 0b0010)
+            // Two short-term reference picture sets, the second predicted from the first
+            .ue(2)
+            .ue(1)
+            .ue(0)
+            .ue(0)
+            .flag(true)
+            .flag(true)
+            .flag(false)
+            .ue(0)
+            .flag(true)
+            .flag(false)
+            .flag(true)
+            // long_term_ref_pics_present_flag, sps_temporal_mvp_enabled_flag and
+            // strong_intra_smoothing_enabled_flag
+            .bits(3, 0b011)
+            // vui_parameters_present_flag
+            .flag(true)
+            .flag(true)
+            .bits(8, 1)
+            .flag(false)
+            .flag(true)
+            .bits(3, 5)
+            .flag(false)
+            .flag(true)
+            .bits(24, 0x010101)
+            .flag(false)
+            .bits(3, 0)
+            .flag(false)
+            .flag(true)
+            .bits(32, 1001)
+            .bits(32, 30000)
+            .bits(3, 0)
+            .finish()
+    }
+
+    fn pps() -> Vec<u8> {
+        header(BitWriter::default(), PPS)
+            .ue(0)
+            .ue(0)
+            .bits(5, 0)
+            .bits(2, 0)
+            .ue(0)
+            .ue(0)
+            .finish()
+    }
+
+    fn nalu(nal_unit_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut nalu = vec![nal_unit_type << 1, 1];
+        nalu.extend_from_slice(payload);
+        nalu
+    }
+
+    /// Builds a slice segment. Only the first one of a picture carries a picture order count.
+    fn slice(nal_unit_type: u8, first: bool, slice_type: u32, poc_lsb: u64) -> Vec<u8> {
+        let mut writer = header(BitWriter::default(), nal_unit_type).flag(first);
+        if !first {
+            // slice_pic_parameter_set_id and slice_segment_address
+            return writer.ue(0).bits(9, 1).ue(slice_type).finish();
+        }
+        if is_irap(nal_unit_type) {
+            writer = writer.flag(false);
+        }
+        writer = writer.ue(0).ue(slice_type);
+        if !matches!(nal_unit_type, 19 | 20) {
+            writer = writer.bits(8, poc_lsb);
+        }
+        writer.bits(4, 0b1010).finish()
+    }
+
+    fn stream(nal_units: &[Vec<u8>]) -> Vec<u8> {
+        let mut stream = vec![];
+        for nalu in nal_units {
+            stream.extend_from_slice(&[0, 0, 0, 1]);
+            stream.extend_from_slice(nalu);
+        }
+        stream
+    }
+
+    fn config() -> FramerConfig {
+        FramerConfig {
+            codec: Codec::H265,
+            ..FramerConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_sps() {
+        let sps = HevcSps::parse(&sps()).unwrap();
+        assert_eq!(sps.general_profile_idc, 1);
+        assert_eq!(sps.general_level_idc, 93);
+        assert_eq!((sps.width(), sps.height()), (1920, 1080));
+        assert_eq!((sps.bit_depth_luma, sps.bit_depth_chroma), (8, 8));
+        assert_eq!(sps.log2_max_pic_order_cnt_lsb, 8);
+        assert_eq!(sps.max_num_reorder_pics, 1);
+        assert_eq!(sps.sample_aspect_ratio, Some(Rational::new(1, 1)));
+        let info = StreamInfo::from(&sps);
+        assert_eq!(
+            (info.codec, info.profile_idc, info.level_idc),
+            (Codec::H265, 1, 93)
+        );
+        assert_eq!(info.frame_rate, Some(Rational::new(30000, 1001)));
+        assert_eq!(sps.colour_primaries, Some(1));
+        assert_eq!(sps.frame_rate(), Some(Rational::new(30000, 1001)));
+
+        assert!(HevcSps::parse(&pps()).is_err());
+        let pps = HevcPps::parse(&pps()).unwrap();
+        assert_eq!((pps.pic_parameter_set_id, pps.seq_parameter_set_id), (0, 0));
+    }
+
+    #[test]
+    fn test_sps_out_of_range() {
+        let large = u32::MAX - 1;
+        let fields = SpsFields::default();
+        for fields in [
+            SpsFields {
+                size: [0, 1088],
+                ..fields
+            },
+            SpsFields {
+                size: [1920, MAX_PIC_SIZE + 1],
+                ..fields
+            },
+            SpsFields {
+                conformance_window: [large, large, 0, 0],
+                ..fields
+            },
+            SpsFields {
+                conformance_window: [0, 0, 272, 272],
+                ..fields
+            },
+            SpsFields {
+                bit_depth_minus8: [9, 0],
+                ..fields
+            },
+            SpsFields {
+                bit_depth_minus8: [0, large],
+                ..fields
+            },
+            SpsFields {
+                log2_max_pic_order_cnt_lsb_minus4: large,
+                ..fields
+            },
+            SpsFields {
+                max_dec_pic_buffering_minus1: 16,
+                ..fields
+            },
+            SpsFields {
+                max_dec_pic_buffering_minus1: large,
+                ..fields
+            },
+            SpsFields {
+                max_num_reorder_pics: 5,
+                ..fields
+            },
+            SpsFields {
+                log2_ctb_size: [large, 0],
+                ..fields
+            },
+            SpsFields {
+                log2_ctb_size: [0, large],
+                ..fields
+            },
+            SpsFields {
+                log2_ctb_size: [1, 3],
+                ..fields
+            },
+        ] {
+            let err = HevcSps::parse(&sps_with(fields)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let sps = HevcSps::parse(&sps_with(SpsFields {
+            size: [MAX_PIC_SIZE, MAX_PIC_SIZE],
+            bit_depth_minus8: [8, 8],
+            max_dec_pic_buffering_minus1: 15,
+            ..fields
+        }))
+        .unwrap();
+        assert_eq!((sps.width(), sps.height()), (16888, 16880));
+        assert_eq!((sps.bit_depth_luma, sps.bit_depth_chroma), (16, 16));
+        assert_eq!(sps.max_dec_pic_buffering, 16);
+
+        // Fields set out of range by hand saturate rather than overflow.
+        let mut sps = sps;
+        sps.conformance_window = Some(FrameCropping {
+            left: u32::MAX,
+            right: u32::MAX,
+            top: u32::MAX,
+            bottom: u32::MAX,
+        });
+        assert_eq!((sps.width(), sps.height()), (0, 0));
+    }
+
+    #[test]
+    fn test_access_units() {
+        let vps = nalu(VPS, &[0x0c, 0x01, 0xff, 0xff]);
+        let prefix_sei = nalu(PREFIX_SEI, &[0x05, 0x01, 0xaa, 0x80]);
+        let suffix_sei = nalu(40, &[0x84, 0x01, 0xbb, 0x80]);
+        let aud = nalu(AUD, &[0x50]);
+        let units = [
+            vec![
+                vps,
+                sps(),
+                pps(),
+                prefix_sei.clone(),
+                slice(19, true, 2, 0),
+                suffix_sei.clone(),
+            ],
+            vec![aud, slice(1, true, 1, 2), slice(1, false, 1, 2)],
+            vec![prefix_sei, slice(0, true, 0, 1), suffix_sei],
+            vec![slice(1, true, 1, 4)],
+            vec![slice(0, true, 0, 3), nalu(EOS, &[])],
+        ];
+        let frames: Vec<_> = read_frames_with_config(&stream(&units.concat()), config())
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(frames.len(), units.len());
+        for (frame, nal_units) in frames.iter().zip(&units) {
+            assert_eq!(frame.data, stream(nal_units));
+        }
+        // A frame lasts 3003 ticks, and presentation is delayed by one frame for the reordering.
+        let timestamps: Vec<_> = frames.iter().map(|frame| (frame.pts, frame.dts)).collect();
+        assert_eq!(
+            timestamps,
+            vec![
+                (3003, 0),
+                (9009, 3003),
+                (6006, 6006),
+                (15015, 9009),
+                (12012, 12012)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let recovery_point = nalu(PREFIX_SEI, &[0x06, 0x01, 0x88, 0x80]);
+        let units = [
+            vec![
+                nalu(VPS, &[0x0c, 0x01, 0xff, 0xff]),
+                sps(),
+                pps(),
+                slice(19, true, 2, 0),
+            ],
+            vec![slice(1, true, 1, 1), slice(1, false, 1, 1)],
+            vec![recovery_point, slice(21, true, 2, 4)],
+            vec![slice(0, true, 0, 3)],
+        ];
+        let metadata: Vec<_> = read_frames_with_metadata(&stream(&units.concat()), config())
+            .into_iter()
+            .map(|result| result.unwrap().1)
+            .collect();
+        assert_eq!(metadata.len(), units.len());
+        for (metadata, nal_units) in metadata.iter().zip(&units) {
+            assert_eq!(metadata.codec, Codec::H265);
+            assert_eq!(metadata.size, stream(nal_units).len());
+        }
+
+        let types: Vec<_> = metadata[0]
+            .nal_units
+            .iter()
+            .map(|nalu| nalu.nal_unit_type)
+            .collect();
+        assert_eq!(types, [VPS, SPS, PPS, 19]);
+        let flags: Vec<_> = metadata
+            .iter()
+            .map(|m| (m.keyframe, m.idr, m.recovery_point, m.reference))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                (true, true, false, true),
+                (false, false, false, true),
+                (true, false, true, true),
+                (false, false, false, false),
+            ]
+        );
+        let slice_types: Vec<_> = metadata.iter().map(|m| m.slice_types.clone()).collect();
+        assert_eq!(
+            slice_types,
+            [
+                vec![SliceType::I],
+                vec![SliceType::P, SliceType::P],
+                vec![SliceType::I],
+                vec![SliceType::B],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cra_continues_poc() {
+        // A CRA picture in the middle of the stream doesn't restart the picture order count, so
+        // the leading picture that follows it is presented before it.
+        let units = [
+            sps(),
+            pps(),
+            slice(19, true, 2, 0),
+            slice(1, true, 1, 1),
+            slice(21, true, 2, 3),
+            slice(8, true, 0, 2),
+        ];
+        let pts: Vec<_> = read_frames_with_config(&stream(&units), config())
+            .into_iter()
+            .map(|frame| frame.unwrap().pts)
+            .collect();
+        assert_eq!(pts, vec![3003, 6006, 12012, 9009]);
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        let units = [
+            sps(),
+            // nuh_temporal_id_plus1 is zero
+            vec![0x02, 0x00, 0x80],
+            // an IDR picture in a temporal sub-layer
+            vec![0x26, 0x02, 0x80],
+            // a reserved type
+            nalu(41, &[0x80]),
+            vec![0x26],
+        ];
+        let errors: Vec<_> = read_frames_with_config(&stream(&units), config())
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+        assert!(matches!(
+            errors[..],
+            [
+                FramingError::MalformedNalHeader {
+                    nal_index: 1,
+                    header: 0x0200,
+                    ..
+                },
+                FramingError::MalformedNalHeader {
+                    nal_index: 2,
+                    header: 0x2602,
+                    ..
+                },
+                FramingError::UnsupportedNalType {
+                    nal_index: 3,
+                    nal_unit_type: 41,
+                    ..
+                },
+                FramingError::MalformedNalHeader {
+                    nal_index: 4,
+                    header: 0x26,
+                    ..
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_unspecified_types() {
+        // Dolby Vision carries its RPU and enhancement layer in unspecified types 62 and 63.
+        let rpu = nalu(62, &[0x01, 0x80]);
+        let enhancement_layer = nalu(63, &[0x01, 0x80]);
+        let units = [
+            sps(),
+            slice(19, true, 2, 0),
+            rpu.clone(),
+            enhancement_layer.clone(),
+            slice(1, true, 1, 1),
+        ];
+        let frames: Vec<_> = read_frames_with_config(&stream(&units), config())
+            .into_iter()
+            .map(|frame| frame.unwrap().data)
+            .collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], stream(&units[..4]));
+    }
+}
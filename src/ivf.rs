@@ -0,0 +1,400 @@
+use std::collections::VecDeque;
+use std::mem;
+use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+use crate::av1::{Av1SequenceHeader, ObuHeader, ObuState, TemporalUnitMetadata};
+use crate::framer::{ErrorPolicy, FramerConfig, FramingError};
+use crate::params::Rational;
+
+const FILE_HEADER_LEN: usize = 32;
+const FRAME_HEADER_LEN: usize = 12;
+/// The largest IVF frame accepted. The largest compressed frame an AV1 level allows, 8K at level
+/// 6.3, is well under this.
+const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// The file header of an IVF file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IvfHeader {
+    pub fourcc: [u8; 4],
+    pub width: u16,
+    pub height: u16,
+    /// The timebase of the frame timestamps, in seconds per tick.
+    pub timebase: Rational,
+    /// The number of frames, as written by the muxer. It's often zero or stale.
+    pub frame_count: u32,
+}
+
+impl IvfHeader {
+    /// Parses a file header, returning it with its length.
+    fn parse(buf: &[u8]) -> Result<(Self, usize), FramingError> {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let header_len = u16_at(6) as usize;
+        if buf[..4] != *b"DKIF" || header_len < FILE_HEADER_LEN {
+            return Err(FramingError::InvalidIvfHeader);
+        }
+        let fourcc = buf[8..12].try_into().unwrap();
+        if fourcc != *b"AV01" {
+            return Err(FramingError::UnsupportedIvfCodec { fourcc });
+        }
+        let header = Self {
+            fourcc,
+            width: u16_at(12),
+            height: u16_at(14),
+            timebase: Rational::new(u32_at(20), u32_at(16)),
+            frame_count: u32_at(24),
+        };
+        Ok((header, header_len))
+    }
+}
+
+/// Splits an IVF file holding AV1 into temporal units as it arrives, one per IVF frame.
+///
+/// The timestamps of the IVF frames are converted from the timebase of the [`IvfHeader`] to the
+/// configured one, and used as both the presentation and decoding timestamps. The OBUs of each
+/// frame are checked, and the latest sequence header is kept. An invalid file header or an
+/// oversized frame always stops the framer; other errors are handled according to the
+/// [`ErrorPolicy`].
+pub struct IvfFramer {
+    config: FramerConfig,
+    /// Input that hasn't been split into frames yet.
+    pending: Vec<u8>,
+    /// The stream offset of the start of `pending`.
+    offset: u64,
+    stopped: bool,
+    header: Option<IvfHeader>,
+    obus: ObuState,
+    ready: VecDeque<Result<(XcoderDecoderInputFrame, TemporalUnitMetadata), FramingError>>,
+}
+
+impl Default for IvfFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IvfFramer {
+    pub fn new() -> Self {
+        Self::with_config(FramerConfig::default())
+    }
+
+    pub fn with_error_policy(error_policy: ErrorPolicy) -> Self {
+        Self::with_config(FramerConfig {
+            error_policy,
+            ..FramerConfig::default()
+        })
+    }
+
+    /// Creates a framer with the given configuration. Only [`FramerConfig::error_policy`] and
+    /// [`FramerConfig::timebase`] are used, as IVF frames carry their own timestamps.
+    pub fn with_config(config: FramerConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+            offset: 0,
+            stopped: false,
+            header: None,
+            obus: ObuState::default(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Returns the file header, once it has been read.
+    pub fn header(&self) -> Option<&IvfHeader> {
+        self.header.as_ref()
+    }
+
+    /// Returns the latest sequence header seen.
+    pub fn sequence_header(&self) -> Option<&Av1SequenceHeader> {
+        self.obus.sequence_header.as_ref()
+    }
+
+    /// Adds a chunk of input and returns the temporal units it completed.
+    pub fn push(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = Result<XcoderDecoderInputFrame, FramingError>> + '_ {
+        self.push_with_metadata(chunk)
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+
+    /// Ends the input, returning any error about its end. The framer can be reused for another
+    /// file afterwards.
+    pub fn finish(
+        &mut self,
+    ) -> impl Iterator<Item = Result<XcoderDecoderInputFrame, FramingError>> + '_ {
+        self.finish_with_metadata()
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+
+    /// Like [`push`](Self::push), but also describes each temporal unit.
+    pub fn push_with_metadata(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = Result<(XcoderDecoderInputFrame, TemporalUnitMetadata), FramingError>> + '_
+    {
+        if !self.stopped {
+            self.pending.extend_from_slice(chunk);
+            self.split_pending();
+        }
+        self.ready.drain(..)
+    }
+
+    /// Like [`finish`](Self::finish), but also describes each temporal unit.
+    pub fn finish_with_metadata(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(XcoderDecoderInputFrame, TemporalUnitMetadata), FramingError>> + '_
+    {
+        if !self.stopped && !self.pending.is_empty() {
+            if self.header.is_none() {
+                self.ready.push_back(Err(FramingError::InvalidIvfHeader));
+            } else {
+                self.fail(FramingError::TruncatedIvfFrame {
+                    offset: self.offset,
+                });
+            }
+        }
+        let ready = mem::take(&mut self.ready);
+        *self = Self {
+            ready,
+            ..Self::with_config(mem::take(&mut self.config))
+        };
+        self.ready.drain(..)
+    }
+
+    fn split_pending(&mut self) {
+        let pending = mem::take(&mut self.pending);
+        let mut start = 0;
+        if self.header.is_none() {
+            if pending.len() < FILE_HEADER_LEN {
+                self.pending = pending;
+                return;
+            }
+            match IvfHeader::parse(&pending) {
+                Ok((_, header_len)) if header_len > pending.len() => {
+                    self.pending = pending;
+                    return;
+                }
+                Ok((header, header_len)) => {
+                    self.header = Some(header);
+                    start = header_len;
+                }
+                Err(err) => {
+                    self.stopped = true;
+                    self.ready.push_back(Err(err));
+                    return;
+                }
+            }
+        }
+        while let Some(frame_header) = pending.get(start..start + FRAME_HEADER_LEN) {
+            let size = u32::from_le_bytes(frame_header[..4].try_into().unwrap());
+            if size as usize > MAX_FRAME_LEN {
+                // There's no way to find the next frame, nor to buffer this one.
+                self.stopped = true;
+                self.ready.push_back(Err(FramingError::OversizedIvfFrame {
+                    offset: self.offset + start as u64,
+                    size,
+                }));
+                return;
+            }
+            let size = size as usize;
+            let pts = u64::from_le_bytes(frame_header[4..].try_into().unwrap()) as i64;
+            let Some(frame) =
+                pending.get(start + FRAME_HEADER_LEN..start + FRAME_HEADER_LEN + size)
+            else {
+                break;
+            };
+            self.add_frame(frame, self.offset + (start + FRAME_HEADER_LEN) as u64, pts);
+            start += FRAME_HEADER_LEN + size;
+            if self.stopped {
+                return;
+            }
+        }
+        self.pending = pending;
+        self.pending.drain(..start);
+        self.offset += start as u64;
+    }
+
+    /// Checks the OBUs of a frame, dropping the invalid ones, and emits the rest as a temporal
+    /// unit.
+    fn add_frame(&mut self, frame: &[u8], offset: u64, pts: i64) {
+        let mut data = Vec::with_capacity(frame.len());
+        let mut start = 0;
+        while start < frame.len() {
+            let obu_offset = offset + start as u64;
+            let header = match ObuHeader::read(&frame[start..]) {
+                Ok(Some(header)) if header.len() <= frame.len() - start => header,
+                Ok(_) => {
+                    self.fail(FramingError::TruncatedObu { offset: obu_offset });
+                    break;
+                }
+                Err(()) => {
+                    self.fail(FramingError::UnsizedObu {
+                        offset: obu_offset,
+                        obu_index: self.obus.obu_index,
+                    });
+                    break;
+                }
+            };
+            let obu = &frame[start..start + header.len()];
+            start += header.len();
+            match self.obus.add_obu(obu, &header, obu_offset) {
+                Ok(()) => data.extend_from_slice(obu),
+                Err(err) => self.fail(err),
+            }
+            if self.stopped {
+                return;
+            }
+        }
+        if !self.stopped && !data.is_empty() {
+            let timebase = self.header.as_ref().unwrap().timebase;
+            let pts = rescale(pts, timebase, self.config.timebase);
+            let metadata = TemporalUnitMetadata::new(&data, self.obus.sequence_header.as_ref());
+            let frame = XcoderDecoderInputFrame {
+                data,
+                pts,
+                dts: pts,
+            };
+            self.ready.push_back(Ok((frame, metadata)));
+        }
+    }
+
+    fn fail(&mut self, err: FramingError) {
+        match self.config.error_policy {
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Stop => {
+                self.pending.clear();
+                self.stopped = true;
+                self.ready.push_back(Err(err));
+            }
+            ErrorPolicy::Propagate => self.ready.push_back(Err(err)),
+        }
+    }
+}
+
+/// Converts a timestamp from one timebase to another, rounding down.
+fn rescale(ticks: i64, from: Rational, to: Rational) -> i64 {
+    let num = ticks as i128 * from.num as i128 * to.den as i128;
+    let den = from.den as i128 * to.num as i128;
+    num.div_euclid(den.max(1)) as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::av1::{obu, sequence_header, OBU_SEQUENCE_HEADER, OBU_TEMPORAL_DELIMITER};
+
+    fn file_header(fourcc: &[u8; 4]) -> Vec<u8> {
+        let mut header = b"DKIF".to_vec();
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&32u16.to_le_bytes());
+        header.extend_from_slice(fourcc);
+        header.extend_from_slice(&1920u16.to_le_bytes());
+        header.extend_from_slice(&1080u16.to_le_bytes());
+        header.extend_from_slice(&30000u32.to_le_bytes());
+        header.extend_from_slice(&1001u32.to_le_bytes());
+        header.extend_from_slice(&3u32.to_le_bytes());
+        header.extend_from_slice(&[0; 4]);
+        header
+    }
+
+    fn frame(pts: u64, obus: &[Vec<u8>]) -> Vec<u8> {
+        let data = obus.concat();
+        let mut frame = (data.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&pts.to_le_bytes());
+        frame.extend_from_slice(&data);
+        frame
+    }
+
+    #[test]
+    fn test_frames() {
+        let delimiter = obu(OBU_TEMPORAL_DELIMITER, &[]);
+        let frames = [
+            vec![
+                delimiter.clone(),
+                obu(OBU_SEQUENCE_HEADER, &sequence_header()),
+                obu(6, &[0x10; 20]),
+            ],
+            vec![delimiter.clone(), obu(6, &[0x20; 5])],
+            vec![delimiter, obu(6, &[0x30; 300])],
+        ];
+        let mut file = file_header(b"AV01");
+        for (pts, obus) in [0, 2, 1].into_iter().zip(&frames) {
+            file.extend_from_slice(&frame(pts, obus));
+        }
+
+        let mut framer = IvfFramer::new();
+        let mut results = vec![];
+        for chunk in file.chunks(7) {
+            results.extend(framer.push(chunk).map(Result::unwrap));
+        }
+        let header = *framer.header().unwrap();
+        assert_eq!((header.width, header.height), (1920, 1080));
+        assert_eq!(header.timebase, Rational::new(1001, 30000));
+        assert_eq!(framer.sequence_header().unwrap().bit_depth, 10);
+        assert_eq!(framer.finish().count(), 0);
+
+        // Timestamps are converted to the default 90 kHz timebase.
+        assert_eq!(results.len(), 3);
+        for ((result, obus), pts) in results.iter().zip(&frames).zip([0, 6006, 3003]) {
+            assert_eq!(result.data, obus.concat());
+            assert_eq!((result.pts, result.dts), (pts, pts));
+        }
+
+        let mut framer = IvfFramer::with_config(FramerConfig {
+            timebase: Rational::new(1001, 30000),
+            ..FramerConfig::default()
+        });
+        let pts: Vec<_> = framer.push(&file).map(|frame| frame.unwrap().pts).collect();
+        assert_eq!(pts, [0, 2, 1]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut framer = IvfFramer::new();
+        let results: Vec<_> = framer.push(&file_header(b"VP90")).collect();
+        assert!(matches!(
+            results[..],
+            [Err(FramingError::UnsupportedIvfCodec {
+                fourcc: [b'V', b'P', b'9', b'0']
+            })]
+        ));
+
+        // An OBU overrunning its frame is dropped, and so is a frame cut short by the end of the
+        // input.
+        let mut framer = IvfFramer::new();
+        let mut file = file_header(b"AV01");
+        file.extend_from_slice(&frame(
+            0,
+            &[obu(OBU_TEMPORAL_DELIMITER, &[]), vec![0x32, 5, 0]],
+        ));
+        file.extend_from_slice(&frame(1, &[obu(6, &[0x10; 8])])[..15]);
+        let mut results: Vec<_> = framer.push(&file).collect();
+        results.extend(framer.finish());
+        assert!(matches!(
+            results[..],
+            [
+                Err(FramingError::TruncatedObu { offset: 46 }),
+                Ok(_),
+                Err(FramingError::TruncatedIvfFrame { offset: 49 }),
+            ]
+        ));
+        assert_eq!(results[1].as_ref().unwrap().data, [0x12, 0]);
+
+        // A bogus frame size stops the framer rather than buffering the rest of the input.
+        let mut framer = IvfFramer::new();
+        let mut file = file_header(b"AV01");
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        file.extend_from_slice(&[0; 8]);
+        let results: Vec<_> = framer.push(&file).collect();
+        assert!(matches!(
+            results[..],
+            [Err(FramingError::OversizedIvfFrame {
+                offset: 32,
+                size: u32::MAX
+            })]
+        ));
+        assert_eq!(framer.push(&[0; 64]).count(), 0);
+        assert_eq!(framer.finish().count(), 0);
+    }
+}
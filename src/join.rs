@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::bits::{header_rbsp, BitReader};
+use crate::framer::Codec;
+use crate::hevc::{self, HevcSps};
+use crate::metadata::FrameMetadata;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Makes a stream joined mid-way decodable, see [`FramerConfig::join_mid_stream`].
+///
+/// [`FramerConfig::join_mid_stream`]: crate::FramerConfig::join_mid_stream
+pub(crate) struct Joiner {
+    codec: Codec,
+    /// The latest parameter sets, by NAL unit type and id. VPSs sort before SPSs, and SPSs before
+    /// PPSs, which is the order they're injected in.
+    parameter_sets: BTreeMap<(u8, u32), Vec<u8>>,
+    /// Whether a keyframe has been seen.
+    joined: bool,
+}
+
+impl Joiner {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            parameter_sets: BTreeMap::new(),
+            joined: false,
+        }
+    }
+
+    /// Takes in the parameter sets of a frame, and returns whether to keep it. Frames before the
+    /// first random access point are dropped: an IDR picture, a recovery point, or another H.265
+    /// IRAP picture. Random access points get the latest parameter sets they don't carry
+    /// themselves injected, after any access unit delimiter and before any later parameter set
+    /// types they do carry.
+    pub fn process(&mut self, data: &mut Vec<u8>, metadata: &FrameMetadata) -> bool {
+        let aud = match self.codec {
+            Codec::H264 => 9,
+            Codec::H265 => hevc::AUD,
+        };
+        let mut carried = BTreeSet::new();
+        for nalu in &metadata.nal_units {
+            let nal_unit_type = nalu.nal_unit_type;
+            if !self.is_parameter_set(nal_unit_type) {
+                continue;
+            }
+            let bytes = &data[nalu.offset..nalu.offset + nalu.len];
+            if let Some(id) = self.parameter_set_id(nal_unit_type, bytes) {
+                self.parameter_sets
+                    .insert((nal_unit_type, id), bytes.to_vec());
+                carried.insert((nal_unit_type, id));
+            }
+        }
+
+        let random_access = metadata.idr
+            || metadata.recovery_point
+            || self.codec == Codec::H265
+                && metadata
+                    .nal_units
+                    .iter()
+                    .any(|nalu| hevc::is_irap(nalu.nal_unit_type));
+        if !random_access {
+            return self.joined;
+        }
+        self.joined = true;
+
+        // Each missing parameter set goes before the first NAL unit that isn't a delimiter or a
+        // parameter set of the same or an earlier type. The types sort in the order they have to
+        // come in, so the positions only move forward.
+        let injected: Vec<_> = self
+            .parameter_sets
+            .iter()
+            .filter(|(key, _)| !carried.contains(key))
+            .map(|(&(nal_unit_type, _), parameter_set)| {
+                let start = metadata
+                    .nal_units
+                    .iter()
+                    .find(|nalu| {
+                        nalu.nal_unit_type != aud
+                            && !(self.is_parameter_set(nalu.nal_unit_type)
+                                && nalu.nal_unit_type <= nal_unit_type)
+                    })
+                    .map_or(data.len(), |nalu| nalu.offset - START_CODE.len());
+                (start, parameter_set)
+            })
+            .collect();
+        for (start, parameter_set) in injected.into_iter().rev() {
+            let bytes = START_CODE.iter().chain(parameter_set);
+            data.splice(start..start, bytes.copied());
+        }
+        true
+    }
+
+    fn is_parameter_set(&self, nal_unit_type: u8) -> bool {
+        match self.codec {
+            Codec::H264 => matches!(nal_unit_type, 7 | 8),
+            Codec::H265 => matches!(nal_unit_type, hevc::VPS | hevc::SPS | hevc::PPS),
+        }
+    }
+
+    /// Reads the id of a parameter set. Only the start of an H.264 SPS is read, but an H.265 SPS
+    /// has to be parsed up to its id.
+    fn parameter_set_id(&self, nal_unit_type: u8, nalu: &[u8]) -> Option<u32> {
+        let rbsp = header_rbsp(nalu, 16);
+        let mut reader = BitReader::new(&rbsp);
+        match (self.codec, nal_unit_type) {
+            // profile_idc, the constraint flags and level_idc come first.
+            (Codec::H264, 7) => reader.skip_bits(32).ok()?,
+            (Codec::H264, _) => reader.skip_bits(8).ok()?,
+            (Codec::H265, hevc::VPS) => {
+                reader.skip_bits(16).ok()?;
+                return reader.read_bits(4).ok();
+            }
+            (Codec::H265, hevc::SPS) => {
+                return HevcSps::parse(nalu)
+                    .ok()
+                    .map(|sps| sps.seq_parameter_set_id);
+            }
+            (Codec::H265, _) => reader.skip_bits(16).ok()?,
+        }
+        reader.read_ue().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::avcc::{annex_b, PPS, SPS};
+    use crate::{read_frames_with_metadata, FramerConfig};
+
+    const NEW_SPS: &[u8] = &[0x67, 0x4d, 0x40, 0x1f, 0xd9];
+    const AUD: &[u8] = &[0x09, 0xf0];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84];
+    const P: &[u8] = &[0x41, 0x9a, 0x02];
+    const RECOVERY_POINT: &[u8] = &[0x06, 0x06, 0x01, 0x84, 0x80];
+
+    #[test]
+    fn test_join_mid_stream() {
+        let stream = annex_b(&[
+            P, P, SPS, PPS, IDR, P, AUD, IDR, P, NEW_SPS, PPS, IDR, AUD, IDR,
+        ]);
+        let config = FramerConfig {
+            join_mid_stream: true,
+            ..FramerConfig::default()
+        };
+        let frames: Vec<_> = read_frames_with_metadata(&stream, config.clone())
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        // The frames before the first IDR are dropped, and the parameter sets are injected after
+        // the delimiter of the IDRs that lack them.
+        let expected = [
+            annex_b(&[SPS, PPS, IDR]),
+            annex_b(&[P]),
+            annex_b(&[AUD, SPS, PPS, IDR]),
+            annex_b(&[P]),
+            annex_b(&[NEW_SPS, PPS, IDR]),
+            annex_b(&[AUD, NEW_SPS, PPS, IDR]),
+        ];
+        assert_eq!(frames.len(), expected.len());
+        for ((frame, metadata), expected) in frames.iter().zip(&expected) {
+            assert_eq!(&frame.data, expected);
+            assert_eq!(metadata.size, expected.len());
+        }
+        let types: Vec<_> = frames[2]
+            .1
+            .nal_units
+            .iter()
+            .map(|nalu| (nalu.nal_unit_type, nalu.offset))
+            .collect();
+        assert_eq!(types, [(9, 4), (7, 10), (8, 19), (5, 27)]);
+
+        // The dropped frames still take up their place in the timeline.
+        let pts: Vec<_> = frames.iter().map(|(frame, _)| frame.pts).collect();
+        assert_eq!(pts, [7200, 10800, 14400, 18000, 21600, 25200]);
+
+        // An IDR with its own SPS but not the PPS gets only the PPS, after the SPS.
+        let stream = annex_b(&[SPS, PPS, P, SPS, IDR]);
+        let frames: Vec<_> = read_frames_with_metadata(&stream, config)
+            .into_iter()
+            .map(|result| result.unwrap().0.data)
+            .collect();
+        assert_eq!(frames, [annex_b(&[SPS, PPS, IDR])]);
+    }
+
+    #[test]
+    fn test_join_at_recovery_point() {
+        // An intra-refresh stream: no IDR, only recovery points on P slices.
+        let stream = annex_b(&[SPS, PPS, P, P, RECOVERY_POINT, P, P, RECOVERY_POINT, P]);
+        let config = FramerConfig {
+            join_mid_stream: true,
+            ..FramerConfig::default()
+        };
+        let frames: Vec<_> = read_frames_with_metadata(&stream, config)
+            .into_iter()
+            .map(|result| result.unwrap().0.data)
+            .collect();
+
+        assert_eq!(
+            frames,
+            [
+                annex_b(&[SPS, PPS, RECOVERY_POINT, P]),
+                annex_b(&[P]),
+                annex_b(&[SPS, PPS, RECOVERY_POINT, P]),
+            ]
+        );
+    }
+}
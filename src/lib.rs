@@ -1,83 +1,634 @@
 use rtrb::{Consumer, Producer, RingBuffer};
-use std::mem;
-use std::thread;
-use std::time::Duration;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
 use xcoder_quadra::decoder::XcoderDecoderInputFrame;
 
+#[cfg(feature = "async")]
+mod async_queue;
+mod av1;
+mod avcc;
+mod bits;
+mod cancel;
+mod captions;
+mod cea608;
+mod cea708;
+mod control;
+mod filter;
+mod framer;
+mod hevc;
+mod ivf;
+mod join;
+mod metadata;
+mod mp4;
+mod multi_producer;
+mod overflow;
+mod params;
+mod poc;
+mod probe;
+mod sei;
+mod slice;
+mod stats;
+mod timestamps;
+mod ts;
+mod wait;
+
+#[cfg(feature = "async")]
+pub use async_queue::{DecoderInputSink, DecoderInputStream, SendError};
+pub use av1::{Av1FrameType, Av1SequenceHeader, ObuFramer, ObuMetadata, TemporalUnitMetadata};
+pub use avcc::{ConversionError, DecoderConfigurationRecord, LengthPrefixedConverter};
+pub use cancel::CancellationToken;
+pub use captions::{
+    extract_captions, write_scc, write_webvtt, Caption, CaptionExtractor, Captions,
+};
+pub use control::{ControlMessage, DecoderInputFrames, DecoderInputItem};
+pub use filter::{NalFilter, NalRule};
+pub use framer::{AnnexBFramer, Codec, ErrorPolicy, FramerConfig, FramingError};
+pub use hevc::{HevcPps, HevcSps};
+pub use ivf::{IvfFramer, IvfHeader};
+pub use metadata::{FrameInspector, FrameMetadata, NalUnitMetadata};
+pub use mp4::{Mp4Demuxer, Mp4Error};
+pub use multi_producer::DecoderInputQueueMultiProducer;
+use overflow::DropCounters;
+pub use overflow::{DroppedFrames, OverflowPolicy};
+pub use params::{
+    BitstreamRestriction, FrameCropping, HrdParameters, Pps, Rational, Sps, TimingInfo, Vui,
+};
+pub use probe::{probe, StreamChange, StreamInfo};
+pub use sei::{
+    CcData, ContentLightLevel, MasteringDisplayColourVolume, PicTiming, RecoveryPoint, SeiMessage,
+    TimeCode, UserDataRegistered, UserDataUnregistered,
+};
+pub use slice::SliceType;
+use stats::{item_bytes, Counters};
+pub use stats::{QueueStats, QueueStatsSnapshot};
+pub use ts::{TsDemuxer, TsError};
+pub use wait::WaitStrategy;
+use wait::{Signal, Waiter};
+
+/// Configuration for a [`DecoderInputQueue`]. A plain `usize` converts into a configuration with
+/// that capacity and the default settings.
+#[derive(Clone, Debug)]
+pub struct DecoderInputQueueConfig {
+    /// The maximum number of items in the queue.
+    pub capacity: usize,
+    /// The maximum total size of the frames' data in the queue, if any. The queue is considered
+    /// full when either this or `capacity` would be exceeded. A frame larger than the whole budget
+    /// is still accepted once the queue is empty.
+    pub max_bytes: Option<u64>,
+    /// How [`DecoderInputQueueProducer::push`] waits while the queue is full.
+    pub producer_wait: WaitStrategy,
+    /// How the consumer waits while the queue is empty.
+    pub consumer_wait: WaitStrategy,
+    /// What [`DecoderInputQueueProducer::push`] does while the queue is full.
+    pub overflow: OverflowPolicy,
+}
+
+impl From<usize> for DecoderInputQueueConfig {
+    fn from(capacity: usize) -> Self {
+        Self {
+            capacity,
+            max_bytes: None,
+            producer_wait: WaitStrategy::default(),
+            consumer_wait: WaitStrategy::default(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// State shared by both halves of the queue.
+#[derive(Default)]
+struct Shared {
+    /// Notified when an item is pushed or the producer is dropped.
+    items_available: Signal,
+    /// Notified when an item is popped or the consumer is dropped.
+    space_available: Signal,
+    producer_closed: AtomicBool,
+    consumer_closed: AtomicBool,
+    /// The reason the queue was cancelled, once it has been.
+    cancellation: OnceLock<String>,
+    dropped: DropCounters,
+    counters: Counters,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The consuming end of the ring. It's only shared with the producer, which locks it to evict
+/// frames, when the overflow policy may call for that.
+enum Ring<E> {
+    Owned(Consumer<DecoderInputItem<E>>),
+    Shared(Arc<Mutex<Consumer<DecoderInputItem<E>>>>),
+}
+
+impl<E> Ring<E> {
+    fn with<T>(&mut self, f: impl FnOnce(&mut Consumer<DecoderInputItem<E>>) -> T) -> T {
+        match self {
+            Self::Owned(ring) => f(ring),
+            Self::Shared(ring) => f(&mut lock(ring)),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Owned(ring) => ring.is_empty(),
+            Self::Shared(ring) => lock(ring).is_empty(),
+        }
+    }
+}
+
+/// The consumer-side logic, used by the queue and lent to its frame views.
+struct Reader<E> {
+    ring: Ring<E>,
+    /// A control message that ended the current run of frames and hasn't been taken yet.
+    control: Option<ControlMessage>,
+    shared: Arc<Shared>,
+    wait: WaitStrategy,
+}
+
 pub struct DecoderInputQueue<E> {
-    receiver: Consumer<Result<XcoderDecoderInputFrame, E>>,
+    reader: Reader<E>,
 }
 
 pub struct DecoderInputQueueProducer<E> {
-    sender: Producer<Result<XcoderDecoderInputFrame, E>>,
+    sender: Producer<DecoderInputItem<E>>,
+    /// The consuming end of the ring, if the overflow policy may evict queued frames.
+    receiver: Option<Arc<Mutex<Consumer<DecoderInputItem<E>>>>>,
+    shared: Arc<Shared>,
+    wait: WaitStrategy,
+    overflow: OverflowPolicy,
+    max_bytes: Option<u64>,
 }
 
 impl<E> DecoderInputQueue<E> {
-    pub fn new(capacity: usize) -> (Self, DecoderInputQueueProducer<E>) {
-        let (producer, consumer) = RingBuffer::new(capacity);
+    pub fn new(config: impl Into<DecoderInputQueueConfig>) -> (Self, DecoderInputQueueProducer<E>) {
+        let config = config.into();
+        let (producer, consumer) = RingBuffer::new(config.capacity);
+        let (ring, receiver) = if config.overflow.evicts() {
+            let consumer = Arc::new(Mutex::new(consumer));
+            (Ring::Shared(consumer.clone()), Some(consumer))
+        } else {
+            (Ring::Owned(consumer), None)
+        };
+        let shared = Arc::new(Shared::default());
         (
-            Self { receiver: consumer },
-            DecoderInputQueueProducer { sender: producer },
+            Self {
+                reader: Reader {
+                    ring,
+                    control: None,
+                    shared: shared.clone(),
+                    wait: config.consumer_wait,
+                },
+            },
+            DecoderInputQueueProducer {
+                sender: producer,
+                receiver,
+                shared,
+                wait: config.producer_wait,
+                overflow: config.overflow,
+                max_bytes: config.max_bytes,
+            },
         )
     }
+
+    /// Returns true once the producer has been dropped. Frames it pushed beforehand may still be
+    /// waiting in the queue.
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_closed()
+    }
+
+    /// Returns the number of frames dropped by the overflow policy so far.
+    pub fn dropped_frames(&self) -> DroppedFrames {
+        self.reader.shared.dropped.snapshot()
+    }
+
+    /// Returns a handle for reading the queue's statistics from any thread.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats::new(self.reader.shared.clone())
+    }
 }
 
-impl<E> DecoderInputQueueProducer<E> {
-    pub fn push(&mut self, frame: Result<XcoderDecoderInputFrame, E>) {
-        let mut frame = frame;
-        loop {
-            match self.sender.push(frame) {
-                Ok(_) => break,
-                Err(rtrb::PushError::Full(returned_frame)) => {
-                    frame = returned_frame;
-                }
+impl<E> Drop for DecoderInputQueue<E> {
+    fn drop(&mut self) {
+        self.reader
+            .shared
+            .consumer_closed
+            .store(true, Ordering::Release);
+        self.reader.shared.space_available.notify();
+    }
+}
+
+/// The error returned when an item can't be pushed into the queue. The item is handed back to
+/// the caller.
+pub enum PushError<T> {
+    /// The queue is full.
+    Full(T),
+    /// The consumer side of the queue has been dropped.
+    Closed(T),
+    /// The queue was full and the frame was dropped by the overflow policy.
+    Dropped(T),
+    /// The queue stayed full until the timeout expired.
+    TimedOut(T),
+    /// The queue has been cancelled. See [`CancellationToken`].
+    Cancelled(T),
+}
+
+impl<T> PushError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Full(v)
+            | Self::Closed(v)
+            | Self::Dropped(v)
+            | Self::TimedOut(v)
+            | Self::Cancelled(v) => v,
+        }
+    }
+
+    /// Maps the item handed back by the error.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> PushError<U> {
+        match self {
+            Self::Full(v) => PushError::Full(f(v)),
+            Self::Closed(v) => PushError::Closed(f(v)),
+            Self::Dropped(v) => PushError::Dropped(f(v)),
+            Self::TimedOut(v) => PushError::TimedOut(f(v)),
+            Self::Cancelled(v) => PushError::Cancelled(f(v)),
+        }
+    }
+}
+
+impl<T> fmt::Debug for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Closed(_) => f.write_str("Closed(..)"),
+            Self::Dropped(_) => f.write_str("Dropped(..)"),
+            Self::TimedOut(_) => f.write_str("TimedOut(..)"),
+            Self::Cancelled(_) => f.write_str("Cancelled(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("the decoder input queue is full"),
+            Self::Closed(_) => f.write_str("the decoder input queue has been closed"),
+            Self::Dropped(_) => {
+                f.write_str("the frame was dropped because the decoder input queue is full")
+            }
+            Self::TimedOut(_) => {
+                f.write_str("timed out waiting for space in the decoder input queue")
             }
+            Self::Cancelled(_) => f.write_str("the decoder input queue has been cancelled"),
         }
     }
 }
 
-impl<E> Iterator for DecoderInputQueue<E> {
-    type Item = Result<XcoderDecoderInputFrame, E>;
+impl<T> Error for PushError<T> {}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.receiver.pop() {
-                Ok(frame) => return Some(frame),
-                Err(rtrb::PopError::Empty) => {}
+/// The error returned when a frame can't be popped from the queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PopError {
+    /// The queue is empty.
+    Empty,
+    /// The queue is empty and the producer has been dropped.
+    Closed,
+    /// The queue stayed empty until the timeout expired.
+    TimedOut,
+    /// A control message ended the current run of frames. It can be retrieved with
+    /// [`DecoderInputQueue::take_control`], after which frames can be popped again.
+    Control,
+    /// The queue has been cancelled. See [`CancellationToken`].
+    Cancelled,
+}
+
+impl fmt::Display for PopError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("the decoder input queue is empty"),
+            Self::Closed => f.write_str("the decoder input queue has been closed"),
+            Self::TimedOut => {
+                f.write_str("timed out waiting for a frame in the decoder input queue")
+            }
+            Self::Control => f.write_str("a control message is pending in the decoder input queue"),
+            Self::Cancelled => f.write_str("the decoder input queue has been cancelled"),
+        }
+    }
+}
+
+impl Error for PopError {}
+
+impl<E> DecoderInputQueueProducer<E> {
+    /// Pushes a frame, applying the queue's [`OverflowPolicy`] if it's full. With
+    /// [`OverflowPolicy::Block`] this waits for space, and a frame the policy drops is handed back
+    /// in [`PushError::Dropped`]. Fails if the consumer side of the queue has been dropped.
+    pub fn push(
+        &mut self,
+        frame: Result<XcoderDecoderInputFrame, E>,
+    ) -> Result<(), PushError<Result<XcoderDecoderInputFrame, E>>> {
+        self.push_within(frame.into(), None)
+            .map_err(|err| err.map(DecoderInputItem::expect_frame))
+    }
+
+    /// Like [`push`](Self::push), but waits for at most `timeout`.
+    pub fn push_timeout(
+        &mut self,
+        frame: Result<XcoderDecoderInputFrame, E>,
+        timeout: Duration,
+    ) -> Result<(), PushError<Result<XcoderDecoderInputFrame, E>>> {
+        self.push_within(frame.into(), Some(timeout))
+            .map_err(|err| err.map(DecoderInputItem::expect_frame))
+    }
+
+    /// Pushes a frame without waiting. If the queue is full, the frame is handed back in
+    /// [`PushError::Full`] regardless of the overflow policy.
+    pub fn try_push(
+        &mut self,
+        frame: Result<XcoderDecoderInputFrame, E>,
+    ) -> Result<(), PushError<Result<XcoderDecoderInputFrame, E>>> {
+        self.try_push_item(frame.into())
+            .map_err(|err| err.map(DecoderInputItem::expect_frame))
+    }
+
+    /// Pushes a control message, waiting for space if the queue is full. Control messages are
+    /// never dropped by the overflow policy.
+    pub fn push_control(
+        &mut self,
+        message: ControlMessage,
+    ) -> Result<(), PushError<ControlMessage>> {
+        self.push_within(message.into(), None)
+            .map_err(|err| err.map(DecoderInputItem::expect_control))
+    }
+
+    fn try_push_item(
+        &mut self,
+        item: DecoderInputItem<E>,
+    ) -> Result<(), PushError<DecoderInputItem<E>>> {
+        if self.shared.is_cancelled() {
+            return Err(PushError::Cancelled(item));
+        }
+        if self.is_closed() {
+            return Err(PushError::Closed(item));
+        }
+        let bytes = item_bytes(&item);
+        if !self.within_byte_budget(bytes) {
+            return Err(PushError::Full(item));
+        }
+        match self.sender.push(item) {
+            Ok(_) => {
+                self.shared.counters.record_push(bytes);
+                self.shared.items_available.notify();
+                Ok(())
+            }
+            Err(rtrb::PushError::Full(item)) => Err(PushError::Full(item)),
+        }
+    }
+
+    fn push_within(
+        &mut self,
+        item: DecoderInputItem<E>,
+        timeout: Option<Duration>,
+    ) -> Result<(), PushError<DecoderInputItem<E>>> {
+        let mut item = item;
+        let bytes = item_bytes(&item);
+        let started = Instant::now();
+        let deadline = timeout.map(|timeout| started + timeout);
+        let mut waiter = Waiter::new(self.wait);
+        let mut waited = false;
+        let result = loop {
+            match self.push_or_overflow(item) {
+                Err(PushError::Full(returned_item)) => item = returned_item,
+                result => break result,
+            }
+            waited = true;
+            if !waiter.wait(&self.shared.space_available, deadline, || {
+                self.has_room_for(bytes) || self.is_closed() || self.shared.is_cancelled()
+            }) {
+                break Err(PushError::TimedOut(item));
+            }
+        };
+        if waited {
+            self.shared.counters.record_producer_wait(started);
+        }
+        result
+    }
+
+    /// Returns true once the consumer side of the queue has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.consumer_closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of frames dropped by the overflow policy so far.
+    pub fn dropped_frames(&self) -> DroppedFrames {
+        self.shared.dropped.snapshot()
+    }
+
+    /// Returns a handle for reading the queue's statistics, the same ones the consumer sees.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats::new(self.shared.clone())
+    }
+
+    fn within_byte_budget(&self, bytes: u64) -> bool {
+        match self.max_bytes {
+            Some(max_bytes) => {
+                let in_flight = self.shared.counters.bytes_in_flight();
+                in_flight == 0 || in_flight + bytes <= max_bytes
             }
+            None => true,
         }
     }
+
+    /// Returns true if an item of the given size would currently fit into the queue.
+    fn has_room_for(&self, bytes: u64) -> bool {
+        !self.sender.is_full() && self.within_byte_budget(bytes)
+    }
+
+    /// Signals the consumer that no more frames will be pushed.
+    fn close(&self) {
+        self.shared.producer_closed.store(true, Ordering::Release);
+        self.shared.items_available.notify();
+    }
 }
 
-pub fn read_frames(buf: &[u8]) -> Vec<Result<XcoderDecoderInputFrame, std::io::Error>> {
-    let nalus: Vec<_> = h264::iterate_annex_b(&buf).collect();
-    let mut ret = vec![];
-    let mut buffer = vec![];
-    let mut h264_counter = h264::AccessUnitCounter::new();
-    for nalu in nalus {
-        let is_new_frame = {
-            let before = h264_counter.count();
-            h264_counter.count_nalu(nalu).unwrap();
-            h264_counter.count() != before
+impl<E> Drop for DecoderInputQueueProducer<E> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<E> Reader<E> {
+    fn is_closed(&self) -> bool {
+        self.shared.producer_closed.load(Ordering::Acquire)
+    }
+
+    /// Pops the next item, starting with a control message that ended a run of frames but hasn't
+    /// been taken yet.
+    fn try_pop_item(&mut self) -> Result<DecoderInputItem<E>, PopError> {
+        if self.shared.is_cancelled() {
+            return Err(PopError::Cancelled);
+        }
+        if let Some(message) = self.control.take() {
+            return Ok(DecoderInputItem::Control(message));
+        }
+        self.pop_next()
+    }
+
+    /// Pops the next frame. A control message is set aside instead, ending the current run of
+    /// frames until it's taken.
+    fn try_pop_frame(&mut self) -> Result<Result<XcoderDecoderInputFrame, E>, PopError> {
+        if self.shared.is_cancelled() {
+            return Err(PopError::Cancelled);
+        }
+        if self.control.is_some() {
+            return Err(PopError::Control);
+        }
+        let control = self.ring.with(|ring| match ring.peek() {
+            Ok(DecoderInputItem::Control(_)) => ring.pop().ok(),
+            _ => None,
+        });
+        match control {
+            Some(item) => {
+                self.control = Some(item.expect_control());
+                self.shared.counters.record_pop(0);
+                self.shared.space_available.notify();
+                Err(PopError::Control)
+            }
+            None => self.pop_next().map(DecoderInputItem::expect_frame),
+        }
+    }
+
+    fn pop_next(&mut self) -> Result<DecoderInputItem<E>, PopError> {
+        // A shared ring has to be unlocked before notifying.
+        let result = self.ring.with(Consumer::pop);
+        match result {
+            Ok(item) => {
+                self.shared.counters.record_pop(item_bytes(&item));
+                self.shared.space_available.notify();
+                Ok(item)
+            }
+            Err(rtrb::PopError::Empty) if self.is_closed() => {
+                // The producer may have pushed more items right before it was dropped.
+                let item = self
+                    .ring
+                    .with(Consumer::pop)
+                    .map_err(|_| PopError::Closed)?;
+                self.shared.counters.record_pop(item_bytes(&item));
+                Ok(item)
+            }
+            Err(rtrb::PopError::Empty) => Err(PopError::Empty),
+        }
+    }
+
+    fn pop_within<T>(
+        &mut self,
+        timeout: Option<Duration>,
+        try_pop: impl Fn(&mut Self) -> Result<T, PopError>,
+    ) -> Result<T, PopError> {
+        // The wait is clocked from the same instant as the deadline, so a call that times out always
+        // records at least its full timeout.
+        let started = Instant::now();
+        let deadline = timeout.map(|timeout| started + timeout);
+        let mut waiter = Waiter::new(self.wait);
+        let mut waited = false;
+        let result = loop {
+            match try_pop(self) {
+                Err(PopError::Empty) => {}
+                result => break result,
+            }
+            waited = true;
+            if !waiter.wait(&self.shared.items_available, deadline, || {
+                self.is_closed() || self.shared.is_cancelled() || !self.ring.is_empty()
+            }) {
+                break Err(PopError::TimedOut);
+            }
         };
-        if is_new_frame && !buffer.is_empty() {
-            ret.push(Ok(XcoderDecoderInputFrame {
-                data: mem::take(&mut buffer),
-                pts: ret.len() as _,
-                dts: ret.len() as _,
-            }));
+        if waited {
+            self.shared.counters.record_consumer_wait(started);
         }
-        buffer.extend_from_slice(&[0, 0, 0, 1]);
-        buffer.extend_from_slice(nalu);
+        result
+    }
+}
+
+impl<E> DecoderInputQueue<E> {
+    /// Pops a frame without waiting.
+    pub fn try_pop(&mut self) -> Result<Result<XcoderDecoderInputFrame, E>, PopError> {
+        self.reader.try_pop_frame()
     }
-    if !buffer.is_empty() {
-        ret.push(Ok(XcoderDecoderInputFrame {
-            data: mem::take(&mut buffer),
-            pts: ret.len() as _,
-            dts: ret.len() as _,
-        }));
+
+    /// Pops a frame, waiting up to `timeout` for one if the queue is empty.
+    pub fn pop_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Result<XcoderDecoderInputFrame, E>, PopError> {
+        self.reader.pop_within(Some(timeout), Reader::try_pop_frame)
+    }
+}
+
+impl<E> Iterator for DecoderInputQueue<E> {
+    type Item = Result<XcoderDecoderInputFrame, E>;
+
+    /// Returns the next frame, waiting for one if necessary. Iteration ends when the producer is
+    /// dropped, the queue is cancelled or a control message is reached; see
+    /// [`DecoderInputQueue::take_control`].
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.pop_within(None, Reader::try_pop_frame).ok()
+    }
+}
+
+/// Splits a complete H.264 Annex B elementary stream into access units. See [`AnnexBFramer`] for
+/// input that arrives incrementally.
+pub fn read_frames(buf: &[u8]) -> Vec<Result<XcoderDecoderInputFrame, FramingError>> {
+    read_frames_with_policy(buf, ErrorPolicy::default())
+}
+
+/// Like [`read_frames`], but handles malformed input according to `error_policy`.
+pub fn read_frames_with_policy(
+    buf: &[u8],
+    error_policy: ErrorPolicy,
+) -> Vec<Result<XcoderDecoderInputFrame, FramingError>> {
+    read_frames_with_config(
+        buf,
+        FramerConfig {
+            error_policy,
+            ..FramerConfig::default()
+        },
+    )
+}
+
+/// Like [`read_frames`], with the given configuration. Set [`FramerConfig::codec`] to split an H.265
+/// stream.
+pub fn read_frames_with_config(
+    buf: &[u8],
+    config: FramerConfig,
+) -> Vec<Result<XcoderDecoderInputFrame, FramingError>> {
+    let mut framer = AnnexBFramer::with_config(config);
+    let mut frames: Vec<_> = framer.push(buf).collect();
+    frames.extend(framer.finish());
+    frames
+}
+
+/// Like [`read_frames_with_config`], but also describes each access unit.
+pub fn read_frames_with_metadata(
+    buf: &[u8],
+    config: FramerConfig,
+) -> Vec<Result<(XcoderDecoderInputFrame, FrameMetadata), FramingError>> {
+    let mut framer = AnnexBFramer::with_config(config);
+    let mut frames: Vec<_> = framer.push_with_metadata(buf).collect();
+    frames.extend(framer.finish_with_metadata());
+    frames
+}
+
+/// Builds a frame holding the header of an IDR slice, with its dts equal to its pts.
+#[cfg(test)]
+pub(crate) fn test_frame(pts: i64) -> XcoderDecoderInputFrame {
+    XcoderDecoderInputFrame {
+        data: vec![0, 0, 0, 1, 0x65],
+        pts,
+        dts: pts,
     }
-    ret
 }
 
 #[cfg(test)]
@@ -85,7 +636,7 @@ mod test {
     use super::*;
     use std::io::Read;
     use std::io::Write;
-    use xcoder_quadra::decoder::{XcoderDecoder, XcoderDecoderCodec, XcoderDecoderConfig};
+    use xcoder_quadra::decoder::XcoderDecoder;
     use xcoder_quadra::encoder::{XcoderEncoder, XcoderEncoderCodec, XcoderEncoderConfig};
     use xcoder_quadra::linux_impl::XcoderPixelFormat;
     use xcoder_quadra::scaler::{XcoderScaler, XcoderScalerConfig};
@@ -97,21 +648,14 @@ mod test {
         let mut buf = Vec::new();
         f.read_to_end(&mut buf).unwrap();
 
-        let (mut decoder_input_queue, mut producer_queue) = DecoderInputQueue::new(1024);
+        let (decoder_input_queue, mut producer_queue) = DecoderInputQueue::new(1024);
 
         let frames = read_frames(&buf);
+        let info = probe(&buf, Codec::H264).expect("no SPS in the stream");
 
         // Initialize the decoder with the iterator
         let mut decoder = XcoderDecoder::new(
-            XcoderDecoderConfig {
-                width: 1920,
-                height: 1080,
-                codec: XcoderDecoderCodec::H264,
-                bit_depth: 8,
-                fps: 29.97,
-                hardware_id: None,
-                multicore_joint_mode: false,
-            },
+            info.decoder_config(),
             decoder_input_queue, // Pass the queue as the input iterator
         )
         .expect("Failed to create decoder");
@@ -144,7 +688,7 @@ mod test {
         let mut encoded = vec![];
 
         for frame in frames {
-            producer_queue.push(frame);
+            producer_queue.push(frame).expect("Failed to push frame");
             dbg!("pushed frame");
             if let Some(decoded_frame) = decoder
                 .try_read_decoded_frame()
@@ -169,7 +713,112 @@ mod test {
             .unwrap()
             .write_all(&encoded)
             .unwrap();
+        assert!(encoded_frames > 0, "no frames were encoded");
         drop(producer_queue);
         dbg!("dropped producer");
     }
+
+    #[test]
+    fn test_dropped_producer_ends_iteration() {
+        let (queue, mut producer) = DecoderInputQueue::<()>::new(4);
+        producer.push(Ok(test_frame(0))).unwrap();
+        producer.push(Ok(test_frame(1))).unwrap();
+        drop(producer);
+
+        let pts: Vec<_> = queue.map(|frame| frame.unwrap().pts).collect();
+        assert_eq!(pts, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_blocking_wait_strategy() {
+        let (queue, mut producer) = DecoderInputQueue::<()>::new(DecoderInputQueueConfig {
+            producer_wait: WaitStrategy::Block,
+            consumer_wait: WaitStrategy::Block,
+            ..2.into()
+        });
+        let consumer =
+            std::thread::spawn(move || queue.map(|frame| frame.unwrap().pts).sum::<i64>());
+        for n in 0..1000 {
+            producer.push(Ok(test_frame(n))).unwrap();
+        }
+        drop(producer);
+        assert_eq!(consumer.join().unwrap(), (0..1000).sum::<i64>());
+    }
+
+    #[test]
+    fn test_non_blocking_and_timed_operations() {
+        let (mut queue, mut producer) = DecoderInputQueue::<()>::new(1);
+        assert_eq!(queue.try_pop().err(), Some(PopError::Empty));
+        assert_eq!(
+            queue.pop_timeout(Duration::from_millis(10)).err(),
+            Some(PopError::TimedOut)
+        );
+
+        producer.try_push(Ok(test_frame(0))).unwrap();
+        match producer.try_push(Ok(test_frame(1))) {
+            Err(PushError::Full(frame)) => assert_eq!(frame.unwrap().pts, 1),
+            _ => panic!("expected the queue to be full"),
+        }
+        assert!(matches!(
+            producer.push_timeout(Ok(test_frame(1)), Duration::from_millis(10)),
+            Err(PushError::TimedOut(_))
+        ));
+
+        drop(producer);
+        assert_eq!(queue.try_pop().unwrap().unwrap().pts, 0);
+        assert_eq!(queue.try_pop().err(), Some(PopError::Closed));
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let (mut queue, mut producer) = DecoderInputQueue::<()>::new(DecoderInputQueueConfig {
+            max_bytes: Some(12),
+            ..4.into()
+        });
+        producer.try_push(Ok(test_frame(0))).unwrap();
+        producer.try_push(Ok(test_frame(1))).unwrap();
+        assert!(matches!(
+            producer.try_push(Ok(test_frame(2))),
+            Err(PushError::Full(_))
+        ));
+        queue.try_pop().unwrap().unwrap();
+        queue.try_pop().unwrap().unwrap();
+
+        // A frame over the whole budget still goes into an empty queue.
+        let large_frame = XcoderDecoderInputFrame {
+            data: vec![0; 100],
+            pts: 3,
+            dts: 3,
+        };
+        producer.try_push(Ok(large_frame)).unwrap();
+        assert!(matches!(
+            producer.try_push(Ok(test_frame(4))),
+            Err(PushError::Full(_))
+        ));
+    }
+
+    #[test]
+    fn test_push_fails_when_consumer_dropped() {
+        let (queue, mut producer) = DecoderInputQueue::<()>::new(1);
+        producer.push(Ok(test_frame(0))).unwrap();
+        drop(queue);
+
+        assert!(producer.is_closed());
+        match producer.push(Ok(test_frame(1))) {
+            Err(PushError::Closed(frame)) => assert_eq!(frame.unwrap().pts, 1),
+            _ => panic!("expected the push to fail"),
+        }
+
+        // It fails even with room left in the queue.
+        let (queue, mut producer) = DecoderInputQueue::<()>::new(4);
+        drop(queue);
+        assert!(matches!(
+            producer.push(Ok(test_frame(0))),
+            Err(PushError::Closed(_))
+        ));
+        assert!(matches!(
+            producer.try_push(Ok(test_frame(1))),
+            Err(PushError::Closed(_))
+        ));
+    }
 }
@@ -0,0 +1,270 @@
+use std::mem;
+
+use crate::bits::to_rbsp;
+use crate::framer::Codec;
+use crate::hevc::{self, HevcParameterSets, HevcPps, HevcSps};
+use crate::params::Sps;
+use crate::probe::StreamChange;
+use crate::sei::{self, PicTimingSyntax, SeiMessage};
+use crate::slice::SliceType;
+
+/// A NAL unit of a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NalUnitMetadata {
+    pub nal_unit_type: u8,
+    /// The offset of the NAL unit header in the frame data, past the start code.
+    pub offset: usize,
+    /// The length of the NAL unit, without the start code.
+    pub len: usize,
+}
+
+/// What a frame holds, so that it doesn't need to be parsed again downstream.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameMetadata {
+    pub codec: Codec,
+    /// The size of the frame data in bytes.
+    pub size: usize,
+    /// Whether decoding can start at this frame: it's an IDR picture, another H.265 random access
+    /// point, or a recovery point whose slices are all intra.
+    pub keyframe: bool,
+    pub idr: bool,
+    /// Whether the frame carries a recovery point SEI message.
+    pub recovery_point: bool,
+    /// Whether other pictures may refer to this one. For H.265, that's any picture that isn't a
+    /// sub-layer non-reference picture.
+    pub reference: bool,
+    /// The types of the slices, in order. H.265 dependent slice segments and slices whose headers
+    /// couldn't be parsed are left out.
+    pub slice_types: Vec<SliceType>,
+    pub nal_units: Vec<NalUnitMetadata>,
+    /// Set on the first frame after an SPS that changes the stream materially. Only
+    /// [`AnnexBFramer`](crate::AnnexBFramer) tracks the stream across frames to set it.
+    pub stream_change: Option<StreamChange>,
+    /// The SEI messages of the frame, in order. Only the payload types of [`SeiMessage`] are
+    /// kept, and picture timing messages need an SPS to have been seen first.
+    pub sei: Vec<SeiMessage>,
+}
+
+/// Describes H.264 or H.265 Annex B frames with [`FrameMetadata`]. [`AnnexBFramer`], the demuxers
+/// and [`LengthPrefixedConverter`] use one for the frames they emit; on its own, it describes
+/// frames from elsewhere.
+///
+/// Parsing H.265 slice types takes the parameter sets, so frames should be passed in decoding
+/// order, starting with the ones that carry the parameter sets.
+///
+/// [`AnnexBFramer`]: crate::AnnexBFramer
+/// [`LengthPrefixedConverter`]: crate::LengthPrefixedConverter
+#[derive(Clone, Debug)]
+pub struct FrameInspector {
+    codec: Codec,
+    parameter_sets: HevcParameterSets,
+    current: FrameMetadata,
+    /// Whether the frame in progress is an H.265 random access point.
+    random_access: bool,
+    /// How to parse picture timing messages, from the latest SPS.
+    pic_timing: Option<PicTimingSyntax>,
+}
+
+impl FrameInspector {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            parameter_sets: HevcParameterSets::default(),
+            current: FrameMetadata {
+                codec,
+                ..FrameMetadata::default()
+            },
+            random_access: false,
+            pic_timing: None,
+        }
+    }
+
+    /// Describes a whole frame.
+    pub fn inspect(&mut self, frame: &[u8]) -> FrameMetadata {
+        for nalu in h264::iterate_annex_b(frame) {
+            let offset = nalu.as_ptr() as usize - frame.as_ptr() as usize;
+            self.add_nalu(nalu, offset);
+        }
+        self.finish_frame(frame.len())
+    }
+
+    /// Adds a NAL unit of the frame in progress, found at `offset` in its data.
+    pub(crate) fn add_nalu(&mut self, nalu: &[u8], offset: usize) {
+        let Some(&header) = nalu.first() else {
+            return;
+        };
+        let nal_unit_type = match self.codec {
+            Codec::H264 => header & 0x1f,
+            Codec::H265 => hevc::nal_unit_type(header),
+        };
+        self.current.nal_units.push(NalUnitMetadata {
+            nal_unit_type,
+            offset,
+            len: nalu.len(),
+        });
+        match self.codec {
+            Codec::H264 => self.add_h264_nalu(nalu, nal_unit_type),
+            Codec::H265 => self.add_hevc_nalu(nalu, nal_unit_type),
+        }
+    }
+
+    fn add_h264_nalu(&mut self, nalu: &[u8], nal_unit_type: u8) {
+        let current = &mut self.current;
+        match nal_unit_type {
+            1..=5 => {
+                current.reference |= nalu[0] & 0x60 != 0;
+                current.idr |= nal_unit_type == 5;
+                if let Ok(slice_type) = SliceType::parse(nalu) {
+                    current.slice_types.push(slice_type);
+                }
+            }
+            6 => self.add_sei(&nalu[1..]),
+            7 => {
+                if let Ok(sps) = Sps::parse(nalu) {
+                    self.pic_timing = Some(PicTimingSyntax::from(&sps));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add_hevc_nalu(&mut self, nalu: &[u8], nal_unit_type: u8) {
+        match nal_unit_type {
+            0..=21 => {
+                let current = &mut self.current;
+                current.reference |= nal_unit_type > 14 || !nal_unit_type.is_multiple_of(2);
+                current.idr |= matches!(nal_unit_type, 19 | 20);
+                self.random_access |= hevc::is_irap(nal_unit_type);
+                if let Ok(Some(slice_type)) = hevc::slice_segment_type(nalu, &self.parameter_sets) {
+                    current.slice_types.push(slice_type);
+                }
+            }
+            hevc::SPS => {
+                if let Ok(sps) = HevcSps::parse(nalu) {
+                    self.pic_timing = Some(PicTimingSyntax::from(&sps));
+                    self.parameter_sets.insert_sps(sps);
+                }
+            }
+            hevc::PPS => {
+                if let Ok(pps) = HevcPps::parse(nalu) {
+                    self.parameter_sets.insert_pps(pps);
+                }
+            }
+            hevc::PREFIX_SEI | hevc::SUFFIX_SEI if nalu.len() > 2 => self.add_sei(&nalu[2..]),
+            _ => {}
+        }
+    }
+
+    /// Adds the messages of an SEI NAL unit, after its header.
+    fn add_sei(&mut self, payload: &[u8]) {
+        let messages = sei::parse(self.codec, &to_rbsp(payload), self.pic_timing.as_ref());
+        self.current.recovery_point |= messages
+            .iter()
+            .any(|message| matches!(message, SeiMessage::RecoveryPoint(_)));
+        self.current.sei.extend(messages);
+    }
+
+    /// Ends the frame in progress, `size` bytes long, and returns its description.
+    pub(crate) fn finish_frame(&mut self, size: usize) -> FrameMetadata {
+        let mut metadata = mem::replace(
+            &mut self.current,
+            FrameMetadata {
+                codec: self.codec,
+                ..FrameMetadata::default()
+            },
+        );
+        let intra = !metadata.slice_types.is_empty()
+            && metadata
+                .slice_types
+                .iter()
+                .all(|slice_type| matches!(slice_type, SliceType::I | SliceType::Si));
+        let random_access = mem::take(&mut self.random_access);
+        metadata.size = size;
+        metadata.keyframe = metadata.idr || random_access || metadata.recovery_point && intra;
+        metadata
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::avcc::annex_b;
+    use crate::{read_frames_with_metadata, FramerConfig};
+
+    #[test]
+    fn test_h264() {
+        let stream = annex_b(&[
+            &[0x67, 0x42, 0xc0, 0x1e],
+            &[0x68, 0xce, 0x3c, 0x80],
+            &[0x65, 0x88, 0x84],
+            &[0x41, 0x9a, 0x02],
+            // A recovery point on a non-IDR I slice, then a non-reference B slice.
+            &[0x06, 0x06, 0x01, 0x84, 0x80],
+            &[0x21, 0x88, 0x84],
+            &[0x01, 0x9c, 0x40],
+        ]);
+        let metadata: Vec<_> = read_frames_with_metadata(&stream, FramerConfig::default())
+            .into_iter()
+            .map(|result| result.unwrap().1)
+            .collect();
+
+        assert_eq!(
+            metadata[0],
+            FrameMetadata {
+                codec: Codec::H264,
+                size: 23,
+                keyframe: true,
+                idr: true,
+                recovery_point: false,
+                reference: true,
+                slice_types: vec![SliceType::I],
+                nal_units: vec![
+                    NalUnitMetadata {
+                        nal_unit_type: 7,
+                        offset: 4,
+                        len: 4,
+                    },
+                    NalUnitMetadata {
+                        nal_unit_type: 8,
+                        offset: 12,
+                        len: 4,
+                    },
+                    NalUnitMetadata {
+                        nal_unit_type: 5,
+                        offset: 20,
+                        len: 3,
+                    },
+                ],
+                stream_change: None,
+                sei: vec![],
+            }
+        );
+        let flags: Vec<_> = metadata
+            .iter()
+            .map(|m| (m.keyframe, m.idr, m.recovery_point, m.reference))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                (true, true, false, true),
+                (false, false, false, true),
+                (true, false, true, true),
+                (false, false, false, false),
+            ]
+        );
+        let slice_types: Vec<_> = metadata.iter().map(|m| m.slice_types.clone()).collect();
+        assert_eq!(
+            slice_types,
+            [
+                vec![SliceType::I],
+                vec![SliceType::P],
+                vec![SliceType::I],
+                vec![SliceType::B],
+            ]
+        );
+
+        // Inspecting a whole frame gives the same description.
+        let mut inspector = FrameInspector::new(Codec::H264);
+        assert_eq!(inspector.inspect(&stream[..23]), metadata[0]);
+    }
+}
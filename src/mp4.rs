@@ -0,0 +1,996 @@
+use std::error::Error;
+use std::fmt;
+use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+use crate::avcc::{ConversionError, DecoderConfigurationRecord, LengthPrefixedConverter};
+use crate::framer::Codec;
+use crate::metadata::FrameMetadata;
+use crate::params::Rational;
+
+/// An error demuxing an MP4 file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mp4Error {
+    /// A box is truncated, or its fields are inconsistent.
+    InvalidBox { box_type: [u8; 4] },
+    /// A box the file can't do without is missing.
+    MissingBox { box_type: [u8; 4] },
+    /// The file has no video track.
+    NoVideoTrack,
+    /// The video track uses a codec other than H.264 or H.265.
+    UnsupportedCodec { fourcc: [u8; 4] },
+    /// A sample lies outside the file. Samples are indexed in decoding order from zero.
+    SampleOutOfBounds { index: usize },
+    /// A sample or the decoder configuration record couldn't be converted to Annex B.
+    Conversion(ConversionError),
+}
+
+impl fmt::Display for Mp4Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidBox { box_type } => {
+                write!(f, "invalid {} box", String::from_utf8_lossy(box_type))
+            }
+            Self::MissingBox { box_type } => {
+                write!(f, "missing {} box", String::from_utf8_lossy(box_type))
+            }
+            Self::NoVideoTrack => write!(f, "no video track"),
+            Self::UnsupportedCodec { fourcc } => {
+                write!(f, "unsupported codec {}", String::from_utf8_lossy(fourcc))
+            }
+            Self::SampleOutOfBounds { index } => {
+                write!(f, "sample {index} lies outside the file")
+            }
+            Self::Conversion(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for Mp4Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Conversion(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConversionError> for Mp4Error {
+    fn from(err: ConversionError) -> Self {
+        Self::Conversion(err)
+    }
+}
+
+/// A box, with its offsets in the file.
+struct Mp4Box<'a> {
+    box_type: [u8; 4],
+    offset: u64,
+    payload_offset: u64,
+    payload: &'a [u8],
+}
+
+impl<'a> Mp4Box<'a> {
+    /// Splits `data`, which starts at `offset` in the file, into boxes.
+    fn parse_all(data: &'a [u8], offset: u64) -> Result<Vec<Self>, Mp4Error> {
+        let mut boxes = Vec::new();
+        let mut start = 0;
+        while data.len() - start >= 8 {
+            let box_type: [u8; 4] = data[start + 4..start + 8].try_into().unwrap();
+            let mut reader = BoxReader {
+                data: &data[start..],
+                box_type,
+            };
+            let (size, header_len) = match reader.u32()? {
+                0 => (data.len() - start, 8),
+                1 => {
+                    reader.skip(4)?;
+                    let size = usize::try_from(reader.u64()?)
+                        .map_err(|_| Mp4Error::InvalidBox { box_type })?;
+                    (size, 16)
+                }
+                size => (size as usize, 8),
+            };
+            if size < header_len || size > data.len() - start {
+                return Err(Mp4Error::InvalidBox { box_type });
+            }
+            boxes.push(Self {
+                box_type,
+                offset: offset + start as u64,
+                payload_offset: offset + (start + header_len) as u64,
+                payload: &data[start + header_len..start + size],
+            });
+            start += size;
+        }
+        Ok(boxes)
+    }
+
+    /// Returns the boxes this box contains.
+    fn children(&self) -> Result<Vec<Mp4Box<'a>>, Mp4Error> {
+        Self::parse_all(self.payload, self.payload_offset)
+    }
+
+    fn reader(&self) -> BoxReader<'a> {
+        BoxReader {
+            data: self.payload,
+            box_type: self.box_type,
+        }
+    }
+}
+
+/// Returns the first box of the given type.
+fn find<'b, 'a>(boxes: &'b [Mp4Box<'a>], box_type: &[u8; 4]) -> Option<&'b Mp4Box<'a>> {
+    boxes.iter().find(|b| b.box_type == *box_type)
+}
+
+fn require<'b, 'a>(
+    boxes: &'b [Mp4Box<'a>],
+    box_type: &[u8; 4],
+) -> Result<&'b Mp4Box<'a>, Mp4Error> {
+    find(boxes, box_type).ok_or(Mp4Error::MissingBox {
+        box_type: *box_type,
+    })
+}
+
+/// Reads big-endian fields from a box, reporting the box when they run out.
+struct BoxReader<'a> {
+    data: &'a [u8],
+    box_type: [u8; 4],
+}
+
+impl<'a> BoxReader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Mp4Error> {
+        if self.data.len() < n {
+            return Err(self.invalid());
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Mp4Error> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u32(&mut self) -> Result<u32, Mp4Error> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Mp4Error> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads an entry count, checking that the entries, `entry_len` bytes each, fit in the rest
+    /// of the box.
+    fn count(&mut self, entry_len: usize) -> Result<usize, Mp4Error> {
+        let count = self.u32()? as usize;
+        if count.saturating_mul(entry_len) > self.data.len() {
+            return Err(self.invalid());
+        }
+        Ok(count)
+    }
+
+    fn invalid(&self) -> Mp4Error {
+        Mp4Error::InvalidBox {
+            box_type: self.box_type,
+        }
+    }
+
+    /// Reads a 32-bit field for version 0 of a box, or a 64-bit one for version 1.
+    fn versioned(&mut self, version: u8) -> Result<u64, Mp4Error> {
+        if version == 1 {
+            self.u64()
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+
+    /// Reads the version and flags of a full box.
+    fn full_box(&mut self) -> Result<(u8, u32), Mp4Error> {
+        let header = self.u32()?;
+        Ok(((header >> 24) as u8, header & 0xff_ffff))
+    }
+}
+
+/// Checks that `count` samples of `size` bytes each fit in a file of `file_len` bytes, so that a
+/// crafted table can't make the demuxer allocate without bound. Samples are taken to be at least a
+/// byte long.
+fn check_sample_count(
+    count: usize,
+    size: u32,
+    file_len: usize,
+    box_type: [u8; 4],
+) -> Result<(), Mp4Error> {
+    if count.saturating_mul(size.max(1) as usize) > file_len {
+        return Err(Mp4Error::InvalidBox { box_type });
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    offset: u64,
+    size: u32,
+    dts: i64,
+    /// The composition time offset, from the decoding time to the presentation time.
+    cts_offset: i64,
+}
+
+/// The defaults for the samples of a track's fragments, from its `trex` box.
+#[derive(Clone, Copy, Default)]
+struct TrackDefaults {
+    duration: u32,
+    size: u32,
+}
+
+/// The video track picked out of the `moov` box.
+struct Track {
+    track_id: u32,
+    timescale: u32,
+    record: DecoderConfigurationRecord,
+    samples: Vec<Sample>,
+    /// The offset the edit list adds to the timestamps of the samples.
+    edit_offset: i64,
+}
+
+impl Track {
+    /// Parses a `trak` box of a file `file_len` bytes long, returning `None` if it isn't a video
+    /// track.
+    fn parse(
+        trak: &Mp4Box,
+        movie_timescale: u32,
+        file_len: usize,
+    ) -> Result<Option<Self>, Mp4Error> {
+        let children = trak.children()?;
+        let mdia = require(&children, b"mdia")?.children()?;
+        let mut hdlr = require(&mdia, b"hdlr")?.reader();
+        // The version and flags, and pre_defined
+        hdlr.skip(8)?;
+        if hdlr.bytes(4)? != b"vide" {
+            return Ok(None);
+        }
+
+        let mut tkhd = require(&children, b"tkhd")?.reader();
+        let (version, _) = tkhd.full_box()?;
+        // creation_time and modification_time
+        tkhd.versioned(version)?;
+        tkhd.versioned(version)?;
+        let track_id = tkhd.u32()?;
+
+        let mut mdhd = require(&mdia, b"mdhd")?.reader();
+        let (version, _) = mdhd.full_box()?;
+        mdhd.versioned(version)?;
+        mdhd.versioned(version)?;
+        let timescale = mdhd.u32()?;
+        if timescale == 0 {
+            return Err(Mp4Error::InvalidBox { box_type: *b"mdhd" });
+        }
+
+        let minf = require(&mdia, b"minf")?.children()?;
+        let stbl = require(&minf, b"stbl")?.children()?;
+        let record = Self::parse_sample_description(require(&stbl, b"stsd")?)?;
+        let samples = Self::parse_sample_table(&stbl, file_len)?;
+
+        let edit_offset = match find(&children, b"edts") {
+            Some(edts) => match find(&edts.children()?, b"elst") {
+                Some(elst) => Self::parse_edit_list(elst, timescale, movie_timescale)?,
+                None => 0,
+            },
+            None => 0,
+        };
+
+        Ok(Some(Self {
+            track_id,
+            timescale,
+            record,
+            samples,
+            edit_offset,
+        }))
+    }
+
+    fn parse_sample_description(stsd: &Mp4Box) -> Result<DecoderConfigurationRecord, Mp4Error> {
+        let mut reader = stsd.reader();
+        // The version and flags, and entry_count
+        reader.skip(8)?;
+        let entries = Mp4Box::parse_all(reader.data, stsd.payload_offset + 8)?;
+        let entry = entries
+            .first()
+            .ok_or(Mp4Error::InvalidBox { box_type: *b"stsd" })?;
+        let (codec, config_type) = match &entry.box_type {
+            b"avc1" | b"avc3" => (Codec::H264, b"avcC"),
+            b"hvc1" | b"hev1" => (Codec::H265, b"hvcC"),
+            fourcc => return Err(Mp4Error::UnsupportedCodec { fourcc: *fourcc }),
+        };
+        // The fields of a visual sample entry come before the boxes it contains.
+        let fields = 78;
+        let boxes = entry.payload.get(fields..).ok_or(Mp4Error::InvalidBox {
+            box_type: entry.box_type,
+        })?;
+        let boxes = Mp4Box::parse_all(boxes, entry.payload_offset + fields as u64)?;
+        let config = require(&boxes, config_type)?;
+        Ok(match codec {
+            Codec::H264 => DecoderConfigurationRecord::parse_avcc(config.payload)?,
+            Codec::H265 => DecoderConfigurationRecord::parse_hvcc(config.payload)?,
+        })
+    }
+
+    /// Builds the samples of a non-fragmented track from its sample table.
+    fn parse_sample_table(stbl: &[Mp4Box], file_len: usize) -> Result<Vec<Sample>, Mp4Error> {
+        let mut stsz = require(stbl, b"stsz")?.reader();
+        stsz.full_box()?;
+        let sample_size = stsz.u32()?;
+        let sizes: Vec<u32> = if sample_size == 0 {
+            (0..stsz.count(4)?)
+                .map(|_| stsz.u32())
+                .collect::<Result<_, _>>()?
+        } else {
+            Vec::new()
+        };
+        let sample_count = if sample_size == 0 {
+            sizes.len()
+        } else {
+            let sample_count = stsz.u32()? as usize;
+            check_sample_count(sample_count, sample_size, file_len, *b"stsz")?;
+            sample_count
+        };
+        let size_of = |index: usize| sizes.get(index).copied().unwrap_or(sample_size);
+
+        let chunk_offsets: Vec<u64> = match (find(stbl, b"stco"), find(stbl, b"co64")) {
+            (Some(stco), _) => {
+                let mut reader = stco.reader();
+                reader.full_box()?;
+                (0..reader.count(4)?)
+                    .map(|_| reader.u32().map(u64::from))
+                    .collect::<Result<_, _>>()?
+            }
+            (None, Some(co64)) => {
+                let mut reader = co64.reader();
+                reader.full_box()?;
+                (0..reader.count(8)?)
+                    .map(|_| reader.u64())
+                    .collect::<Result<_, _>>()?
+            }
+            (None, None) => return Err(Mp4Error::MissingBox { box_type: *b"stco" }),
+        };
+
+        let mut stsc = require(stbl, b"stsc")?.reader();
+        stsc.full_box()?;
+        let mut chunk_runs = Vec::new();
+        for _ in 0..stsc.count(12)? {
+            let first_chunk = stsc.u32()?;
+            let samples_per_chunk = stsc.u32()?;
+            // sample_description_index
+            stsc.skip(4)?;
+            chunk_runs.push((first_chunk, samples_per_chunk));
+        }
+
+        let mut samples = Vec::with_capacity(sizes.len());
+        'chunks: for (i, &chunk_offset) in chunk_offsets.iter().enumerate() {
+            let chunk = i as u32 + 1;
+            let samples_per_chunk = chunk_runs
+                .iter()
+                .take_while(|(first_chunk, _)| *first_chunk <= chunk)
+                .last()
+                .map_or(0, |(_, samples_per_chunk)| *samples_per_chunk);
+            let mut offset = chunk_offset;
+            for _ in 0..samples_per_chunk {
+                if samples.len() == sample_count {
+                    break 'chunks;
+                }
+                let size = size_of(samples.len());
+                samples.push(Sample {
+                    offset,
+                    size,
+                    dts: 0,
+                    cts_offset: 0,
+                });
+                offset = offset
+                    .checked_add(size.into())
+                    .ok_or(Mp4Error::InvalidBox { box_type: *b"stsz" })?;
+            }
+        }
+
+        let mut stts = require(stbl, b"stts")?.reader();
+        stts.full_box()?;
+        let mut dts = 0;
+        let mut remaining = samples.iter_mut();
+        for _ in 0..stts.count(8)? {
+            let count = stts.u32()?;
+            let delta = stts.u32()? as i64;
+            for sample in remaining.by_ref().take(count as usize) {
+                sample.dts = dts;
+                dts = dts
+                    .checked_add(delta)
+                    .ok_or(Mp4Error::InvalidBox { box_type: *b"stts" })?;
+            }
+        }
+
+        if let Some(ctts) = find(stbl, b"ctts") {
+            let mut reader = ctts.reader();
+            reader.full_box()?;
+            let mut remaining = samples.iter_mut();
+            for _ in 0..reader.count(8)? {
+                let count = reader.u32()?;
+                // Offsets are signed in version 1, and in practice in version 0 too.
+                let offset = reader.u32()? as i32 as i64;
+                for sample in remaining.by_ref().take(count as usize) {
+                    sample.cts_offset = offset;
+                }
+            }
+        }
+        Ok(samples)
+    }
+
+    /// Returns the offset the edit list adds to the timestamps: the initial empty edits delay
+    /// presentation, and the first media edit says which media time is presented first.
+    fn parse_edit_list(
+        elst: &Mp4Box,
+        timescale: u32,
+        movie_timescale: u32,
+    ) -> Result<i64, Mp4Error> {
+        let invalid = || Mp4Error::InvalidBox { box_type: *b"elst" };
+        let mut reader = elst.reader();
+        let (version, _) = reader.full_box()?;
+        let mut delay = 0i64;
+        for _ in 0..reader.u32()? {
+            let segment_duration = reader.versioned(version)?;
+            let media_time = match version {
+                1 => reader.u64()? as i64,
+                _ => reader.u32()? as i32 as i64,
+            };
+            // media_rate_integer and media_rate_fraction
+            reader.skip(4)?;
+            if media_time != -1 {
+                return delay.checked_sub(media_time).ok_or_else(invalid);
+            }
+            if movie_timescale != 0 {
+                let duration =
+                    segment_duration as i128 * timescale as i128 / movie_timescale as i128;
+                delay = i64::try_from(duration)
+                    .ok()
+                    .and_then(|duration| delay.checked_add(duration))
+                    .ok_or_else(invalid)?;
+            }
+        }
+        Ok(delay)
+    }
+
+    /// Appends the samples of the track's fragments in a `moof` box, of a file `file_len` bytes
+    /// long.
+    fn add_fragment(
+        &mut self,
+        moof: &Mp4Box,
+        defaults: TrackDefaults,
+        file_len: usize,
+    ) -> Result<(), Mp4Error> {
+        for traf in moof.children()?.iter().filter(|b| &b.box_type == b"traf") {
+            let children = traf.children()?;
+            let mut tfhd = require(&children, b"tfhd")?.reader();
+            let (_, flags) = tfhd.full_box()?;
+            if tfhd.u32()? != self.track_id {
+                continue;
+            }
+            // Without an explicit base, data offsets are relative to the moof box.
+            let base_offset = if flags & 0x1 != 0 {
+                tfhd.u64()?
+            } else {
+                moof.offset
+            };
+            if flags & 0x2 != 0 {
+                // sample_description_index
+                tfhd.skip(4)?;
+            }
+            let default_duration = if flags & 0x8 != 0 {
+                tfhd.u32()?
+            } else {
+                defaults.duration
+            };
+            let default_size = if flags & 0x10 != 0 {
+                tfhd.u32()?
+            } else {
+                defaults.size
+            };
+
+            // Decoding times come from the tfdt box, or follow on from the previous fragment.
+            let invalid_dts = || Mp4Error::InvalidBox { box_type: *b"tfdt" };
+            let mut dts = match find(&children, b"tfdt") {
+                Some(tfdt) => {
+                    let mut reader = tfdt.reader();
+                    let (version, _) = reader.full_box()?;
+                    i64::try_from(reader.versioned(version)?).map_err(|_| invalid_dts())?
+                }
+                None => match self.samples.last() {
+                    Some(sample) => sample
+                        .dts
+                        .checked_add(default_duration.into())
+                        .ok_or_else(invalid_dts)?,
+                    None => 0,
+                },
+            };
+            let mut offset = base_offset;
+            for trun in children.iter().filter(|b| &b.box_type == b"trun") {
+                let mut reader = trun.reader();
+                let (_, flags) = reader.full_box()?;
+                // Each sample has a field for each of the flags from 0x100 to 0x800 that's set.
+                let entry_len = 4 * (flags >> 8 & 0xf).count_ones() as usize;
+                let count = if entry_len > 0 {
+                    reader.count(entry_len)?
+                } else {
+                    let count = reader.u32()? as usize;
+                    check_sample_count(count, default_size, file_len, *b"trun")?;
+                    count
+                };
+                if flags & 0x1 != 0 {
+                    offset = base_offset.wrapping_add_signed(reader.u32()? as i32 as i64);
+                }
+                if flags & 0x4 != 0 {
+                    // first_sample_flags
+                    reader.skip(4)?;
+                }
+                for _ in 0..count {
+                    let duration = if flags & 0x100 != 0 {
+                        reader.u32()?
+                    } else {
+                        default_duration
+                    };
+                    let size = if flags & 0x200 != 0 {
+                        reader.u32()?
+                    } else {
+                        default_size
+                    };
+                    if flags & 0x400 != 0 {
+                        // sample_flags
+                        reader.skip(4)?;
+                    }
+                    let cts_offset = if flags & 0x800 != 0 {
+                        reader.u32()? as i32 as i64
+                    } else {
+                        0
+                    };
+                    self.samples.push(Sample {
+                        offset,
+                        size,
+                        dts,
+                        cts_offset,
+                    });
+                    offset = offset.checked_add(size.into()).ok_or(reader.invalid())?;
+                    dts = dts.checked_add(duration.into()).ok_or_else(invalid_dts)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the video track of an MP4 file, fragmented or not, as Annex B access units.
+///
+/// The whole file is expected in memory. The first video track is picked, and its samples are
+/// read in decoding order, from the sample table of the `moov` box followed by the fragments of
+/// the `moof` boxes. Timestamps are in the timescale of the track, see
+/// [`timebase`](Self::timebase), and are shifted by its edit list, so that the first presented
+/// sample of the edit is presented at the time the edit starts.
+pub struct Mp4Demuxer<'a> {
+    data: &'a [u8],
+    track_id: u32,
+    timescale: u32,
+    converter: LengthPrefixedConverter,
+    samples: Vec<Sample>,
+    next: usize,
+}
+
+impl<'a> Mp4Demuxer<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Mp4Error> {
+        let boxes = Mp4Box::parse_all(data, 0)?;
+        let moov = require(&boxes, b"moov")?.children()?;
+
+        let mut mvhd = require(&moov, b"mvhd")?.reader();
+        let (version, _) = mvhd.full_box()?;
+        mvhd.versioned(version)?;
+        mvhd.versioned(version)?;
+        let movie_timescale = mvhd.u32()?;
+
+        let mut track = None;
+        for trak in moov.iter().filter(|b| &b.box_type == b"trak") {
+            if let Some(video) = Track::parse(trak, movie_timescale, data.len())? {
+                track = Some(video);
+                break;
+            }
+        }
+        let mut track = track.ok_or(Mp4Error::NoVideoTrack)?;
+
+        let mut defaults = TrackDefaults::default();
+        if let Some(mvex) = find(&moov, b"mvex") {
+            for trex in mvex.children()?.iter().filter(|b| &b.box_type == b"trex") {
+                let mut reader = trex.reader();
+                reader.full_box()?;
+                if reader.u32()? == track.track_id {
+                    // default_sample_description_index
+                    reader.skip(4)?;
+                    defaults.duration = reader.u32()?;
+                    defaults.size = reader.u32()?;
+                }
+            }
+        }
+        for moof in boxes.iter().filter(|b| &b.box_type == b"moof") {
+            track.add_fragment(moof, defaults, data.len())?;
+        }
+
+        let edit_offset = track.edit_offset;
+        for sample in &mut track.samples {
+            sample.dts = sample
+                .dts
+                .checked_add(edit_offset)
+                .ok_or(Mp4Error::InvalidBox { box_type: *b"elst" })?;
+        }
+        Ok(Self {
+            data,
+            track_id: track.track_id,
+            timescale: track.timescale,
+            converter: LengthPrefixedConverter::new(track.record),
+            samples: track.samples,
+            next: 0,
+        })
+    }
+
+    pub fn track_id(&self) -> u32 {
+        self.track_id
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.converter.record().codec
+    }
+
+    pub fn record(&self) -> &DecoderConfigurationRecord {
+        self.converter.record()
+    }
+
+    /// The timebase of the timestamps, in seconds per tick.
+    pub fn timebase(&self) -> Rational {
+        Rational::new(1, self.timescale)
+    }
+
+    /// The number of samples in the track.
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Like [`next`](Iterator::next), but also describes the frame.
+    pub fn next_with_metadata(
+        &mut self,
+    ) -> Option<Result<(XcoderDecoderInputFrame, FrameMetadata), Mp4Error>> {
+        let index = self.next;
+        let sample = *self.samples.get(index)?;
+        self.next += 1;
+        let data = usize::try_from(sample.offset).ok().and_then(|start| {
+            self.data
+                .get(start..start.checked_add(sample.size as usize)?)
+        });
+        let Some(data) = data else {
+            return Some(Err(Mp4Error::SampleOutOfBounds { index }));
+        };
+        // Composition offsets are only 32 bits, so this can only saturate on nonsense timing.
+        let pts = sample.dts.saturating_add(sample.cts_offset);
+        Some(
+            self.converter
+                .convert_with_metadata(data, pts, sample.dts)
+                .map_err(Mp4Error::from),
+        )
+    }
+}
+
+impl Iterator for Mp4Demuxer<'_> {
+    type Item = Result<XcoderDecoderInputFrame, Mp4Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_metadata()
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::avcc::{annex_b, length_prefixed, PPS, SPS};
+
+    fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn full_box(box_type: &[u8; 4], version: u8, flags: u32, fields: &[u8]) -> Vec<u8> {
+        let mut payload = (u32::from(version) << 24 | flags).to_be_bytes().to_vec();
+        payload.extend_from_slice(fields);
+        mp4_box(box_type, &payload)
+    }
+
+    /// Concatenates big-endian 32-bit fields.
+    fn fields(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    /// Builds a version 0 `elst` box with an 80 ms empty edit followed by a media edit.
+    fn elst(duration: u32, media_time: i32) -> Vec<u8> {
+        let entries = fields(&[
+            2,
+            80,
+            u32::MAX,
+            1 << 16,
+            duration,
+            media_time as u32,
+            1 << 16,
+        ]);
+        full_box(b"elst", 0, 0, &entries)
+    }
+
+    /// Builds the `trak` box of an H.264 track with the given edit list and sample table boxes.
+    fn trak(elst: Option<Vec<u8>>, sample_table: &[Vec<u8>]) -> Vec<u8> {
+        let mut avcc = vec![1, 0x42, 0xc0, 0x1e, 0xff, 0xe1, 0, SPS.len() as u8];
+        avcc.extend_from_slice(SPS);
+        avcc.extend_from_slice(&[1, 0, PPS.len() as u8]);
+        avcc.extend_from_slice(PPS);
+        let mut avc1 = vec![0; 78];
+        avc1.extend_from_slice(&mp4_box(b"avcC", &avcc));
+        let mut stsd = fields(&[1]);
+        stsd.extend_from_slice(&mp4_box(b"avc1", &avc1));
+        let stbl = [vec![full_box(b"stsd", 0, 0, &stsd)], sample_table.to_vec()].concat();
+
+        let mut hdlr = fields(&[0]);
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0; 13]);
+        let mdia = [
+            full_box(b"mdhd", 0, 0, &fields(&[0, 0, 12800, 0, 0])),
+            full_box(b"hdlr", 0, 0, &hdlr),
+            mp4_box(b"minf", &mp4_box(b"stbl", &stbl.concat())),
+        ];
+        let mut trak = vec![full_box(b"tkhd", 0, 3, &fields(&[0, 0, 1, 0, 0]))];
+        if let Some(elst) = elst {
+            trak.push(mp4_box(b"edts", &elst));
+        }
+        trak.push(mp4_box(b"mdia", &mdia.concat()));
+        mp4_box(b"trak", &trak.concat())
+    }
+
+    fn mvhd() -> Vec<u8> {
+        full_box(b"mvhd", 0, 0, &fields(&[0, 0, 1000, 0]))
+    }
+
+    /// Builds the `moov` box of a fragmented file, whose samples default to 512 ticks.
+    fn fragmented_moov() -> Vec<u8> {
+        let empty_table = [
+            full_box(b"stts", 0, 0, &fields(&[0])),
+            full_box(b"stsc", 0, 0, &fields(&[0])),
+            full_box(b"stsz", 0, 0, &fields(&[0, 0])),
+            full_box(b"stco", 0, 0, &fields(&[0])),
+        ];
+        let trex = full_box(b"trex", 0, 0, &fields(&[1, 1, 512, 0, 0]));
+        mp4_box(
+            b"moov",
+            &[mvhd(), trak(None, &empty_table), mp4_box(b"mvex", &trex)].concat(),
+        )
+    }
+
+    fn tfdt(version: u8, decode_time: u64) -> Vec<u8> {
+        let decode_time = match version {
+            1 => decode_time.to_be_bytes().to_vec(),
+            _ => fields(&[decode_time as u32]),
+        };
+        full_box(b"tfdt", version, 0, &decode_time)
+    }
+
+    /// Builds a fragment holding one sample, with its size in the trun box and the default
+    /// duration.
+    fn fragment(sequence: u32, tfdt: Vec<u8>, sample: &[u8]) -> Vec<u8> {
+        let sample = length_prefixed(&[sample], 4);
+        let moof = |data_offset: u32| {
+            let traf = [
+                full_box(b"tfhd", 0, 0x20000, &fields(&[1])),
+                tfdt.clone(),
+                full_box(
+                    b"trun",
+                    0,
+                    0x201,
+                    &fields(&[1, data_offset, sample.len() as u32]),
+                ),
+            ];
+            mp4_box(
+                b"moof",
+                &[
+                    full_box(b"mfhd", 0, 0, &fields(&[sequence])),
+                    mp4_box(b"traf", &traf.concat()),
+                ]
+                .concat(),
+            )
+        };
+        let data_offset = moof(0).len() as u32 + 8;
+        [moof(data_offset), mp4_box(b"mdat", &sample)].concat()
+    }
+
+    #[test]
+    fn test_sample_tables() {
+        let idr: &[u8] = &[0x65, 0x88, 0x84];
+        let p: &[u8] = &[0x41, 0x9a, 0x02, 0x03];
+        let b: &[u8] = &[0x01, 0x9e, 0x04];
+        let samples = [
+            length_prefixed(&[idr], 4),
+            length_prefixed(&[p], 4),
+            length_prefixed(&[b], 4),
+        ];
+        let ftyp = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        let mdat = mp4_box(b"mdat", &samples.concat());
+        let mdat_start = (ftyp.len() + 8) as u32;
+
+        // Two chunks, of two samples and then one. The B frame is presented before the P frame,
+        // and the edit list starts presentation with the IDR frame after an 80 ms delay.
+        let sample_table = [
+            full_box(b"stts", 0, 0, &fields(&[1, 3, 512])),
+            full_box(b"ctts", 0, 0, &fields(&[3, 1, 512, 1, 1024, 1, 0])),
+            full_box(b"stsc", 0, 0, &fields(&[2, 1, 2, 1, 2, 1, 1])),
+            full_box(
+                b"stsz",
+                0,
+                0,
+                &fields(&[0, 3, samples[0].len() as u32, samples[1].len() as u32, 7]),
+            ),
+            full_box(
+                b"stco",
+                0,
+                0,
+                &fields(&[
+                    2,
+                    mdat_start,
+                    mdat_start + (samples[0].len() + samples[1].len()) as u32,
+                ]),
+            ),
+        ];
+        let moov = mp4_box(
+            b"moov",
+            &[mvhd(), trak(Some(elst(3000, 512)), &sample_table)].concat(),
+        );
+        let file = [ftyp, mdat, moov].concat();
+
+        let demuxer = Mp4Demuxer::new(&file).unwrap();
+        assert_eq!(demuxer.codec(), Codec::H264);
+        assert_eq!(demuxer.timebase(), Rational::new(1, 12800));
+        assert_eq!(demuxer.sample_count(), 3);
+        let frames: Vec<_> = demuxer.map(Result::unwrap).collect();
+        assert_eq!(frames[0].data, annex_b(&[SPS, PPS, idr]));
+        assert_eq!(frames[1].data, annex_b(&[p]));
+        assert_eq!(frames[2].data, annex_b(&[b]));
+        let timestamps: Vec<_> = frames.iter().map(|frame| (frame.pts, frame.dts)).collect();
+        assert_eq!(timestamps, vec![(1024, 512), (2048, 1024), (1536, 1536)]);
+
+        let mut demuxer = Mp4Demuxer::new(&file).unwrap();
+        let keyframes: Vec<_> = std::iter::from_fn(|| demuxer.next_with_metadata())
+            .map(|result| result.unwrap().1.keyframe)
+            .collect();
+        assert_eq!(keyframes, [true, false, false]);
+    }
+
+    #[test]
+    fn test_fragments() {
+        let idr: &[u8] = &[0x65, 0x88, 0x84];
+        let p: &[u8] = &[0x41, 0x9a, 0x02, 0x03];
+        let file = [
+            mp4_box(b"ftyp", b"iso6\0\0\0\0"),
+            fragmented_moov(),
+            fragment(1, tfdt(0, 0), idr),
+            fragment(2, tfdt(0, 512), p),
+        ]
+        .concat();
+
+        let frames: Vec<_> = Mp4Demuxer::new(&file)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].data, annex_b(&[SPS, PPS, idr]));
+        assert_eq!(frames[1].data, annex_b(&[p]));
+        assert_eq!((frames[1].pts, frames[1].dts), (512, 512));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Mp4Demuxer::new(&mp4_box(b"ftyp", b"isom")).err(),
+            Some(Mp4Error::MissingBox { box_type: *b"moov" })
+        );
+        let mut truncated = mp4_box(b"moov", &mvhd());
+        truncated.truncate(truncated.len() - 1);
+        assert_eq!(
+            Mp4Demuxer::new(&truncated).err(),
+            Some(Mp4Error::InvalidBox { box_type: *b"moov" })
+        );
+        assert_eq!(
+            Mp4Demuxer::new(&mp4_box(b"moov", &mvhd())).err(),
+            Some(Mp4Error::NoVideoTrack)
+        );
+
+        // A sample past the end of the file.
+        let sample_table = [
+            full_box(b"stts", 0, 0, &fields(&[1, 1, 512])),
+            full_box(b"stsc", 0, 0, &fields(&[1, 1, 1, 1])),
+            full_box(b"stsz", 0, 0, &fields(&[16, 1])),
+            full_box(b"stco", 0, 0, &fields(&[1, 1 << 20])),
+        ];
+        let file = mp4_box(b"moov", &[mvhd(), trak(None, &sample_table)].concat());
+        let mut demuxer = Mp4Demuxer::new(&file).unwrap();
+        assert!(matches!(
+            demuxer.next(),
+            Some(Err(Mp4Error::SampleOutOfBounds { index: 0 }))
+        ));
+        assert!(demuxer.next().is_none());
+
+        // Tables that claim more entries or samples than the file holds, and chunk offsets that
+        // overflow.
+        let stsc = full_box(b"stsc", 0, 0, &fields(&[1, 1, 2, 1]));
+        let stco = full_box(b"stco", 0, 0, &fields(&[1, 0]));
+        let crafted = [
+            (
+                full_box(b"stsz", 0, 0, &fields(&[16, u32::MAX])),
+                stco.clone(),
+            ),
+            (
+                full_box(b"stsz", 0, 0, &fields(&[0, u32::MAX])),
+                stco.clone(),
+            ),
+            (
+                full_box(b"stsz", 0, 0, &fields(&[16, 2])),
+                full_box(b"stco", 0, 0, &fields(&[u32::MAX])),
+            ),
+            (
+                full_box(b"stsz", 0, 0, &fields(&[16, 2])),
+                full_box(b"co64", 0, 0, &fields(&[1, u32::MAX, u32::MAX - 4])),
+            ),
+        ];
+        for (box_type, (stsz, chunk_offsets)) in [*b"stsz", *b"stsz", *b"stco", *b"stsz"]
+            .into_iter()
+            .zip(crafted)
+        {
+            let sample_table = [
+                full_box(b"stts", 0, 0, &fields(&[0])),
+                stsc.clone(),
+                stsz,
+                chunk_offsets,
+            ];
+            let file = mp4_box(b"moov", &[mvhd(), trak(None, &sample_table)].concat());
+            assert_eq!(
+                Mp4Demuxer::new(&file).err(),
+                Some(Mp4Error::InvalidBox { box_type })
+            );
+        }
+
+        // Timestamps that overflow: a media time that can't be negated, a fragment decoding time
+        // that the sample's duration runs past the end of the range, and one that doesn't fit.
+        let mut entries = fields(&[1]);
+        entries.extend_from_slice(&1000u64.to_be_bytes());
+        entries.extend_from_slice(&i64::MIN.to_be_bytes());
+        entries.extend_from_slice(&fields(&[1 << 16]));
+        let sample_table = [
+            full_box(b"stts", 0, 0, &fields(&[0])),
+            full_box(b"stsc", 0, 0, &fields(&[0])),
+            full_box(b"stsz", 0, 0, &fields(&[0, 0])),
+            full_box(b"stco", 0, 0, &fields(&[0])),
+        ];
+        let edit_list = full_box(b"elst", 1, 0, &entries);
+        let file = mp4_box(
+            b"moov",
+            &[mvhd(), trak(Some(edit_list), &sample_table)].concat(),
+        );
+        assert_eq!(
+            Mp4Demuxer::new(&file).err(),
+            Some(Mp4Error::InvalidBox { box_type: *b"elst" })
+        );
+
+        let idr: &[u8] = &[0x65, 0x88, 0x84];
+        for decode_time in [i64::MAX as u64, u64::MAX] {
+            let file = [fragmented_moov(), fragment(1, tfdt(1, decode_time), idr)].concat();
+            assert_eq!(
+                Mp4Demuxer::new(&file).err(),
+                Some(Mp4Error::InvalidBox { box_type: *b"tfdt" })
+            );
+        }
+    }
+}
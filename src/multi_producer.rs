@@ -0,0 +1,194 @@
+use crate::stats::item_bytes;
+use crate::wait::{WaitStrategy, Waiter};
+use crate::{
+    CancellationToken, ControlMessage, DecoderInputItem, DecoderInputQueue,
+    DecoderInputQueueConfig, DecoderInputQueueProducer, DroppedFrames, PushError, QueueStats,
+    Shared,
+};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+/// A cloneable producer for a [`DecoderInputQueue`], for feeding one decoder from several threads.
+///
+/// Frames pushed through the same handle (or its clones, from a single thread) arrive in the order
+/// they were pushed. Frames pushed concurrently from different threads are interleaved in the order
+/// their pushes complete; there's no fairness between threads waiting for space. The queue is
+/// closed once every clone has been dropped.
+pub struct DecoderInputQueueMultiProducer<E> {
+    producer: Arc<Mutex<DecoderInputQueueProducer<E>>>,
+    shared: Arc<Shared>,
+    wait: WaitStrategy,
+}
+
+impl<E> Clone for DecoderInputQueueMultiProducer<E> {
+    fn clone(&self) -> Self {
+        Self {
+            producer: self.producer.clone(),
+            shared: self.shared.clone(),
+            wait: self.wait,
+        }
+    }
+}
+
+impl<E> DecoderInputQueue<E> {
+    /// Creates a queue that can be fed by several threads. See [`DecoderInputQueueMultiProducer`].
+    pub fn new_multi_producer(
+        config: impl Into<DecoderInputQueueConfig>,
+    ) -> (Self, DecoderInputQueueMultiProducer<E>) {
+        let (queue, producer) = Self::new(config);
+        (
+            queue,
+            DecoderInputQueueMultiProducer {
+                shared: producer.shared.clone(),
+                wait: producer.wait,
+                producer: Arc::new(Mutex::new(producer)),
+            },
+        )
+    }
+}
+
+impl<E> DecoderInputQueueMultiProducer<E> {
+    /// Pushes a frame, applying the queue's [`OverflowPolicy`](crate::OverflowPolicy) if it's full.
+    /// With [`OverflowPolicy::Block`](crate::OverflowPolicy::Block) this waits for space, and a
+    /// frame the policy drops is handed back in [`PushError::Dropped`]. Fails if the consumer side
+    /// of the queue has been dropped.
+    pub fn push(
+        &self,
+        frame: Result<XcoderDecoderInputFrame, E>,
+    ) -> Result<(), PushError<Result<XcoderDecoderInputFrame, E>>> {
+        self.push_within(frame.into(), None)
+            .map_err(|err| err.map(DecoderInputItem::expect_frame))
+    }
+
+    /// Like [`push`](Self::push), but waits for at most `timeout`.
+    pub fn push_timeout(
+        &self,
+        frame: Result<XcoderDecoderInputFrame, E>,
+        timeout: Duration,
+    ) -> Result<(), PushError<Result<XcoderDecoderInputFrame, E>>> {
+        self.push_within(frame.into(), Some(timeout))
+            .map_err(|err| err.map(DecoderInputItem::expect_frame))
+    }
+
+    /// Pushes a frame without waiting. If the queue is full, the frame is handed back in
+    /// [`PushError::Full`] regardless of the overflow policy.
+    pub fn try_push(
+        &self,
+        frame: Result<XcoderDecoderInputFrame, E>,
+    ) -> Result<(), PushError<Result<XcoderDecoderInputFrame, E>>> {
+        self.lock().try_push(frame)
+    }
+
+    /// Pushes a control message, waiting for space if the queue is full. Control messages are
+    /// never dropped by the overflow policy.
+    pub fn push_control(&self, message: ControlMessage) -> Result<(), PushError<ControlMessage>> {
+        self.push_within(message.into(), None)
+            .map_err(|err| err.map(DecoderInputItem::expect_control))
+    }
+
+    /// Returns true once the consumer side of the queue has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.consumer_closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of frames dropped by the overflow policy so far.
+    pub fn dropped_frames(&self) -> DroppedFrames {
+        self.shared.dropped.snapshot()
+    }
+
+    /// Returns a handle for reading the queue's statistics, the same ones the consumer sees.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats::new(self.shared.clone())
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        CancellationToken::new(self.shared.clone())
+    }
+
+    /// Returns the reason the queue was cancelled with, if it has been.
+    pub fn cancellation_reason(&self) -> Option<&str> {
+        self.shared.cancellation_reason()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DecoderInputQueueProducer<E>> {
+        self.producer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push_within(
+        &self,
+        item: DecoderInputItem<E>,
+        timeout: Option<Duration>,
+    ) -> Result<(), PushError<DecoderInputItem<E>>> {
+        let mut item = item;
+        let bytes = item_bytes(&item);
+        let started = Instant::now();
+        let deadline = timeout.map(|timeout| started + timeout);
+        let mut waiter = Waiter::new(self.wait);
+        let mut waited = false;
+        let result = loop {
+            // The lock is only held while pushing so that other producers can make progress while
+            // this one waits.
+            match self.lock().push_or_overflow(item) {
+                Err(PushError::Full(returned_item)) => item = returned_item,
+                result => break result,
+            }
+            waited = true;
+            if !waiter.wait(&self.shared.space_available, deadline, || {
+                self.is_closed() || self.shared.is_cancelled() || self.lock().has_room_for(bytes)
+            }) {
+                break Err(PushError::TimedOut(item));
+            }
+        };
+        if waited {
+            self.shared.counters.record_producer_wait(started);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_frame;
+    use std::thread;
+
+    #[test]
+    fn test_multiple_producers() {
+        let (queue, producer) =
+            DecoderInputQueue::<()>::new_multi_producer(DecoderInputQueueConfig {
+                producer_wait: WaitStrategy::Block,
+                consumer_wait: WaitStrategy::Block,
+                ..4.into()
+            });
+        let threads: Vec<_> = (0..2)
+            .map(|id| {
+                let producer = producer.clone();
+                thread::spawn(move || {
+                    for n in 0..500 {
+                        producer
+                            .push(Ok(XcoderDecoderInputFrame {
+                                dts: id,
+                                ..test_frame(n)
+                            }))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(producer);
+
+        // Each producer's frames must arrive in order.
+        let mut next = [0, 0];
+        for frame in queue {
+            let frame = frame.unwrap();
+            assert_eq!(frame.pts, next[frame.dts as usize]);
+            next[frame.dts as usize] += 1;
+        }
+        assert_eq!(next, [500, 500]);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
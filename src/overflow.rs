@@ -0,0 +1,256 @@
+use crate::stats::item_bytes;
+use crate::{lock, DecoderInputItem, DecoderInputQueueProducer, PushError};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Determines what happens when a frame is pushed into a full queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for space using the producer's [`WaitStrategy`](crate::WaitStrategy).
+    #[default]
+    Block,
+    /// Drop the frame being pushed.
+    DropNewest,
+    /// Drop the oldest queued frame to make room for the new one.
+    DropOldest,
+    /// Drop disposable H.264 frames, i.e. frames whose slices all have `nal_ref_idc == 0`. The
+    /// frame being pushed is dropped if it's disposable, otherwise the oldest disposable frame in
+    /// the queue is. IDR frames and errors are never dropped. If there's nothing to drop, this
+    /// waits like [`OverflowPolicy::Block`].
+    DropNonReference,
+}
+
+impl OverflowPolicy {
+    /// Returns true if the policy may evict frames that are already queued.
+    pub(crate) fn evicts(self) -> bool {
+        matches!(self, Self::DropOldest | Self::DropNonReference)
+    }
+}
+
+/// The number of frames dropped by the [`OverflowPolicy`], by reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DroppedFrames {
+    /// Frames dropped by [`OverflowPolicy::DropNewest`].
+    pub newest: u64,
+    /// Frames dropped by [`OverflowPolicy::DropOldest`].
+    pub oldest: u64,
+    /// Frames dropped by [`OverflowPolicy::DropNonReference`].
+    pub non_reference: u64,
+}
+
+impl DroppedFrames {
+    pub fn total(&self) -> u64 {
+        self.newest + self.oldest + self.non_reference
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct DropCounters {
+    newest: AtomicU64,
+    oldest: AtomicU64,
+    non_reference: AtomicU64,
+}
+
+impl DropCounters {
+    pub fn snapshot(&self) -> DroppedFrames {
+        DroppedFrames {
+            newest: self.newest.load(Ordering::Relaxed),
+            oldest: self.oldest.load(Ordering::Relaxed),
+            non_reference: self.non_reference.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Returns true if no other frame references this one, so it can be dropped without breaking
+/// decoding of the rest of the stream.
+fn is_disposable<E>(item: &DecoderInputItem<E>) -> bool {
+    let DecoderInputItem::Frame(Ok(frame)) = item else {
+        return false;
+    };
+    let mut has_slice = false;
+    for nalu in h264::iterate_annex_b(&frame.data) {
+        let Some(&header) = nalu.first() else {
+            continue;
+        };
+        match header & 0x1f {
+            5 => return false,
+            1..=4 => {
+                if header & 0x60 != 0 {
+                    return false;
+                }
+                has_slice = true;
+            }
+            _ => {}
+        }
+    }
+    has_slice
+}
+
+impl<E> DecoderInputQueueProducer<E> {
+    /// Pushes an item, applying the overflow policy if the queue is full. Returns
+    /// [`PushError::Full`] if the caller should wait for space, and [`PushError::Dropped`] if the
+    /// item itself was dropped. Control messages are never dropped, but frames may be evicted to
+    /// make room for them.
+    pub(crate) fn push_or_overflow(
+        &mut self,
+        item: DecoderInputItem<E>,
+    ) -> Result<(), PushError<DecoderInputItem<E>>> {
+        let mut item = match self.try_push_item(item) {
+            Err(PushError::Full(item)) => item,
+            result => return result,
+        };
+        match self.overflow {
+            OverflowPolicy::Block => Err(PushError::Full(item)),
+            OverflowPolicy::DropNewest if item.is_frame() => {
+                self.shared.dropped.newest.fetch_add(1, Ordering::Relaxed);
+                Err(PushError::Dropped(item))
+            }
+            OverflowPolicy::DropNewest => Err(PushError::Full(item)),
+            OverflowPolicy::DropOldest => {
+                // With a byte budget, several frames may have to go to make room.
+                while self.evict_first(DecoderInputItem::is_frame) {
+                    self.shared.dropped.oldest.fetch_add(1, Ordering::Relaxed);
+                    match self.try_push_item(item) {
+                        Err(PushError::Full(returned_item)) => item = returned_item,
+                        result => return result,
+                    }
+                }
+                // The consumer may have emptied the queue in the meantime.
+                self.try_push_item(item)
+            }
+            OverflowPolicy::DropNonReference => {
+                if is_disposable(&item) {
+                    self.shared
+                        .dropped
+                        .non_reference
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Err(PushError::Dropped(item));
+                }
+                while self.evict_first(is_disposable) {
+                    self.shared
+                        .dropped
+                        .non_reference
+                        .fetch_add(1, Ordering::Relaxed);
+                    match self.try_push_item(item) {
+                        Err(PushError::Full(returned_item)) => item = returned_item,
+                        result => return result,
+                    }
+                }
+                Err(PushError::Full(item))
+            }
+        }
+    }
+
+    /// Removes the oldest queued item matching the predicate. Returns false if there isn't one.
+    fn evict_first(&mut self, predicate: impl Fn(&DecoderInputItem<E>) -> bool) -> bool {
+        let Some(receiver) = &self.receiver else {
+            return false;
+        };
+        let mut ring = lock(receiver);
+        let slots = ring.slots();
+        let Ok(mut chunk) = ring.read_chunk(slots) else {
+            return false;
+        };
+        let (first, second) = chunk.as_mut_slices();
+        let Some(index) = first.iter().chain(second.iter()).position(predicate) else {
+            return false;
+        };
+
+        // Items can only be taken from the front, so the evicted one is moved there first. The
+        // consumer is locked out meanwhile, so it sees the others in the same order.
+        move_to_front(first, second, index);
+        if let Ok(item) = ring.pop() {
+            self.shared.counters.record_eviction(item_bytes(&item));
+        }
+        true
+    }
+}
+
+/// Moves the item at `index` of a ring's contents, given as its two slices, to the front. The items
+/// before it move back by one.
+fn move_to_front<T>(first: &mut [T], second: &mut [T], index: usize) {
+    let Some(index) = index.checked_sub(first.len()) else {
+        first[..=index].rotate_right(1);
+        return;
+    };
+    second[..=index].rotate_right(1);
+    if let Some(last) = first.last_mut() {
+        std::mem::swap(last, &mut second[0]);
+        first.rotate_right(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_frame, DecoderInputQueue, DecoderInputQueueConfig};
+    use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+    fn frame(pts: i64, nal_header: u8) -> Result<XcoderDecoderInputFrame, ()> {
+        Ok(XcoderDecoderInputFrame {
+            data: vec![0, 0, 0, 1, nal_header, 0x88],
+            ..test_frame(pts)
+        })
+    }
+
+    /// Pushes five frames into a queue of three, returning the timestamps of the frames that come
+    /// out, those of the frames handed back as dropped, and the drop counters.
+    fn queue_with_policy(overflow: OverflowPolicy) -> (Vec<i64>, Vec<i64>, DroppedFrames) {
+        let (queue, mut producer) = DecoderInputQueue::new(DecoderInputQueueConfig {
+            overflow,
+            ..3.into()
+        });
+        // IDR, reference P, disposable B, disposable B, reference P
+        let mut rejected = Vec::new();
+        for (pts, header) in [(0, 0x65), (1, 0x41), (2, 0x01), (3, 0x01), (4, 0x41)] {
+            match producer.push(frame(pts, header)) {
+                Ok(()) => {}
+                Err(PushError::Dropped(frame)) => rejected.push(frame.unwrap().pts),
+                Err(err) => panic!("{err}"),
+            }
+        }
+        let dropped = producer.dropped_frames();
+        drop(producer);
+        (
+            queue.map(|frame| frame.unwrap().pts).collect(),
+            rejected,
+            dropped,
+        )
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let (pts, rejected, dropped) = queue_with_policy(OverflowPolicy::DropNewest);
+        assert_eq!(pts, vec![0, 1, 2]);
+        assert_eq!(rejected, vec![3, 4]);
+        assert_eq!(dropped.newest, 2);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let (pts, rejected, dropped) = queue_with_policy(OverflowPolicy::DropOldest);
+        assert_eq!(pts, vec![2, 3, 4]);
+        assert!(rejected.is_empty());
+        assert_eq!(dropped.oldest, 2);
+    }
+
+    #[test]
+    fn test_drop_non_reference() {
+        let (pts, rejected, dropped) = queue_with_policy(OverflowPolicy::DropNonReference);
+        assert_eq!(pts, vec![0, 1, 4]);
+        assert_eq!(rejected, vec![3]);
+        assert_eq!(dropped.non_reference, 2);
+        assert_eq!(dropped.total(), 2);
+    }
+
+    #[test]
+    fn test_move_to_front() {
+        for index in 0..5 {
+            let mut items = [0, 1, 2, 3, 4];
+            let (first, second) = items.split_at_mut(2);
+            move_to_front(first, second, index);
+            let mut expected = vec![index];
+            expected.extend((0..5).filter(|&i| i != index));
+            assert_eq!(items[..], expected);
+        }
+    }
+}
@@ -0,0 +1,886 @@
+use crate::bits::{to_rbsp, BitReader, BitWriter};
+use std::io;
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The largest frame width or height in macroblocks that any level allows, `Sqrt(MaxFS * 8)` for
+/// the largest `MaxFS` in table A-1 of H.264.
+const MAX_PIC_SIZE_IN_MBS: u32 = 1055;
+
+/// A ratio of two integers, used for frame rates and timebases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Rational {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl Rational {
+    pub const fn new(num: u32, den: u32) -> Self {
+        Self { num, den }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+}
+
+/// An H.264 sequence parameter set. Only the fields needed to frame and time the stream, and to
+/// configure a decoder for it, are kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    /// The `constraint_set0_flag` to `constraint_set5_flag`, from the most significant bit down.
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane: bool,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub log2_max_frame_num: u32,
+    pub pic_order_cnt_type: u32,
+    pub log2_max_pic_order_cnt_lsb: u32,
+    pub delta_pic_order_always_zero: bool,
+    pub offset_for_non_ref_pic: i32,
+    pub offset_for_top_to_bottom_field: i32,
+    pub offset_for_ref_frame: Vec<i32>,
+    pub max_num_ref_frames: u32,
+    pub gaps_in_frame_num_allowed: bool,
+    pub pic_width_in_mbs: u32,
+    pub pic_height_in_map_units: u32,
+    pub frame_mbs_only: bool,
+    pub mb_adaptive_frame_field: bool,
+    pub direct_8x8_inference: bool,
+    pub frame_cropping: Option<FrameCropping>,
+    pub vui: Option<Vui>,
+}
+
+/// The frame cropping offsets of an [`Sps`], in crop units.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameCropping {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+}
+
+/// The video usability information of an [`Sps`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Vui {
+    /// The sample aspect ratio, if signalled.
+    pub sample_aspect_ratio: Option<Rational>,
+    pub video_format: Option<u8>,
+    pub video_full_range: bool,
+    pub colour_primaries: Option<u8>,
+    pub transfer_characteristics: Option<u8>,
+    pub matrix_coefficients: Option<u8>,
+    pub timing_info: Option<TimingInfo>,
+    pub nal_hrd: Option<HrdParameters>,
+    pub vcl_hrd: Option<HrdParameters>,
+    pub low_delay_hrd: bool,
+    pub pic_struct_present: bool,
+    pub bitstream_restriction: Option<BitstreamRestriction>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimingInfo {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate: bool,
+}
+
+/// The parts of the hypothetical reference decoder parameters needed to parse SEI messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HrdParameters {
+    pub cpb_cnt: u32,
+    pub initial_cpb_removal_delay_length: u32,
+    pub cpb_removal_delay_length: u32,
+    pub dpb_output_delay_length: u32,
+    pub time_offset_length: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitstreamRestriction {
+    pub max_num_reorder_frames: u32,
+    pub max_dec_frame_buffering: u32,
+}
+
+pub(crate) const SAMPLE_ASPECT_RATIOS: [(u32, u32); 17] = [
+    (0, 0),
+    (1, 1),
+    (12, 11),
+    (10, 11),
+    (16, 11),
+    (40, 33),
+    (24, 11),
+    (20, 11),
+    (32, 11),
+    (80, 33),
+    (18, 11),
+    (15, 11),
+    (64, 33),
+    (160, 99),
+    (4, 3),
+    (3, 2),
+    (2, 1),
+];
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> io::Result<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            next_scale = (last_scale + reader.read_se()? + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+/// Where the timing info of an SPS is in its RBSP, or would go, as a bit position.
+enum TimingPosition {
+    /// The position of `vui_parameters_present_flag`, when it's not set.
+    NoVui(usize),
+    /// The position of `timing_info_present_flag`.
+    Vui(usize),
+}
+
+impl Sps {
+    /// Parses an SPS NAL unit, including its header byte.
+    pub fn parse(nalu: &[u8]) -> io::Result<Self> {
+        Self::parse_with_timing_position(nalu).map(|(sps, _)| sps)
+    }
+
+    /// Parses an SPS NAL unit, also returning where its timing info is, or would go.
+    fn parse_with_timing_position(nalu: &[u8]) -> io::Result<(Self, TimingPosition)> {
+        let rbsp = to_rbsp(nalu);
+        let mut reader = BitReader::new(&rbsp);
+        if reader.read_bits(8)? & 0x1f != 7 {
+            return Err(invalid("not an SPS"));
+        }
+        let profile_idc = reader.read_bits(8)? as u8;
+        let constraint_flags = reader.read_bits(8)? as u8;
+        let level_idc = reader.read_bits(8)? as u8;
+        let seq_parameter_set_id = reader.read_ue()?;
+        if seq_parameter_set_id > 31 {
+            return Err(invalid("SPS id out of range"));
+        }
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+        if matches!(
+            profile_idc,
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+        ) {
+            chroma_format_idc = reader.read_ue()?;
+            if chroma_format_idc > 3 {
+                return Err(invalid("chroma_format_idc out of range"));
+            }
+            if chroma_format_idc == 3 {
+                separate_colour_plane = reader.read_flag()?;
+            }
+            bit_depth_luma = 8 + reader.read_ue()?.min(6) as u8;
+            bit_depth_chroma = 8 + reader.read_ue()?.min(6) as u8;
+            // qpprime_y_zero_transform_bypass_flag
+            reader.skip_bits(1)?;
+            if reader.read_flag()? {
+                let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..lists {
+                    if reader.read_flag()? {
+                        skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        let log2_max_frame_num = reader
+            .read_ue()?
+            .checked_add(4)
+            .filter(|&n| n <= 16)
+            .ok_or_else(|| invalid("log2_max_frame_num out of range"))?;
+        let pic_order_cnt_type = reader.read_ue()?;
+        let mut log2_max_pic_order_cnt_lsb = 0;
+        let mut delta_pic_order_always_zero = false;
+        let mut offset_for_non_ref_pic = 0;
+        let mut offset_for_top_to_bottom_field = 0;
+        let mut offset_for_ref_frame = Vec::new();
+        match pic_order_cnt_type {
+            0 => {
+                log2_max_pic_order_cnt_lsb = reader
+                    .read_ue()?
+                    .checked_add(4)
+                    .filter(|&n| n <= 16)
+                    .ok_or_else(|| invalid("log2_max_pic_order_cnt_lsb out of range"))?;
+            }
+            1 => {
+                delta_pic_order_always_zero = reader.read_flag()?;
+                offset_for_non_ref_pic = reader.read_se()?;
+                offset_for_top_to_bottom_field = reader.read_se()?;
+                let cycle_len = reader.read_ue()?;
+                if cycle_len > 255 {
+                    return Err(invalid(
+                        "num_ref_frames_in_pic_order_cnt_cycle out of range",
+                    ));
+                }
+                for _ in 0..cycle_len {
+                    offset_for_ref_frame.push(reader.read_se()?);
+                }
+            }
+            2 => {}
+            _ => return Err(invalid("pic_order_cnt_type out of range")),
+        }
+
+        let max_num_ref_frames = reader.read_ue()?;
+        let gaps_in_frame_num_allowed = reader.read_flag()?;
+        let pic_width_in_mbs = reader
+            .read_ue()?
+            .checked_add(1)
+            .filter(|&n| n <= MAX_PIC_SIZE_IN_MBS)
+            .ok_or_else(|| invalid("pic_width_in_mbs out of range"))?;
+        let pic_height_in_map_units = reader
+            .read_ue()?
+            .checked_add(1)
+            .filter(|&n| n <= MAX_PIC_SIZE_IN_MBS)
+            .ok_or_else(|| invalid("pic_height_in_map_units out of range"))?;
+        let frame_mbs_only = reader.read_flag()?;
+        let mb_adaptive_frame_field = !frame_mbs_only && reader.read_flag()?;
+        let direct_8x8_inference = reader.read_flag()?;
+        let frame_cropping = if reader.read_flag()? {
+            Some(FrameCropping {
+                left: reader.read_ue()?,
+                right: reader.read_ue()?,
+                top: reader.read_ue()?,
+                bottom: reader.read_ue()?,
+            })
+        } else {
+            None
+        };
+        let vui_position = reader.position();
+        let (vui, timing_position) = if reader.read_flag()? {
+            let (vui, position) = Vui::parse(&mut reader)?;
+            (Some(vui), TimingPosition::Vui(position))
+        } else {
+            (None, TimingPosition::NoVui(vui_position))
+        };
+
+        let sps = Self {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            separate_colour_plane,
+            bit_depth_luma,
+            bit_depth_chroma,
+            log2_max_frame_num,
+            pic_order_cnt_type,
+            log2_max_pic_order_cnt_lsb,
+            delta_pic_order_always_zero,
+            offset_for_non_ref_pic,
+            offset_for_top_to_bottom_field,
+            offset_for_ref_frame,
+            max_num_ref_frames,
+            gaps_in_frame_num_allowed,
+            pic_width_in_mbs,
+            pic_height_in_map_units,
+            frame_mbs_only,
+            mb_adaptive_frame_field,
+            direct_8x8_inference,
+            frame_cropping,
+            vui,
+        };
+        if let Some(crop) = sps.frame_cropping {
+            // The cropped frame must keep at least one sample in each direction.
+            let (unit_x, unit_y) = sps.crop_units();
+            let frame_height = (2 - frame_mbs_only as u64) * pic_height_in_map_units as u64 * 16;
+            if unit_x as u64 * (crop.left as u64 + crop.right as u64)
+                >= pic_width_in_mbs as u64 * 16
+                || unit_y as u64 * (crop.top as u64 + crop.bottom as u64) >= frame_height
+            {
+                return Err(invalid("frame cropping out of range"));
+            }
+        }
+        Ok((sps, timing_position))
+    }
+
+    /// Rewrites an SPS NAL unit to signal a fixed frame rate in its VUI timing info, adding a VUI
+    /// if it has none. The rest of the SPS is kept as it is.
+    pub(crate) fn rewrite_frame_rate(nalu: &[u8], frame_rate: Rational) -> io::Result<Vec<u8>> {
+        let (sps, timing_position) = Self::parse_with_timing_position(nalu)?;
+        let time_scale = frame_rate
+            .num
+            .checked_mul(2)
+            .filter(|_| frame_rate.den != 0)
+            .ok_or_else(|| invalid("frame rate out of range"))?;
+        let rbsp = to_rbsp(nalu);
+        // The stop bit of the trailing bits, which the writer adds back.
+        let end = rbsp
+            .iter()
+            .rposition(|&b| b != 0)
+            .map(|i| i * 8 + 7 - rbsp[i].trailing_zeros() as usize)
+            .ok_or_else(|| invalid("SPS without trailing bits"))?;
+
+        let mut reader = BitReader::new(&rbsp);
+        let copy = |reader: &mut BitReader, mut writer: BitWriter, to: usize| {
+            while reader.position() < to {
+                writer = writer.flag(reader.read_flag()?);
+            }
+            io::Result::Ok(writer)
+        };
+        let timing = |writer: BitWriter| {
+            writer
+                .flag(true)
+                .bits(32, frame_rate.den.into())
+                .bits(32, time_scale.into())
+                .flag(true)
+        };
+        let writer = match timing_position {
+            TimingPosition::NoVui(position) => {
+                let writer = copy(&mut reader, BitWriter::default(), position)?;
+                reader.skip_bits(1)?;
+                // A VUI with only the timing info: the aspect ratio, overscan, video signal type
+                // and chroma location flags come before it, the HRD, pic_struct and bitstream
+                // restriction flags after.
+                timing(writer.flag(true).bits(4, 0)).bits(4, 0)
+            }
+            TimingPosition::Vui(position) => {
+                let writer = copy(&mut reader, BitWriter::default(), position)?;
+                let old_timing = sps.vui.and_then(|vui| vui.timing_info).is_some();
+                reader.skip_bits(if old_timing { 66 } else { 1 })?;
+                timing(writer)
+            }
+        };
+        Ok(copy(&mut reader, writer, end)?.finish())
+    }
+
+    /// Returns the `ChromaArrayType` variable, which is zero when the colour planes are coded
+    /// separately.
+    pub fn chroma_array_type(&self) -> u32 {
+        if self.separate_colour_plane {
+            0
+        } else {
+            self.chroma_format_idc
+        }
+    }
+
+    fn crop_units(&self) -> (u32, u32) {
+        let field_factor = 2 - self.frame_mbs_only as u32;
+        match self.chroma_array_type() {
+            0 => (1, field_factor),
+            1 => (2, 2 * field_factor),
+            2 => (2, field_factor),
+            _ => (1, field_factor),
+        }
+    }
+
+    /// Returns the width of the decoded frames after cropping.
+    pub fn width(&self) -> u32 {
+        let width = self.pic_width_in_mbs.saturating_mul(16);
+        match self.frame_cropping {
+            Some(crop) => width.saturating_sub(
+                self.crop_units()
+                    .0
+                    .saturating_mul(crop.left.saturating_add(crop.right)),
+            ),
+            None => width,
+        }
+    }
+
+    /// Returns the height of the decoded frames after cropping.
+    pub fn height(&self) -> u32 {
+        let height = (2 - self.frame_mbs_only as u32)
+            .saturating_mul(self.pic_height_in_map_units)
+            .saturating_mul(16);
+        match self.frame_cropping {
+            Some(crop) => height.saturating_sub(
+                self.crop_units()
+                    .1
+                    .saturating_mul(crop.top.saturating_add(crop.bottom)),
+            ),
+            None => height,
+        }
+    }
+
+    /// Returns the frame rate signalled by the VUI timing info, if any.
+    pub fn frame_rate(&self) -> Option<Rational> {
+        let timing = self.vui.as_ref()?.timing_info?;
+        if timing.num_units_in_tick == 0 || timing.time_scale == 0 {
+            return None;
+        }
+        Some(Rational::new(
+            timing.time_scale,
+            timing.num_units_in_tick.checked_mul(2)?,
+        ))
+    }
+
+    /// Returns the size of the decoded picture buffer in frames, as implied by the level.
+    pub fn max_dpb_frames(&self) -> u32 {
+        let max_dpb_mbs = match self.level_idc {
+            9 | 10 => 396,
+            // Level 1b is signalled as 1.1 with constraint_set3_flag in the Baseline, Main and
+            // Extended profiles.
+            11 if self.constraint_flags & 0x10 != 0 && matches!(self.profile_idc, 66 | 77 | 88) => {
+                396
+            }
+            11 => 900,
+            12 | 13 | 20 => 2376,
+            21 => 4752,
+            22 | 30 => 8100,
+            31 => 18000,
+            32 => 20480,
+            40 | 41 => 32768,
+            42 => 34816,
+            50 => 110400,
+            51 | 52 => 184320,
+            _ => 696320,
+        };
+        let frame_mbs = self
+            .pic_width_in_mbs
+            .saturating_mul(self.pic_height_in_map_units)
+            .saturating_mul(2 - self.frame_mbs_only as u32);
+        (max_dpb_mbs / frame_mbs.max(1)).clamp(1, 16)
+    }
+
+    /// Returns the maximum number of frames that can precede any frame in decoding order and
+    /// follow it in output order. When the VUI doesn't say, it's inferred as the spec does.
+    pub fn max_num_reorder_frames(&self) -> u32 {
+        if let Some(restriction) = self.vui.as_ref().and_then(|vui| vui.bitstream_restriction) {
+            return restriction.max_num_reorder_frames;
+        }
+        let intra_only = self.constraint_flags & 0x10 != 0
+            && matches!(self.profile_idc, 44 | 86 | 100 | 110 | 122 | 244);
+        if intra_only {
+            0
+        } else {
+            self.max_dpb_frames()
+        }
+    }
+}
+
+impl Vui {
+    /// Parses the VUI, also returning the bit position of its `timing_info_present_flag`.
+    fn parse(reader: &mut BitReader) -> io::Result<(Self, usize)> {
+        let mut vui = Vui::default();
+        if reader.read_flag()? {
+            let aspect_ratio_idc = reader.read_bits(8)?;
+            vui.sample_aspect_ratio = match aspect_ratio_idc {
+                255 => Some(Rational::new(reader.read_bits(16)?, reader.read_bits(16)?)),
+                1..=16 => {
+                    let (num, den) = SAMPLE_ASPECT_RATIOS[aspect_ratio_idc as usize];
+                    Some(Rational::new(num, den))
+                }
+                _ => None,
+            };
+        }
+        if reader.read_flag()? {
+            // overscan_appropriate_flag
+            reader.skip_bits(1)?;
+        }
+        if reader.read_flag()? {
+            vui.video_format = Some(reader.read_bits(3)? as u8);
+            vui.video_full_range = reader.read_flag()?;
+            if reader.read_flag()? {
+                vui.colour_primaries = Some(reader.read_bits(8)? as u8);
+                vui.transfer_characteristics = Some(reader.read_bits(8)? as u8);
+                vui.matrix_coefficients = Some(reader.read_bits(8)? as u8);
+            }
+        }
+        if reader.read_flag()? {
+            // chroma_sample_loc_type_top_field and chroma_sample_loc_type_bottom_field
+            reader.read_ue()?;
+            reader.read_ue()?;
+        }
+        let timing_position = reader.position();
+        if reader.read_flag()? {
+            vui.timing_info = Some(TimingInfo {
+                num_units_in_tick: reader.read_bits(32)?,
+                time_scale: reader.read_bits(32)?,
+                fixed_frame_rate: reader.read_flag()?,
+            });
+        }
+        if reader.read_flag()? {
+            vui.nal_hrd = Some(HrdParameters::parse(reader)?);
+        }
+        if reader.read_flag()? {
+            vui.vcl_hrd = Some(HrdParameters::parse(reader)?);
+        }
+        if vui.nal_hrd.is_some() || vui.vcl_hrd.is_some() {
+            vui.low_delay_hrd = reader.read_flag()?;
+        }
+        vui.pic_struct_present = reader.read_flag()?;
+        if reader.read_flag()? {
+            // motion_vectors_over_pic_boundaries_flag
+            reader.skip_bits(1)?;
+            // max_bytes_per_pic_denom, max_bits_per_mb_denom and the maximum motion vector lengths
+            for _ in 0..4 {
+                reader.read_ue()?;
+            }
+            vui.bitstream_restriction = Some(BitstreamRestriction {
+                max_num_reorder_frames: reader.read_ue()?,
+                max_dec_frame_buffering: reader.read_ue()?,
+            });
+        }
+        Ok((vui, timing_position))
+    }
+}
+
+impl HrdParameters {
+    fn parse(reader: &mut BitReader) -> io::Result<Self> {
+        let cpb_cnt = reader
+            .read_ue()?
+            .checked_add(1)
+            .filter(|&n| n <= 32)
+            .ok_or_else(|| invalid("cpb_cnt out of range"))?;
+        // bit_rate_scale and cpb_size_scale
+        reader.skip_bits(8)?;
+        for _ in 0..cpb_cnt {
+            // bit_rate_value_minus1 and cpb_size_value_minus1
+            reader.read_ue()?;
+            reader.read_ue()?;
+            // cbr_flag
+            reader.skip_bits(1)?;
+        }
+        Ok(Self {
+            cpb_cnt,
+            initial_cpb_removal_delay_length: reader.read_bits(5)? + 1,
+            cpb_removal_delay_length: reader.read_bits(5)? + 1,
+            dpb_output_delay_length: reader.read_bits(5)? + 1,
+            time_offset_length: reader.read_bits(5)?,
+        })
+    }
+}
+
+/// An H.264 picture parameter set, up to the fields needed to parse slice headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pps {
+    pub pic_parameter_set_id: u32,
+    pub seq_parameter_set_id: u32,
+    pub entropy_coding_mode: bool,
+    pub bottom_field_pic_order_in_frame_present: bool,
+    pub num_slice_groups: u32,
+    pub num_ref_idx_l0_default_active: u32,
+    pub num_ref_idx_l1_default_active: u32,
+    pub weighted_pred: bool,
+    pub weighted_bipred_idc: u32,
+    pub deblocking_filter_control_present: bool,
+    pub constrained_intra_pred: bool,
+    pub redundant_pic_cnt_present: bool,
+}
+
+impl Pps {
+    /// Parses a PPS NAL unit, including its header byte.
+    pub fn parse(nalu: &[u8]) -> io::Result<Self> {
+        let rbsp = to_rbsp(nalu);
+        let mut reader = BitReader::new(&rbsp);
+        if reader.read_bits(8)? & 0x1f != 8 {
+            return Err(invalid("not a PPS"));
+        }
+        let pic_parameter_set_id = reader.read_ue()?;
+        if pic_parameter_set_id > 255 {
+            return Err(invalid("PPS id out of range"));
+        }
+        let seq_parameter_set_id = reader.read_ue()?;
+        if seq_parameter_set_id > 31 {
+            return Err(invalid("SPS id out of range"));
+        }
+        let entropy_coding_mode = reader.read_flag()?;
+        let bottom_field_pic_order_in_frame_present = reader.read_flag()?;
+        let num_slice_groups = reader
+            .read_ue()?
+            .checked_add(1)
+            .filter(|&n| n <= 8)
+            .ok_or_else(|| invalid("num_slice_groups out of range"))?;
+        if num_slice_groups > 1 {
+            match reader.read_ue()? {
+                0 => {
+                    for _ in 0..num_slice_groups {
+                        // run_length_minus1
+                        reader.read_ue()?;
+                    }
+                }
+                2 => {
+                    for _ in 0..num_slice_groups - 1 {
+                        // top_left and bottom_right
+                        reader.read_ue()?;
+                        reader.read_ue()?;
+                    }
+                }
+                3..=5 => {
+                    // slice_group_change_direction_flag and slice_group_change_rate_minus1
+                    reader.skip_bits(1)?;
+                    reader.read_ue()?;
+                }
+                6 => {
+                    let pic_size_in_map_units = reader.read_ue()? as usize + 1;
+                    let bits = u32::BITS - (num_slice_groups - 1).leading_zeros();
+                    reader.skip_bits(pic_size_in_map_units * bits as usize)?;
+                }
+                _ => {}
+            }
+        }
+        let mut read_num_ref_idx_default_active = || {
+            reader
+                .read_ue()?
+                .checked_add(1)
+                .filter(|&n| n <= 32)
+                .ok_or_else(|| invalid("num_ref_idx_default_active out of range"))
+        };
+        let num_ref_idx_l0_default_active = read_num_ref_idx_default_active()?;
+        let num_ref_idx_l1_default_active = read_num_ref_idx_default_active()?;
+        let weighted_pred = reader.read_flag()?;
+        let weighted_bipred_idc = reader.read_bits(2)?;
+        // pic_init_qp_minus26, pic_init_qs_minus26 and chroma_qp_index_offset
+        reader.read_se()?;
+        reader.read_se()?;
+        reader.read_se()?;
+        let deblocking_filter_control_present = reader.read_flag()?;
+        let constrained_intra_pred = reader.read_flag()?;
+        let redundant_pic_cnt_present = reader.read_flag()?;
+        Ok(Self {
+            pic_parameter_set_id,
+            seq_parameter_set_id,
+            entropy_coding_mode,
+            bottom_field_pic_order_in_frame_present,
+            num_slice_groups,
+            num_ref_idx_l0_default_active,
+            num_ref_idx_l1_default_active,
+            weighted_pred,
+            weighted_bipred_idc,
+            deblocking_filter_control_present,
+            constrained_intra_pred,
+            redundant_pic_cnt_present,
+        })
+    }
+}
+
+/// The fields of an SPS built by [`test_sps`], as they're coded in the bitstream.
+#[cfg(test)]
+#[derive(Clone, Copy)]
+pub(crate) struct SpsFields {
+    pub log2_max_frame_num_minus4: u32,
+    /// `pic_order_cnt_type`, 0 or 2.
+    pub pic_order_cnt_type: u32,
+    pub log2_max_pic_order_cnt_lsb_minus4: u32,
+    pub pic_width_in_mbs_minus1: u32,
+    pub pic_height_in_map_units_minus1: u32,
+    /// The left, right, top and bottom cropping offsets.
+    pub crop: Option<[u32; 4]>,
+    /// Adds a VUI with NAL HRD parameters for this many CPBs, minus one.
+    pub cpb_cnt_minus1: Option<u32>,
+}
+
+#[cfg(test)]
+impl Default for SpsFields {
+    /// 1080p, cropped from 1088 lines, with 4 bit frame numbers and 6 bit picture order counts.
+    fn default() -> Self {
+        Self {
+            log2_max_frame_num_minus4: 0,
+            pic_order_cnt_type: 0,
+            log2_max_pic_order_cnt_lsb_minus4: 2,
+            pic_width_in_mbs_minus1: 119,
+            pic_height_in_map_units_minus1: 67,
+            crop: Some([0, 0, 0, 4]),
+            cpb_cnt_minus1: None,
+        }
+    }
+}
+
+/// Builds a Main profile SPS at level 4.0 with id 0.
+#[cfg(test)]
+pub(crate) fn test_sps(fields: SpsFields) -> Vec<u8> {
+    let mut writer = BitWriter::default()
+        .bits(8, 0x67)
+        .bits(8, 77)
+        .bits(8, 0)
+        .bits(8, 40)
+        .ue(0)
+        .ue(fields.log2_max_frame_num_minus4)
+        .ue(fields.pic_order_cnt_type);
+    if fields.pic_order_cnt_type == 0 {
+        writer = writer.ue(fields.log2_max_pic_order_cnt_lsb_minus4);
+    }
+    // max_num_ref_frames and gaps_in_frame_num_value_allowed_flag
+    writer = writer
+        .ue(1)
+        .flag(false)
+        .ue(fields.pic_width_in_mbs_minus1)
+        .ue(fields.pic_height_in_map_units_minus1)
+        // frame_mbs_only_flag and direct_8x8_inference_flag
+        .bits(2, 0b11)
+        .flag(fields.crop.is_some());
+    for offset in fields.crop.into_iter().flatten() {
+        writer = writer.ue(offset);
+    }
+    writer = writer.flag(fields.cpb_cnt_minus1.is_some());
+    if let Some(cpb_cnt_minus1) = fields.cpb_cnt_minus1 {
+        // No aspect ratio, overscan, video signal type, chroma location or timing info, then
+        // nal_hrd_parameters_present_flag
+        writer = writer.bits(5, 0).flag(true).ue(cpb_cnt_minus1).bits(8, 0);
+        for _ in 0..=cpb_cnt_minus1.min(31) {
+            writer = writer.ue(0).ue(0).flag(false);
+        }
+        // The delay lengths, no VCL HRD parameters, low_delay_hrd_flag, pic_struct_present_flag
+        // and bitstream_restriction_flag
+        writer = writer.bits(20, 0).bits(4, 0);
+    }
+    writer.finish()
+}
+
+/// Builds a CAVLC PPS with id 0 referring to SPS 0. With several slice groups, it ends right after
+/// their number.
+#[cfg(test)]
+pub(crate) fn test_pps(
+    num_slice_groups_minus1: u32,
+    num_ref_idx_l0_default_active_minus1: u32,
+) -> Vec<u8> {
+    let writer = BitWriter::default()
+        .bits(8, 0x68)
+        .ue(0)
+        .ue(0)
+        .bits(2, 0)
+        .ue(num_slice_groups_minus1);
+    if num_slice_groups_minus1 > 0 {
+        return writer.finish();
+    }
+    writer
+        .ue(num_ref_idx_l0_default_active_minus1)
+        .ue(0)
+        // weighted_pred_flag and weighted_bipred_idc
+        .bits(3, 0)
+        .se(0)
+        .se(0)
+        .se(0)
+        // deblocking_filter_control_present_flag, constrained_intra_pred_flag and
+        // redundant_pic_cnt_present_flag
+        .bits(3, 0b100)
+        .finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_invalid<T: std::fmt::Debug>(result: io::Result<T>) {
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_sps() {
+        let sps = Sps::parse(&test_sps(SpsFields::default())).unwrap();
+        assert_eq!((sps.profile_idc, sps.level_idc), (77, 40));
+        assert_eq!(sps.log2_max_frame_num, 4);
+        assert_eq!(sps.log2_max_pic_order_cnt_lsb, 6);
+        assert_eq!((sps.width(), sps.height()), (1920, 1080));
+        // 32768 macroblocks at level 4.0 over 8160 per frame
+        assert_eq!(sps.max_dpb_frames(), 4);
+        assert_eq!(sps.vui, None);
+
+        let sps = Sps::parse(&test_sps(SpsFields {
+            log2_max_frame_num_minus4: 12,
+            log2_max_pic_order_cnt_lsb_minus4: 12,
+            pic_width_in_mbs_minus1: MAX_PIC_SIZE_IN_MBS - 1,
+            pic_height_in_map_units_minus1: MAX_PIC_SIZE_IN_MBS - 1,
+            crop: Some([0, 0, 0, 0]),
+            cpb_cnt_minus1: Some(31),
+            ..SpsFields::default()
+        }))
+        .unwrap();
+        assert_eq!(
+            (sps.log2_max_frame_num, sps.log2_max_pic_order_cnt_lsb),
+            (16, 16)
+        );
+        assert_eq!((sps.width(), sps.height()), (16880, 16880));
+        assert_eq!(sps.vui.unwrap().nal_hrd.unwrap().cpb_cnt, 32);
+    }
+
+    #[test]
+    fn test_sps_out_of_range() {
+        let large = u32::MAX - 1;
+        let fields = SpsFields::default();
+        for fields in [
+            SpsFields {
+                log2_max_frame_num_minus4: 13,
+                ..fields
+            },
+            SpsFields {
+                log2_max_frame_num_minus4: large,
+                ..fields
+            },
+            SpsFields {
+                log2_max_pic_order_cnt_lsb_minus4: large,
+                ..fields
+            },
+            SpsFields {
+                pic_width_in_mbs_minus1: MAX_PIC_SIZE_IN_MBS,
+                ..fields
+            },
+            SpsFields {
+                pic_width_in_mbs_minus1: large,
+                ..fields
+            },
+            SpsFields {
+                pic_height_in_map_units_minus1: large,
+                ..fields
+            },
+            // Cropping by the whole width, in units of two samples for 4:2:0
+            SpsFields {
+                crop: Some([480, 480, 0, 0]),
+                ..fields
+            },
+            SpsFields {
+                crop: Some([large, large, 0, 0]),
+                ..fields
+            },
+            SpsFields {
+                crop: Some([0, 0, large, large]),
+                ..fields
+            },
+            SpsFields {
+                cpb_cnt_minus1: Some(large),
+                ..fields
+            },
+        ] {
+            assert_invalid(Sps::parse(&test_sps(fields)));
+        }
+
+        // Fields set out of range by hand saturate rather than overflow.
+        let mut sps = Sps::parse(&test_sps(fields)).unwrap();
+        sps.pic_width_in_mbs = u32::MAX;
+        sps.pic_height_in_map_units = u32::MAX;
+        assert_eq!((sps.width(), sps.height()), (u32::MAX, u32::MAX - 8));
+        assert_eq!(sps.max_dpb_frames(), 1);
+        sps.frame_cropping = Some(FrameCropping {
+            left: u32::MAX,
+            right: u32::MAX,
+            top: u32::MAX,
+            bottom: u32::MAX,
+        });
+        assert_eq!((sps.width(), sps.height()), (0, 0));
+    }
+
+    #[test]
+    fn test_parse_pps() {
+        let pps = Pps::parse(&test_pps(0, 2)).unwrap();
+        assert_eq!((pps.pic_parameter_set_id, pps.seq_parameter_set_id), (0, 0));
+        assert_eq!(pps.num_slice_groups, 1);
+        assert_eq!(
+            (
+                pps.num_ref_idx_l0_default_active,
+                pps.num_ref_idx_l1_default_active
+            ),
+            (3, 1)
+        );
+        assert!(pps.deblocking_filter_control_present);
+
+        assert_invalid(Pps::parse(&test_pps(8, 0)));
+        assert_invalid(Pps::parse(&test_pps(u32::MAX - 1, 0)));
+        assert_invalid(Pps::parse(&test_pps(0, 32)));
+        assert_invalid(Pps::parse(&test_pps(0, u32::MAX - 1)));
+    }
+}
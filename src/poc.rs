@@ -0,0 +1,206 @@
+use crate::slice::SliceHeader;
+
+/// The state carried between pictures to derive picture order counts, as specified in clause
+/// 8.2.1 of H.264.
+#[derive(Default)]
+pub(crate) struct PocState {
+    /// `PicOrderCntMsb` and `pic_order_cnt_lsb` of the previous reference picture, for type 0.
+    prev_poc_msb: i64,
+    prev_poc_lsb: i64,
+    /// `FrameNumOffset` and `frame_num` of the previous picture, for types 1 and 2.
+    prev_frame_num_offset: i64,
+    prev_frame_num: i64,
+}
+
+impl PocState {
+    /// Returns the picture order count of a picture from its first slice header. For frames it's
+    /// the smaller of the two field order counts, as used for output ordering. Pictures with
+    /// `memory_management_control_operation` 5 get the count they have after the reset, so that
+    /// they order before the pictures that follow them.
+    pub fn next(&mut self, header: &SliceHeader) -> i64 {
+        let (top, bottom) = match header.sps.pic_order_cnt_type {
+            0 => self.type_0(header),
+            1 => self.type_1(header),
+            _ => self.type_2(header),
+        };
+        let poc = if !header.field_pic {
+            top.min(bottom)
+        } else if header.bottom_field {
+            bottom
+        } else {
+            top
+        };
+
+        if header.has_mmco5 {
+            // The picture is treated as if it had been an IDR picture from here on.
+            self.prev_poc_msb = 0;
+            self.prev_poc_lsb = if header.bottom_field { 0 } else { top - poc };
+            self.prev_frame_num_offset = 0;
+            self.prev_frame_num = 0;
+            return 0;
+        }
+        poc
+    }
+
+    fn frame_num_offset(&mut self, header: &SliceHeader) -> i64 {
+        let frame_num = header.frame_num as i64;
+        let frame_num_offset = if header.is_idr() {
+            0
+        } else if self.prev_frame_num > frame_num {
+            self.prev_frame_num_offset + (1 << header.sps.log2_max_frame_num)
+        } else {
+            self.prev_frame_num_offset
+        };
+        self.prev_frame_num_offset = frame_num_offset;
+        self.prev_frame_num = frame_num;
+        frame_num_offset
+    }
+
+    fn type_0(&mut self, header: &SliceHeader) -> (i64, i64) {
+        if header.is_idr() {
+            self.prev_poc_msb = 0;
+            self.prev_poc_lsb = 0;
+        }
+        let max_lsb = 1i64 << header.sps.log2_max_pic_order_cnt_lsb;
+        let lsb = header.pic_order_cnt_lsb as i64;
+        let msb = if lsb < self.prev_poc_lsb && self.prev_poc_lsb - lsb >= max_lsb / 2 {
+            self.prev_poc_msb + max_lsb
+        } else if lsb > self.prev_poc_lsb && lsb - self.prev_poc_lsb > max_lsb / 2 {
+            self.prev_poc_msb - max_lsb
+        } else {
+            self.prev_poc_msb
+        };
+        if header.nal_ref_idc != 0 {
+            self.prev_poc_msb = msb;
+            self.prev_poc_lsb = lsb;
+        }
+
+        let top = msb + lsb;
+        let bottom = if header.field_pic {
+            top
+        } else {
+            top + header.delta_pic_order_cnt_bottom as i64
+        };
+        (top, bottom)
+    }
+
+    fn type_1(&mut self, header: &SliceHeader) -> (i64, i64) {
+        let sps = &header.sps;
+        let frame_num_offset = self.frame_num_offset(header);
+        let cycle_len = sps.offset_for_ref_frame.len() as i64;
+        let mut abs_frame_num = if cycle_len != 0 {
+            frame_num_offset + header.frame_num as i64
+        } else {
+            0
+        };
+        if header.nal_ref_idc == 0 && abs_frame_num > 0 {
+            abs_frame_num -= 1;
+        }
+
+        let mut expected_poc = 0;
+        if abs_frame_num > 0 {
+            let cycle = (abs_frame_num - 1) / cycle_len;
+            let frame_in_cycle = ((abs_frame_num - 1) % cycle_len) as usize;
+            let delta_per_cycle: i64 = sps.offset_for_ref_frame.iter().map(|&o| o as i64).sum();
+            expected_poc = cycle * delta_per_cycle
+                + sps.offset_for_ref_frame[..=frame_in_cycle]
+                    .iter()
+                    .map(|&o| o as i64)
+                    .sum::<i64>();
+        }
+        if header.nal_ref_idc == 0 {
+            expected_poc += sps.offset_for_non_ref_pic as i64;
+        }
+
+        let [delta_0, delta_1] = header.delta_pic_order_cnt.map(i64::from);
+        let top_to_bottom = sps.offset_for_top_to_bottom_field as i64;
+        if !header.field_pic {
+            let top = expected_poc + delta_0;
+            (top, top + top_to_bottom + delta_1)
+        } else if header.bottom_field {
+            let bottom = expected_poc + top_to_bottom + delta_0;
+            (bottom, bottom)
+        } else {
+            let top = expected_poc + delta_0;
+            (top, top)
+        }
+    }
+
+    fn type_2(&mut self, header: &SliceHeader) -> (i64, i64) {
+        let frame_num_offset = self.frame_num_offset(header);
+        let poc = if header.is_idr() {
+            0
+        } else if header.nal_ref_idc == 0 {
+            2 * (frame_num_offset + header.frame_num as i64) - 1
+        } else {
+            2 * (frame_num_offset + header.frame_num as i64)
+        };
+        (poc, poc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::params::{test_sps, Sps, SpsFields};
+    use std::sync::Arc;
+
+    /// Returns a frame's slice header, with `pic_order_cnt_lsb` set for type 0 and `frame_num` for
+    /// type 2.
+    fn header(sps: &Arc<Sps>, nal_unit_type: u8, nal_ref_idc: u8, count: u32) -> SliceHeader {
+        SliceHeader {
+            nal_unit_type,
+            nal_ref_idc,
+            frame_num: count,
+            field_pic: false,
+            bottom_field: false,
+            pic_order_cnt_lsb: count,
+            delta_pic_order_cnt_bottom: 0,
+            delta_pic_order_cnt: [0; 2],
+            has_mmco5: false,
+            sps: sps.clone(),
+        }
+    }
+
+    fn sps(pic_order_cnt_type: u32) -> Arc<Sps> {
+        Arc::new(
+            Sps::parse(&test_sps(SpsFields {
+                pic_order_cnt_type,
+                ..SpsFields::default()
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_type_0() {
+        // The least significant bits wrap at 64.
+        let sps = sps(0);
+        let mut state = PocState::default();
+        let pocs: Vec<_> = [(5, 0), (1, 60), (0, 62), (1, 4), (1, 8)]
+            .into_iter()
+            .map(|(nal_unit_type, lsb)| state.next(&header(&sps, nal_unit_type, 1, lsb)))
+            .collect();
+        assert_eq!(pocs, [0, -4, -2, 4, 8]);
+
+        // Memory management control operation 5 resets the count.
+        let mut mmco5 = header(&sps, 1, 1, 10);
+        mmco5.has_mmco5 = true;
+        assert_eq!(state.next(&mmco5), 0);
+        assert_eq!(state.next(&header(&sps, 1, 1, 2)), 2);
+    }
+
+    #[test]
+    fn test_type_2() {
+        // Frame numbers wrap at 16, and non-reference pictures come just before the next frame.
+        let sps = sps(2);
+        let mut state = PocState::default();
+        let pocs: Vec<_> = [(5, 1, 0), (1, 1, 1), (1, 0, 2), (1, 1, 15), (1, 1, 0)]
+            .into_iter()
+            .map(|(nal_unit_type, nal_ref_idc, frame_num)| {
+                state.next(&header(&sps, nal_unit_type, nal_ref_idc, frame_num))
+            })
+            .collect();
+        assert_eq!(pocs, [0, 2, 3, 30, 32]);
+    }
+}
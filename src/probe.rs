@@ -0,0 +1,240 @@
+use xcoder_quadra::decoder::{XcoderDecoderCodec, XcoderDecoderConfig};
+
+use crate::control::ControlMessage;
+use crate::framer::Codec;
+use crate::hevc::{self, HevcSps};
+use crate::params::{Rational, Sps};
+
+/// The frame rate to configure the decoder with when the SPS has no timing info, as for
+/// [`FramerConfig::frame_rate`](crate::FramerConfig::frame_rate).
+const DEFAULT_FRAME_RATE: f64 = 25.0;
+
+/// The properties of a stream that a decoder needs to be configured for, from its SPS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub codec: Codec,
+    /// The width of the decoded pictures after cropping.
+    pub width: u32,
+    /// The height of the decoded pictures after cropping.
+    pub height: u32,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub chroma_format_idc: u32,
+    /// `profile_idc` for H.264, `general_profile_idc` for H.265.
+    pub profile_idc: u8,
+    /// `level_idc` for H.264, `general_level_idc` for H.265.
+    pub level_idc: u8,
+    /// The frame rate signalled by the VUI timing info, if any.
+    pub frame_rate: Option<Rational>,
+}
+
+impl StreamInfo {
+    /// Reads the stream info from an SPS NAL unit. Returns `None` for other NAL units, and for
+    /// an SPS that fails to parse.
+    pub(crate) fn from_nalu(codec: Codec, nalu: &[u8]) -> Option<Self> {
+        let &header = nalu.first()?;
+        match codec {
+            Codec::H264 if header & 0x1f == 7 => Sps::parse(nalu).ok().map(|sps| Self::from(&sps)),
+            Codec::H265 if hevc::nal_unit_type(header) == hevc::SPS => {
+                HevcSps::parse(nalu).ok().map(|sps| Self::from(&sps))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns a decoder configuration for the stream, on no particular hardware. Streams without
+    /// a frame rate are configured for 25 fps.
+    pub fn decoder_config(&self) -> XcoderDecoderConfig {
+        XcoderDecoderConfig {
+            width: self.width as _,
+            height: self.height as _,
+            codec: match self.codec {
+                Codec::H264 => XcoderDecoderCodec::H264,
+                Codec::H265 => XcoderDecoderCodec::H265,
+            },
+            bit_depth: self.bit_depth_luma,
+            fps: self
+                .frame_rate
+                .map_or(DEFAULT_FRAME_RATE, |frame_rate| frame_rate.as_f64()),
+            hardware_id: None,
+            multicore_joint_mode: false,
+        }
+    }
+}
+
+impl From<&Sps> for StreamInfo {
+    fn from(sps: &Sps) -> Self {
+        Self {
+            codec: Codec::H264,
+            width: sps.width(),
+            height: sps.height(),
+            bit_depth_luma: sps.bit_depth_luma,
+            bit_depth_chroma: sps.bit_depth_chroma,
+            chroma_format_idc: sps.chroma_format_idc,
+            profile_idc: sps.profile_idc,
+            level_idc: sps.level_idc,
+            frame_rate: sps.frame_rate(),
+        }
+    }
+}
+
+impl From<&HevcSps> for StreamInfo {
+    fn from(sps: &HevcSps) -> Self {
+        Self {
+            codec: Codec::H265,
+            width: sps.width(),
+            height: sps.height(),
+            bit_depth_luma: sps.bit_depth_luma,
+            bit_depth_chroma: sps.bit_depth_chroma,
+            chroma_format_idc: sps.chroma_format_idc,
+            profile_idc: sps.general_profile_idc,
+            level_idc: sps.general_level_idc,
+            frame_rate: sps.frame_rate(),
+        }
+    }
+}
+
+/// A change of SPS that calls for a new decoder: the resolution, bit depth, profile or chroma
+/// format differ from those of the previous SPS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamChange {
+    pub previous: StreamInfo,
+    pub current: StreamInfo,
+}
+
+impl StreamChange {
+    /// Returns the change if the two SPSs differ in a way the decoder can't follow.
+    pub(crate) fn between(previous: StreamInfo, current: StreamInfo) -> Option<Self> {
+        let material = |info: &StreamInfo| {
+            (
+                info.codec,
+                info.width,
+                info.height,
+                info.bit_depth_luma,
+                info.bit_depth_chroma,
+                info.profile_idc,
+                info.chroma_format_idc,
+            )
+        };
+        (material(&previous) != material(&current)).then_some(Self { previous, current })
+    }
+
+    /// Returns the message that tells the pipeline to rebuild the decoder for the new stream.
+    pub fn control_message(&self) -> ControlMessage {
+        ControlMessage::Reconfigure(self.current.decoder_config())
+    }
+}
+
+/// Scans the start of an Annex B stream for its first SPS and returns the stream info from it.
+/// The buffer needs to hold the whole SPS. See
+/// [`AnnexBFramer::stream_info`](crate::AnnexBFramer::stream_info) for input that arrives
+/// incrementally.
+pub fn probe(buf: &[u8], codec: Codec) -> Option<StreamInfo> {
+    h264::iterate_annex_b(buf).find_map(|nalu| StreamInfo::from_nalu(codec, nalu))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::avcc::annex_b;
+    use crate::bits::BitWriter;
+    use crate::{read_frames_with_metadata, AnnexBFramer, FramerConfig};
+
+    /// Builds a High 10 SPS at 29.97 fps, 720p unless the size is given in macroblocks.
+    fn sps() -> Vec<u8> {
+        sized_sps(80, 45)
+    }
+
+    fn sized_sps(width_in_mbs: u32, height_in_mbs: u32) -> Vec<u8> {
+        BitWriter::default()
+            .bits(8, 0x67)
+            .bits(8, 110)
+            .bits(8, 0)
+            .bits(8, 31)
+            .ue(0)
+            // chroma_format_idc, bit_depth_luma_minus8 and bit_depth_chroma_minus8
+            .ue(1)
+            .ue(2)
+            .ue(2)
+            // qpprime_y_zero_transform_bypass_flag and seq_scaling_matrix_present_flag
+            .bits(2, 0)
+            .ue(0)
+            // pic_order_cnt_type
+            .ue(2)
+            .ue(1)
+            .flag(false)
+            .ue(width_in_mbs - 1)
+            .ue(height_in_mbs - 1)
+            .flag(true)
+            .flag(true)
+            .flag(false)
+            // vui_parameters_present_flag, then everything up to the timing info absent
+            .flag(true)
+            .bits(4, 0)
+            .flag(true)
+            .bits(32, 1001)
+            .bits(32, 60000)
+            .flag(true)
+            .bits(4, 0)
+            .finish()
+    }
+
+    #[test]
+    fn test_probe() {
+        let stream = [&[0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1][..], &sps()].concat();
+        let info = probe(&stream, Codec::H264).unwrap();
+        assert_eq!(
+            info,
+            StreamInfo {
+                codec: Codec::H264,
+                width: 1280,
+                height: 720,
+                bit_depth_luma: 10,
+                bit_depth_chroma: 10,
+                chroma_format_idc: 1,
+                profile_idc: 110,
+                level_idc: 31,
+                frame_rate: Some(Rational::new(60000, 2002)),
+            }
+        );
+        let config = info.decoder_config();
+        assert_eq!((config.width, config.height), (1280, 720));
+        assert_eq!(config.bit_depth, 10);
+        assert!((config.fps - 29.97).abs() < 0.001);
+        assert!(matches!(config.codec, XcoderDecoderCodec::H264));
+
+        assert_eq!(probe(&stream[..6], Codec::H264), None);
+        assert_eq!(probe(&stream, Codec::H265), None);
+
+        let mut framer = AnnexBFramer::new();
+        assert_eq!(framer.push(&stream[..10]).count(), 0);
+        assert_eq!(framer.stream_info(), None);
+        // The SPS is only parsed once it's known to be complete.
+        assert_eq!(framer.push(&stream[10..]).count(), 0);
+        assert_eq!(framer.push(&[0, 0, 1]).count(), 0);
+        assert_eq!(framer.stream_info(), Some(info));
+    }
+
+    #[test]
+    fn test_stream_change() {
+        let idr: &[u8] = &[0x65, 0x88, 0x84];
+        let (sps, larger_sps) = (sps(), sized_sps(120, 68));
+        let stream = annex_b(&[&sps, idr, &sps, idr, &larger_sps, idr, idr]);
+        let changes: Vec<_> = read_frames_with_metadata(&stream, FramerConfig::default())
+            .into_iter()
+            .map(|result| result.unwrap().1.stream_change)
+            .collect();
+
+        // Repeating the same SPS isn't a change.
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[..2], [None, None]);
+        assert_eq!(changes[3], None);
+        let change = changes[2].unwrap();
+        assert_eq!((change.previous.width, change.previous.height), (1280, 720));
+        assert_eq!((change.current.width, change.current.height), (1920, 1088));
+        let ControlMessage::Reconfigure(config) = change.control_message() else {
+            panic!("expected a reconfiguration");
+        };
+        assert_eq!((config.width, config.height), (1920, 1088));
+    }
+}
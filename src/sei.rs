@@ -0,0 +1,628 @@
+use std::io;
+
+use crate::bits::BitReader;
+use crate::framer::Codec;
+use crate::hevc::HevcSps;
+use crate::params::{HrdParameters, Sps};
+
+const PIC_TIMING: u32 = 1;
+const USER_DATA_REGISTERED: u32 = 4;
+const USER_DATA_UNREGISTERED: u32 = 5;
+const RECOVERY_POINT: u32 = 6;
+const TIME_CODE: u32 = 136;
+const MASTERING_DISPLAY_COLOUR_VOLUME: u32 = 137;
+const CONTENT_LIGHT_LEVEL: u32 = 144;
+
+/// The ITU-T T.35 country code of the United States, under which ATSC registers its captions.
+const COUNTRY_CODE_US: u8 = 0xb5;
+/// The ATSC provider code, followed by the A/53 user identifier and the `cc_data` type code.
+const ATSC_CAPTIONS: [u8; 7] = [0x00, 0x31, b'G', b'A', b'9', b'4', 0x03];
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// An SEI message of one of the payload types that are parsed. Other payload types are skipped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SeiMessage {
+    PicTiming(PicTiming),
+    UserDataRegistered(UserDataRegistered),
+    UserDataUnregistered(UserDataUnregistered),
+    RecoveryPoint(RecoveryPoint),
+    /// The clock timestamps of an H.265 time code message.
+    TimeCode(Vec<TimeCode>),
+    MasteringDisplayColourVolume(MasteringDisplayColourVolume),
+    ContentLightLevel(ContentLightLevel),
+}
+
+/// A picture timing message. The fields that depend on the HRD parameters are only read for
+/// H.264; for H.265, the message is read up to the frame-field info.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PicTiming {
+    pub cpb_removal_delay: Option<u32>,
+    pub dpb_output_delay: Option<u32>,
+    pub pic_struct: Option<u8>,
+    /// `source_scan_type`, for H.265 only.
+    pub source_scan_type: Option<u8>,
+    /// `duplicate_flag`, for H.265 only.
+    pub duplicate: bool,
+    /// The clock timestamps, for H.264 only.
+    pub time_codes: Vec<TimeCode>,
+}
+
+/// A clock timestamp, as carried by H.264 picture timing and H.265 time code messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeCode {
+    pub counting_type: u8,
+    pub discontinuity: bool,
+    /// `cnt_dropped_flag`, set when frame counts were skipped for drop-frame counting.
+    pub drop_frame: bool,
+    pub frames: u16,
+    /// The seconds, minutes and hours, when sent. Those that aren't are unchanged from the previous
+    /// time code.
+    pub seconds: Option<u8>,
+    pub minutes: Option<u8>,
+    pub hours: Option<u8>,
+    pub time_offset: i32,
+}
+
+/// User data registered by ITU-T T.35, which carries CEA-608 and CEA-708 captions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserDataRegistered {
+    pub country_code: u8,
+    /// Present when `country_code` is 0xff.
+    pub country_code_extension: Option<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// A caption data triplet of an ATSC A/53 `cc_data` structure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CcData {
+    pub valid: bool,
+    /// 0 and 1 for CEA-608 data of the first and second field, 2 and 3 for CEA-708 DTVCC packet
+    /// data and packet starts.
+    pub cc_type: u8,
+    pub data: [u8; 2],
+}
+
+impl UserDataRegistered {
+    /// Returns the caption data triplets, if this is an ATSC A/53 `cc_data` message with caption
+    /// data to process.
+    pub fn cc_data(&self) -> Option<Vec<CcData>> {
+        if self.country_code != COUNTRY_CODE_US {
+            return None;
+        }
+        let rest = self.payload.strip_prefix(&ATSC_CAPTIONS[..])?;
+        let (&flags, rest) = rest.split_first()?;
+        if flags & 0x40 == 0 {
+            return None;
+        }
+        let cc_count = usize::from(flags & 0x1f);
+        // em_data
+        let triplets = rest.get(1..1 + 3 * cc_count)?;
+        Some(
+            triplets
+                .chunks_exact(3)
+                .map(|triplet| CcData {
+                    valid: triplet[0] & 0x04 != 0,
+                    cc_type: triplet[0] & 0x03,
+                    data: [triplet[1], triplet[2]],
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserDataUnregistered {
+    pub uuid: [u8; 16],
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecoveryPoint {
+    /// `recovery_frame_cnt` for H.264, `recovery_poc_cnt` for H.265.
+    pub recovery_count: i32,
+    pub exact_match: bool,
+    pub broken_link: bool,
+}
+
+/// The colour volume of the mastering display, as for HDR10.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MasteringDisplayColourVolume {
+    /// The x and y chromaticity coordinates of the primaries, in increments of 0.00002, in the
+    /// order they're coded: usually green, blue, then red.
+    pub display_primaries: [[u16; 2]; 3],
+    pub white_point: [u16; 2],
+    /// The luminances, in increments of 0.0001 cd/m².
+    pub max_display_mastering_luminance: u32,
+    pub min_display_mastering_luminance: u32,
+}
+
+/// The content light levels, in cd/m².
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContentLightLevel {
+    pub max_content_light_level: u16,
+    pub max_pic_average_light_level: u16,
+}
+
+/// What picture timing messages depend on in the active SPS.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum PicTimingSyntax {
+    H264 {
+        hrd: Option<HrdParameters>,
+        pic_struct_present: bool,
+    },
+    H265 {
+        frame_field_info_present: bool,
+    },
+}
+
+impl From<&Sps> for PicTimingSyntax {
+    fn from(sps: &Sps) -> Self {
+        let vui = sps.vui.as_ref();
+        Self::H264 {
+            hrd: vui.and_then(|vui| vui.nal_hrd.or(vui.vcl_hrd)),
+            pic_struct_present: vui.is_some_and(|vui| vui.pic_struct_present),
+        }
+    }
+}
+
+impl From<&HevcSps> for PicTimingSyntax {
+    fn from(sps: &HevcSps) -> Self {
+        Self::H265 {
+            frame_field_info_present: sps.frame_field_info_present,
+        }
+    }
+}
+
+/// Parses the messages of an SEI NAL unit from its RBSP, after the NAL unit header. Picture timing
+/// messages are skipped without the syntax of the active SPS, and malformed messages are
+/// skipped.
+pub(crate) fn parse(
+    codec: Codec,
+    rbsp: &[u8],
+    pic_timing: Option<&PicTimingSyntax>,
+) -> Vec<SeiMessage> {
+    messages(rbsp)
+        .filter_map(|(payload_type, payload)| {
+            parse_message(codec, payload_type, payload, pic_timing)
+                .ok()
+                .flatten()
+        })
+        .collect()
+}
+
+fn parse_message(
+    codec: Codec,
+    payload_type: u32,
+    payload: &[u8],
+    pic_timing: Option<&PicTimingSyntax>,
+) -> io::Result<Option<SeiMessage>> {
+    let mut reader = BitReader::new(payload);
+    let message = match payload_type {
+        PIC_TIMING => match pic_timing {
+            Some(syntax) => SeiMessage::PicTiming(parse_pic_timing(&mut reader, syntax)?),
+            None => return Ok(None),
+        },
+        USER_DATA_REGISTERED => {
+            let (&country_code, rest) = payload
+                .split_first()
+                .ok_or_else(|| invalid("empty user data"))?;
+            let (country_code_extension, payload) = match rest.split_first() {
+                Some((&extension, payload)) if country_code == 0xff => (Some(extension), payload),
+                _ => (None, rest),
+            };
+            SeiMessage::UserDataRegistered(UserDataRegistered {
+                country_code,
+                country_code_extension,
+                payload: payload.to_vec(),
+            })
+        }
+        USER_DATA_UNREGISTERED => {
+            if payload.len() < 16 {
+                return Err(invalid("user data shorter than its UUID"));
+            }
+            let (uuid, payload) = payload.split_at(16);
+            SeiMessage::UserDataUnregistered(UserDataUnregistered {
+                uuid: uuid.try_into().unwrap(),
+                payload: payload.to_vec(),
+            })
+        }
+        RECOVERY_POINT => SeiMessage::RecoveryPoint(RecoveryPoint {
+            recovery_count: match codec {
+                Codec::H264 => reader.read_ue()? as i32,
+                Codec::H265 => reader.read_se()?,
+            },
+            exact_match: reader.read_flag()?,
+            broken_link: reader.read_flag()?,
+        }),
+        TIME_CODE => {
+            let mut time_codes = Vec::new();
+            for _ in 0..reader.read_bits(2)? {
+                if reader.read_flag()? {
+                    time_codes.push(read_time_code(&mut reader, Codec::H265, 0)?);
+                }
+            }
+            SeiMessage::TimeCode(time_codes)
+        }
+        MASTERING_DISPLAY_COLOUR_VOLUME => {
+            let mut read_xy = || -> io::Result<[u16; 2]> {
+                Ok([reader.read_bits(16)? as u16, reader.read_bits(16)? as u16])
+            };
+            let display_primaries = [read_xy()?, read_xy()?, read_xy()?];
+            let white_point = read_xy()?;
+            SeiMessage::MasteringDisplayColourVolume(MasteringDisplayColourVolume {
+                display_primaries,
+                white_point,
+                max_display_mastering_luminance: reader.read_bits(32)?,
+                min_display_mastering_luminance: reader.read_bits(32)?,
+            })
+        }
+        CONTENT_LIGHT_LEVEL => SeiMessage::ContentLightLevel(ContentLightLevel {
+            max_content_light_level: reader.read_bits(16)? as u16,
+            max_pic_average_light_level: reader.read_bits(16)? as u16,
+        }),
+        _ => return Ok(None),
+    };
+    Ok(Some(message))
+}
+
+fn parse_pic_timing(reader: &mut BitReader, syntax: &PicTimingSyntax) -> io::Result<PicTiming> {
+    let mut pic_timing = PicTiming::default();
+    match *syntax {
+        PicTimingSyntax::H264 {
+            hrd,
+            pic_struct_present,
+        } => {
+            if let Some(hrd) = hrd {
+                pic_timing.cpb_removal_delay =
+                    Some(reader.read_bits(hrd.cpb_removal_delay_length)?);
+                pic_timing.dpb_output_delay = Some(reader.read_bits(hrd.dpb_output_delay_length)?);
+            }
+            if pic_struct_present {
+                let pic_struct = reader.read_bits(4)? as u8;
+                let num_clock_ts = match pic_struct {
+                    0..=2 => 1,
+                    3 | 4 | 7 => 2,
+                    5 | 6 | 8 => 3,
+                    _ => return Err(invalid("pic_struct out of range")),
+                };
+                pic_timing.pic_struct = Some(pic_struct);
+                // Without HRD parameters, time offsets are 24 bits long.
+                let time_offset_length = hrd.map_or(24, |hrd| hrd.time_offset_length);
+                for _ in 0..num_clock_ts {
+                    if reader.read_flag()? {
+                        let time_code = read_time_code(reader, Codec::H264, time_offset_length)?;
+                        pic_timing.time_codes.push(time_code);
+                    }
+                }
+            }
+        }
+        PicTimingSyntax::H265 {
+            frame_field_info_present,
+        } => {
+            if frame_field_info_present {
+                pic_timing.pic_struct = Some(reader.read_bits(4)? as u8);
+                pic_timing.source_scan_type = Some(reader.read_bits(2)? as u8);
+                pic_timing.duplicate = reader.read_flag()?;
+            }
+        }
+    }
+    Ok(pic_timing)
+}
+
+/// Reads a clock timestamp, after its `clock_timestamp_flag`. H.265 codes the length of the time
+/// offset in the timestamp, while H.264 takes it from the HRD parameters.
+fn read_time_code(
+    reader: &mut BitReader,
+    codec: Codec,
+    time_offset_length: u32,
+) -> io::Result<TimeCode> {
+    let frames_bits = match codec {
+        Codec::H264 => {
+            // ct_type and nuit_field_based_flag
+            reader.skip_bits(3)?;
+            8
+        }
+        Codec::H265 => {
+            // units_field_based_flag
+            reader.skip_bits(1)?;
+            9
+        }
+    };
+    let counting_type = reader.read_bits(5)? as u8;
+    let full_timestamp = reader.read_flag()?;
+    let discontinuity = reader.read_flag()?;
+    let drop_frame = reader.read_flag()?;
+    let frames = reader.read_bits(frames_bits)? as u16;
+    let (mut seconds, mut minutes, mut hours) = (None, None, None);
+    if full_timestamp {
+        seconds = Some(reader.read_bits(6)? as u8);
+        minutes = Some(reader.read_bits(6)? as u8);
+        hours = Some(reader.read_bits(5)? as u8);
+    } else if reader.read_flag()? {
+        seconds = Some(reader.read_bits(6)? as u8);
+        if reader.read_flag()? {
+            minutes = Some(reader.read_bits(6)? as u8);
+            if reader.read_flag()? {
+                hours = Some(reader.read_bits(5)? as u8);
+            }
+        }
+    }
+    let time_offset_length = match codec {
+        Codec::H264 => time_offset_length,
+        Codec::H265 => reader.read_bits(5)?,
+    };
+    let time_offset = match time_offset_length {
+        0 => 0,
+        n => {
+            let value = reader.read_bits(n)?;
+            // Sign-extend the n-bit value.
+            ((value << (32 - n)) as i32) >> (32 - n)
+        }
+    };
+    Ok(TimeCode {
+        counting_type,
+        discontinuity,
+        drop_frame,
+        frames,
+        seconds,
+        minutes,
+        hours,
+        time_offset,
+    })
+}
+
+/// Splits the RBSP of an SEI NAL unit, after its header, into `(payloadType, payload)` pairs.
+/// Splitting stops at the trailing bits, or at a message that overruns the RBSP.
+pub(crate) fn messages(rbsp: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    let mut rest = rbsp;
+    std::iter::from_fn(move || {
+        if rest.is_empty() || rest == [0x80] {
+            return None;
+        }
+        let payload_type = read_value(&mut rest)?;
+        let payload_size = read_value(&mut rest)? as usize;
+        if payload_size > rest.len() {
+            return None;
+        }
+        let (payload, tail) = rest.split_at(payload_size);
+        rest = tail;
+        Some((payload_type, payload))
+    })
+}
+
+/// Splits an SEI RBSP into its messages, or returns `None` unless they run exactly up to the
+/// trailing bits.
+pub(crate) fn all_messages(rbsp: &[u8]) -> Option<Vec<(u32, &[u8])>> {
+    let mut rest = rbsp;
+    let mut messages = Vec::new();
+    while rest != [0x80] {
+        let payload_type = read_value(&mut rest)?;
+        let payload_size = read_value(&mut rest)? as usize;
+        let payload = rest.get(..payload_size)?;
+        rest = &rest[payload_size..];
+        messages.push((payload_type, payload));
+    }
+    Some(messages)
+}
+
+/// Reads a `payloadType` or `payloadSize`, coded as a run of 0xff bytes and a last byte that are
+/// summed.
+fn read_value(buf: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    loop {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value = value.checked_add(byte.into())?;
+        if byte != 0xff {
+            return Some(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::avcc::annex_b;
+    use crate::bits::BitWriter;
+    use crate::{read_frames_with_metadata, FramerConfig};
+
+    fn message(payload_type: u8, payload: &[u8]) -> Vec<u8> {
+        [&[payload_type, payload.len() as u8][..], payload].concat()
+    }
+
+    const CAPTIONS: &[u8] = &[
+        0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03, 0x42, 0xff, 0xfc, 0x94, 0x2c, 0xf9, 0x80,
+        0x80, 0xff,
+    ];
+
+    #[test]
+    fn test_parse() {
+        let pic_timing = BitWriter::default()
+            .bits(24, 2)
+            .bits(24, 4)
+            // pic_struct, clock_timestamp_flag, ct_type and nuit_field_based_flag
+            .bits(4, 0)
+            .flag(true)
+            .bits(3, 0)
+            .bits(5, 4)
+            // full_timestamp_flag, discontinuity_flag and cnt_dropped_flag
+            .bits(3, 0b101)
+            .bits(8, 29)
+            .bits(6, 59)
+            .bits(6, 9)
+            .bits(5, 1)
+            .trailing_bits();
+        let uuid = [0x11; 16];
+        let rbsp = [
+            message(1, &pic_timing),
+            message(4, CAPTIONS),
+            message(5, &[&uuid[..], b"x264"].concat()),
+            // An unknown payload type past 255, then a truncated recovery point.
+            vec![0xff, 0x01, 0x01, 0x00],
+            message(6, &[]),
+            message(
+                6,
+                &BitWriter::default().ue(3).bits(3, 0b100).trailing_bits(),
+            ),
+            message(
+                137,
+                &[
+                    0x33, 0xc2, 0x86, 0xc4, 0x1d, 0x4c, 0x0b, 0xb8, 0x84, 0xd0, 0x3e, 0x80, 0x3d,
+                    0x13, 0x40, 0x42, 0x00, 0x98, 0x96, 0x80, 0x00, 0x00, 0x00, 0x32,
+                ],
+            ),
+            message(144, &[0x03, 0xe8, 0x01, 0x90]),
+            vec![0x80],
+        ]
+        .concat();
+        let syntax = PicTimingSyntax::H264 {
+            hrd: Some(HrdParameters {
+                cpb_cnt: 1,
+                initial_cpb_removal_delay_length: 24,
+                cpb_removal_delay_length: 24,
+                dpb_output_delay_length: 24,
+                time_offset_length: 0,
+            }),
+            pic_struct_present: true,
+        };
+        let messages = parse(Codec::H264, &rbsp, Some(&syntax));
+
+        assert_eq!(
+            messages,
+            [
+                SeiMessage::PicTiming(PicTiming {
+                    cpb_removal_delay: Some(2),
+                    dpb_output_delay: Some(4),
+                    pic_struct: Some(0),
+                    source_scan_type: None,
+                    duplicate: false,
+                    time_codes: vec![TimeCode {
+                        counting_type: 4,
+                        discontinuity: false,
+                        drop_frame: true,
+                        frames: 29,
+                        seconds: Some(59),
+                        minutes: Some(9),
+                        hours: Some(1),
+                        time_offset: 0,
+                    }],
+                }),
+                SeiMessage::UserDataRegistered(UserDataRegistered {
+                    country_code: 0xb5,
+                    country_code_extension: None,
+                    payload: CAPTIONS[1..].to_vec(),
+                }),
+                SeiMessage::UserDataUnregistered(UserDataUnregistered {
+                    uuid,
+                    payload: b"x264".to_vec(),
+                }),
+                SeiMessage::RecoveryPoint(RecoveryPoint {
+                    recovery_count: 3,
+                    exact_match: true,
+                    broken_link: false,
+                }),
+                SeiMessage::MasteringDisplayColourVolume(MasteringDisplayColourVolume {
+                    display_primaries: [[13250, 34500], [7500, 3000], [34000, 16000]],
+                    white_point: [15635, 16450],
+                    max_display_mastering_luminance: 10_000_000,
+                    min_display_mastering_luminance: 50,
+                }),
+                SeiMessage::ContentLightLevel(ContentLightLevel {
+                    max_content_light_level: 1000,
+                    max_pic_average_light_level: 400,
+                }),
+            ]
+        );
+        let SeiMessage::UserDataRegistered(user_data) = &messages[1] else {
+            unreachable!();
+        };
+        assert_eq!(
+            user_data.cc_data(),
+            Some(vec![
+                CcData {
+                    valid: true,
+                    cc_type: 0,
+                    data: [0x94, 0x2c],
+                },
+                CcData {
+                    valid: false,
+                    cc_type: 1,
+                    data: [0x80, 0x80],
+                },
+            ])
+        );
+
+        // Picture timing can't be parsed without the SPS.
+        assert_eq!(parse(Codec::H264, &rbsp, None).len(), messages.len() - 1);
+    }
+
+    #[test]
+    fn test_hevc_time_code() {
+        let time_code = BitWriter::default()
+            // num_clock_ts, clock_timestamp_flag and units_field_based_flag
+            .bits(2, 1)
+            .bits(2, 0b10)
+            .bits(5, 0)
+            .bits(3, 0)
+            .bits(9, 100)
+            // seconds_flag, seconds_value and minutes_flag
+            .flag(true)
+            .bits(6, 5)
+            .flag(false)
+            .bits(5, 4)
+            .bits(4, 0b1111)
+            .trailing_bits();
+        let pic_timing = BitWriter::default()
+            .bits(4, 1)
+            .bits(2, 2)
+            .flag(true)
+            .trailing_bits();
+        let rbsp = [message(136, &time_code), message(1, &pic_timing)].concat();
+        let syntax = PicTimingSyntax::H265 {
+            frame_field_info_present: true,
+        };
+
+        assert_eq!(
+            parse(Codec::H265, &rbsp, Some(&syntax)),
+            [
+                SeiMessage::TimeCode(vec![TimeCode {
+                    counting_type: 0,
+                    discontinuity: false,
+                    drop_frame: false,
+                    frames: 100,
+                    seconds: Some(5),
+                    minutes: None,
+                    hours: None,
+                    time_offset: -1,
+                }]),
+                SeiMessage::PicTiming(PicTiming {
+                    pic_struct: Some(1),
+                    source_scan_type: Some(2),
+                    duplicate: true,
+                    ..PicTiming::default()
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_frame_metadata() {
+        let sei = [&[0x06][..], &message(4, CAPTIONS), &[0x80]].concat();
+        let stream = annex_b(&[&sei, &[0x65, 0x88, 0x84], &[0x41, 0x9a, 0x02]]);
+        let metadata: Vec<_> = read_frames_with_metadata(&stream, FramerConfig::default())
+            .into_iter()
+            .map(|result| result.unwrap().1)
+            .collect();
+
+        // The messages go with the frame they precede.
+        assert_eq!(metadata.len(), 2);
+        assert!(matches!(
+            &metadata[0].sei[..],
+            [SeiMessage::UserDataRegistered(user_data)] if user_data.cc_data().unwrap().len() == 2
+        ));
+        assert!(metadata[1].sei.is_empty());
+    }
+}
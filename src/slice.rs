@@ -0,0 +1,357 @@
+use crate::bits::{header_rbsp, to_rbsp, BitReader};
+use crate::params::{Pps, Sps};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The parameter sets seen so far in a stream, by id.
+#[derive(Clone, Default)]
+pub(crate) struct ParameterSets {
+    sps: HashMap<u32, Arc<Sps>>,
+    pps: HashMap<u32, Arc<Pps>>,
+}
+
+impl ParameterSets {
+    pub fn insert_sps(&mut self, sps: Sps) {
+        self.sps.insert(sps.seq_parameter_set_id, Arc::new(sps));
+    }
+
+    pub fn insert_pps(&mut self, pps: Pps) {
+        self.pps.insert(pps.pic_parameter_set_id, Arc::new(pps));
+    }
+
+    /// Returns the PPS with the given id and the SPS it refers to.
+    pub fn get(&self, pps_id: u32) -> Option<(&Arc<Sps>, &Arc<Pps>)> {
+        let pps = self.pps.get(&pps_id)?;
+        Some((self.sps.get(&pps.seq_parameter_set_id)?, pps))
+    }
+}
+
+/// The type of a coded slice. H.265 only has P, B and I slices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SliceType {
+    P,
+    B,
+    I,
+    Sp,
+    Si,
+}
+
+impl SliceType {
+    fn from_raw(slice_type: u32) -> io::Result<Self> {
+        if slice_type > 9 {
+            return Err(invalid("slice_type out of range"));
+        }
+        Ok(match slice_type % 5 {
+            0 => Self::P,
+            1 => Self::B,
+            2 => Self::I,
+            3 => Self::Sp,
+            _ => Self::Si,
+        })
+    }
+
+    /// Reads the type of an H.264 slice from its NAL unit, including its header byte. Unlike the
+    /// rest of the slice header, it doesn't depend on the parameter sets.
+    pub(crate) fn parse(nalu: &[u8]) -> io::Result<Self> {
+        let rbsp = header_rbsp(nalu, 16);
+        let mut reader = BitReader::new(&rbsp);
+        if !matches!(reader.read_bits(8)? & 0x1f, 1 | 5) {
+            return Err(invalid("not a coded slice"));
+        }
+        // first_mb_in_slice
+        reader.read_ue()?;
+        Self::from_raw(reader.read_ue()?)
+    }
+}
+
+/// The start of an H.264 slice header, up to and including the reference picture marking.
+#[derive(Clone, Debug)]
+pub(crate) struct SliceHeader {
+    pub nal_unit_type: u8,
+    pub nal_ref_idc: u8,
+    pub frame_num: u32,
+    pub field_pic: bool,
+    pub bottom_field: bool,
+    pub pic_order_cnt_lsb: u32,
+    pub delta_pic_order_cnt_bottom: i32,
+    pub delta_pic_order_cnt: [i32; 2],
+    /// Whether the reference picture marking includes `memory_management_control_operation` 5,
+    /// which resets the picture order count and frame numbering like an IDR picture.
+    pub has_mmco5: bool,
+    pub sps: Arc<Sps>,
+}
+
+impl SliceHeader {
+    pub fn is_idr(&self) -> bool {
+        self.nal_unit_type == 5
+    }
+
+    /// Parses the header of a coded slice NAL unit (type 1 or 5), including its header byte.
+    pub fn parse(nalu: &[u8], parameter_sets: &ParameterSets) -> io::Result<Self> {
+        let rbsp = to_rbsp(nalu);
+        let mut reader = BitReader::new(&rbsp);
+        let header = reader.read_bits(8)? as u8;
+        let nal_unit_type = header & 0x1f;
+        let nal_ref_idc = header >> 5 & 0x3;
+        if !matches!(nal_unit_type, 1 | 5) {
+            return Err(invalid("not a coded slice"));
+        }
+
+        // first_mb_in_slice
+        reader.read_ue()?;
+        let slice_type = SliceType::from_raw(reader.read_ue()?)?;
+        let pic_parameter_set_id = reader.read_ue()?;
+        let (sps, pps) = parameter_sets
+            .get(pic_parameter_set_id)
+            .ok_or_else(|| invalid("slice refers to an unknown parameter set"))?;
+        let (sps, pps) = (sps.clone(), pps.clone());
+
+        if sps.separate_colour_plane {
+            // colour_plane_id
+            reader.skip_bits(2)?;
+        }
+        let frame_num = reader.read_bits(sps.log2_max_frame_num)?;
+        let mut field_pic = false;
+        let mut bottom_field = false;
+        if !sps.frame_mbs_only {
+            field_pic = reader.read_flag()?;
+            if field_pic {
+                bottom_field = reader.read_flag()?;
+            }
+        }
+        if nal_unit_type == 5 {
+            // idr_pic_id
+            reader.read_ue()?;
+        }
+        let mut pic_order_cnt_lsb = 0;
+        let mut delta_pic_order_cnt_bottom = 0;
+        let mut delta_pic_order_cnt = [0; 2];
+        if sps.pic_order_cnt_type == 0 {
+            pic_order_cnt_lsb = reader.read_bits(sps.log2_max_pic_order_cnt_lsb)?;
+            if pps.bottom_field_pic_order_in_frame_present && !field_pic {
+                delta_pic_order_cnt_bottom = reader.read_se()?;
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero {
+            delta_pic_order_cnt[0] = reader.read_se()?;
+            if pps.bottom_field_pic_order_in_frame_present && !field_pic {
+                delta_pic_order_cnt[1] = reader.read_se()?;
+            }
+        }
+        if pps.redundant_pic_cnt_present {
+            // redundant_pic_cnt
+            reader.read_ue()?;
+        }
+
+        let has_mmco5 = if nal_ref_idc != 0 {
+            Self::skip_to_ref_pic_marking(&mut reader, slice_type, &sps, &pps)?;
+            Self::parse_mmco5(&mut reader, nal_unit_type == 5)?
+        } else {
+            false
+        };
+
+        Ok(Self {
+            nal_unit_type,
+            nal_ref_idc,
+            frame_num,
+            field_pic,
+            bottom_field,
+            pic_order_cnt_lsb,
+            delta_pic_order_cnt_bottom,
+            delta_pic_order_cnt,
+            has_mmco5,
+            sps,
+        })
+    }
+
+    fn skip_to_ref_pic_marking(
+        reader: &mut BitReader,
+        slice_type: SliceType,
+        sps: &Sps,
+        pps: &Pps,
+    ) -> io::Result<()> {
+        let is_b = slice_type == SliceType::B;
+        let is_p = matches!(slice_type, SliceType::P | SliceType::Sp);
+        if is_b {
+            // direct_spatial_mv_pred_flag
+            reader.skip_bits(1)?;
+        }
+        let mut num_ref_idx_active = [
+            pps.num_ref_idx_l0_default_active,
+            pps.num_ref_idx_l1_default_active,
+        ];
+        if (is_p || is_b) && reader.read_flag()? {
+            let lists = if is_b { 2 } else { 1 };
+            for n in &mut num_ref_idx_active[..lists] {
+                *n = reader
+                    .read_ue()?
+                    .checked_add(1)
+                    .filter(|&n| n <= 32)
+                    .ok_or_else(|| invalid("num_ref_idx_active out of range"))?;
+            }
+        }
+        let lists = if is_b {
+            2
+        } else if is_p {
+            1
+        } else {
+            0
+        };
+
+        // ref_pic_list_modification
+        for _ in 0..lists {
+            if reader.read_flag()? {
+                loop {
+                    match reader.read_ue()? {
+                        3 => break,
+                        0..=2 => {
+                            // abs_diff_pic_num_minus1 or long_term_pic_num
+                            reader.read_ue()?;
+                        }
+                        _ => return Err(invalid("modification_of_pic_nums_idc out of range")),
+                    }
+                }
+            }
+        }
+
+        // pred_weight_table
+        if pps.weighted_pred && is_p || pps.weighted_bipred_idc == 1 && is_b {
+            let has_chroma = sps.chroma_array_type() != 0;
+            // luma_log2_weight_denom
+            reader.read_ue()?;
+            if has_chroma {
+                // chroma_log2_weight_denom
+                reader.read_ue()?;
+            }
+            for &count in &num_ref_idx_active[..lists] {
+                for _ in 0..count {
+                    if reader.read_flag()? {
+                        // luma_weight and luma_offset
+                        reader.read_se()?;
+                        reader.read_se()?;
+                    }
+                    if has_chroma && reader.read_flag()? {
+                        for _ in 0..4 {
+                            reader.read_se()?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses `dec_ref_pic_marking`, returning whether it includes operation 5.
+    fn parse_mmco5(reader: &mut BitReader, idr: bool) -> io::Result<bool> {
+        if idr {
+            // no_output_of_prior_pics_flag and long_term_reference_flag
+            reader.skip_bits(2)?;
+            return Ok(false);
+        }
+        let mut has_mmco5 = false;
+        if reader.read_flag()? {
+            loop {
+                match reader.read_ue()? {
+                    0 => break,
+                    1 | 2 | 4 | 6 => {
+                        reader.read_ue()?;
+                    }
+                    3 => {
+                        reader.read_ue()?;
+                        reader.read_ue()?;
+                    }
+                    5 => has_mmco5 = true,
+                    _ => return Err(invalid("memory_management_control_operation out of range")),
+                }
+            }
+        }
+        Ok(has_mmco5)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bits::BitWriter;
+    use crate::params::{test_pps, test_sps, SpsFields};
+
+    fn parameter_sets() -> ParameterSets {
+        let mut parameter_sets = ParameterSets::default();
+        parameter_sets.insert_sps(Sps::parse(&test_sps(SpsFields::default())).unwrap());
+        parameter_sets.insert_pps(Pps::parse(&test_pps(0, 0)).unwrap());
+        parameter_sets
+    }
+
+    /// Builds a reference P slice of the SPS and PPS above, with the given override of the number
+    /// of active reference indices and memory management control operations.
+    fn p_slice(num_ref_idx_active_minus1: Option<u32>, mmcos: &[u32]) -> Vec<u8> {
+        let mut writer = BitWriter::default()
+            .bits(8, 0x41)
+            .ue(0)
+            .ue(0)
+            .ue(0)
+            // frame_num and pic_order_cnt_lsb
+            .bits(4, 3)
+            .bits(6, 6)
+            .flag(num_ref_idx_active_minus1.is_some());
+        if let Some(num_ref_idx_active_minus1) = num_ref_idx_active_minus1 {
+            writer = writer.ue(num_ref_idx_active_minus1);
+        }
+        // ref_pic_list_modification_flag_l0, then adaptive_ref_pic_marking_mode_flag
+        writer = writer.flag(false).flag(!mmcos.is_empty());
+        for &mmco in mmcos {
+            writer = writer.ue(mmco);
+            if mmco != 5 {
+                writer = writer.ue(0);
+            }
+        }
+        if !mmcos.is_empty() {
+            writer = writer.ue(0);
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn test_slice_type() {
+        let slice = |slice_type| {
+            BitWriter::default()
+                .bits(8, 0x65)
+                .ue(0)
+                .ue(slice_type)
+                .finish()
+        };
+        assert_eq!(SliceType::parse(&slice(7)).unwrap(), SliceType::I);
+        assert_eq!(SliceType::parse(&slice(5)).unwrap(), SliceType::P);
+        assert!(SliceType::parse(&slice(10)).is_err());
+        assert!(SliceType::parse(&[0x67, 0x80]).is_err());
+    }
+
+    #[test]
+    fn test_slice_header() {
+        let parameter_sets = parameter_sets();
+        let header = SliceHeader::parse(&p_slice(None, &[]), &parameter_sets).unwrap();
+        assert_eq!((header.nal_unit_type, header.nal_ref_idc), (1, 2));
+        assert_eq!((header.frame_num, header.pic_order_cnt_lsb), (3, 6));
+        assert!(!header.field_pic && !header.has_mmco5);
+
+        let header = SliceHeader::parse(&p_slice(Some(31), &[1, 5]), &parameter_sets).unwrap();
+        assert!(header.has_mmco5);
+
+        assert!(SliceHeader::parse(&p_slice(None, &[]), &ParameterSets::default()).is_err());
+    }
+
+    #[test]
+    fn test_num_ref_idx_out_of_range() {
+        let parameter_sets = parameter_sets();
+        for num_ref_idx_active_minus1 in [32, u32::MAX - 1] {
+            let slice = p_slice(Some(num_ref_idx_active_minus1), &[]);
+            let err = SliceHeader::parse(&slice, &parameter_sets).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
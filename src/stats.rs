@@ -0,0 +1,181 @@
+use crate::{DecoderInputItem, DroppedFrames, Shared};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Counters updated by both halves of the queue. Everything is monotonic so that the hot path only
+/// needs relaxed increments; derived values like the depth are computed when a snapshot is taken.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pushed: AtomicU64,
+    popped: AtomicU64,
+    /// Frames removed from the queue by the overflow policy.
+    evicted: AtomicU64,
+    bytes_pushed: AtomicU64,
+    bytes_removed: AtomicU64,
+    high_watermark: AtomicU64,
+    producer_wait_nanos: AtomicU64,
+    consumer_wait_nanos: AtomicU64,
+}
+
+pub(crate) fn item_bytes<E>(item: &DecoderInputItem<E>) -> u64 {
+    match item {
+        DecoderInputItem::Frame(Ok(frame)) => frame.data.len() as u64,
+        _ => 0,
+    }
+}
+
+impl Counters {
+    pub fn record_push(&self, bytes: u64) {
+        let pushed = self.pushed.fetch_add(1, Ordering::Relaxed) + 1;
+        self.bytes_pushed.fetch_add(bytes, Ordering::Relaxed);
+        self.high_watermark
+            .fetch_max(pushed.saturating_sub(self.removed()), Ordering::Relaxed);
+    }
+
+    pub fn record_pop(&self, bytes: u64) {
+        self.popped.fetch_add(1, Ordering::Relaxed);
+        self.bytes_removed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_eviction(&self, bytes: u64) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+        self.bytes_removed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_producer_wait(&self, since: Instant) {
+        self.producer_wait_nanos
+            .fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_consumer_wait(&self, since: Instant) {
+        self.consumer_wait_nanos
+            .fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in_flight(&self) -> u64 {
+        let bytes_pushed = self.bytes_pushed.load(Ordering::Relaxed);
+        bytes_pushed.saturating_sub(self.bytes_removed.load(Ordering::Relaxed))
+    }
+
+    fn removed(&self) -> u64 {
+        self.popped.load(Ordering::Relaxed) + self.evicted.load(Ordering::Relaxed)
+    }
+}
+
+/// A handle for reading a queue's statistics from any thread. It can be cloned freely and stays
+/// valid after the queue itself has been dropped.
+#[derive(Clone)]
+pub struct QueueStats {
+    shared: Arc<Shared>,
+}
+
+impl QueueStats {
+    pub(crate) fn new(shared: Arc<Shared>) -> Self {
+        Self { shared }
+    }
+
+    pub fn snapshot(&self) -> QueueStatsSnapshot {
+        let counters = &self.shared.counters;
+        let pushed = counters.pushed.load(Ordering::Relaxed);
+        let popped = counters.popped.load(Ordering::Relaxed);
+        let evicted = counters.evicted.load(Ordering::Relaxed);
+        QueueStatsSnapshot {
+            taken_at: Instant::now(),
+            depth: pushed.saturating_sub(popped + evicted),
+            high_watermark: counters.high_watermark.load(Ordering::Relaxed),
+            pushed,
+            popped,
+            bytes_in_flight: counters.bytes_in_flight(),
+            producer_wait: Duration::from_nanos(
+                counters.producer_wait_nanos.load(Ordering::Relaxed),
+            ),
+            consumer_wait: Duration::from_nanos(
+                counters.consumer_wait_nanos.load(Ordering::Relaxed),
+            ),
+            dropped: self.shared.dropped.snapshot(),
+        }
+    }
+}
+
+/// A point-in-time view of a queue's statistics. The counters are updated independently, so they
+/// may be very slightly out of sync with each other.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueStatsSnapshot {
+    pub taken_at: Instant,
+    /// The number of frames currently in the queue.
+    pub depth: u64,
+    /// The largest depth seen so far.
+    pub high_watermark: u64,
+    /// The total number of frames that made it into the queue.
+    pub pushed: u64,
+    /// The total number of frames taken out by the consumer.
+    pub popped: u64,
+    /// The total size of the frames currently in the queue.
+    pub bytes_in_flight: u64,
+    /// The total time the producer has spent blocked in `push` waiting for space.
+    pub producer_wait: Duration,
+    /// The total time the consumer has spent blocked waiting for frames.
+    pub consumer_wait: Duration,
+    pub dropped: DroppedFrames,
+}
+
+impl QueueStatsSnapshot {
+    /// Returns the rate at which frames were pushed between an earlier snapshot and this one, in
+    /// frames per second.
+    pub fn push_rate_since(&self, earlier: &QueueStatsSnapshot) -> f64 {
+        Self::rate(
+            self.pushed.saturating_sub(earlier.pushed),
+            self.taken_at.saturating_duration_since(earlier.taken_at),
+        )
+    }
+
+    /// Returns the rate at which frames were popped between an earlier snapshot and this one, in
+    /// frames per second.
+    pub fn pop_rate_since(&self, earlier: &QueueStatsSnapshot) -> f64 {
+        Self::rate(
+            self.popped.saturating_sub(earlier.popped),
+            self.taken_at.saturating_duration_since(earlier.taken_at),
+        )
+    }
+
+    fn rate(frames: u64, elapsed: Duration) -> f64 {
+        if elapsed.is_zero() {
+            0.0
+        } else {
+            frames as f64 / elapsed.as_secs_f64()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{test_frame, DecoderInputQueue};
+    use std::time::Duration;
+
+    #[test]
+    fn test_stats() {
+        let (mut queue, mut producer) = DecoderInputQueue::<()>::new(4);
+        let stats = producer.stats();
+        for n in 0..3 {
+            producer.push(Ok(test_frame(n))).unwrap();
+        }
+        queue.next().unwrap().unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.depth, 2);
+        assert_eq!(snapshot.high_watermark, 3);
+        assert_eq!(snapshot.pushed, 3);
+        assert_eq!(snapshot.popped, 1);
+        assert_eq!(snapshot.bytes_in_flight, 10);
+        assert_eq!(snapshot.consumer_wait, Duration::ZERO);
+
+        queue.next().unwrap().unwrap();
+        queue.next().unwrap().unwrap();
+        assert!(queue.pop_timeout(Duration::from_millis(5)).is_err());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.depth, 0);
+        assert_eq!(snapshot.bytes_in_flight, 0);
+        assert!(snapshot.consumer_wait >= Duration::from_millis(5));
+    }
+}
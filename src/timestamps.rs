@@ -0,0 +1,331 @@
+use crate::params::Rational;
+
+/// The frame rate assumed when neither the stream nor the caller provides one.
+const DEFAULT_FRAME_RATE: Rational = Rational::new(25, 1);
+
+/// What the timestamper needs to know about a picture, whatever its codec. Picture order counts
+/// and durations are in the units the [`Timestamper`] was created with.
+pub(crate) struct PictureTiming {
+    pub poc: i64,
+    pub duration: i64,
+    /// Whether the picture order count restarts at this picture, as it does at an IDR picture.
+    pub starts_period: bool,
+    /// Whether pictures are output in decoding order, whatever their picture order counts.
+    pub decoding_order: bool,
+    pub max_num_reorder_frames: u32,
+    /// The frame rate signalled by the stream, if any.
+    pub frame_rate: Option<Rational>,
+}
+
+/// Derives presentation and decoding timestamps for access units in decoding order.
+///
+/// Time is tracked in the units picture order counts advance by, `units_per_frame` per frame: two
+/// for H.264, where they conventionally count fields, and one for H.265. Decoding timestamps
+/// advance by each picture's duration. Presentation timestamps follow the picture order count
+/// within each period between IDR pictures, and are delayed by the maximum reordering depth of the
+/// stream so that they're never earlier than the decoding timestamps.
+pub(crate) struct Timestamper {
+    timebase: Rational,
+    units_per_frame: i64,
+    /// The frame rate used when the stream has no timing info.
+    fallback_frame_rate: Rational,
+    frame_rate: Rational,
+    /// The point in time, in units and in the timebase, that the current frame rate applies from.
+    rate_base_units: i64,
+    rate_base_ticks: i64,
+    dts: i64,
+    /// The presentation time the current period starts at and the picture order count it maps to.
+    period: Option<(i64, i64)>,
+    /// The end of the latest picture in presentation order.
+    presentation_end: i64,
+    reorder_delay: i64,
+}
+
+impl Timestamper {
+    pub fn new(timebase: Rational, frame_rate: Option<Rational>, units_per_frame: i64) -> Self {
+        let frame_rate = frame_rate.unwrap_or(DEFAULT_FRAME_RATE);
+        Self {
+            timebase,
+            units_per_frame,
+            fallback_frame_rate: frame_rate,
+            frame_rate,
+            rate_base_units: 0,
+            rate_base_ticks: 0,
+            dts: 0,
+            period: None,
+            presentation_end: 0,
+            reorder_delay: 0,
+        }
+    }
+
+    /// Returns the presentation and decoding timestamps of the next access unit. Access units
+    /// whose pictures couldn't be parsed last a frame and are presented right after they're
+    /// decoded.
+    pub fn next(&mut self, picture: Option<&PictureTiming>) -> (i64, i64) {
+        let duration = picture.map_or(self.units_per_frame, |picture| picture.duration);
+        let dts = self.dts;
+        self.dts += duration;
+
+        let Some(picture) = picture else {
+            self.presentation_end = self.presentation_end.max(dts + duration);
+            return (self.to_ticks(dts + self.reorder_delay), self.to_ticks(dts));
+        };
+
+        let frame_rate = picture.frame_rate.unwrap_or(self.fallback_frame_rate);
+        if frame_rate != self.frame_rate {
+            self.rate_base_ticks = self.to_ticks(dts);
+            self.rate_base_units = dts;
+            self.frame_rate = frame_rate;
+        }
+        let presentation = if picture.decoding_order {
+            dts
+        } else {
+            self.reorder_delay = self
+                .reorder_delay
+                .max(self.units_per_frame * picture.max_num_reorder_frames as i64);
+            let (period_start, period_poc) = match self.period {
+                Some(period) if !picture.starts_period => period,
+                _ => *self.period.insert((self.presentation_end, picture.poc)),
+            };
+            period_start + picture.poc - period_poc
+        };
+        self.presentation_end = self.presentation_end.max(presentation + duration);
+        (
+            self.to_ticks(presentation + self.reorder_delay),
+            self.to_ticks(dts),
+        )
+    }
+
+    /// Converts a time in units to the timebase.
+    fn to_ticks(&self, units: i64) -> i64 {
+        let num = (units - self.rate_base_units) as i128
+            * self.frame_rate.den as i128
+            * self.timebase.den as i128;
+        let den =
+            self.units_per_frame as i128 * self.frame_rate.num as i128 * self.timebase.num as i128;
+        self.rate_base_ticks + num.div_euclid(den.max(1)) as i64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bits::BitWriter;
+    use crate::{read_frames_with_config, FramerConfig, Rational};
+
+    /// Builds an SPS for 1080p at 29.97 fps with the given picture order count fields.
+    fn sps(poc_fields: impl FnOnce(BitWriter) -> BitWriter, reorder: u32) -> Vec<u8> {
+        let writer = BitWriter::default()
+            .bits(8, 0x67)
+            .bits(8, 77)
+            .bits(8, 0)
+            .bits(8, 40)
+            .ue(0)
+            // log2_max_frame_num_minus4
+            .ue(0);
+        poc_fields(writer)
+            .ue(2)
+            .flag(false)
+            .ue(119)
+            .ue(67)
+            .flag(true)
+            .flag(true)
+            // frame_cropping_flag
+            .flag(false)
+            // vui_parameters_present_flag, then everything up to the timing info absent
+            .flag(true)
+            .bits(4, 0)
+            .flag(true)
+            .bits(32, 1001)
+            .bits(32, 60000)
+            .flag(true)
+            // no HRD parameters or pic_struct
+            .bits(3, 0)
+            // bitstream_restriction_flag
+            .flag(true)
+            .flag(true)
+            .ue(0)
+            .ue(0)
+            .ue(16)
+            .ue(16)
+            .ue(reorder)
+            .ue(reorder + 1)
+            .finish()
+    }
+
+    fn pps() -> Vec<u8> {
+        BitWriter::default()
+            .bits(8, 0x68)
+            .ue(0)
+            .ue(0)
+            .bits(2, 0)
+            .ue(0)
+            .ue(0)
+            .ue(0)
+            .bits(3, 0)
+            .se(0)
+            .se(0)
+            .se(0)
+            .flag(true)
+            .bits(2, 0)
+            .finish()
+    }
+
+    #[derive(Clone, Copy)]
+    enum Picture {
+        Idr,
+        P,
+        B,
+    }
+
+    /// Builds a single-slice picture. The picture order count fields are written by `poc_fields`.
+    fn slice(
+        picture: Picture,
+        frame_num: u64,
+        poc_fields: impl FnOnce(BitWriter) -> BitWriter,
+    ) -> Vec<u8> {
+        let (header, slice_type) = match picture {
+            Picture::Idr => (0x65, 7),
+            Picture::P => (0x41, 5),
+            Picture::B => (0x01, 6),
+        };
+        let mut writer = BitWriter::default()
+            .bits(8, header)
+            .ue(0)
+            .ue(slice_type)
+            .ue(0)
+            .bits(4, frame_num);
+        if let Picture::Idr = picture {
+            writer = writer.ue(0);
+        }
+        writer = poc_fields(writer);
+        writer = match picture {
+            // no_output_of_prior_pics_flag and long_term_reference_flag
+            Picture::Idr => writer.bits(2, 0),
+            // num_ref_idx_active_override_flag, ref_pic_list_modification_flag_l0 and
+            // adaptive_ref_pic_marking_mode_flag
+            Picture::P => writer.bits(3, 0),
+            // direct_spatial_mv_pred_flag, num_ref_idx_active_override_flag and both
+            // ref_pic_list_modification flags
+            Picture::B => writer.bits(4, 0),
+        };
+        writer.flag(true).finish()
+    }
+
+    fn timestamps(sps: Vec<u8>, slices: Vec<Vec<u8>>) -> Vec<(i64, i64)> {
+        let mut stream = vec![];
+        for nalu in [sps, pps()].into_iter().chain(slices) {
+            stream.extend_from_slice(&[0, 0, 0, 1]);
+            stream.extend_from_slice(&nalu);
+        }
+        read_frames_with_config(&stream, FramerConfig::default())
+            .into_iter()
+            .map(|frame| {
+                let frame = frame.unwrap();
+                (frame.pts, frame.dts)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_poc_type_0() {
+        let sps = sps(|writer| writer.ue(0).ue(2), 1);
+        let lsb = |lsb| move |writer: BitWriter| writer.bits(6, lsb);
+        let slices = vec![
+            slice(Picture::Idr, 0, lsb(0)),
+            slice(Picture::P, 1, lsb(6)),
+            slice(Picture::B, 2, lsb(2)),
+            slice(Picture::B, 2, lsb(4)),
+            slice(Picture::P, 2, lsb(12)),
+        ];
+        // A frame lasts 3003 ticks, and presentation is delayed by one frame for the reordering.
+        assert_eq!(
+            timestamps(sps, slices),
+            vec![
+                (3003, 0),
+                (12012, 3003),
+                (6006, 6006),
+                (9009, 9009),
+                (21021, 12012)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_poc_type_1() {
+        // Reference frames advance the count by 4 and non-reference frames sit 2 before them.
+        let sps = sps(|writer| writer.ue(1).flag(true).se(-2).se(0).ue(1).se(4), 1);
+        let none = |writer| writer;
+        let slices = vec![
+            slice(Picture::Idr, 0, none),
+            slice(Picture::P, 1, none),
+            slice(Picture::B, 2, none),
+            slice(Picture::P, 2, none),
+            slice(Picture::B, 3, none),
+        ];
+        assert_eq!(
+            timestamps(sps, slices),
+            vec![
+                (3003, 0),
+                (9009, 3003),
+                (6006, 6006),
+                (15015, 9009),
+                (12012, 12012)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_poc_type_2() {
+        let sps = sps(|writer| writer.ue(2), 0);
+        let none = |writer| writer;
+        let slices = vec![
+            slice(Picture::Idr, 0, none),
+            slice(Picture::P, 1, none),
+            slice(Picture::P, 2, none),
+        ];
+        assert_eq!(
+            timestamps(sps, slices),
+            vec![(0, 0), (3003, 3003), (6006, 6006)]
+        );
+    }
+
+    #[test]
+    fn test_caller_frame_rate_and_timebase() {
+        // Without timing info in the SPS, the configured frame rate is used.
+        let sps = BitWriter::default()
+            .bits(8, 0x67)
+            .bits(8, 66)
+            .bits(8, 0)
+            .bits(8, 30)
+            .ue(0)
+            .ue(0)
+            .ue(2)
+            .ue(1)
+            .flag(false)
+            .ue(19)
+            .ue(14)
+            .flag(true)
+            .flag(true)
+            .bits(2, 0)
+            .finish();
+        let mut stream = vec![];
+        for nalu in [sps, pps()] {
+            stream.extend_from_slice(&[0, 0, 0, 1]);
+            stream.extend_from_slice(&nalu);
+        }
+        for (picture, frame_num) in [(Picture::Idr, 0), (Picture::P, 1), (Picture::P, 2)] {
+            stream.extend_from_slice(&[0, 0, 0, 1]);
+            stream.extend_from_slice(&slice(picture, frame_num, |writer| writer));
+        }
+        let config = FramerConfig {
+            timebase: Rational::new(1, 1000),
+            frame_rate: Some(Rational::new(50, 1)),
+            ..FramerConfig::default()
+        };
+        let pts: Vec<_> = read_frames_with_config(&stream, config)
+            .into_iter()
+            .map(|frame| frame.unwrap().pts)
+            .collect();
+        assert_eq!(pts, vec![0, 20, 40]);
+    }
+}
@@ -0,0 +1,768 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use xcoder_quadra::decoder::XcoderDecoderInputFrame;
+
+use crate::framer::{Codec, ErrorPolicy};
+use crate::metadata::{FrameInspector, FrameMetadata};
+use crate::params::Rational;
+
+const PACKET_LEN: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+const STREAM_TYPE_H264: u8 = 0x1b;
+const STREAM_TYPE_H265: u8 = 0x24;
+/// PTS and DTS are 33-bit counters of a 90 kHz clock.
+const TIMESTAMP_WRAP: i64 = 1 << 33;
+
+/// An error demuxing an MPEG transport stream. Offsets are in bytes from the start of the stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TsError {
+    /// The input doesn't start with a sync byte where a packet should. Bytes up to the next sync
+    /// byte are dropped.
+    LostSync { offset: u64 },
+    /// A packet has its transport error indicator set, and was dropped.
+    TransportError { offset: u64 },
+    /// A packet's adaptation field overruns it.
+    InvalidPacket { offset: u64 },
+    /// A packet of a PID has an unexpected continuity counter, so packets were lost. The PES
+    /// packet or section in progress on that PID is dropped.
+    ContinuityError {
+        pid: u16,
+        offset: u64,
+        expected: u8,
+        found: u8,
+    },
+    /// The video PID signalled a discontinuity, so its timestamps may jump.
+    Discontinuity { pid: u16, offset: u64 },
+    /// A PAT or PMT section is truncated or fails its CRC.
+    InvalidSection { pid: u16 },
+    /// The program has no H.264 or H.265 stream.
+    NoVideoStream { program_number: u16 },
+    /// A PES packet of the video PID has an invalid header.
+    InvalidPes { offset: u64 },
+}
+
+impl fmt::Display for TsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LostSync { offset } => write!(f, "lost sync at offset {offset}"),
+            Self::TransportError { offset } => {
+                write!(f, "transport error in packet at offset {offset}")
+            }
+            Self::InvalidPacket { offset } => write!(f, "invalid packet at offset {offset}"),
+            Self::ContinuityError {
+                pid,
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "continuity error on PID {pid} at offset {offset}: expected {expected}, found {found}"
+            ),
+            Self::Discontinuity { pid, offset } => {
+                write!(f, "discontinuity on PID {pid} at offset {offset}")
+            }
+            Self::InvalidSection { pid } => write!(f, "invalid section on PID {pid}"),
+            Self::NoVideoStream { program_number } => {
+                write!(f, "program {program_number} has no H.264 or H.265 stream")
+            }
+            Self::InvalidPes { offset } => write!(f, "invalid PES packet at offset {offset}"),
+        }
+    }
+}
+
+impl Error for TsError {}
+
+/// The CRC-32 of MPEG-2 sections. It's zero over a whole section, CRC included.
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(u32::MAX, |crc, &byte| {
+        (0..8).fold(crc ^ (u32::from(byte) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn u16_at(buf: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes([buf[i], buf[i + 1]])
+}
+
+/// Reads a 33-bit PTS or DTS field.
+fn read_timestamp(buf: &[u8]) -> i64 {
+    (i64::from(buf[0] >> 1 & 0x07) << 30)
+        | (i64::from(buf[1]) << 22)
+        | (i64::from(buf[2] >> 1) << 15)
+        | (i64::from(buf[3]) << 7)
+        | i64::from(buf[4] >> 1)
+}
+
+/// The elementary stream picked from the PMT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct VideoStream {
+    pid: u16,
+    codec: Codec,
+}
+
+/// A PES packet being reassembled.
+struct Pes {
+    data: Vec<u8>,
+    /// The stream offset of the packet that started it.
+    offset: u64,
+}
+
+impl Pes {
+    /// The length of the whole PES packet, if its header gives one. Video PES packets often leave
+    /// it unbounded, in which case they end with the next one.
+    fn len(&self) -> Option<usize> {
+        match self.data.get(4..6) {
+            Some(&[0, 0]) | None => None,
+            Some(len) => Some(6 + usize::from(u16::from_be_bytes([len[0], len[1]]))),
+        }
+    }
+}
+
+/// Demuxes the video stream of an MPEG transport stream as it arrives, one frame per PES packet.
+///
+/// The first program of the PAT is followed, and its first H.264 or H.265 stream is picked from
+/// its PMT. The PES packets of that stream are emitted with their PTS and DTS, in 90 kHz ticks and
+/// unwrapped past 33 bits. A PES packet without a PTS takes the timestamps of the one before it,
+/// and one without a DTS uses its PTS.
+///
+/// Continuity counters are checked on the PIDs the demuxer reads, and a gap drops the PES packet
+/// or section in progress on that PID. Lost packets, lost sync and signalled discontinuities are
+/// handled according to the [`ErrorPolicy`].
+pub struct TsDemuxer {
+    error_policy: ErrorPolicy,
+    /// Input that hasn't been split into packets yet.
+    pending: Vec<u8>,
+    /// The stream offset of the start of `pending`.
+    offset: u64,
+    /// Cleared when sync is lost, so that it's only reported once.
+    synced: bool,
+    stopped: bool,
+    program_number: Option<u16>,
+    pmt_pid: Option<u16>,
+    pmt_version: Option<u8>,
+    video: Option<VideoStream>,
+    continuity: HashMap<u16, u8>,
+    /// Sections being reassembled, by PID.
+    sections: HashMap<u16, Vec<u8>>,
+    pes: Option<Pes>,
+    /// The last unwrapped DTS.
+    last_dts: Option<i64>,
+    last_pts: Option<i64>,
+    /// Describes the frames of the video stream, once it has been picked.
+    inspector: Option<FrameInspector>,
+    ready: VecDeque<Result<(XcoderDecoderInputFrame, FrameMetadata), TsError>>,
+}
+
+impl Default for TsDemuxer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TsDemuxer {
+    pub fn new() -> Self {
+        Self::with_error_policy(ErrorPolicy::default())
+    }
+
+    pub fn with_error_policy(error_policy: ErrorPolicy) -> Self {
+        Self {
+            error_policy,
+            pending: Vec::new(),
+            offset: 0,
+            synced: true,
+            stopped: false,
+            program_number: None,
+            pmt_pid: None,
+            pmt_version: None,
+            video: None,
+            continuity: HashMap::new(),
+            sections: HashMap::new(),
+            pes: None,
+            last_dts: None,
+            last_pts: None,
+            inspector: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Returns the PID of the video stream, once the PMT has been read.
+    pub fn pid(&self) -> Option<u16> {
+        self.video.map(|video| video.pid)
+    }
+
+    /// Returns the codec of the video stream, once the PMT has been read.
+    pub fn codec(&self) -> Option<Codec> {
+        self.video.map(|video| video.codec)
+    }
+
+    /// The timebase of the timestamps, in seconds per tick.
+    pub fn timebase(&self) -> Rational {
+        Rational::new(1, 90000)
+    }
+
+    /// Adds a chunk of input and returns the frames it completed.
+    pub fn push(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = Result<XcoderDecoderInputFrame, TsError>> + '_ {
+        self.push_with_metadata(chunk)
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+
+    /// Ends the input, returning the last frame. The demuxer can be reused for another stream
+    /// afterwards.
+    pub fn finish(
+        &mut self,
+    ) -> impl Iterator<Item = Result<XcoderDecoderInputFrame, TsError>> + '_ {
+        self.finish_with_metadata()
+            .map(|result| result.map(|(frame, _)| frame))
+    }
+
+    /// Like [`push`](Self::push), but also describes each frame.
+    pub fn push_with_metadata(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = Result<(XcoderDecoderInputFrame, FrameMetadata), TsError>> + '_ {
+        if !self.stopped {
+            self.pending.extend_from_slice(chunk);
+            self.split_pending();
+        }
+        self.ready.drain(..)
+    }
+
+    /// Like [`finish`](Self::finish), but also describes each frame.
+    pub fn finish_with_metadata(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(XcoderDecoderInputFrame, FrameMetadata), TsError>> + '_ {
+        if !self.stopped {
+            if let Some(pes) = self.pes.take() {
+                self.emit_pes(pes);
+            }
+        }
+        let ready = mem::take(&mut self.ready);
+        *self = Self {
+            ready,
+            ..Self::with_error_policy(self.error_policy)
+        };
+        self.ready.drain(..)
+    }
+
+    fn split_pending(&mut self) {
+        let pending = mem::take(&mut self.pending);
+        let mut start = 0;
+        while !self.stopped && pending.len() - start >= PACKET_LEN {
+            if pending[start] != SYNC_BYTE {
+                if self.synced {
+                    self.synced = false;
+                    self.fail(TsError::LostSync {
+                        offset: self.offset + start as u64,
+                    });
+                }
+                start = pending[start + 1..]
+                    .iter()
+                    .position(|&byte| byte == SYNC_BYTE)
+                    .map_or(pending.len(), |i| start + 1 + i);
+                continue;
+            }
+            self.synced = true;
+            let packet = &pending[start..start + PACKET_LEN];
+            self.add_packet(packet, self.offset + start as u64);
+            start += PACKET_LEN;
+        }
+        if !self.stopped {
+            self.pending = pending;
+            self.pending.drain(..start);
+            self.offset += start as u64;
+        }
+    }
+
+    fn add_packet(&mut self, packet: &[u8], offset: u64) {
+        if packet[1] & 0x80 != 0 {
+            self.fail(TsError::TransportError { offset });
+            return;
+        }
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16_at(packet, 1) & 0x1fff;
+        let adaptation_field_control = packet[3] >> 4 & 0x03;
+        let continuity_counter = packet[3] & 0x0f;
+        let video_pid = self.pid();
+        if pid != PAT_PID && Some(pid) != self.pmt_pid && Some(pid) != video_pid {
+            return;
+        }
+
+        let mut payload_start = 4;
+        let mut discontinuity = false;
+        if adaptation_field_control & 0x02 != 0 {
+            let len = usize::from(packet[4]);
+            payload_start = 5 + len;
+            if payload_start > PACKET_LEN {
+                self.fail(TsError::InvalidPacket { offset });
+                return;
+            }
+            discontinuity = len > 0 && packet[5] & 0x80 != 0;
+        }
+        if discontinuity && Some(pid) == video_pid {
+            self.last_dts = None;
+            self.last_pts = None;
+            self.fail(TsError::Discontinuity { pid, offset });
+        }
+        // Packets without a payload don't advance the counter.
+        if adaptation_field_control & 0x01 == 0 {
+            return;
+        }
+        if let Some(last) = self.continuity.insert(pid, continuity_counter) {
+            let expected = (last + 1) & 0x0f;
+            if continuity_counter == last && !discontinuity {
+                // A packet may be sent twice in a row.
+                return;
+            }
+            if continuity_counter != expected && !discontinuity {
+                self.sections.remove(&pid);
+                if Some(pid) == video_pid {
+                    self.pes = None;
+                }
+                self.fail(TsError::ContinuityError {
+                    pid,
+                    offset,
+                    expected,
+                    found: continuity_counter,
+                });
+            }
+        }
+
+        let payload = &packet[payload_start..];
+        if Some(pid) == video_pid {
+            self.add_pes_payload(payload, unit_start, offset);
+        } else {
+            self.add_psi_payload(pid, payload, unit_start);
+        }
+    }
+
+    fn add_pes_payload(&mut self, payload: &[u8], unit_start: bool, offset: u64) {
+        if unit_start {
+            if let Some(pes) = self.pes.take() {
+                self.emit_pes(pes);
+            }
+            self.pes = Some(Pes {
+                data: Vec::new(),
+                offset,
+            });
+        }
+        let Some(pes) = &mut self.pes else {
+            // The start of this PES packet was lost.
+            return;
+        };
+        pes.data.extend_from_slice(payload);
+        if pes.len().is_some_and(|len| pes.data.len() >= len) {
+            let pes = self.pes.take().unwrap();
+            self.emit_pes(pes);
+        }
+    }
+
+    fn emit_pes(&mut self, mut pes: Pes) {
+        if let Some(len) = pes.len() {
+            pes.data.truncate(len);
+        }
+        let data = &pes.data;
+        if data.len() < 9 || data[..3] != [0, 0, 1] || data[6] & 0xc0 != 0x80 {
+            self.fail(TsError::InvalidPes { offset: pes.offset });
+            return;
+        }
+        let flags = data[7] >> 6;
+        let header_end = 9 + usize::from(data[8]);
+        let timestamps_len = match flags {
+            0b10 => 5,
+            0b11 => 10,
+            _ => 0,
+        };
+        if header_end > data.len() || 9 + timestamps_len > header_end {
+            self.fail(TsError::InvalidPes { offset: pes.offset });
+            return;
+        }
+        let (pts, dts) = match flags {
+            0b10 => {
+                let pts = self.unwrap_timestamp(read_timestamp(&data[9..]));
+                (pts, pts)
+            }
+            0b11 => {
+                let dts = self.unwrap_timestamp(read_timestamp(&data[14..]));
+                let pts = dts + (read_timestamp(&data[9..]) - dts).rem_euclid(TIMESTAMP_WRAP);
+                (pts, dts)
+            }
+            _ => (
+                self.last_pts.unwrap_or_default(),
+                self.last_dts.unwrap_or_default(),
+            ),
+        };
+        self.last_pts = Some(pts);
+        self.last_dts = Some(dts);
+        if header_end < data.len() {
+            pes.data.drain(..header_end);
+            let Some(inspector) = &mut self.inspector else {
+                return;
+            };
+            let metadata = inspector.inspect(&pes.data);
+            let frame = XcoderDecoderInputFrame {
+                data: pes.data,
+                pts,
+                dts,
+            };
+            self.ready.push_back(Ok((frame, metadata)));
+        }
+    }
+
+    /// Extends a 33-bit timestamp to the unwrapped value nearest the last DTS.
+    fn unwrap_timestamp(&self, timestamp: i64) -> i64 {
+        let Some(last) = self.last_dts else {
+            return timestamp;
+        };
+        let base = last - last.rem_euclid(TIMESTAMP_WRAP);
+        let unwrapped = base + timestamp;
+        if unwrapped - last > TIMESTAMP_WRAP / 2 {
+            unwrapped - TIMESTAMP_WRAP
+        } else if last - unwrapped > TIMESTAMP_WRAP / 2 {
+            unwrapped + TIMESTAMP_WRAP
+        } else {
+            unwrapped
+        }
+    }
+
+    fn add_psi_payload(&mut self, pid: u16, payload: &[u8], unit_start: bool) {
+        let mut buf = self.sections.remove(&pid).unwrap_or_default();
+        let mut next = None;
+        if unit_start {
+            let Some((&pointer, rest)) = payload.split_first() else {
+                return;
+            };
+            let (tail, head) = rest.split_at(usize::from(pointer).min(rest.len()));
+            buf.extend_from_slice(tail);
+            next = Some(head);
+        } else if !buf.is_empty() {
+            buf.extend_from_slice(payload);
+        }
+        self.parse_sections(pid, &mut buf);
+        if let Some(head) = next {
+            buf = head.to_vec();
+            self.parse_sections(pid, &mut buf);
+        }
+        if !buf.is_empty() {
+            self.sections.insert(pid, buf);
+        }
+    }
+
+    /// Parses the complete sections at the start of `buf`, leaving any partial one.
+    fn parse_sections(&mut self, pid: u16, buf: &mut Vec<u8>) {
+        while buf.len() >= 3 {
+            if buf[0] == 0xff {
+                // Stuffing runs to the end of the packet.
+                buf.clear();
+                return;
+            }
+            let len = 3 + usize::from(u16_at(buf, 1) & 0x0fff);
+            if buf.len() < len {
+                return;
+            }
+            let section: Vec<u8> = buf.drain(..len).collect();
+            if len < 12 || crc32(&section) != 0 {
+                self.fail(TsError::InvalidSection { pid });
+                continue;
+            }
+            // Sections that aren't current yet are ignored.
+            if section[5] & 0x01 == 0 {
+                continue;
+            }
+            let body = &section[8..len - 4];
+            match (pid, section[0]) {
+                (PAT_PID, 0x00) => self.parse_pat(body),
+                (_, 0x02) if Some(pid) == self.pmt_pid => {
+                    let version = section[5] >> 1 & 0x1f;
+                    if self.pmt_version != Some(version) {
+                        self.pmt_version = Some(version);
+                        self.parse_pmt(pid, body);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn parse_pat(&mut self, body: &[u8]) {
+        let program = body
+            .chunks_exact(4)
+            .map(|entry| (u16_at(entry, 0), u16_at(entry, 2) & 0x1fff))
+            .find(|&(program_number, _)| program_number != 0);
+        if let Some((program_number, pmt_pid)) = program {
+            if self.pmt_pid != Some(pmt_pid) {
+                self.program_number = Some(program_number);
+                self.pmt_pid = Some(pmt_pid);
+                self.pmt_version = None;
+            }
+        }
+    }
+
+    fn parse_pmt(&mut self, pid: u16, body: &[u8]) {
+        let Some(program_info_len) = body.get(2..4).map(|len| u16_at(len, 0) & 0x0fff) else {
+            self.fail(TsError::InvalidSection { pid });
+            return;
+        };
+        let mut start = 4 + usize::from(program_info_len);
+        let mut video = None;
+        while let Some(entry) = body.get(start..start + 5) {
+            let codec = match entry[0] {
+                STREAM_TYPE_H264 => Some(Codec::H264),
+                STREAM_TYPE_H265 => Some(Codec::H265),
+                _ => None,
+            };
+            if let Some(codec) = codec {
+                video = Some(VideoStream {
+                    pid: u16_at(entry, 1) & 0x1fff,
+                    codec,
+                });
+                break;
+            }
+            start += 5 + usize::from(u16_at(entry, 3) & 0x0fff);
+        }
+        if video.is_none() {
+            self.fail(TsError::NoVideoStream {
+                program_number: self.program_number.unwrap_or_default(),
+            });
+        }
+        if video != self.video {
+            self.video = video;
+            self.inspector = video.map(|video| FrameInspector::new(video.codec));
+            self.pes = None;
+            self.last_dts = None;
+            self.last_pts = None;
+        }
+    }
+
+    fn fail(&mut self, err: TsError) {
+        match self.error_policy {
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Stop => {
+                self.pending.clear();
+                self.pes = None;
+                self.stopped = true;
+                self.ready.push_back(Err(err));
+            }
+            ErrorPolicy::Propagate => self.ready.push_back(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PMT_PID: u16 = 0x100;
+    const VIDEO_PID: u16 = 0x101;
+
+    fn packet(pid: u16, unit_start: bool, cc: u8, discontinuity: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            SYNC_BYTE,
+            (pid >> 8) as u8 | if unit_start { 0x40 } else { 0 },
+            pid as u8,
+            cc,
+        ];
+        let stuffing = PACKET_LEN - 4 - payload.len();
+        if stuffing > 0 || discontinuity {
+            packet[3] |= 0x30;
+            let len = stuffing.max(2) - 1;
+            packet.push(len as u8);
+            if len > 0 {
+                packet.push(if discontinuity { 0x80 } else { 0 });
+                packet.resize(5 + len, 0xff);
+            }
+        } else {
+            packet[3] |= 0x10;
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn section(table_id: u8, body: &[u8]) -> Vec<u8> {
+        let len = 5 + body.len() + 4;
+        let mut section = vec![
+            table_id,
+            0xb0 | (len >> 8) as u8,
+            len as u8,
+            0,
+            1,
+            0xc1,
+            0,
+            0,
+        ];
+        section.extend_from_slice(body);
+        section.extend_from_slice(&crc32(&section).to_be_bytes());
+        section
+    }
+
+    fn psi_packet(pid: u16, section: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0];
+        payload.extend_from_slice(section);
+        payload.resize(PACKET_LEN - 4, 0xff);
+        packet(pid, true, 0, false, &payload)
+    }
+
+    fn program(stream_type: u8) -> Vec<u8> {
+        let mut stream = psi_packet(PAT_PID, &section(0x00, &[0, 1, 0xe1, 0x00]));
+        stream.extend_from_slice(&psi_packet(
+            PMT_PID,
+            &section(
+                0x02,
+                &[
+                    0xe1,
+                    0x01,
+                    0xf0,
+                    0x00,
+                    0x0f,
+                    0xe1,
+                    0x02,
+                    0xf0,
+                    0x00,
+                    stream_type,
+                    0xe1,
+                    0x01,
+                    0xf0,
+                    0x00,
+                ],
+            ),
+        ));
+        stream
+    }
+
+    fn timestamp(prefix: u8, value: i64) -> [u8; 5] {
+        [
+            prefix << 4 | (value >> 29 & 0x0e) as u8 | 1,
+            (value >> 22) as u8,
+            (value >> 14) as u8 | 1,
+            (value >> 7) as u8,
+            (value << 1) as u8 | 1,
+        ]
+    }
+
+    fn pes(pts: i64, dts: Option<i64>, data: &[u8]) -> Vec<u8> {
+        let mut pes = vec![0, 0, 1, 0xe0, 0, 0, 0x80];
+        match dts {
+            Some(dts) => {
+                pes.extend_from_slice(&[0xc0, 10]);
+                pes.extend_from_slice(&timestamp(0b0011, pts));
+                pes.extend_from_slice(&timestamp(0b0001, dts));
+            }
+            None => {
+                pes.extend_from_slice(&[0x80, 5]);
+                pes.extend_from_slice(&timestamp(0b0010, pts));
+            }
+        }
+        pes.extend_from_slice(data);
+        pes
+    }
+
+    /// Splits a PES packet into transport packets, starting at continuity counter `cc`.
+    fn pes_packets(pes: &[u8], cc: &mut u8) -> Vec<u8> {
+        let mut stream = vec![];
+        for (i, chunk) in pes.chunks(PACKET_LEN - 4).enumerate() {
+            stream.extend_from_slice(&packet(VIDEO_PID, i == 0, *cc, false, chunk));
+            *cc = (*cc + 1) & 0x0f;
+        }
+        stream
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut stream = program(STREAM_TYPE_H265);
+        let mut cc = 0;
+        let frames = [
+            (vec![0, 0, 0, 1, 0x40, 1, 0xaa], 3003, Some(0)),
+            (vec![0x55; 500], 1501, Some(1501)),
+            (vec![0x66; 30], TIMESTAMP_WRAP - 1500, None),
+            (vec![0x77; 30], 1502, Some(1501)),
+        ];
+        for (data, pts, dts) in &frames {
+            stream.extend_from_slice(&pes_packets(&pes(*pts, *dts, data), &mut cc));
+        }
+
+        let mut demuxer = TsDemuxer::new();
+        let mut results = vec![];
+        for chunk in stream.chunks(100) {
+            results.extend(demuxer.push(chunk).map(Result::unwrap));
+        }
+        assert_eq!(demuxer.pid(), Some(VIDEO_PID));
+        assert_eq!(demuxer.codec(), Some(Codec::H265));
+        results.extend(demuxer.finish().map(Result::unwrap));
+
+        // The third frame wraps backwards, and the fourth wraps forwards again.
+        let timestamps = [(3003, 0), (1501, 1501), (-1500, -1500), (1502, 1501)];
+        assert_eq!(results.len(), 4);
+        for ((result, (data, _, _)), timestamps) in results.iter().zip(&frames).zip(timestamps) {
+            assert_eq!(&result.data, data);
+            assert_eq!((result.pts, result.dts), timestamps);
+        }
+
+        let mut demuxer = TsDemuxer::new();
+        let mut metadata: Vec<_> = demuxer
+            .push_with_metadata(&stream)
+            .map(|result| result.unwrap().1)
+            .collect();
+        metadata.extend(
+            demuxer
+                .finish_with_metadata()
+                .map(|result| result.unwrap().1),
+        );
+        assert_eq!(metadata.len(), 4);
+        assert_eq!(metadata[0].codec, Codec::H265);
+        assert_eq!(metadata[0].nal_units[0].nal_unit_type, 32);
+        assert_eq!(metadata[1].size, 500);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut stream = program(STREAM_TYPE_H264);
+        let mut cc = 0;
+        stream.extend_from_slice(&pes_packets(&pes(0, None, &[0x11; 300]), &mut cc));
+        // Lose the first packet of the next PES packet, leaving the second one orphaned.
+        let lost = pes_packets(&pes(3000, None, &[0x22; 300]), &mut cc);
+        stream.extend_from_slice(&lost[PACKET_LEN..]);
+        stream.extend_from_slice(&[0x00; 10]);
+        stream.extend_from_slice(&packet(
+            VIDEO_PID,
+            true,
+            7,
+            true,
+            &pes(100, None, &[0x33; 20]),
+        ));
+
+        let mut demuxer = TsDemuxer::new();
+        let mut results: Vec<_> = demuxer.push(&stream).collect();
+        results.extend(demuxer.finish());
+        let lost_offset = 4 * PACKET_LEN as u64;
+        let discontinuity_offset = 5 * PACKET_LEN as u64 + 10;
+        assert!(matches!(
+            results[..],
+            [
+                Err(TsError::ContinuityError {
+                    pid: VIDEO_PID,
+                    offset: o1,
+                    expected: 2,
+                    found: 3,
+                }),
+                Err(TsError::LostSync { offset: o2 }),
+                Err(TsError::Discontinuity {
+                    pid: VIDEO_PID,
+                    offset: o3,
+                }),
+                Ok(_),
+            ] if o1 == lost_offset && o2 == lost_offset + PACKET_LEN as u64 && o3 == discontinuity_offset
+        ));
+        let frame = results[3].as_ref().unwrap();
+        assert_eq!((&frame.data[..], frame.pts), (&[0x33; 20][..], 100));
+    }
+}
@@ -0,0 +1,147 @@
+use std::hint;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Determines what one side of the queue does while it waits for the other side, trading latency
+/// for CPU usage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Busy-spin. This has the lowest latency, but burns a full core while waiting.
+    #[default]
+    Spin,
+    /// Busy-spin for the given number of attempts, then yield to the scheduler between attempts.
+    SpinThenYield { spins: u32 },
+    /// Sleep between attempts, starting at `min` and doubling up to `max`.
+    Backoff { min: Duration, max: Duration },
+    /// Park the thread until the other side of the queue makes progress.
+    Block,
+}
+
+/// Wakes up threads that are waiting with [`WaitStrategy::Block`], as well as any registered
+/// async task.
+#[derive(Default)]
+pub(crate) struct Signal {
+    waker: Mutex<Option<Waker>>,
+    condvar: Condvar,
+    /// The number of blocked threads plus one if a waker is registered.
+    waiters: AtomicUsize,
+}
+
+impl Signal {
+    /// Wakes up all waiters. This is cheap when nobody is waiting.
+    pub fn notify(&self) {
+        // Pairs with the fences in `block_until` and `register` so that either the waiter sees our
+        // progress or we see the waiter.
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            let waker = {
+                let mut registered = self.waker.lock().unwrap_or_else(PoisonError::into_inner);
+                let waker = registered.take();
+                if waker.is_some() {
+                    self.waiters.fetch_sub(1, Ordering::Relaxed);
+                }
+                waker
+            };
+            self.condvar.notify_all();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Registers a task to be woken by the next notification. Callers must check for progress
+    /// again after registering.
+    #[cfg(feature = "async")]
+    pub fn register(&self, waker: &Waker) {
+        let mut registered = self.waker.lock().unwrap_or_else(PoisonError::into_inner);
+        match &mut *registered {
+            Some(registered) => registered.clone_from(waker),
+            None => {
+                *registered = Some(waker.clone());
+                self.waiters.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    /// Blocks until `ready` returns true or the deadline passes.
+    fn block_until(&self, deadline: Option<Instant>, mut ready: impl FnMut() -> bool) {
+        let mut guard = self.waker.lock().unwrap_or_else(PoisonError::into_inner);
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        while !ready() {
+            guard = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    self.condvar
+                        .wait_timeout(guard, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .condvar
+                    .wait(guard)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Tracks the state of a single wait, e.g. the current backoff delay.
+pub(crate) struct Waiter {
+    strategy: WaitStrategy,
+    attempts: u32,
+    delay: Duration,
+}
+
+impl Waiter {
+    pub fn new(strategy: WaitStrategy) -> Self {
+        Self {
+            strategy,
+            attempts: 0,
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Pauses before the next attempt. Returns false without pausing if the deadline has already
+    /// passed. With [`WaitStrategy::Block`] this sleeps on `signal` until `ready` returns true or
+    /// the deadline passes.
+    pub fn wait(
+        &mut self,
+        signal: &Signal,
+        deadline: Option<Instant>,
+        ready: impl FnMut() -> bool,
+    ) -> bool {
+        let remaining = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return false,
+            },
+            None => None,
+        };
+        match self.strategy {
+            WaitStrategy::Spin => hint::spin_loop(),
+            WaitStrategy::SpinThenYield { spins } => {
+                if self.attempts < spins {
+                    hint::spin_loop();
+                } else {
+                    thread::yield_now();
+                }
+            }
+            WaitStrategy::Backoff { min, max } => {
+                self.delay = (self.delay * 2).max(min).min(max);
+                thread::sleep(remaining.map_or(self.delay, |r| r.min(self.delay)));
+            }
+            WaitStrategy::Block => signal.block_until(deadline, ready),
+        }
+        self.attempts = self.attempts.saturating_add(1);
+        true
+    }
+}